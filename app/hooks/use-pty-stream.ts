@@ -4,7 +4,8 @@
  * same protocol as useTerminalCore but without any xterm.js dependency.
  */
 import { useEffect, useRef, useState, useCallback } from "react";
-import { WS_MSG, type Session } from "../../shared/types";
+import { WS_MSG, CAP, type Session } from "../../shared/types";
+import { encodeHelloMessage } from "../lib/ws-messages";
 
 export interface PtyStreamCallbacks {
   /** Raw PTY output data */
@@ -64,6 +65,9 @@ export function usePtyStream(wsPath: string, callbacks: PtyStreamCallbacks) {
         setStatus("connected");
         lastServerMessage = Date.now();
 
+        ws.send(encodeHelloMessage(
+          CAP.BUFFER_REPLAY_GZ | CAP.TITLE | CAP.NOTIFICATION | CAP.SESSION_STATE | CAP.SESSION_METRICS,
+        ));
         // RESUME from current offset
         const resumeMsg = new Uint8Array(9);
         resumeMsg[0] = WS_MSG.RESUME;
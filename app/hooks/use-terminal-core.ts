@@ -9,7 +9,8 @@ import type { Terminal } from "@xterm/xterm";
 import type { FitAddon } from "@xterm/addon-fit";
 import type { WebglAddon } from "@xterm/addon-webgl";
 import type { SearchAddon } from "@xterm/addon-search";
-import { WS_MSG, type Session } from "../../shared/types";
+import { WS_MSG, CAP, type Session } from "../../shared/types";
+import { encodeHelloMessage } from "../lib/ws-messages";
 import { loadCache, deleteCache, BufferCacheWriter } from "../lib/buffer-cache";
 import { createFileLinkProvider, type FileLink } from "../lib/file-link-provider";
 import { normalizeSgrColors } from "../lib/sgr-normalize";
@@ -143,7 +144,7 @@ export interface TerminalCoreOpts {
   onSessionUpdate?: (session: Session) => void;
   /** Called when a CLIPBOARD message arrives from another device (cross-device clipboard sync) */
   onClipboard?: (text: string) => void;
-  /** Called when an IMAGE message arrives (iTerm2 OSC 1337 inline image) */
+  /** Called when an IMAGE message arrives (iTerm2 OSC 1337, kitty graphics or sixel inline image) */
   onImage?: (image: { id: string; blobUrl: string }) => void;
   /** Ref to a boolean that, when true, disables touch scroll interception for text selection */
   selectionModeRef?: React.RefObject<boolean>;
@@ -1126,7 +1127,11 @@ export function useTerminalCore(containerRef: React.RefObject<HTMLDivElement | n
         setStatus("connected");
         lastServerMessage = Date.now();
 
-        // RESUME must be sent before RESIZE to arrive within the 100ms handshake window
+        // HELLO, then RESUME before RESIZE so RESIZE doesn't start the replay
+        ws.send(encodeHelloMessage(
+          CAP.BUFFER_REPLAY_GZ | CAP.TITLE | CAP.NOTIFICATION | CAP.SESSION_STATE |
+          CAP.SESSION_METRICS | CAP.CLIPBOARD | CAP.IMAGE,
+        ));
         const resumeMsg = new Uint8Array(9);
         resumeMsg[0] = WS_MSG.RESUME;
         new DataView(resumeMsg.buffer).setFloat64(1, byteOffset, false);
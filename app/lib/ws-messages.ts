@@ -1,4 +1,15 @@
-import { WS_MSG } from "../../shared/types";
+import { WS_MSG, PROTOCOL_VERSION } from "../../shared/types";
+import type { SearchRequest, WatchRule } from "../../shared/types";
+
+/** Encode a HELLO declaring the optional message types this view handles (see CAP). */
+export function encodeHelloMessage(capabilities: number): Uint8Array {
+  const msg = new Uint8Array(7);
+  msg[0] = WS_MSG.HELLO;
+  const view = new DataView(msg.buffer);
+  view.setUint16(1, PROTOCOL_VERSION, false);
+  view.setUint32(3, capabilities, false);
+  return msg;
+}
 
 /** Encode keyboard/text input as a WS DATA frame. */
 export function encodeDataMessage(text: string): Uint8Array {
@@ -22,3 +33,55 @@ export function encodeResizeMessage(cols: number, rows: number): Uint8Array {
 export function encodeClearScrollbackMessage(): Uint8Array {
   return new Uint8Array([WS_MSG.CLEAR_SCROLLBACK]);
 }
+
+/** Encode a server-side output search as a WS SEARCH_REQUEST frame. */
+export function encodeSearchRequestMessage(request: SearchRequest): Uint8Array {
+  const encoded = new TextEncoder().encode(JSON.stringify(request));
+  const msg = new Uint8Array(1 + encoded.length);
+  msg[0] = WS_MSG.SEARCH_REQUEST;
+  msg.set(encoded, 1);
+  return msg;
+}
+
+/** Encode a history page request as a WS RANGE_REQUEST frame (end 0 = up to now). */
+export function encodeRangeRequestMessage(start: number, end: number, maxBytes?: number): Uint8Array {
+  const msg = new Uint8Array(maxBytes === undefined ? 17 : 21);
+  msg[0] = WS_MSG.RANGE_REQUEST;
+  const view = new DataView(msg.buffer);
+  view.setFloat64(1, start, false);
+  view.setFloat64(9, end, false);
+  if (maxBytes !== undefined) view.setUint32(17, maxBytes, false);
+  return msg;
+}
+
+/** Encode an output export request as a WS EXPORT frame. */
+export function encodeExportMessage(format: "text" | "html"): Uint8Array {
+  return new Uint8Array([WS_MSG.EXPORT, format === "html" ? 1 : 0]);
+}
+
+/** Encode an IMAGE_REQUEST frame: fetch a stored image by id (e.g. "img-3"). */
+export function encodeImageRequestMessage(id: string): Uint8Array {
+  const encoded = new TextEncoder().encode(id);
+  const msg = new Uint8Array(1 + encoded.length);
+  msg[0] = WS_MSG.IMAGE_REQUEST;
+  msg.set(encoded, 1);
+  return msg;
+}
+
+/** Encode a CLIPBOARD_SET frame: the text programs get back from an OSC 52 read query. */
+export function encodeClipboardSetMessage(text: string): Uint8Array {
+  const encoded = new TextEncoder().encode(text);
+  const msg = new Uint8Array(1 + encoded.length);
+  msg[0] = WS_MSG.CLIPBOARD_SET;
+  msg.set(encoded, 1);
+  return msg;
+}
+
+/** Encode a WATCH frame: replace the session's watch rules, or query them if omitted. */
+export function encodeWatchMessage(rules?: WatchRule[]): Uint8Array {
+  const encoded = rules ? new TextEncoder().encode(JSON.stringify({ rules })) : new Uint8Array(0);
+  const msg = new Uint8Array(1 + encoded.length);
+  msg[0] = WS_MSG.WATCH;
+  msg.set(encoded, 1);
+  return msg;
+}
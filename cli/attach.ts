@@ -3,8 +3,8 @@ import * as net from "node:net";
 import * as path from "node:path";
 import * as os from "node:os";
 import { gunzipSync } from "node:zlib";
-import { WS_MSG } from "../shared/types.js";
-import { parseFrames } from "../shared/framing.js";
+import { WS_MSG, CAP } from "../shared/types.js";
+import { parseFrames, encodeHello } from "../shared/framing.js";
 
 /**
  * Core attach logic: connects to a PTY session via Unix socket and enters
@@ -224,6 +224,11 @@ export function attachSocket(socketPath: string, opts: AttachOpts = {}): Promise
         if (s.cleanExit || s.userDetached) { newSock.destroy(); return; }
         s.retryDelay = 500;
         if (!s.rawMode) enterRaw(s, onStdinData, onResize);
+        // Only gzip replay is optional among the types handleMessage parses
+        writeFrame(newSock, encodeHello(CAP.BUFFER_REPLAY_GZ));
+        const resume = Buffer.alloc(9);
+        resume[0] = WS_MSG.RESUME;
+        writeFrame(newSock, resume);
         sendResize(s);
       });
 
@@ -11,8 +11,8 @@ const { Terminal } = xtermHeadless;
 type Terminal = InstanceType<typeof Terminal>;
 type IBufferCell = xtermHeadless.IBufferCell;
 type IBufferLine = xtermHeadless.IBufferLine;
-import { WS_MSG } from "../shared/types.js";
-import { parseFrames, encodeFrame } from "../shared/framing.js";
+import { WS_MSG, CAP } from "../shared/types.js";
+import { parseFrames, encodeFrame, encodeHello } from "../shared/framing.js";
 import { getSocketPath } from "./spawn.js";
 
 // ── Viewport serializer ─────────────────────────────────────────────────
@@ -190,6 +190,7 @@ export class PreviewConnection {
     this.socket = net.createConnection(socketPath);
 
     this.socket.on("connect", () => {
+      this.socket!.write(encodeFrame(encodeHello(CAP.BUFFER_REPLAY_GZ)));
       // Send RESUME(0) to request full buffer replay
       const payload = Buffer.alloc(9);
       payload[0] = WS_MSG.RESUME;
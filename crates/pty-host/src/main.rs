@@ -2,16 +2,38 @@
 //! Rust replacement for server/pty-host.ts — drop-in compatible with the same
 //! Unix socket protocol, session metadata format, and WS_MSG types.
 //!
-//! Usage: relay-pty-host <id> <cols> <rows> <cwd> <command> [args...]
+//! Usage: relay-pty-host spawn [options] [--id <id>] [--cols <n>] [--rows <n>]
+//!            [--cwd <dir>] [--login] [--detach] [--] <command> [args...]
+//!        relay-pty-host replay [options] [--id <id>] <file>
+//!        relay-pty-host attach <id>
+//!        relay-pty-host list [--json]
+//!        relay-pty-host kill <id>
+//!        relay-pty-host export [--html] <id>
+//!
+//! The older positional form is still accepted (see `parse_legacy`).
+//!
+//! Options: --record <file>, --allow-uid <uid,...>, --allow-gid <primary-gid,...>,
+//! --unlisted-peers reject|read-only, --require-token (see `PeerPolicy`),
+//! --watch [notify:|exit:|mark:]<regex> (see `Watcher`), --clipboard-read
+//! (see `Clipboard`), --set <key>=<value> (see `Config`), --metrics-listen
+//! [host:]port|unix (see `MetricsSnapshot`).
+//!
+//! SIGUSR2 re-execs the binary in place, handing the session over to it
+//! (see `exec_upgrade`).
+//!
+//! Diagnostics go to ~/.relay-tty/logs/<id>.log (see `log_init`).
 
+use std::collections::VecDeque;
 use std::env;
+use std::fmt;
 use std::fs;
 use std::io::{self, Write};
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::net::TcpListener as StdTcpListener;
 use std::os::unix::net::UnixListener as StdUnixListener;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
@@ -24,6 +46,13 @@ use tokio::net::UnixListener;
 use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
 use tokio::time;
 
+mod screen;
+
+use screen::{
+    parse_csi_params, Attrs, Color, Screen, ATTR_BOLD, ATTR_DIM, ATTR_HIDDEN, ATTR_INVERSE, ATTR_ITALIC,
+    ATTR_OVERLINE, ATTR_STRIKE, ATTR_UNDERLINE, SCROLLBACK_LINES,
+};
+
 // ── WS_MSG constants (must match shared/types.ts) ────────────────────
 
 const WS_MSG_DATA: u8 = 0x00;
@@ -43,9 +72,28 @@ const WS_MSG_SPARKLINE_REQUEST: u8 = 0x18;
 const WS_MSG_SPARKLINE_HISTORY: u8 = 0x19;
 const WS_MSG_DETACH: u8 = 0x22;
 const WS_MSG_CLEAR_SCROLLBACK: u8 = 0x23;
+const WS_MSG_PLAYBACK_CONTROL: u8 = 0x24;
+const WS_MSG_PLAYBACK_STATE: u8 = 0x25;
+const WS_MSG_AUTH: u8 = 0x26;
+const WS_MSG_HELLO: u8 = 0x27;
+const WS_MSG_SEARCH_REQUEST: u8 = 0x28;
+const WS_MSG_SEARCH_RESULTS: u8 = 0x29;
+const WS_MSG_RANGE_REQUEST: u8 = 0x2a;
+const WS_MSG_RANGE_DATA: u8 = 0x2b;
+const WS_MSG_EXPORT: u8 = 0x2c;
+const WS_MSG_WATCH: u8 = 0x2d;
+const WS_MSG_COMMANDS: u8 = 0x2e;
+const WS_MSG_LINKS: u8 = 0x2f;
+const WS_MSG_IMAGE_REQUEST: u8 = 0x30;
+const WS_MSG_CLIPBOARD_SET: u8 = 0x31;
+
+// AUTH roles
+const AUTH_ROLE_VIEWER: u8 = 0x00;
+const AUTH_ROLE_READ_WRITE: u8 = 0x01;
 
 // ── Constants ────────────────────────────────────────────────────────
 
+// Defaults for the `Config` limits
 const BUFFER_SIZE: usize = 10 * 1024 * 1024; // 10MB ring buffer
 const ALT_BUFFER_CAP: usize = 2 * 1024 * 1024; // 2MB alt screen cap
 const GZIP_THRESHOLD: usize = 4096;
@@ -53,6 +101,350 @@ const IDLE_TIMEOUT_MS: u64 = 60_000;
 const JSON_WRITE_INTERVAL_MS: u64 = 5_000;
 const METRICS_INTERVAL_MS: u64 = 1_000;
 const RESUME_TIMEOUT_MS: u64 = 100;
+const RANGE_MAX_BYTES: usize = 1024 * 1024; // largest RANGE_DATA slice
+
+// ── Configuration ───────────────────────────────────────────────────
+//
+// Buffer sizes and limits, layered: built-in defaults, then
+// ~/.relay-tty/pty-host.toml, then RELAY_<KEY> environment variables, then
+// `--set <key>=<value>` flags. The file is TOML with top-level keys only:
+//
+//   buffer_size = "100MB"   # sizes take KB/MB/GB suffixes (powers of 1024)
+//   idle_timeout_ms = 30_000
+//
+// The resolved config is carried over a live upgrade, so per-session
+// overrides stick.
+
+/// Session limits. See the section comment for how they are set.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Config {
+    /// Main-screen ring buffer, allocated up front.
+    buffer_size: usize,
+    /// Alternate-screen capture cap.
+    alt_buffer_cap: usize,
+    /// Replays at least this large are gzipped for clients that accept it.
+    gzip_threshold: usize,
+    /// Quiet time before a session is reported idle.
+    idle_timeout_ms: u64,
+    /// Largest OSC 52 / CLIPBOARD_SET text.
+    clipboard_max_size: usize,
+    /// Largest decoded inline image.
+    image_max_size: usize,
+    /// Seconds of throughput history kept for sparklines.
+    sparkline_ring_cap: usize,
+    /// Least severe level written to the session log.
+    log_level: LogLevel,
+    /// Session log size that triggers rotation.
+    log_max_size: usize,
+    /// Lines of scrollback kept by the screen model for full replays.
+    scrollback_lines: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            buffer_size: BUFFER_SIZE,
+            alt_buffer_cap: ALT_BUFFER_CAP,
+            gzip_threshold: GZIP_THRESHOLD,
+            idle_timeout_ms: IDLE_TIMEOUT_MS,
+            clipboard_max_size: CLIPBOARD_MAX_SIZE,
+            image_max_size: IMAGE_MAX_SIZE,
+            sparkline_ring_cap: SPARKLINE_RING_CAP,
+            log_level: LogLevel::Info,
+            log_max_size: LOG_MAX_SIZE,
+            scrollback_lines: SCROLLBACK_LINES,
+        }
+    }
+}
+
+impl Config {
+    const KEYS: [&'static str; 10] = [
+        "buffer_size",
+        "alt_buffer_cap",
+        "gzip_threshold",
+        "idle_timeout_ms",
+        "clipboard_max_size",
+        "image_max_size",
+        "sparkline_ring_cap",
+        "log_level",
+        "log_max_size",
+        "scrollback_lines",
+    ];
+
+    /// Resolve the config for a new session: defaults, the config file in
+    /// `data_dir` (if any), RELAY_<KEY> env vars, then `--set` overrides.
+    fn load(data_dir: &Path, overrides: &[String]) -> Result<Self, String> {
+        let mut config = Self::default();
+        let path = data_dir.join("pty-host.toml");
+        match fs::read_to_string(&path) {
+            Ok(text) => config.apply_file(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        for key in Self::KEYS {
+            let var = format!("RELAY_{}", key.to_uppercase());
+            if let Ok(value) = env::var(&var) {
+                config.set(key, &value).map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+        for flag in overrides {
+            let (key, value) = flag
+                .split_once('=')
+                .ok_or_else(|| format!("--set {}: expected <key>=<value>", flag))?;
+            config.set(key.trim(), value.trim()).map_err(|e| format!("--set {}: {}", flag, e))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Apply a TOML config file. Values are strings or integers and go
+    /// through `set`, so a file accepts the same values as `--set`.
+    fn apply_file(&mut self, text: &str) -> Result<(), String> {
+        let table: toml::Table = toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, e.message()),
+            None => e.message().to_string(),
+        })?;
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(n) => n.to_string(),
+                _ => return Err(format!("{} must be a string or an integer", key)),
+            };
+            self.set(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let size = || parse_size(value).ok_or_else(|| format!("invalid size for {}: {}", key, value));
+        let count = || {
+            value
+                .replace('_', "")
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number for {}: {}", key, value))
+        };
+        match key {
+            "buffer_size" => self.buffer_size = size()?,
+            "alt_buffer_cap" => self.alt_buffer_cap = size()?,
+            "gzip_threshold" => self.gzip_threshold = size()?,
+            "idle_timeout_ms" => self.idle_timeout_ms = count()?,
+            "clipboard_max_size" => self.clipboard_max_size = size()?,
+            "image_max_size" => self.image_max_size = size()?,
+            "sparkline_ring_cap" => self.sparkline_ring_cap = count()? as usize,
+            "log_level" => {
+                self.log_level = LogLevel::parse(value)
+                    .ok_or_else(|| format!("invalid log level {} (known: error, warn, info, debug)", value))?
+            }
+            "log_max_size" => self.log_max_size = size()?,
+            "scrollback_lines" => self.scrollback_lines = count()? as usize,
+            _ => return Err(format!("unknown key {} (known: {})", key, Self::KEYS.join(", "))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        const KB: usize = 1024;
+        const MB: usize = 1024 * KB;
+        let check = |ok: bool, msg: &str| if ok { Ok(()) } else { Err(msg.to_string()) };
+        check((64 * KB..=1024 * MB).contains(&self.buffer_size), "buffer_size must be 64KB-1GB")?;
+        check((16 * KB..=1024 * MB).contains(&self.alt_buffer_cap), "alt_buffer_cap must be 16KB-1GB")?;
+        check(self.idle_timeout_ms >= 1000, "idle_timeout_ms must be at least 1000")?;
+        check((1..=64 * MB).contains(&self.clipboard_max_size), "clipboard_max_size must be 1B-64MB")?;
+        check(self.image_max_size <= 256 * MB, "image_max_size must be at most 256MB")?;
+        check(
+            (1..=u16::MAX as usize).contains(&self.sparkline_ring_cap),
+            "sparkline_ring_cap must be 1-65535",
+        )?;
+        check((4 * KB..=1024 * MB).contains(&self.log_max_size), "log_max_size must be 4KB-1GB")?;
+        check(self.scrollback_lines <= 1_000_000, "scrollback_lines must be at most 1000000")
+    }
+}
+
+/// Parse a byte size: a plain number (`_` separators allowed) or one with a
+/// KB/MB/GB suffix (powers of 1024, `K`/`KiB` forms accepted too).
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim().replace('_', "");
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+// ── Logging ─────────────────────────────────────────────────────────
+//
+// A detached pty-host has nowhere useful for stderr, so diagnostics go to
+// ~/.relay-tty/logs/<id>.log as logfmt lines:
+//
+//   time=2025-01-01T12:00:00.000Z level=info msg="client connected" client=3 transport=unix
+//
+// The file is rotated to <id>.log.1 (up to LOG_KEEP old files) once it
+// passes `log_max_size`. Warnings and errors are also written to stderr,
+// which is all there is before `log_init` and in the forked child.
+
+const LOG_KEEP: usize = 3;
+const LOG_MAX_SIZE: usize = 1024 * 1024; // default `log_max_size`
+
+/// Log verbosity, least verbose first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 4] = [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug];
+
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.as_str().eq_ignore_ascii_case(value))
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An append-only log file that rotates itself when it grows too large.
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, max_size })
+    }
+
+    fn append(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("pty-host: failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    /// <id>.log.N -> <id>.log.N+1 (the oldest is overwritten), <id>.log ->
+    /// <id>.log.1, then start a fresh <id>.log.
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..LOG_KEEP).rev() {
+            let _ = fs::rename(numbered(n), numbered(n + 1));
+        }
+        fs::rename(&self.path, numbered(1))?;
+        *self = Self::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static LOG_FILE: std::sync::Mutex<Option<LogFile>> = std::sync::Mutex::new(None);
+
+/// Start writing the session log at the configured level. If the file
+/// cannot be opened, warnings and errors still reach stderr.
+fn log_init(data_dir: &Path, id: &str, config: &Config) {
+    LOG_LEVEL.store(config.log_level as u8, Ordering::Relaxed);
+    let path = data_dir.join("logs").join(format!("{}.log", id));
+    match LogFile::open(&path, config.log_max_size as u64) {
+        Ok(file) => *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file),
+        Err(e) => eprintln!("pty-host: failed to open log {}: {}", path.display(), e),
+    }
+}
+
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+fn log_record(level: LogLevel, msg: &str, fields: &[(&str, &dyn fmt::Display)]) {
+    if level <= LogLevel::Warn {
+        let mut line = format!("pty-host: {}", msg);
+        for (key, value) in fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        eprintln!("{}", line);
+    }
+    let mut file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = file.as_mut() {
+        file.append(&format_record(&iso_now(), level, msg, fields));
+    }
+}
+
+/// One logfmt line, newline included.
+fn format_record(time: &str, level: LogLevel, msg: &str, fields: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut line = format!("time={} level={} msg={}", time, level, logfmt_value(msg));
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, logfmt_value(&value.to_string())));
+    }
+    line.push('\n');
+    line
+}
+
+/// Quote a logfmt value if it is empty or has spaces, quotes, `=` or
+/// control characters.
+fn logfmt_value(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value.contains(|c: char| c == ' ' || c == '"' || c == '=' || c == '\\' || c.is_control());
+    if plain {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// `log_at!(level, msg, key = value, ...)`: values are anything `Display`.
+macro_rules! log_at {
+    ($level:expr, $msg:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if log_enabled($level) {
+            log_record($level, &$msg, &[$((stringify!($key), &$value as &dyn fmt::Display)),*]);
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { log_at!(LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log_at!(LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { log_at!(LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log_at!(LogLevel::Debug, $($arg)*) };
+}
 
 // ── Alt screen mode numbers ─────────────────────────────────────────
 const ALT_SCREEN_MODES: &[u16] = &[1049, 47, 1047];
@@ -195,6 +587,7 @@ struct OutputBuffer {
     max_size: usize,
     // Alt screen capture
     alt_buf: Vec<u8>,
+    alt_cap: usize,
     in_alt_screen: bool,
     alt_content_start: f64, // total_written at start of current alt content
     // Monotonic byte counter across both buffers
@@ -205,13 +598,14 @@ struct OutputBuffer {
 }
 
 impl OutputBuffer {
-    fn new(max_size: usize) -> Self {
+    fn new(max_size: usize, alt_cap: usize) -> Self {
         Self {
             buffer: vec![0u8; max_size],
             write_pos: 0,
             filled: false,
             max_size,
             alt_buf: Vec::new(),
+            alt_cap,
             in_alt_screen: false,
             alt_content_start: 0.0,
             total_written: 0.0,
@@ -233,6 +627,40 @@ impl OutputBuffer {
         self.pending_seq.clear();
     }
 
+    /// Seed the buffer with output persisted by a previous pty-host, so
+    /// stream offsets continue from where that process left off.
+    fn restore(&mut self, start_offset: f64, data: &[u8]) {
+        self.total_written = start_offset;
+        self.alt_content_start = start_offset;
+        self.write(data);
+    }
+
+    /// Describe the buffer for a live upgrade. The main ring (linearized)
+    /// and alt buffer contents travel separately as raw blobs.
+    fn handoff(&self) -> BufferHandoff {
+        BufferHandoff {
+            total_written: self.total_written,
+            in_alt_screen: self.in_alt_screen,
+            alt_content_start: self.alt_content_start,
+            pending_seq: self.pending_seq.clone(),
+            main_len: self.main_size(),
+            alt_len: self.alt_buf.len(),
+        }
+    }
+
+    /// Rebuild a buffer handed over by a previous pty-host.
+    fn from_handoff(config: &Config, handoff: &BufferHandoff, main: &[u8], alt: &[u8]) -> Self {
+        let mut buf = Self::new(config.buffer_size, config.alt_buffer_cap);
+        buf.write_main_slice(main);
+        buf.alt_buf = alt.to_vec();
+        buf.in_alt_screen = handoff.in_alt_screen;
+        buf.alt_content_start = handoff.alt_content_start;
+        buf.total_written = handoff.total_written;
+        // Bytes held mid-sequence were never counted; re-scan them.
+        buf.write(&handoff.pending_seq);
+        buf
+    }
+
     /// Write data to the appropriate buffer, scanning for alt screen transitions.
     fn write(&mut self, data: &[u8]) {
         if data.is_empty() {
@@ -362,8 +790,8 @@ impl OutputBuffer {
         self.alt_buf.extend_from_slice(data);
 
         // Enforce cap: truncate from front if exceeded
-        if self.alt_buf.len() > ALT_BUFFER_CAP {
-            let excess = self.alt_buf.len() - ALT_BUFFER_CAP;
+        if self.alt_buf.len() > self.alt_cap {
+            let excess = self.alt_buf.len() - self.alt_cap;
             self.alt_buf.drain(..excess);
             self.alt_content_start += excess as f64;
         }
@@ -379,16 +807,7 @@ impl OutputBuffer {
             return None;
         }
         // Scan backwards for \x1b [ 2 J
-        for i in (0..=data.len() - 4).rev() {
-            if data[i] == 0x1b
-                && data[i + 1] == b'['
-                && data[i + 2] == b'2'
-                && data[i + 3] == b'J'
-            {
-                return Some(i);
-            }
-        }
-        None
+        (0..=data.len() - 4).rev().find(|&i| &data[i..i + 4] == b"\x1b[2J")
     }
 
     /// Called on resize — discards old alt screen content that will be redrawn.
@@ -404,14 +823,7 @@ impl OutputBuffer {
     /// Truncates at the last full-screen clear (ED mode 2/3) to avoid
     /// replaying dead frames from TUI apps that redraw via clearTerminal().
     fn read(&self) -> Vec<u8> {
-        let main = if !self.filled {
-            self.buffer[..self.write_pos].to_vec()
-        } else {
-            let mut result = Vec::with_capacity(self.max_size);
-            result.extend_from_slice(&self.buffer[self.write_pos..]);
-            result.extend_from_slice(&self.buffer[..self.write_pos]);
-            sanitize_start(result)
-        };
+        let main = self.read_main();
 
         let combined = if self.in_alt_screen && !self.alt_buf.is_empty() {
             let mut c = Vec::with_capacity(main.len() + self.alt_buf.len());
@@ -491,6 +903,89 @@ impl OutputBuffer {
         }
     }
 
+    /// Copy the bytes between stream offsets `start` and `end` out of the
+    /// main ring and alt buffer, without linearizing the whole ring.
+    /// Returns None if `start` has been overwritten.
+    fn slice(&self, start: f64, end: f64) -> Option<Vec<u8>> {
+        let end = end.min(self.total_written);
+        if start >= end {
+            return Some(Vec::new());
+        }
+        let main_end = if self.in_alt_screen {
+            self.alt_content_start
+        } else {
+            self.total_written
+        };
+        let main_start = main_end - self.main_size() as f64;
+        if start < main_start {
+            return None;
+        }
+
+        let mut result = Vec::with_capacity((end - start) as usize);
+        if start < main_end {
+            let lo = (start - main_start) as usize;
+            let hi = (end.min(main_end) - main_start) as usize;
+            if !self.filled {
+                result.extend_from_slice(&self.buffer[lo..hi]);
+            } else {
+                // Logical bytes [0, tail) sit at the end of the Vec
+                let tail = self.max_size - self.write_pos;
+                if lo < tail {
+                    result.extend_from_slice(&self.buffer[self.write_pos + lo..self.write_pos + hi.min(tail)]);
+                }
+                if hi > tail {
+                    result.extend_from_slice(&self.buffer[lo.max(tail) - tail..hi - tail]);
+                }
+            }
+        }
+        if self.in_alt_screen && end > self.alt_content_start {
+            let lo = (start.max(self.alt_content_start) - self.alt_content_start) as usize;
+            let hi = ((end - self.alt_content_start) as usize).min(self.alt_buf.len());
+            if lo < hi {
+                result.extend_from_slice(&self.alt_buf[lo..hi]);
+            }
+        }
+        Some(result)
+    }
+
+    /// Read a slice of the main ring between two stream offsets, for paging
+    /// in history. At most `max` bytes are returned, counted back from `end`
+    /// (`end <= 0` means the newest byte). Both ends move inward so that no
+    /// escape sequence or UTF-8 character is split: the end to just after a
+    /// newline or just before an ESC, the start to a line start if the slice
+    /// has one. Returns the slice's start offset and bytes.
+    fn read_range(&self, start: f64, end: f64, max: usize) -> (f64, Vec<u8>) {
+        let main_end = if self.in_alt_screen {
+            self.alt_content_start
+        } else {
+            self.total_written
+        };
+        let main_size = self.main_size();
+        let main_start = main_end - main_size as f64;
+        let end = if end <= 0.0 { main_end } else { end.min(main_end) };
+
+        let hi = (end - main_start).max(0.0) as usize;
+        let lo = ((start - main_start).max(0.0) as usize).max(hi.saturating_sub(max)).min(hi);
+        // Copy just the window, plus the byte either side the boundary checks look at
+        let base = lo.saturating_sub(1);
+        let mut window = self
+            .slice(main_start + base as f64, main_start + (hi + 1).min(main_size) as f64)
+            .unwrap_or_default();
+        let byte = |i: usize| window[i - base];
+        // A wrapped ring may begin mid-sequence
+        let line_start = |i: usize| if i == 0 { !self.filled } else { byte(i - 1) == b'\n' };
+        let boundary = |i: usize| i == main_size || line_start(i) || byte(i) == 0x1b;
+
+        let hi = (lo..=hi).rev().find(|&i| boundary(i)).unwrap_or(lo);
+        let lo = (lo..=hi)
+            .find(|&i| line_start(i))
+            .or_else(|| (lo..=hi).find(|&i| boundary(i)))
+            .unwrap_or(hi);
+        window.truncate(hi - base);
+        window.drain(..lo - base);
+        (main_start + lo as f64, window)
+    }
+
     /// Total logical size across both buffers.
     #[cfg(test)]
     fn size(&self) -> usize {
@@ -506,6 +1001,15 @@ impl OutputBuffer {
         }
     }
 
+    /// Read the main ring buffer, starting at a line boundary once wrapped.
+    fn read_main(&self) -> Vec<u8> {
+        if self.filled {
+            sanitize_start(self.read_main_raw())
+        } else {
+            self.read_main_raw()
+        }
+    }
+
     /// Read raw linearized main buffer without sanitization.
     fn read_main_raw(&self) -> Vec<u8> {
         if !self.filled {
@@ -517,6 +1021,20 @@ impl OutputBuffer {
         result
     }
 
+    /// Everything buffered as one contiguous copy, for search: the main
+    /// ring followed by the alt screen content if active, with the stream
+    /// offset of its first byte. This is a single allocation and a memcpy,
+    /// so it is cheap to take under the state lock.
+    fn snapshot(&self) -> (f64, Vec<u8>) {
+        let main_end = if self.in_alt_screen {
+            self.alt_content_start
+        } else {
+            self.total_written
+        };
+        let start = main_end - self.main_size() as f64;
+        (start, self.slice(start, self.total_written).unwrap_or_default())
+    }
+
     /// Read raw linearized buffer without sanitization (backward compat).
     #[cfg(test)]
     fn read_raw(&self) -> Vec<u8> {
@@ -538,970 +1056,5849 @@ fn sanitize_start(buf: Vec<u8>) -> Vec<u8> {
     }
 }
 
-// ── Strip terminal query sequences ──────────────────────────────────
+// ── Scrollback search ───────────────────────────────────────────────
+//
+// SEARCH_REQUEST lets clients search everything pty-host buffers, not just
+// what their terminal has loaded. Output is stripped of escape sequences
+// before matching; each match reports the stream offset (same coordinates
+// as SYNC) of its first byte.
+
+/// Upper bound on matches per request.
+const SEARCH_MAX_RESULTS: usize = 1000;
+/// Upper bound on context lines either side of a match.
+const SEARCH_MAX_CONTEXT: usize = 10;
+/// Lines in results are clipped to this many bytes.
+const SEARCH_MAX_LINE: usize = 512;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchRequest {
+    /// Echoed in the results so clients can match them to requests.
+    #[serde(default)]
+    id: u32,
+    query: String,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    case_sensitive: bool,
+    #[serde(default = "default_search_context")]
+    context: usize,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
 
-fn strip_terminal_queries(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::with_capacity(data.len());
-    let mut i = 0;
+fn default_search_context() -> usize {
+    2
+}
 
-    while i < data.len() {
-        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5b {
-            // Potential CSI sequence
-            let mut j = i + 2;
-            // Skip parameter and intermediate bytes (0x20-0x3f)
-            while j < data.len() && data[j] >= 0x20 && data[j] <= 0x3f {
-                j += 1;
-            }
+fn default_search_limit() -> usize {
+    100
+}
 
-            if j < data.len() {
-                let final_byte = data[j];
-                let params = &data[i + 2..j];
-                let params_str = std::str::from_utf8(params).unwrap_or("");
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct SearchMatch {
+    /// Stream offset of the first byte of the match.
+    offset: f64,
+    /// Length of the match in stream bytes (including any escape sequences
+    /// inside it).
+    length: usize,
+    line: String,
+    before: Vec<String>,
+    after: Vec<String>,
+}
 
-                // Check if this is a query we should strip
-                let is_dsr = final_byte == 0x6e
-                    && (params_str == "6" || params_str == "?6");
-                let is_da = final_byte == 0x63
-                    && (params_str.is_empty()
-                        || params_str == ">"
-                        || params_str == "="
-                        || params_str == "0");
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResults {
+    id: u32,
+    matches: Vec<SearchMatch>,
+    /// More matches exist than `limit`.
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-                if is_dsr || is_da {
-                    i = j + 1;
-                    continue;
-                }
-            }
-        }
+/// Output with escape sequences and control characters removed. Each entry
+/// of `runs` is `(index into text, stream offset)` and starts a stretch of
+/// `text` whose bytes came from consecutive stream offsets; a new run starts
+/// wherever bytes were dropped.
+struct StrippedText {
+    text: String,
+    runs: Vec<(usize, f64)>,
+}
 
-        result.push(data[i]);
-        i += 1;
+impl StrippedText {
+    /// Stream offset of byte `i` of `text`.
+    fn offset(&self, i: usize) -> f64 {
+        let (at, start) = self.runs[self.runs.partition_point(|&(at, _)| at <= i) - 1];
+        start + (i - at) as f64
     }
-
-    result
 }
 
-// ── OSC parsing ─────────────────────────────────────────────────────
-
-/// Parse OSC 0/2 title from data. Returns (title, was_found).
-fn parse_osc_title(data: &[u8]) -> Option<String> {
-    // Look for ESC ] 0|2 ; <title> BEL|ESC\
+/// Strip CSI/OSC/DCS and other escape sequences, carriage returns and
+/// control characters (except `\n` and `\t`), and invalid UTF-8.
+fn strip_for_search(data: &[u8], start: f64) -> StrippedText {
+    let mut text = Vec::with_capacity(data.len());
+    let mut runs: Vec<(usize, f64)> = Vec::new();
+    // Byte `i` of data continues the last run if nothing was dropped since
+    let mut keep = |text: &mut Vec<u8>, i: usize| {
+        let contiguous = runs.last().is_some_and(|&(at, run)| run + (text.len() - at) as f64 == start + i as f64);
+        if !contiguous {
+            runs.push((text.len(), start + i as f64));
+        }
+    };
     let mut i = 0;
-    while i + 3 < data.len() {
-        if data[i] == 0x1b && data[i + 1] == 0x5d {
-            // ESC ]
-            if data[i + 2] == b'0' || data[i + 2] == b'2' {
-                if i + 3 < data.len() && data[i + 3] == b';' {
-                    // Find the terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
-                    let start = i + 4;
-                    let mut end = start;
-                    while end < data.len() {
-                        if data[end] == 0x07 {
-                            let title = String::from_utf8_lossy(&data[start..end]).to_string();
-                            return Some(title);
-                        }
-                        if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
-                            let title = String::from_utf8_lossy(&data[start..end]).to_string();
-                            return Some(title);
-                        }
-                        end += 1;
-                    }
-                }
+    while i < data.len() {
+        let b = data[i];
+        if b == 0x1b {
+            i = skip_escape(data, i);
+            continue;
+        }
+        if b < 0x20 || b == 0x7f {
+            if b == b'\n' || b == b'\t' {
+                keep(&mut text, i);
+                text.push(b);
             }
+            i += 1;
+            continue;
         }
-        i += 1;
+        let len = utf8_sequence_len(&data[i..]);
+        if len == 0 {
+            i += 1;
+            continue;
+        }
+        keep(&mut text, i);
+        text.extend_from_slice(&data[i..i + len]);
+        i += len;
+    }
+    StrippedText {
+        // Only whole, valid sequences were copied
+        text: String::from_utf8(text).unwrap_or_default(),
+        runs,
     }
-    None
 }
 
-/// Parse OSC 7 CWD notification from data. Returns the decoded path if found.
-/// Format: ESC ] 7 ; file://hostname/path BEL|ESC\
-/// The path is percent-decoded (e.g., %20 → space).
-fn parse_osc7_cwd(data: &[u8]) -> Option<String> {
-    let mut i = 0;
-    while i + 3 < data.len() {
-        if data[i] == 0x1b && data[i + 1] == 0x5d && data[i + 2] == b'7' {
-            // ESC ] 7
-            if i + 3 < data.len() && data[i + 3] == b';' {
-                // Find the terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
-                let start = i + 4;
-                let mut end = start;
-                while end < data.len() {
-                    if data[end] == 0x07 {
-                        let url = String::from_utf8_lossy(&data[start..end]);
-                        return extract_path_from_file_url(&url);
-                    }
-                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
-                        let url = String::from_utf8_lossy(&data[start..end]);
-                        return extract_path_from_file_url(&url);
-                    }
-                    end += 1;
+/// Index just past the escape sequence starting at `data[i]` (an ESC).
+fn skip_escape(data: &[u8], i: usize) -> usize {
+    match data.get(i + 1) {
+        // CSI: parameters and intermediates, then a final byte
+        Some(b'[') => {
+            let mut j = i + 2;
+            while j < data.len() && !(0x40..=0x7e).contains(&data[j]) {
+                j += 1;
+            }
+            (j + 1).min(data.len())
+        }
+        // OSC, DCS, APC, PM, SOS: until BEL or ST
+        Some(b']' | b'P' | b'_' | b'^' | b'X') => {
+            let mut j = i + 2;
+            while j < data.len() {
+                if data[j] == 0x07 {
+                    return j + 1;
                 }
+                if data[j] == 0x1b && data.get(j + 1) == Some(&b'\\') {
+                    return j + 2;
+                }
+                j += 1;
             }
+            data.len()
         }
-        i += 1;
+        // Charset designation takes one more byte
+        Some(b'(' | b')' | b'*' | b'+' | b'#' | b'%') => (i + 3).min(data.len()),
+        Some(_) => i + 2,
+        None => i + 1,
     }
-    None
 }
 
-/// Extract and percent-decode the path from a file:// URL.
-/// Handles `file:///path` (no host) and `file://hostname/path`.
-fn extract_path_from_file_url(url: &str) -> Option<String> {
-    let rest = url.strip_prefix("file://")?;
-    // After "file://", the next component is either empty (file:///path)
-    // or a hostname (file://host/path). Either way, the path starts at the first '/'.
-    let path_start = rest.find('/')?;
-    let encoded_path = &rest[path_start..];
-    Some(percent_decode(encoded_path))
+/// Length of the valid UTF-8 character at the start of `data`, or 0.
+fn utf8_sequence_len(data: &[u8]) -> usize {
+    let len = match data[0] {
+        0x00..=0x7f => return 1,
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return 0,
+    };
+    match data.get(..len) {
+        Some(seq) if std::str::from_utf8(seq).is_ok() => len,
+        _ => 0,
+    }
 }
 
-/// Percent-decode a string (e.g., %20 → space, %C3%A9 → é).
-fn percent_decode(input: &str) -> String {
-    let mut bytes = Vec::with_capacity(input.len());
-    let input_bytes = input.as_bytes();
-    let mut i = 0;
-    while i < input_bytes.len() {
-        if input_bytes[i] == b'%' && i + 2 < input_bytes.len() {
-            if let Ok(val) = u8::from_str_radix(
-                &input[i + 1..i + 3],
-                16,
-            ) {
-                bytes.push(val);
-                i += 3;
-                continue;
-            }
-        }
-        bytes.push(input_bytes[i]);
-        i += 1;
+/// Clip `line` to at most `SEARCH_MAX_LINE` bytes around byte `at`.
+fn clip_line(line: &str, at: usize) -> String {
+    if line.len() <= SEARCH_MAX_LINE {
+        return line.to_string();
     }
-    String::from_utf8_lossy(&bytes).to_string()
+    let mut start = at.saturating_sub(SEARCH_MAX_LINE / 4).min(line.len() - SEARCH_MAX_LINE);
+    while !line.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = start + SEARCH_MAX_LINE;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line[start..end].to_string()
 }
 
-/// Maximum clipboard payload size (1 MB)
-const CLIPBOARD_MAX_SIZE: usize = 1 * 1024 * 1024;
+/// Up to `context` lines before and after the line `text[start..end]`.
+fn context_lines(text: &str, start: usize, end: usize, context: usize) -> (Vec<String>, Vec<String>) {
+    let mut before = Vec::new();
+    if start > 0 {
+        before = text[..start - 1].rsplit('\n').take(context).map(|l| clip_line(l, 0)).collect();
+        before.reverse();
+    }
+    let mut after = Vec::new();
+    if end < text.len() {
+        after = text[end + 1..].split_terminator('\n').take(context).map(|l| clip_line(l, 0)).collect();
+    }
+    (before, after)
+}
 
-/// Maximum decoded image size (10 MB)
-const IMAGE_MAX_SIZE: usize = 10 * 1024 * 1024;
+fn compile_search(req: &SearchRequest) -> Result<regex_lite::Regex, regex_lite::Error> {
+    let pattern = if req.regex {
+        req.query.clone()
+    } else {
+        regex_lite::escape(&req.query)
+    };
+    regex_lite::RegexBuilder::new(&pattern)
+        .case_insensitive(!req.case_sensitive)
+        .multi_line(true)
+        .build()
+}
 
-/// Simple base64 decoder (standard alphabet + padding).
-fn base64_decode(input: &[u8]) -> Option<Vec<u8>> {
-    const DECODE_TABLE: [u8; 256] = {
-        let mut t = [255u8; 256];
-        let mut i = 0u8;
-        while i < 26 {
-            t[(b'A' + i) as usize] = i;
-            t[(b'a' + i) as usize] = i + 26;
-            i += 1;
-        }
-        let mut d = 0u8;
-        while d < 10 {
-            t[(b'0' + d) as usize] = d + 52;
-            d += 1;
+/// Run a search over buffered output `data`, which starts at stream offset
+/// `start`.
+fn search_output(req: &SearchRequest, start: f64, data: &[u8]) -> SearchResults {
+    let mut results = SearchResults {
+        id: req.id,
+        ..SearchResults::default()
+    };
+    if req.query.is_empty() {
+        return results;
+    }
+    let re = match compile_search(req) {
+        Ok(re) => re,
+        Err(e) => {
+            results.error = Some(e.to_string());
+            return results;
         }
-        t[b'+' as usize] = 62;
-        t[b'/' as usize] = 63;
-        t[b'=' as usize] = 0; // padding
-        t
     };
-
-    let mut result = Vec::with_capacity(input.len() * 3 / 4);
-    let filtered: Vec<u8> = input.iter().copied().filter(|&b| b != b'\n' && b != b'\r' && b != b' ').collect();
-    if filtered.is_empty() {
-        return Some(result);
+    let limit = req.limit.min(SEARCH_MAX_RESULTS);
+    let context = req.context.min(SEARCH_MAX_CONTEXT);
+
+    let stripped = strip_for_search(data, start);
+    let text = &stripped.text;
+    for m in re.find_iter(text) {
+        if m.is_empty() {
+            continue;
+        }
+        if results.matches.len() == limit {
+            results.truncated = true;
+            return results;
+        }
+        let line_start = text[..m.start()].rfind('\n').map_or(0, |p| p + 1);
+        let line_end = text[m.start()..].find('\n').map_or(text.len(), |p| m.start() + p);
+        let (before, after) = context_lines(text, line_start, line_end, context);
+        let offset = stripped.offset(m.start());
+        results.matches.push(SearchMatch {
+            offset,
+            length: (stripped.offset(m.end() - 1) - offset) as usize + 1,
+            line: clip_line(&text[line_start..line_end], m.start() - line_start),
+            before,
+            after,
+        });
     }
+    results
+}
 
-    let mut i = 0;
-    while i + 3 < filtered.len() {
-        let a = DECODE_TABLE[filtered[i] as usize];
-        let b = DECODE_TABLE[filtered[i + 1] as usize];
-        let c = DECODE_TABLE[filtered[i + 2] as usize];
-        let d = DECODE_TABLE[filtered[i + 3] as usize];
-        if a == 255 || b == 255 || c == 255 || d == 255 {
-            return None;
+// ── Export ──────────────────────────────────────────────────────────
+//
+// EXPORT renders buffered output as a document for pasting elsewhere:
+// plain text, or HTML that keeps SGR colors and styles. Output is rendered
+// line by line (carriage returns, backspaces, tabs and erase-in-line are
+// honoured; cursor movement is not), which suits shell output. Full-screen
+// apps on the alternate screen are not included.
+
+const EXPORT_FORMAT_TEXT: u8 = 0x00;
+const EXPORT_FORMAT_HTML: u8 = 0x01;
+
+/// Page colors for HTML export, also used for inverse video.
+const EXPORT_FG: &str = "#e5e5e5";
+const EXPORT_BG: &str = "#000000";
+
+/// xterm's default 16-color palette.
+const ANSI_PALETTE: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// CSS color for a terminal color, or None for the default.
+fn css_color(color: Color) -> Option<String> {
+    match color {
+        Color::Default => None,
+        Color::Indexed(n) if n < 16 => Some(ANSI_PALETTE[n as usize].to_string()),
+        Color::Indexed(n) if n < 232 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let n = n - 16;
+            Some(format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6)))
         }
-        result.push((a << 2) | (b >> 4));
-        if filtered[i + 2] != b'=' {
-            result.push((b << 4) | (c >> 2));
+        Color::Indexed(n) => {
+            let v = 8 + (n - 232) * 10;
+            Some(format!("#{:02x}{:02x}{:02x}", v, v, v))
         }
-        if filtered[i + 3] != b'=' {
-            result.push((c << 6) | d);
+        Color::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+    }
+}
+
+/// Inline CSS for a run of cells with these attributes.
+fn css_style(attrs: &Attrs) -> String {
+    let (mut fg, mut bg) = (css_color(attrs.fg), css_color(attrs.bg));
+    if attrs.flags & ATTR_INVERSE != 0 {
+        (fg, bg) = (
+            Some(bg.unwrap_or_else(|| EXPORT_BG.to_string())),
+            Some(fg.unwrap_or_else(|| EXPORT_FG.to_string())),
+        );
+    }
+    let mut style = String::new();
+    if let Some(fg) = fg {
+        style.push_str(&format!("color:{};", fg));
+    }
+    if let Some(bg) = bg {
+        style.push_str(&format!("background:{};", bg));
+    }
+    if attrs.flags & ATTR_BOLD != 0 {
+        style.push_str("font-weight:bold;");
+    }
+    if attrs.flags & ATTR_DIM != 0 {
+        style.push_str("opacity:0.6;");
+    }
+    if attrs.flags & ATTR_ITALIC != 0 {
+        style.push_str("font-style:italic;");
+    }
+    if attrs.flags & ATTR_HIDDEN != 0 {
+        style.push_str("visibility:hidden;");
+    }
+    let decorations: Vec<&str> = [
+        (ATTR_UNDERLINE, "underline"),
+        (ATTR_STRIKE, "line-through"),
+        (ATTR_OVERLINE, "overline"),
+    ]
+    .iter()
+    .filter(|(flag, _)| attrs.flags & flag != 0)
+    .map(|&(_, name)| name)
+    .collect();
+    if !decorations.is_empty() {
+        style.push_str(&format!("text-decoration:{};", decorations.join(" ")));
+    }
+    style
+}
+
+fn escape_html(text: &str, out: &mut String) {
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(ch),
         }
-        i += 4;
     }
-    Some(result)
 }
 
-/// Extract OSC 52 clipboard sequences from data. Returns (cleaned_data, clipboard_texts).
-/// Format: ESC ] 52 ; <selection> ; <base64data> BEL|ESC\
-/// The base64 data is decoded and returned as UTF-8 text.
-fn extract_osc52_clipboard(data: &[u8]) -> (Vec<u8>, Vec<String>) {
-    let mut clips = Vec::new();
-    let mut cleaned = Vec::with_capacity(data.len());
-    let mut i = 0;
+/// Line-at-a-time terminal renderer for export.
+struct ExportRenderer {
+    html: bool,
+    out: String,
+    line: Vec<(char, Attrs)>,
+    col: usize,
+    attrs: Attrs,
+}
 
-    while i < data.len() {
-        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5d {
-            // ESC ]
-            // Check for "52;"
-            if i + 4 < data.len() && data[i + 2] == b'5' && data[i + 3] == b'2' && data[i + 4] == b';' {
-                // Find the selection parameter (single char like 'c', 'p', 's', etc.) then ';'
-                let after_52 = i + 5;
-                // Find the second ';' that separates selection from base64 data
-                let mut semi_pos = after_52;
-                while semi_pos < data.len() && data[semi_pos] != b';' {
-                    // Selection parameter chars (short — usually 1-2 chars)
-                    if semi_pos - after_52 > 10 {
-                        break; // too long for selection param
+impl ExportRenderer {
+    fn new(html: bool) -> Self {
+        Self {
+            html,
+            out: String::new(),
+            line: Vec::new(),
+            col: 0,
+            attrs: Attrs::default(),
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            match data[i] {
+                0x1b => {
+                    let end = skip_escape(data, i);
+                    if data.get(i + 1) == Some(&b'[') && end > i + 2 {
+                        self.csi(&data[i + 2..end - 1], data[end - 1]);
                     }
-                    semi_pos += 1;
+                    i = end;
+                    continue;
                 }
-                if semi_pos < data.len() && data[semi_pos] == b';' {
-                    let b64_start = semi_pos + 1;
-                    // Find terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
-                    let mut end = b64_start;
-                    let mut found = false;
-                    let mut term_len = 0usize;
-                    while end < data.len() {
-                        if data[end] == 0x07 {
-                            term_len = 1;
-                            found = true;
-                            break;
-                        }
-                        if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
-                            term_len = 2;
-                            found = true;
-                            break;
-                        }
-                        end += 1;
+                b'\n' => self.end_line(),
+                b'\r' => self.col = 0,
+                0x08 => self.col = self.col.saturating_sub(1),
+                b'\t' => {
+                    for _ in 0..8 - self.col % 8 {
+                        self.put(' ');
                     }
-                    if found {
-                        let b64_data = &data[b64_start..end];
-                        // Check if this is a query (just "?")
-                        if b64_data == b"?" {
-                            // OSC 52 query — strip it, don't respond
-                            i = end + term_len;
-                            continue;
-                        }
-                        if let Some(decoded) = base64_decode(b64_data) {
-                            if decoded.len() <= CLIPBOARD_MAX_SIZE {
-                                if let Ok(text) = String::from_utf8(decoded) {
-                                    clips.push(text);
-                                }
-                            }
-                        }
-                        // Strip the OSC 52 from output (don't pass to xterm)
-                        i = end + term_len;
+                }
+                b if b < 0x20 || b == 0x7f => {}
+                _ => {
+                    let len = utf8_sequence_len(&data[i..]);
+                    if len == 0 {
+                        i += 1;
                         continue;
                     }
+                    let ch = std::str::from_utf8(&data[i..i + len]).unwrap().chars().next().unwrap();
+                    self.put(ch);
+                    i += len;
+                    continue;
                 }
             }
+            i += 1;
         }
-        cleaned.push(data[i]);
-        i += 1;
     }
 
-    (cleaned, clips)
+    fn csi(&mut self, params: &[u8], final_byte: u8) {
+        match final_byte {
+            // Private and intermediate forms (e.g. `?25l`) don't affect text
+            b'm' if params.first().is_none_or(u8::is_ascii_digit) => {
+                self.attrs.apply_sgr(&parse_csi_params(params));
+            }
+            // Erase in line: to end, or the whole line
+            b'K' => match parse_csi_params(params).first().and_then(|g| g.first()).copied().unwrap_or(0) {
+                0 => self.line.truncate(self.col),
+                2 => self.line.clear(),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        while self.line.len() < self.col {
+            self.line.push((' ', Attrs::default()));
+        }
+        if self.col < self.line.len() {
+            self.line[self.col] = (ch, self.attrs);
+        } else {
+            self.line.push((ch, self.attrs));
+        }
+        self.col += 1;
+    }
+
+    fn end_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        let visible = line.iter().rposition(|(ch, attrs)| *ch != ' ' || attrs.bg != Color::Default);
+        let line = &line[..visible.map_or(0, |p| p + 1)];
+        if self.html {
+            for run in line.chunk_by(|a, b| a.1 == b.1) {
+                let text: String = run.iter().map(|(ch, _)| ch).collect();
+                let style = css_style(&run[0].1);
+                if style.is_empty() {
+                    escape_html(&text, &mut self.out);
+                } else {
+                    self.out.push_str(&format!("<span style=\"{}\">", style));
+                    escape_html(&text, &mut self.out);
+                    self.out.push_str("</span>");
+                }
+            }
+        } else {
+            self.out.extend(line.iter().map(|(ch, _)| ch));
+        }
+        self.out.push('\n');
+        self.col = 0;
+    }
+
+    fn finish(mut self) -> String {
+        if !self.line.is_empty() {
+            self.end_line();
+        }
+        self.out
+    }
 }
 
-/// Extract OSC 9 notifications from data. Returns (cleaned_data, notifications).
-fn extract_osc9_notifications(data: &[u8]) -> (Vec<u8>, Vec<String>) {
-    let mut notifications = Vec::new();
-    let mut cleaned = Vec::with_capacity(data.len());
-    let mut i = 0;
+/// Render output as plain text, or as a standalone HTML page.
+fn render_export(data: &[u8], format: u8, title: &str) -> String {
+    let html = format == EXPORT_FORMAT_HTML;
+    let mut renderer = ExportRenderer::new(html);
+    renderer.feed(data);
+    let body = renderer.finish();
+    if !html {
+        return body;
+    }
+    let mut escaped_title = String::new();
+    escape_html(title, &mut escaped_title);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n\
+         <body style=\"margin:0;background:{};color:{}\">\n\
+         <pre style=\"margin:0;padding:8px;font-family:monospace\">{}</pre>\n</body>\n</html>\n",
+        escaped_title, EXPORT_BG, EXPORT_FG, body
+    )
+}
 
-    while i < data.len() {
-        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5d {
-            // ESC ]
-            if i + 2 < data.len() && data[i + 2] == b'9' {
-                if i + 3 < data.len() && data[i + 3] == b';' {
-                    // Find the terminator
-                    let start = i + 4;
-                    let mut end = start;
-                    let mut found = false;
-                    while end < data.len() {
-                        if data[end] == 0x07 {
-                            let msg =
-                                String::from_utf8_lossy(&data[start..end]).to_string();
-                            notifications.push(msg);
-                            i = end + 1;
-                            found = true;
-                            break;
-                        }
-                        if data[end] == 0x1b
-                            && end + 1 < data.len()
-                            && data[end + 1] == 0x5c
-                        {
-                            let msg =
-                                String::from_utf8_lossy(&data[start..end]).to_string();
-                            notifications.push(msg);
-                            i = end + 2;
-                            found = true;
-                            break;
-                        }
-                        end += 1;
-                    }
-                    if found {
-                        continue;
-                    }
-                }
+/// `relay-pty-host export [--html] <id>`: print a running session's output.
+/// A session whose pty-host is gone is exported from its persisted
+/// scrollback, if it kept one.
+fn run_export(id: &str, format: u8) -> i32 {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let data_dir = PathBuf::from(home).join(".relay-tty");
+    let socket_path = data_dir.join("sockets").join(format!("{}.sock", id));
+    let result = match request_export(&socket_path, format) {
+        Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => {
+            match read_dead_scrollback(&data_dir, id, PERSIST_MAX_BYTES) {
+                Ok(Some(data)) => Ok(render_export(&data, format, &format!("relay-tty {}", id)).into_bytes()),
+                Ok(None) => Err(e),
+                Err(e) => Err(e),
             }
         }
-        cleaned.push(data[i]);
-        i += 1;
+        result => result,
+    }
+    .and_then(|doc| io::stdout().write_all(&doc));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("pty-host: export from {} failed: {}", socket_path.display(), e);
+            1
+        }
     }
+}
 
-    (cleaned, notifications)
+/// Ask the pty-host on `socket_path` for an export. HELLO first, so the
+/// session's output isn't replayed to us.
+fn request_export(socket_path: &Path, format: u8) -> io::Result<Vec<u8>> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut hello = vec![WS_MSG_HELLO];
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello.extend_from_slice(&0u32.to_be_bytes());
+    stream.write_all(&encode_frame(&hello))?;
+    stream.write_all(&encode_frame(&[WS_MSG_EXPORT, format]))?;
+    loop {
+        let payload = read_frame_blocking(&mut stream)?;
+        if payload.len() >= 2 && payload[0] == WS_MSG_EXPORT {
+            return Ok(payload[2..].to_vec());
+        }
+    }
 }
 
-/// An extracted inline image from an iTerm2 OSC 1337 sequence.
-struct InlineImage {
-    /// Unique image ID (monotonic counter, formatted as string)
-    id: String,
-    /// MIME type inferred from magic bytes (defaults to "image/png")
-    mime: String,
-    /// Raw decoded image bytes
-    data: Vec<u8>,
+// ── Output watchers ─────────────────────────────────────────────────
+//
+// Watch rules run a regex over each line of PTY output (escapes stripped)
+// and act on a match: send a NOTIFICATION, hang up the session, or record a
+// mark in the session metadata. Rules come from `--watch` at spawn and can
+// be replaced at runtime with a WATCH message. An unterminated line is
+// matched as it grows, so prompts like `password:` fire without a newline.
+
+/// Upper bound on rules per session.
+const WATCH_MAX_RULES: usize = 32;
+/// Marks kept in the session metadata; older ones are dropped.
+const WATCH_MAX_MARKS: usize = 100;
+/// Bytes of an unterminated line kept for matching.
+const WATCH_MAX_LINE: usize = 4096;
+/// A rule acts at most once per interval, so a flood of matching lines
+/// doesn't become a flood of notifications.
+const WATCH_COOLDOWN: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WatchAction {
+    /// Broadcast a NOTIFICATION.
+    #[default]
+    Notify,
+    /// Hang up the session, as if its terminal had been closed.
+    Exit,
+    /// Append a `WatchMark` to the session metadata.
+    Mark,
 }
 
-/// Global image counter for unique IDs within this pty-host process.
-static IMAGE_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchRule {
+    pattern: String,
+    #[serde(default)]
+    action: WatchAction,
+    /// Notification text; defaults to the matching line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
 
-/// Infer MIME type from the first few bytes (magic number).
-fn infer_mime(data: &[u8]) -> &'static str {
-    if data.len() >= 8 && data[..8] == [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a] {
-        "image/png"
-    } else if data.len() >= 3 && data[..3] == [0xFF, 0xD8, 0xFF] {
-        "image/jpeg"
-    } else if data.len() >= 4 && &data[..4] == b"GIF8" {
-        "image/gif"
-    } else if data.len() >= 4 && &data[..4] == b"RIFF" && data.len() >= 12 && &data[8..12] == b"WEBP" {
-        "image/webp"
-    } else {
-        "image/png" // fallback
+impl WatchRule {
+    /// Parse a `--watch` value: `[notify:|exit:|mark:]<regex>`.
+    fn parse_flag(spec: &str) -> Self {
+        let (action, pattern) = match spec.split_once(':') {
+            Some(("notify", rest)) => (WatchAction::Notify, rest),
+            Some(("exit", rest)) => (WatchAction::Exit, rest),
+            Some(("mark", rest)) => (WatchAction::Mark, rest),
+            _ => (WatchAction::Notify, spec),
+        };
+        WatchRule {
+            pattern: pattern.to_string(),
+            action,
+            message: None,
+        }
     }
 }
 
-/// Extract iTerm2 OSC 1337 inline image sequences from data.
-/// Format: ESC ] 1337 ; File=[args] : base64data BEL|ESC\
-/// Returns (cleaned_data, images).
-/// The OSC 1337 sequences are stripped from the output (they are huge base64
-/// blobs that must not go into the ring buffer). A small placeholder is
-/// inserted so the cursor advances one line.
-fn extract_osc1337_images(data: &[u8]) -> (Vec<u8>, Vec<InlineImage>) {
-    let mut images = Vec::new();
-    let mut cleaned = Vec::with_capacity(data.len());
-    let mut i = 0;
+/// A match recorded by a `mark` rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchMark {
+    pattern: String,
+    /// The matching line, without escape sequences.
+    line: String,
+    /// Stream offset of the start of the line, in SYNC coordinates.
+    offset: f64,
+    /// Milliseconds since the epoch.
+    at: u64,
+}
 
-    // Prefix: ESC ] 1337 ; File=
-    // In bytes: 0x1b 0x5d '1' '3' '3' '7' ';' 'F' 'i' 'l' 'e' '='
-    const PREFIX: &[u8] = b"\x1b]1337;File=";
+/// WATCH request from a client: replaces the rule set.
+#[derive(Debug, Deserialize)]
+struct WatchRequest {
+    rules: Vec<WatchRule>,
+}
 
-    while i < data.len() {
-        // Quick check: ESC at this position?
-        if data[i] == 0x1b && i + PREFIX.len() <= data.len() && &data[i..i + PREFIX.len()] == PREFIX {
-            let args_start = i + PREFIX.len();
-            // Find the ':' that separates args from base64 data.
-            // Args are key=value pairs separated by ';' (e.g., name=..;size=..;inline=1)
-            let mut colon_pos = None;
-            let mut j = args_start;
-            while j < data.len() {
-                if data[j] == b':' {
-                    colon_pos = Some(j);
-                    break;
-                }
-                // If we hit a terminator before ':', it's malformed
-                if data[j] == 0x07 || (data[j] == 0x1b && j + 1 < data.len() && data[j + 1] == 0x5c) {
-                    break;
-                }
-                j += 1;
-            }
+/// WATCH reply: the rules in effect.
+#[derive(Debug, Default, Serialize)]
+struct WatchReply {
+    rules: Vec<WatchRule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-            if let Some(colon) = colon_pos {
-                let b64_start = colon + 1;
-                // Find terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
-                let mut end = b64_start;
-                let mut found = false;
-                let mut term_len = 0usize;
-                while end < data.len() {
-                    if data[end] == 0x07 {
-                        term_len = 1;
-                        found = true;
-                        break;
-                    }
-                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
-                        term_len = 2;
-                        found = true;
-                        break;
-                    }
-                    end += 1;
-                }
-
-                if found {
-                    let b64_data = &data[b64_start..end];
-
-                    // Parse args to check inline=1
-                    let args_str = std::str::from_utf8(&data[args_start..colon]).unwrap_or("");
-                    let mut is_inline = false;
-                    for arg in args_str.split(';') {
-                        if let Some((key, val)) = arg.split_once('=') {
-                            if key == "inline" && val == "1" {
-                                is_inline = true;
-                            }
-                        }
-                    }
-
-                    // Only process inline images — preserve non-inline sequences
-                    if !is_inline {
-                        // Not inline: preserve the entire sequence in output
-                        cleaned.extend_from_slice(&data[i..end + term_len]);
-                        i = end + term_len;
-                        continue;
-                    }
-
-                    if let Some(decoded) = base64_decode(b64_data) {
-                        if !decoded.is_empty() && decoded.len() <= IMAGE_MAX_SIZE {
-                            let img_id = IMAGE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                            let mime = infer_mime(&decoded).to_string();
-                            images.push(InlineImage {
-                                id: format!("img-{}", img_id),
-                                mime,
-                                data: decoded,
-                            });
-
-                            // Insert a placeholder line so the terminal advances the cursor.
-                            // Styled with dim+italic CSI so it's unobtrusive.
-                            let placeholder = format!(
-                                "\x1b[2;3m[image: img-{}]\x1b[0m\r\n",
-                                img_id
-                            );
-                            cleaned.extend_from_slice(placeholder.as_bytes());
-                        }
-                    }
-                    // Skip past the entire OSC 1337 sequence
-                    i = end + term_len;
-                    continue;
-                }
-            }
-        }
-        cleaned.push(data[i]);
-        i += 1;
-    }
-
-    (cleaned, images)
+/// What one chunk of output set off.
+#[derive(Debug, Default, PartialEq)]
+struct WatchHits {
+    notifications: Vec<String>,
+    marks: Vec<WatchMark>,
+    exit: bool,
 }
 
-/// Results from a single OscExtractor::feed() call.
-struct OscExtractResult {
-    /// Cleaned data with all recognized OSC sequences stripped.
-    cleaned: Vec<u8>,
-    /// Extracted clipboard texts (OSC 52).
-    clipboard_texts: Vec<String>,
-    /// Extracted notification messages (OSC 9).
-    notifications: Vec<String>,
-    /// Extracted inline images (OSC 1337).
-    inline_images: Vec<InlineImage>,
+struct CompiledRule {
+    rule: WatchRule,
+    regex: regex_lite::Regex,
+    last_fired: Option<Instant>,
 }
 
-/// Stateful OSC sequence extractor that handles sequences split across
-/// PTY read() boundaries. Buffers partial (unterminated) OSC sequences
-/// and prepends them to the next read's data.
-struct OscExtractor {
-    /// Bytes from a partial OSC sequence at the end of the previous read.
+/// Evaluates watch rules over the output stream, line by line.
+#[derive(Default)]
+struct Watcher {
+    rules: Vec<CompiledRule>,
+    /// The current, unterminated line.
+    line: String,
+    line_start: f64,
+    /// Which rules already fired on `line`.
+    fired: Vec<bool>,
+    /// A CSI sequence cut off at the end of the previous chunk.
     pending: Vec<u8>,
 }
 
-impl OscExtractor {
-    fn new() -> Self {
-        Self {
-            pending: Vec::new(),
+impl Watcher {
+    /// Replace the rule set. On error the old rules stay in effect.
+    fn set_rules(&mut self, rules: Vec<WatchRule>) -> Result<(), String> {
+        if rules.len() > WATCH_MAX_RULES {
+            return Err(format!("at most {} watch rules", WATCH_MAX_RULES));
         }
+        let compiled = rules
+            .into_iter()
+            .map(|rule| {
+                if rule.pattern.is_empty() {
+                    return Err("empty watch pattern".to_string());
+                }
+                let regex = regex_lite::Regex::new(&rule.pattern).map_err(|e| e.to_string())?;
+                Ok(CompiledRule {
+                    rule,
+                    regex,
+                    last_fired: None,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.fired = vec![false; compiled.len()];
+        self.rules = compiled;
+        Ok(())
     }
 
-    /// Feed a chunk of PTY output. Returns cleaned data and any extracted
-    /// OSC payloads. Partial OSC sequences at the end of `data` are buffered
-    /// internally and will be completed on the next call.
-    fn feed(&mut self, data: &[u8]) -> OscExtractResult {
-        // If we have pending bytes from a previous partial sequence,
-        // prepend them to form a combined buffer.
+    fn rules(&self) -> Vec<WatchRule> {
+        self.rules.iter().map(|r| r.rule.clone()).collect()
+    }
+
+    /// Match rules against `data`, which starts at stream offset `start`.
+    fn feed(&mut self, data: &[u8], start: f64, now: Instant) -> WatchHits {
+        let mut hits = WatchHits::default();
+        if self.rules.is_empty() {
+            return hits;
+        }
         let combined: Vec<u8>;
-        let input = if self.pending.is_empty() {
-            data
+        let (input, start) = if self.pending.is_empty() {
+            (data, start)
         } else {
-            combined = [self.pending.as_slice(), data].concat();
-            self.pending.clear();
-            &combined
+            let held = self.pending.len() as f64;
+            combined = [std::mem::take(&mut self.pending).as_slice(), data].concat();
+            (combined.as_slice(), start - held)
+        };
+        let input = match trailing_partial_csi(input) {
+            Some(cut) => {
+                self.pending = input[cut..].to_vec();
+                &input[..cut]
+            }
+            None => input,
         };
 
-        // Run the three extraction passes.
-        let (after_osc9, notifications) = extract_osc9_notifications(input);
-        let (after_osc52, clipboard_texts) = extract_osc52_clipboard(&after_osc9);
-        let (mut cleaned, inline_images) = extract_osc1337_images(&after_osc52);
-
-        // Check if `cleaned` ends with a partial (unterminated) OSC sequence.
-        // The extract functions pass incomplete sequences through to cleaned,
-        // so we need to find and stash any trailing partial for the next call.
-        if let Some(partial_start) = find_trailing_partial_osc(&cleaned) {
-            self.pending = cleaned.split_off(partial_start);
-        }
-
-        OscExtractResult {
-            cleaned,
-            clipboard_texts,
-            notifications,
-            inline_images,
+        let stripped = strip_for_search(input, start);
+        let mut pos = 0;
+        for piece in stripped.text.split_inclusive('\n') {
+            if self.line.is_empty() && !piece.starts_with('\n') {
+                self.line_start = stripped.offset(pos);
+            }
+            pos += piece.len();
+            let (text, ended) = match piece.strip_suffix('\n') {
+                Some(text) => (text, true),
+                None => (piece, false),
+            };
+            let room = WATCH_MAX_LINE.saturating_sub(self.line.len());
+            let mut take = text.len().min(room);
+            while !text.is_char_boundary(take) {
+                take -= 1;
+            }
+            self.line.push_str(&text[..take]);
+            self.check_line(now, &mut hits);
+            if ended {
+                self.line.clear();
+                self.fired.iter_mut().for_each(|f| *f = false);
+            }
         }
+        hits
     }
-}
 
-/// Scan data for a trailing partial OSC sequence (ESC ] ... without terminator).
-/// Returns the byte offset where the partial sequence starts, or None.
-///
-/// We look for the last ESC ] (0x1b 0x5d) and check whether it's followed
-/// by a BEL (0x07) or ST (ESC \) terminator. If not, it's a partial sequence
-/// that was passed through by the extract functions.
-fn find_trailing_partial_osc(data: &[u8]) -> Option<usize> {
-    // Search backward for the last ESC (0x1b)
-    let mut i = data.len();
-    while i > 0 {
-        i -= 1;
-        if data[i] == 0x1b {
-            // Check if this could be the start of an OSC: ESC ]
-            if i + 1 < data.len() && data[i + 1] == 0x5d {
-                // Found ESC ]. Check if there's a terminator after it.
-                // Scan forward from here looking for BEL or ESC\.
-                let mut j = i + 2;
-                while j < data.len() {
-                    if data[j] == 0x07 {
-                        // Found BEL terminator — this OSC is complete, not partial.
-                        return None;
-                    }
-                    if data[j] == 0x1b && j + 1 < data.len() && data[j + 1] == 0x5c {
-                        // Found ESC\ terminator — this OSC is complete.
-                        return None;
-                    }
-                    j += 1;
-                }
-                // No terminator found — this is a partial OSC sequence.
-                return Some(i);
+    fn check_line(&mut self, now: Instant, hits: &mut WatchHits) {
+        for (rule, fired) in self.rules.iter_mut().zip(self.fired.iter_mut()) {
+            if *fired {
+                continue;
             }
-            // Lone ESC at the very end of data — do NOT buffer it.
-            // It's far more likely to be the start of CSI (ESC [) than
-            // OSC (ESC ]). Buffering it delays critical escape sequence
-            // data, causing visible rendering stalls in programs like fzf
-            // that go idle after their initial render. xterm.js's parser
-            // handles split escape sequences across write() calls correctly,
-            // so the ESC will be joined with the next chunk on the client.
-            if i + 1 == data.len() {
-                return None;
+            let Some(m) = rule.regex.find(&self.line) else { continue };
+            *fired = true;
+            if rule.last_fired.is_some_and(|t| now.duration_since(t) < WATCH_COOLDOWN) {
+                continue;
+            }
+            rule.last_fired = Some(now);
+            match rule.rule.action {
+                WatchAction::Notify => hits.notifications.push(
+                    rule.rule.message.clone().unwrap_or_else(|| clip_line(&self.line, m.start())),
+                ),
+                WatchAction::Exit => hits.exit = true,
+                WatchAction::Mark => hits.marks.push(WatchMark {
+                    pattern: rule.rule.pattern.clone(),
+                    line: clip_line(&self.line, m.start()),
+                    offset: self.line_start,
+                    at: now_millis(),
+                }),
             }
         }
     }
-    None
 }
 
-// ── Foreground process name resolution ──────────────────────────────
+/// Start of a CSI sequence (or lone ESC) cut off at the end of `data`.
+fn trailing_partial_csi(data: &[u8]) -> Option<usize> {
+    let i = data.iter().rposition(|&b| b == 0x1b)?;
+    match data.get(i + 1) {
+        None => Some(i),
+        Some(b'[') if !data[i + 2..].iter().any(|b| (0x40..=0x7e).contains(b)) => Some(i),
+        _ => None,
+    }
+}
 
-/// Resolve a PID to its process name.
-/// On macOS, uses `proc_name()` from libproc.
-/// On Linux, reads `/proc/<pid>/comm`.
-/// Returns None if the process cannot be found (race: it may have exited).
-fn get_process_name(pid: libc::pid_t) -> Option<String> {
-    #[cfg(target_os = "macos")]
-    {
-        // libproc's proc_name: fills a buffer with the process name
-        let mut buf = [0u8; 256];
-        let len = unsafe {
-            libc::proc_name(
-                pid,
-                buf.as_mut_ptr() as *mut libc::c_void,
-                buf.len() as u32,
-            )
-        };
-        if len > 0 {
-            return Some(
-                String::from_utf8_lossy(&buf[..len as usize])
-                    .trim_end_matches('\0')
-                    .to_string(),
-            );
+/// Hang up the session for an `exit` watch rule: SIGHUP the foreground job
+/// (if it isn't the shell) and the shell, like closing its terminal.
+fn hang_up(master_fd: RawFd, child_pid: libc::pid_t) {
+    let fg_pgrp = unsafe { libc::tcgetpgrp(master_fd) };
+    if fg_pgrp > 0 && fg_pgrp != child_pid {
+        unsafe {
+            libc::kill(-fg_pgrp, libc::SIGHUP);
         }
-        None
     }
-    #[cfg(not(target_os = "macos"))]
-    {
-        // Linux: read /proc/<pid>/comm
-        std::fs::read_to_string(format!("/proc/{}/comm", pid))
-            .ok()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
+    unsafe {
+        libc::kill(child_pid, libc::SIGHUP);
     }
 }
 
-// ── Process CWD resolution ──────────────────────────────────────────
+// ── Command history ─────────────────────────────────────────────────
+//
+// Shells with FinalTerm / OSC 133 integration mark where each prompt (A),
+// command line (B), output (C) and completion (D, with exit status) start.
+// pty-host turns those marks into a command history with stream offsets,
+// so clients can jump between command outputs and learn when a command
+// finishes. Marks are stripped from the output.
 
-/// Resolve a PID to its current working directory.
-/// On macOS, uses `proc_pidinfo(PROC_PIDVNODEPATHINFO)` from libproc.
-/// On Linux, reads `/proc/<pid>/cwd` symlink.
-/// Returns None if the process cannot be found or CWD cannot be resolved.
-fn get_process_cwd(pid: libc::pid_t) -> Option<String> {
-    #[cfg(target_os = "macos")]
-    {
-        // PROC_PIDVNODEPATHINFO returns vnode path info including CWD
-        const PROC_PIDVNODEPATHINFO: i32 = 9;
-        // struct proc_vnodepathinfo layout (from libproc.h):
-        //   pvi_cdir: struct vnode_info_path (offset 0, CWD path at +152)
-        //   pvi_rdir: struct vnode_info_path (root dir)
-        // Total struct size: 2352 bytes, CWD path at offset 152.
-        const STRUCT_SIZE: usize = 2352;
-        const CWD_PATH_OFFSET: usize = 152;
-        const MAXPATHLEN: usize = 1024;
+/// Finished commands kept in the history.
+const COMMAND_HISTORY_MAX: usize = 1000;
+/// Command lines longer than this (in output bytes) aren't captured.
+const COMMAND_LINE_MAX_BYTES: usize = 64 * 1024;
 
-        let mut buf = vec![0u8; STRUCT_SIZE];
-        let ret = unsafe {
-            libc::proc_pidinfo(
-                pid,
-                PROC_PIDVNODEPATHINFO,
-                0,
-                buf.as_mut_ptr() as *mut libc::c_void,
-                STRUCT_SIZE as i32,
-            )
-        };
-        if ret == STRUCT_SIZE as i32 {
-            let path_bytes = &buf[CWD_PATH_OFFSET..CWD_PATH_OFFSET + MAXPATHLEN];
-            let end = path_bytes.iter().position(|&b| b == 0).unwrap_or(MAXPATHLEN);
-            if end > 0 {
-                return String::from_utf8_lossy(&path_bytes[..end])
-                    .to_string()
-                    .into();
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandRecord {
+    /// Sequence number, from 1.
+    id: u64,
+    /// Stream offset of the prompt (A), in SYNC coordinates.
+    prompt_offset: f64,
+    /// Stream offset of the command line (B).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_offset: Option<f64>,
+    /// Stream offset where the command's output starts (C).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_offset: Option<f64>,
+    /// Stream offset where the command finished (D).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_offset: Option<f64>,
+    /// The command line as displayed, without escapes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    /// Milliseconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+}
+
+/// COMMANDS payload, both for history replies and live updates.
+#[derive(Debug, Serialize)]
+struct CommandsMessage<'a> {
+    commands: Vec<&'a CommandRecord>,
+}
+
+/// Builds the command history from OSC 133 marks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandTracker {
+    /// Finished commands, oldest first.
+    history: VecDeque<CommandRecord>,
+    /// The command whose prompt is showing or which is running.
+    current: Option<CommandRecord>,
+    next_id: u64,
+}
+
+impl CommandTracker {
+    /// Apply a mark at stream offset `offset`. `output` must already hold
+    /// the output up to the mark. Returns the record if a command started
+    /// or finished.
+    fn mark(&mut self, mark: PromptMark, offset: f64, now: u64, output: &OutputBuffer) -> Option<&CommandRecord> {
+        match mark {
+            PromptMark::PromptStart => {
+                // A command that never reported D (e.g. the shell was
+                // replaced by exec) ends at the next prompt.
+                if self.current.as_ref().is_some_and(|c| c.output_offset.is_some()) {
+                    self.finish(offset, None, now);
+                }
+                self.start_prompt(offset);
+                None
+            }
+            PromptMark::CommandStart => {
+                self.current_or_new(offset).input_offset = Some(offset);
+                None
+            }
+            PromptMark::OutputStart => {
+                let current = self.current_or_new(offset);
+                current.output_offset = Some(offset);
+                current.started_at = Some(now);
+                current.command = current.input_offset.and_then(|start| command_line(output, start, offset));
+                self.current.as_ref()
+            }
+            // D without C: an empty command line or a prompt redraw
+            PromptMark::CommandEnd(exit_code) => {
+                self.current.as_ref()?.output_offset?;
+                self.finish(offset, exit_code, now);
+                self.history.back()
             }
         }
-        None
     }
-    #[cfg(not(target_os = "macos"))]
-    {
-        // Linux: read /proc/<pid>/cwd symlink
-        std::fs::read_link(format!("/proc/{}/cwd", pid))
-            .ok()
-            .and_then(|p| p.to_str().map(|s| s.to_string()))
+
+    fn start_prompt(&mut self, offset: f64) -> &mut CommandRecord {
+        self.next_id += 1;
+        self.current.insert(CommandRecord {
+            id: self.next_id,
+            prompt_offset: offset,
+            ..CommandRecord::default()
+        })
+    }
+
+    fn current_or_new(&mut self, offset: f64) -> &mut CommandRecord {
+        if self.current.is_none() {
+            self.start_prompt(offset);
+        }
+        self.current.as_mut().unwrap()
+    }
+
+    fn finish(&mut self, offset: f64, exit_code: Option<i32>, now: u64) {
+        let Some(mut record) = self.current.take() else { return };
+        record.end_offset = Some(offset);
+        record.finished_at = Some(now);
+        record.duration_ms = record.started_at.map(|t| now.saturating_sub(t));
+        record.exit_code = exit_code;
+        if self.history.len() == COMMAND_HISTORY_MAX {
+            self.history.pop_front();
+        }
+        self.history.push_back(record);
+    }
+
+    /// The most recent command: the running one, else the last finished.
+    fn last(&self) -> Option<&CommandRecord> {
+        self.current
+            .as_ref()
+            .filter(|c| c.output_offset.is_some())
+            .or(self.history.back())
+    }
+
+    /// Finished commands and the running one, oldest first.
+    fn all(&self) -> Vec<&CommandRecord> {
+        self.history
+            .iter()
+            .chain(self.current.iter().filter(|c| c.output_offset.is_some()))
+            .collect()
     }
 }
 
-// ── Session metadata ────────────────────────────────────────────────
+/// The command line between offsets `start` and `end`, rendered as text so
+/// line editing (backspaces, redraws) is applied.
+fn command_line(output: &OutputBuffer, start: f64, end: f64) -> Option<String> {
+    let len = (end - start) as usize;
+    if len > COMMAND_LINE_MAX_BYTES {
+        return None;
+    }
+    let bytes = output.slice(start, end)?;
+    let text = render_export(&bytes, EXPORT_FORMAT_TEXT, "");
+    let text = text.trim();
+    (!text.is_empty()).then(|| clip_line(text, 0))
+}
 
-#[derive(Serialize, Deserialize, Clone)]
+// ── Link index ──────────────────────────────────────────────────────
+//
+// OSC 8 hyperlinks (`ls --hyperlink`, compilers, test runners) pass through
+// to clients untouched, and are also indexed with their URI, display text
+// and stream offset so clients can list the links in a session without
+// re-parsing the buffer. LINKS returns the index.
+
+/// Links kept in the index; older ones are dropped.
+const LINK_INDEX_MAX: usize = 2000;
+/// URIs longer than this are not indexed.
+const LINK_MAX_URI: usize = 2048;
+/// Link text spanning more output bytes than this is not captured.
+const LINK_MAX_TEXT_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct SessionMeta {
-    id: String,
-    command: String,
-    args: Vec<String>,
-    cwd: String,
-    created_at: u64,
+struct LinkRecord {
+    uri: String,
+    /// The link's text as displayed, without escapes.
+    text: String,
+    /// Stream offset of the OSC 8 sequence that opened the link.
+    offset: f64,
+}
+
+/// LINKS reply.
+#[derive(Debug, Serialize)]
+struct LinksMessage<'a> {
+    links: &'a VecDeque<LinkRecord>,
+}
+
+/// Builds the link index from OSC 8 boundaries.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkIndex {
+    /// Oldest first.
+    links: VecDeque<LinkRecord>,
+    /// The link whose text is being output: its URI, offset and where its
+    /// text starts.
+    open: Option<(String, f64, f64)>,
+}
+
+impl LinkIndex {
+    /// Apply an OSC 8 boundary at stream offset `offset`. `base` is the
+    /// stream offset that event positions are relative to, and `output` must
+    /// already hold the output up to the boundary.
+    fn apply(&mut self, offset: f64, event: &LinkEvent, base: f64, output: &OutputBuffer) {
+        // Opening a link implicitly closes the previous one
+        if let Some((uri, start, text_start)) = self.open.take() {
+            let text = link_text(output, text_start, offset);
+            if self.links.len() == LINK_INDEX_MAX {
+                self.links.pop_front();
+            }
+            self.links.push_back(LinkRecord { uri, text, offset: start });
+        }
+        if let LinkEvent::Open { uri, text_start } = event {
+            if uri.len() <= LINK_MAX_URI {
+                self.open = Some((uri.clone(), offset, base + *text_start as f64));
+            }
+        }
+    }
+}
+
+/// The text between offsets `start` and `end`, rendered as plain text.
+fn link_text(output: &OutputBuffer, start: f64, end: f64) -> String {
+    if end - start > LINK_MAX_TEXT_BYTES as f64 {
+        return String::new();
+    }
+    let Some(bytes) = output.slice(start, end) else {
+        return String::new();
+    };
+    clip_line(render_export(&bytes, EXPORT_FORMAT_TEXT, "").trim(), 0)
+}
+
+// ── Persistent scrollback ───────────────────────────────────────────
+
+/// Size at which the active scrollback segment is closed and a new one started.
+const PERSIST_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Total on-disk cap; the oldest segments are deleted beyond this.
+const PERSIST_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Append-only on-disk copy of the output stream, opted into with
+/// `RELAY_PERSIST_SCROLLBACK=1`. Lives in `sessions/<id>.scrollback/` as
+/// segment files named by the stream offset of their first byte
+/// (`{offset:016x}.seg`), so any offset maps to a segment by name alone.
+struct ScrollbackStore {
+    dir: PathBuf,
+    segment_size: u64,
+    max_bytes: u64,
+    /// (start offset, length) of each segment, oldest first.
+    segments: VecDeque<(u64, u64)>,
+    /// Append handle for the newest segment.
+    file: Option<fs::File>,
+    /// Data written since the last fsync.
+    unsynced: bool,
+}
+
+impl ScrollbackStore {
+    /// Open (or create) the store, picking up segments left by a previous
+    /// pty-host for this session. Segments before a gap are discarded.
+    fn open(dir: PathBuf, segment_size: u64, max_bytes: u64) -> io::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        // Segments hold the session's full output: keep them owner-only
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        let store = Self::open_read_only(dir, segment_size, max_bytes)?;
+        store.prune_stale()?;
+        Ok(store)
+    }
+
+    /// Open an existing store for reading only, e.g. a session whose
+    /// pty-host is gone. Nothing on disk is created or deleted; segments
+    /// before a gap are ignored rather than discarded.
+    fn open_read_only(dir: PathBuf, segment_size: u64, max_bytes: u64) -> io::Result<Self> {
+        let mut found: Vec<(u64, u64)> = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(start) = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".seg"))
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            else {
+                continue;
+            };
+            found.push((start, entry.metadata()?.len()));
+        }
+        found.sort_unstable();
+
+        // Only the run after the last gap is usable
+        let mut segments: VecDeque<(u64, u64)> = VecDeque::new();
+        for (start, len) in found {
+            if let Some(&(prev_start, prev_len)) = segments.back() {
+                if prev_start + prev_len != start {
+                    segments.clear();
+                }
+            }
+            segments.push_back((start, len));
+        }
+
+        Ok(Self {
+            dir,
+            segment_size,
+            max_bytes,
+            segments,
+            file: None,
+            unsynced: false,
+        })
+    }
+
+    /// Delete segment files that aren't part of the usable run.
+    fn prune_stale(&self) -> io::Result<()> {
+        let first = self.start_offset();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let stale = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".seg"))
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .is_some_and(|start| start < first);
+            if stale {
+                let _ = fs::remove_file(self.dir.join(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn segment_name(start: u64) -> String {
+        format!("{:016x}.seg", start)
+    }
+
+    fn segment_path(&self, start: u64) -> PathBuf {
+        self.dir.join(Self::segment_name(start))
+    }
+
+    /// Stream offset of the first persisted byte.
+    fn start_offset(&self) -> u64 {
+        self.segments.front().map_or(0, |&(start, _)| start)
+    }
+
+    /// Stream offset just past the last persisted byte.
+    fn end_offset(&self) -> u64 {
+        self.segments.back().map_or(0, |&(start, len)| start + len)
+    }
+
+    /// Append output, rolling to a new segment when the current one is full
+    /// and deleting the oldest segments once over the size cap.
+    fn append(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let needs_segment = match self.segments.back() {
+                Some(&(_, len)) => len >= self.segment_size,
+                None => true,
+            };
+            if needs_segment {
+                self.start_segment()?;
+            }
+            if self.file.is_none() {
+                let (start, _) = *self.segments.back().unwrap();
+                self.file = Some(Self::open_segment(&self.segment_path(start))?);
+            }
+
+            let (_, len) = self.segments.back_mut().unwrap();
+            let room = (self.segment_size - *len).min(data.len() as u64) as usize;
+            self.file.as_mut().unwrap().write_all(&data[..room])?;
+            *len += room as u64;
+            self.unsynced = true;
+            data = &data[room..];
+        }
+        self.evict();
+        Ok(())
+    }
+
+    /// Close the current segment and open an empty one at the end offset.
+    fn start_segment(&mut self) -> io::Result<()> {
+        self.sync();
+        let start = self.end_offset();
+        self.file = Some(Self::open_segment(&self.segment_path(start))?);
+        self.segments.push_back((start, 0));
+        Ok(())
+    }
+
+    /// Open a segment for appending, creating it owner-only.
+    fn open_segment(path: &Path) -> io::Result<fs::File> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        fs::OpenOptions::new().create(true).append(true).mode(0o600).open(path)
+    }
+
+    fn evict(&mut self) {
+        let mut total: u64 = self.segments.iter().map(|&(_, len)| len).sum();
+        while total > self.max_bytes && self.segments.len() > 1 {
+            let (start, len) = self.segments.pop_front().unwrap();
+            let _ = fs::remove_file(self.segment_path(start));
+            total -= len;
+        }
+    }
+
+    /// Flush appended data to disk so it survives a host crash.
+    fn sync(&mut self) {
+        if self.unsynced {
+            if let Some(ref file) = self.file {
+                let _ = file.sync_data();
+            }
+            self.unsynced = false;
+        }
+    }
+
+    /// Drop all persisted output (CLEAR_SCROLLBACK). An empty segment is left
+    /// at the current end offset so a restarted pty-host resumes numbering.
+    fn clear(&mut self) -> io::Result<()> {
+        let end = self.end_offset();
+        self.file = None;
+        for (start, _) in self.segments.drain(..) {
+            let _ = fs::remove_file(self.dir.join(Self::segment_name(start)));
+        }
+        self.segments.push_back((end, 0));
+        Self::open_segment(&self.segment_path(end))?;
+        Ok(())
+    }
+
+    /// Read persisted bytes in `[offset, end)`. Returns None if `offset`
+    /// predates the oldest segment.
+    fn read_range(&self, offset: u64, end: u64) -> io::Result<Option<Vec<u8>>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        if offset < self.start_offset() {
+            return Ok(None);
+        }
+        let end = end.min(self.end_offset());
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        for &(start, len) in &self.segments {
+            let seg_end = start + len;
+            if seg_end <= offset || start >= end {
+                continue;
+            }
+            let from = offset.max(start);
+            let to = end.min(seg_end);
+            let mut file = fs::File::open(self.segment_path(start))?;
+            file.seek(SeekFrom::Start(from - start))?;
+            file.take(to - from).read_to_end(&mut out)?;
+        }
+        Ok(Some(out))
+    }
+
+    /// The last `max_len` persisted bytes and the offset they start at.
+    fn read_tail(&self, max_len: u64) -> io::Result<(u64, Vec<u8>)> {
+        let end = self.end_offset();
+        let start = end.saturating_sub(max_len).max(self.start_offset());
+        let data = self.read_range(start, end)?.unwrap_or_default();
+        Ok((start, data))
+    }
+}
+
+/// The last `max_len` bytes persisted by session `id`, for reading a session
+/// whose pty-host is gone without spawning a new one. None if the session
+/// didn't persist its scrollback.
+fn read_dead_scrollback(data_dir: &Path, id: &str, max_len: u64) -> io::Result<Option<Vec<u8>>> {
+    let dir = data_dir.join("sessions").join(format!("{}.scrollback", id));
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let store = ScrollbackStore::open_read_only(dir, PERSIST_SEGMENT_SIZE, PERSIST_MAX_BYTES)?;
+    Ok(Some(store.read_tail(max_len)?.1))
+}
+
+// ── asciicast recording ─────────────────────────────────────────────
+
+/// Writes session output to an asciicast v2 file (`--record <file>` or
+/// `RELAY_RECORD=<file>`): a JSON header line followed by one
+/// `[time, "o", data]` event per output chunk and `[time, "r", "COLSxROWS"]`
+/// per resize, with times in seconds since recording started.
+struct AsciicastRecorder {
+    file: fs::File,
+    start: Instant,
+    /// Incomplete UTF-8 sequence held back from the previous chunk, since
+    /// event data must be a valid JSON string.
+    utf8_carry: Vec<u8>,
+}
+
+impl AsciicastRecorder {
+    fn create(path: &Path, cols: u16, rows: u16, command: &str) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let header = serde_json::json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+            "command": command,
+            "env": { "TERM": "xterm-256color" },
+        });
+        writeln!(file, "{}", header)?;
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            utf8_carry: Vec::new(),
+        })
+    }
+
+    /// Continue a recording whose file was inherited through a live upgrade.
+    fn adopt(file: fs::File, handoff: RecorderHandoff) -> Self {
+        let elapsed = Duration::from_secs_f64(handoff.elapsed.max(0.0));
+        Self {
+            file,
+            start: Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now),
+            utf8_carry: handoff.utf8_carry,
+        }
+    }
+
+    fn handoff(&self) -> RecorderHandoff {
+        RecorderHandoff {
+            fd: self.file.as_raw_fd(),
+            elapsed: self.start.elapsed().as_secs_f64(),
+            utf8_carry: self.utf8_carry.clone(),
+        }
+    }
+
+    fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut bytes = std::mem::take(&mut self.utf8_carry);
+        bytes.extend_from_slice(data);
+        let keep = incomplete_utf8_suffix(&bytes);
+        self.utf8_carry = bytes.split_off(bytes.len() - keep);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.event("o", &String::from_utf8_lossy(&bytes))
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", cols, rows))
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let data = serde_json::to_string(data).unwrap_or_default();
+        // One write per event so a crash never leaves a torn line mid-file
+        self.file
+            .write_all(format!("[{:.6}, \"{}\", {}]\n", elapsed, code, data).as_bytes())
+    }
+}
+
+/// Length of a trailing UTF-8 sequence that is cut off at the end of `data`
+/// (0 if the data ends on a character boundary or with invalid bytes).
+fn incomplete_utf8_suffix(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        if byte & 0xc0 == 0x80 {
+            continue; // continuation byte — keep looking for the lead byte
+        }
+        let needed = match byte {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return 0,
+        };
+        return if needed > back { back } else { 0 };
+    }
+    0
+}
+
+// ── Strip terminal query sequences ──────────────────────────────────
+
+fn strip_terminal_queries(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5b {
+            // Potential CSI sequence
+            let mut j = i + 2;
+            // Skip parameter and intermediate bytes (0x20-0x3f)
+            while j < data.len() && data[j] >= 0x20 && data[j] <= 0x3f {
+                j += 1;
+            }
+
+            if j < data.len() {
+                let final_byte = data[j];
+                let params = &data[i + 2..j];
+                let params_str = std::str::from_utf8(params).unwrap_or("");
+
+                // Check if this is a query we should strip
+                let is_dsr = final_byte == 0x6e
+                    && (params_str == "6" || params_str == "?6");
+                let is_da = final_byte == 0x63
+                    && (params_str.is_empty()
+                        || params_str == ">"
+                        || params_str == "="
+                        || params_str == "0");
+
+                if is_dsr || is_da {
+                    i = j + 1;
+                    continue;
+                }
+            }
+        }
+
+        result.push(data[i]);
+        i += 1;
+    }
+
+    result
+}
+
+// ── OSC parsing ─────────────────────────────────────────────────────
+
+/// Parse OSC 0/2 title from data. Returns (title, was_found).
+fn parse_osc_title(data: &[u8]) -> Option<String> {
+    // Look for ESC ] 0|2 ; <title> BEL|ESC\
+    let mut i = 0;
+    while i + 3 < data.len() {
+        if data[i] == 0x1b && data[i + 1] == 0x5d {
+            // ESC ]
+            if (data[i + 2] == b'0' || data[i + 2] == b'2') && data[i + 3] == b';' {
+                // Find the terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
+                let start = i + 4;
+                let mut end = start;
+                while end < data.len() {
+                    if data[end] == 0x07 {
+                        let title = String::from_utf8_lossy(&data[start..end]).to_string();
+                        return Some(title);
+                    }
+                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
+                        let title = String::from_utf8_lossy(&data[start..end]).to_string();
+                        return Some(title);
+                    }
+                    end += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Parse OSC 7 CWD notification from data. Returns the decoded path if found.
+/// Format: ESC ] 7 ; file://hostname/path BEL|ESC\
+/// The path is percent-decoded (e.g., %20 → space).
+fn parse_osc7_cwd(data: &[u8]) -> Option<String> {
+    let mut i = 0;
+    while i + 3 < data.len() {
+        if data[i] == 0x1b && data[i + 1] == 0x5d && data[i + 2] == b'7' {
+            // ESC ] 7
+            if i + 3 < data.len() && data[i + 3] == b';' {
+                // Find the terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
+                let start = i + 4;
+                let mut end = start;
+                while end < data.len() {
+                    if data[end] == 0x07 {
+                        let url = String::from_utf8_lossy(&data[start..end]);
+                        return extract_path_from_file_url(&url);
+                    }
+                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
+                        let url = String::from_utf8_lossy(&data[start..end]);
+                        return extract_path_from_file_url(&url);
+                    }
+                    end += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Extract and percent-decode the path from a file:// URL.
+/// Handles `file:///path` (no host) and `file://hostname/path`.
+fn extract_path_from_file_url(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    // After "file://", the next component is either empty (file:///path)
+    // or a hostname (file://host/path). Either way, the path starts at the first '/'.
+    let path_start = rest.find('/')?;
+    let encoded_path = &rest[path_start..];
+    Some(percent_decode(encoded_path))
+}
+
+/// Percent-decode a string (e.g., %20 → space, %C3%A9 → é).
+fn percent_decode(input: &str) -> String {
+    let mut bytes = Vec::with_capacity(input.len());
+    let input_bytes = input.as_bytes();
+    let mut i = 0;
+    while i < input_bytes.len() {
+        if input_bytes[i] == b'%' && i + 2 < input_bytes.len() {
+            if let Ok(val) = u8::from_str_radix(
+                &input[i + 1..i + 3],
+                16,
+            ) {
+                bytes.push(val);
+                i += 3;
+                continue;
+            }
+        }
+        bytes.push(input_bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Default maximum clipboard payload size (1 MB)
+const CLIPBOARD_MAX_SIZE: usize = 1024 * 1024;
+
+/// Default maximum decoded image size (10 MB)
+const IMAGE_MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Simple base64 decoder (standard alphabet + padding).
+fn base64_decode(input: &[u8]) -> Option<Vec<u8>> {
+    const DECODE_TABLE: [u8; 256] = {
+        let mut t = [255u8; 256];
+        let mut i = 0u8;
+        while i < 26 {
+            t[(b'A' + i) as usize] = i;
+            t[(b'a' + i) as usize] = i + 26;
+            i += 1;
+        }
+        let mut d = 0u8;
+        while d < 10 {
+            t[(b'0' + d) as usize] = d + 52;
+            d += 1;
+        }
+        t[b'+' as usize] = 62;
+        t[b'/' as usize] = 63;
+        t[b'=' as usize] = 0; // padding
+        t
+    };
+
+    let mut result = Vec::with_capacity(input.len() * 3 / 4);
+    let filtered: Vec<u8> = input.iter().copied().filter(|&b| b != b'\n' && b != b'\r' && b != b' ').collect();
+    if filtered.is_empty() {
+        return Some(result);
+    }
+
+    let mut i = 0;
+    while i + 3 < filtered.len() {
+        let a = DECODE_TABLE[filtered[i] as usize];
+        let b = DECODE_TABLE[filtered[i + 1] as usize];
+        let c = DECODE_TABLE[filtered[i + 2] as usize];
+        let d = DECODE_TABLE[filtered[i + 3] as usize];
+        if a == 255 || b == 255 || c == 255 || d == 255 {
+            return None;
+        }
+        result.push((a << 2) | (b >> 4));
+        if filtered[i + 2] != b'=' {
+            result.push((b << 4) | (c >> 2));
+        }
+        if filtered[i + 3] != b'=' {
+            result.push((c << 6) | d);
+        }
+        i += 4;
+    }
+    Some(result)
+}
+
+/// An OSC 52 read query (`ESC ] 52 ; <selection> ; ?`) from the program.
+#[derive(Debug, PartialEq)]
+struct ClipboardQuery {
+    selection: String,
+    /// Terminator of the query (BEL or ST), repeated in the answer.
+    terminator: &'static str,
+}
+
+/// Extract OSC 52 clipboard sequences from data. Returns (cleaned_data,
+/// clipboard_texts, queries).
+/// Format: ESC ] 52 ; <selection> ; <base64data> BEL|ESC\
+/// The base64 data is decoded and returned as UTF-8 text.
+fn extract_osc52_clipboard(data: &[u8], max_size: usize) -> (Vec<u8>, Vec<String>, Vec<ClipboardQuery>) {
+    let mut clips = Vec::new();
+    let mut queries = Vec::new();
+    let mut cleaned = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5d {
+            // ESC ]
+            // Check for "52;"
+            if i + 4 < data.len() && data[i + 2] == b'5' && data[i + 3] == b'2' && data[i + 4] == b';' {
+                // Find the selection parameter (single char like 'c', 'p', 's', etc.) then ';'
+                let after_52 = i + 5;
+                // Find the second ';' that separates selection from base64 data
+                let mut semi_pos = after_52;
+                while semi_pos < data.len() && data[semi_pos] != b';' {
+                    // Selection parameter chars (short — usually 1-2 chars)
+                    if semi_pos - after_52 > 10 {
+                        break; // too long for selection param
+                    }
+                    semi_pos += 1;
+                }
+                if semi_pos < data.len() && data[semi_pos] == b';' {
+                    let b64_start = semi_pos + 1;
+                    // Find terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
+                    let mut end = b64_start;
+                    let mut found = false;
+                    let mut term_len = 0usize;
+                    while end < data.len() {
+                        if data[end] == 0x07 {
+                            term_len = 1;
+                            found = true;
+                            break;
+                        }
+                        if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
+                            term_len = 2;
+                            found = true;
+                            break;
+                        }
+                        end += 1;
+                    }
+                    if found {
+                        let b64_data = &data[b64_start..end];
+                        // Check if this is a query (just "?")
+                        if b64_data == b"?" {
+                            // OSC 52 query — strip it; answered per the clipboard policy
+                            queries.push(ClipboardQuery {
+                                selection: String::from_utf8_lossy(&data[after_52..semi_pos]).to_string(),
+                                terminator: if term_len == 1 { "\x07" } else { "\x1b\\" },
+                            });
+                            i = end + term_len;
+                            continue;
+                        }
+                        if let Some(decoded) = base64_decode(b64_data) {
+                            if decoded.len() <= max_size {
+                                if let Ok(text) = String::from_utf8(decoded) {
+                                    clips.push(text);
+                                }
+                            }
+                        }
+                        // Strip the OSC 52 from output (don't pass to xterm)
+                        i = end + term_len;
+                        continue;
+                    }
+                }
+            }
+        }
+        cleaned.push(data[i]);
+        i += 1;
+    }
+
+    (cleaned, clips, queries)
+}
+
+// ── Clipboard ───────────────────────────────────────────────────────
+
+/// Per-session clipboard: the last text copied by the program (OSC 52) or
+/// set by a client (CLIPBOARD_SET). Programs can read it back with an OSC 52
+/// query only when pty-host runs with `--clipboard-read`, because that hands
+/// whatever a user pasted in to anything running in the session.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Clipboard {
+    text: Option<String>,
+    allow_read: bool,
+}
+
+impl Clipboard {
+    /// The OSC 52 answer to a query, or None when reads aren't allowed.
+    /// An empty clipboard answers with empty data.
+    fn answer(&self, query: &ClipboardQuery) -> Option<Vec<u8>> {
+        if !self.allow_read {
+            return None;
+        }
+        let data = base64_encode(self.text.as_deref().unwrap_or_default().as_bytes());
+        Some(format!("\x1b]52;{};{}{}", query.selection, data, query.terminator).into_bytes())
+    }
+}
+
+/// Extract OSC 9 notifications from data. Returns (cleaned_data, notifications).
+fn extract_osc9_notifications(data: &[u8]) -> (Vec<u8>, Vec<String>) {
+    let mut notifications = Vec::new();
+    let mut cleaned = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == 0x1b && i + 1 < data.len() && data[i + 1] == 0x5d {
+            // ESC ]
+            if i + 3 < data.len() && data[i + 2] == b'9' && data[i + 3] == b';' {
+                // Find the terminator
+                let start = i + 4;
+                let mut end = start;
+                let mut found = false;
+                while end < data.len() {
+                    if data[end] == 0x07 {
+                        let msg = String::from_utf8_lossy(&data[start..end]).to_string();
+                        notifications.push(msg);
+                        i = end + 1;
+                        found = true;
+                        break;
+                    }
+                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
+                        let msg = String::from_utf8_lossy(&data[start..end]).to_string();
+                        notifications.push(msg);
+                        i = end + 2;
+                        found = true;
+                        break;
+                    }
+                    end += 1;
+                }
+                if found {
+                    continue;
+                }
+            }
+        }
+        cleaned.push(data[i]);
+        i += 1;
+    }
+
+    (cleaned, notifications)
+}
+
+/// A FinalTerm / OSC 133 semantic prompt mark.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PromptMark {
+    /// `A`: the prompt starts.
+    PromptStart,
+    /// `B`: the prompt ends and the command line starts.
+    CommandStart,
+    /// `C`: the command was entered; its output starts.
+    OutputStart,
+    /// `D[;exit]`: the command finished, with its exit status if given.
+    CommandEnd(Option<i32>),
+}
+
+/// Extract OSC 133 prompt marks from data. Returns (cleaned_data, marks),
+/// each mark with its position in the cleaned data.
+fn extract_osc133_marks(data: &[u8]) -> (Vec<u8>, Vec<(usize, PromptMark)>) {
+    let mut marks = Vec::new();
+    let mut cleaned = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i..].starts_with(b"\x1b]133;") {
+            let start = i + 6;
+            let terminator = (start..data.len()).find_map(|end| match data[end] {
+                0x07 => Some((end, end + 1)),
+                0x1b if data.get(end + 1) == Some(&b'\\') => Some((end, end + 2)),
+                _ => None,
+            });
+            if let Some((end, next)) = terminator {
+                let body = String::from_utf8_lossy(&data[start..end]);
+                let mut params = body.split(';');
+                let mark = match params.next() {
+                    Some("A") => Some(PromptMark::PromptStart),
+                    Some("B") => Some(PromptMark::CommandStart),
+                    Some("C") => Some(PromptMark::OutputStart),
+                    Some("D") => Some(PromptMark::CommandEnd(params.next().and_then(|c| c.parse().ok()))),
+                    _ => None,
+                };
+                marks.extend(mark.map(|m| (cleaned.len(), m)));
+                i = next;
+                continue;
+            }
+        }
+        cleaned.push(data[i]);
+        i += 1;
+    }
+
+    (cleaned, marks)
+}
+
+/// An OSC 8 hyperlink boundary.
+#[derive(Debug, Clone, PartialEq)]
+enum LinkEvent {
+    /// `OSC 8 ; params ; URI`: a link starts. `text_start` is the position
+    /// just past the sequence, where the link's text begins.
+    Open { uri: String, text_start: usize },
+    /// `OSC 8 ; ;`: the link ends.
+    Close,
+}
+
+/// Find OSC 8 hyperlink boundaries in data. Unlike the other OSC passes this
+/// leaves the data alone: xterm.js renders the links itself.
+fn scan_osc8_links(data: &[u8]) -> Vec<(usize, LinkEvent)> {
+    let mut events = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if !data[i..].starts_with(b"\x1b]8;") {
+            i += 1;
+            continue;
+        }
+        let start = i + 4;
+        let terminator = (start..data.len()).find_map(|end| match data[end] {
+            0x07 => Some((end, end + 1)),
+            0x1b if data.get(end + 1) == Some(&b'\\') => Some((end, end + 2)),
+            _ => None,
+        });
+        let Some((end, next)) = terminator else { break };
+        // Params (id=...) come before the URI, which may itself contain ';'
+        let body = String::from_utf8_lossy(&data[start..end]);
+        let uri = body.split_once(';').map_or("", |(_, uri)| uri);
+        let event = if uri.is_empty() {
+            LinkEvent::Close
+        } else {
+            LinkEvent::Open {
+                uri: uri.to_string(),
+                text_start: next,
+            }
+        };
+        events.push((i, event));
+        i = next;
+    }
+    events
+}
+
+/// An inline image extracted from the output (OSC 1337, kitty graphics or sixel).
+struct InlineImage {
+    /// Unique image ID (monotonic counter, formatted as string)
+    id: String,
+    /// MIME type inferred from magic bytes (defaults to "image/png")
+    mime: String,
+    /// Raw decoded image bytes
+    data: Vec<u8>,
+}
+
+/// Global image counter for unique IDs within this pty-host process.
+static IMAGE_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+impl InlineImage {
+    /// Wrap decoded image bytes under a fresh ID.
+    fn new(data: Vec<u8>) -> Self {
+        let img_id = IMAGE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Self {
+            id: format!("img-{}", img_id),
+            mime: infer_mime(&data).to_string(),
+            data,
+        }
+    }
+
+    /// Placeholder line inserted into the stream where the image was, so
+    /// the terminal advances the cursor. Styled with dim+italic CSI so it's
+    /// unobtrusive.
+    fn placeholder(&self) -> String {
+        format!("\x1b[2;3m[image: {}]\x1b[0m\r\n", self.id)
+    }
+
+    /// IMAGE message payload:
+    /// [0x17][4B id_len BE][id UTF-8][mime UTF-8 NUL-terminated][raw image bytes]
+    fn message(&self) -> Vec<u8> {
+        let id_bytes = self.id.as_bytes();
+        let mime_bytes = self.mime.as_bytes();
+        let msg_len = 1 + 4 + id_bytes.len() + mime_bytes.len() + 1 + self.data.len();
+        let mut msg = Vec::with_capacity(msg_len);
+        msg.push(WS_MSG_IMAGE);
+        msg.extend_from_slice(&(id_bytes.len() as u32).to_be_bytes());
+        msg.extend_from_slice(id_bytes);
+        msg.extend_from_slice(mime_bytes);
+        msg.push(0); // NUL terminator for MIME
+        msg.extend_from_slice(&self.data);
+        msg
+    }
+}
+
+// ── Image store ─────────────────────────────────────────────────────
+//
+// Images are broadcast once as they arrive, but only their placeholders
+// stay in the output buffer. The most recent ones are kept so replays can
+// resend the images their placeholders refer to, and clients can fetch one
+// with IMAGE_REQUEST.
+
+/// Most images kept, and their total size.
+const IMAGE_STORE_MAX: usize = 64;
+const IMAGE_STORE_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Bounded store of recent images, oldest first.
+#[derive(Default)]
+struct ImageStore {
+    images: VecDeque<InlineImage>,
+    bytes: usize,
+}
+
+impl ImageStore {
+    fn insert(&mut self, image: InlineImage) {
+        self.bytes += image.data.len();
+        self.images.push_back(image);
+        while self.images.len() > IMAGE_STORE_MAX || self.bytes > IMAGE_STORE_MAX_BYTES {
+            match self.images.pop_front() {
+                Some(evicted) => self.bytes -= evicted.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<&InlineImage> {
+        self.images.iter().find(|image| image.id == id)
+    }
+
+    /// Stored images whose placeholders appear in `data`, in order of first
+    /// appearance.
+    fn referenced_in(&self, data: &[u8]) -> Vec<&InlineImage> {
+        const MARKER: &[u8] = b"[image: ";
+        let mut found: Vec<&InlineImage> = Vec::new();
+        let mut rest = data;
+        while let Some(at) = rest.windows(MARKER.len()).position(|w| w == MARKER) {
+            rest = &rest[at + MARKER.len()..];
+            let Some(end) = rest.iter().take(32).position(|&b| b == b']') else { continue };
+            let id = String::from_utf8_lossy(&rest[..end]);
+            if let Some(image) = self.get(&id) {
+                if !found.iter().any(|f| f.id == image.id) {
+                    found.push(image);
+                }
+            }
+        }
+        found
+    }
+
+    /// Image headers and data for a live upgrade.
+    fn handoff(&self) -> (Vec<ImageHandoff>, Vec<&[u8]>) {
+        self.images
+            .iter()
+            .map(|image| {
+                let header = ImageHandoff {
+                    id: image.id.clone(),
+                    mime: image.mime.clone(),
+                    len: image.data.len(),
+                };
+                (header, image.data.as_slice())
+            })
+            .unzip()
+    }
+
+    fn from_handoff(headers: Vec<ImageHandoff>, mut data: &[u8]) -> Self {
+        let mut store = Self::default();
+        for header in headers {
+            let (bytes, rest) = data.split_at(header.len);
+            data = rest;
+            store.insert(InlineImage {
+                id: header.id,
+                mime: header.mime,
+                data: bytes.to_vec(),
+            });
+        }
+        store
+    }
+}
+
+/// Infer MIME type from the first few bytes (magic number).
+fn infer_mime(data: &[u8]) -> &'static str {
+    if data.len() >= 8 && data[..8] == [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a] {
+        "image/png"
+    } else if data.len() >= 3 && data[..3] == [0xFF, 0xD8, 0xFF] {
+        "image/jpeg"
+    } else if data.len() >= 4 && &data[..4] == b"GIF8" {
+        "image/gif"
+    } else if data.len() >= 4 && &data[..4] == b"RIFF" && data.len() >= 12 && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "image/png" // fallback
+    }
+}
+
+/// Extract iTerm2 OSC 1337 inline image sequences from data.
+/// Format: ESC ] 1337 ; File=[args] : base64data BEL|ESC\
+/// Returns (cleaned_data, images).
+/// The OSC 1337 sequences are stripped from the output (they are huge base64
+/// blobs that must not go into the ring buffer). A small placeholder is
+/// inserted so the cursor advances one line. Images over `max_size` bytes
+/// are dropped.
+fn extract_osc1337_images(data: &[u8], max_size: usize) -> (Vec<u8>, Vec<InlineImage>) {
+    let mut images = Vec::new();
+    let mut cleaned = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    // Prefix: ESC ] 1337 ; File=
+    // In bytes: 0x1b 0x5d '1' '3' '3' '7' ';' 'F' 'i' 'l' 'e' '='
+    const PREFIX: &[u8] = b"\x1b]1337;File=";
+
+    while i < data.len() {
+        // Quick check: ESC at this position?
+        if data[i] == 0x1b && i + PREFIX.len() <= data.len() && &data[i..i + PREFIX.len()] == PREFIX {
+            let args_start = i + PREFIX.len();
+            // Find the ':' that separates args from base64 data.
+            // Args are key=value pairs separated by ';' (e.g., name=..;size=..;inline=1)
+            let mut colon_pos = None;
+            let mut j = args_start;
+            while j < data.len() {
+                if data[j] == b':' {
+                    colon_pos = Some(j);
+                    break;
+                }
+                // If we hit a terminator before ':', it's malformed
+                if data[j] == 0x07 || (data[j] == 0x1b && j + 1 < data.len() && data[j + 1] == 0x5c) {
+                    break;
+                }
+                j += 1;
+            }
+
+            if let Some(colon) = colon_pos {
+                let b64_start = colon + 1;
+                // Find terminator: BEL (0x07) or ESC\ (0x1b 0x5c)
+                let mut end = b64_start;
+                let mut found = false;
+                let mut term_len = 0usize;
+                while end < data.len() {
+                    if data[end] == 0x07 {
+                        term_len = 1;
+                        found = true;
+                        break;
+                    }
+                    if data[end] == 0x1b && end + 1 < data.len() && data[end + 1] == 0x5c {
+                        term_len = 2;
+                        found = true;
+                        break;
+                    }
+                    end += 1;
+                }
+
+                if found {
+                    let b64_data = &data[b64_start..end];
+
+                    // Parse args to check inline=1
+                    let args_str = std::str::from_utf8(&data[args_start..colon]).unwrap_or("");
+                    let mut is_inline = false;
+                    for arg in args_str.split(';') {
+                        if let Some((key, val)) = arg.split_once('=') {
+                            if key == "inline" && val == "1" {
+                                is_inline = true;
+                            }
+                        }
+                    }
+
+                    // Only process inline images — preserve non-inline sequences
+                    if !is_inline {
+                        // Not inline: preserve the entire sequence in output
+                        cleaned.extend_from_slice(&data[i..end + term_len]);
+                        i = end + term_len;
+                        continue;
+                    }
+
+                    if let Some(decoded) = base64_decode(b64_data) {
+                        if !decoded.is_empty() && decoded.len() <= max_size {
+                            let image = InlineImage::new(decoded);
+                            cleaned.extend_from_slice(image.placeholder().as_bytes());
+                            images.push(image);
+                        }
+                    }
+                    // Skip past the entire OSC 1337 sequence
+                    i = end + term_len;
+                    continue;
+                }
+            }
+        }
+        cleaned.push(data[i]);
+        i += 1;
+    }
+
+    (cleaned, images)
+}
+
+// ── Kitty graphics ──────────────────────────────────────────────────
+//
+// Kitty's graphics protocol sends images in APC sequences:
+//   ESC _ G <key=value,...> ; <base64 payload> ESC \
+// Large images are split into chunks with m=1 on all but the last one, and
+// the chunks (or a single sequence) may straddle PTY reads. Transmitted
+// images are stripped like OSC 1337 ones and sent as IMAGE frames with a
+// placeholder in the stream. Only direct transmission (t=d) is supported.
+// Programs probe for support with a=q before drawing, so those queries are
+// answered here rather than left for a client terminal that lacks them.
+
+/// APC prefix of a kitty graphics command: ESC _ G
+const KITTY_PREFIX: &[u8] = b"\x1b_G";
+
+/// Images kept from a=t transmissions for a later a=p placement.
+const KITTY_STORED_MAX: usize = 16;
+const KITTY_STORED_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+/// Control keys of one kitty graphics command. Unset keys take the
+/// protocol's defaults (transmit, 32-bit RGBA, direct).
+#[derive(Debug, Clone, PartialEq)]
+struct KittyCommand {
+    /// a= (t transmit, T transmit and display, p put, q query, d delete, ...)
+    action: char,
+    /// f= (24 RGB, 32 RGBA, 100 PNG)
+    format: u32,
+    /// t= transmission medium (d direct, f file, t temp file, s shared memory)
+    medium: char,
+    /// s= and v=: pixel dimensions of raw RGB(A) data
+    width: u32,
+    height: u32,
+    /// o=z: payload is zlib-compressed
+    compressed: bool,
+    /// m=1: more chunks follow
+    more: bool,
+    /// i= image id and I= image number, echoed in replies
+    id: u32,
+    number: u32,
+    /// q=1 suppresses OK replies, q=2 errors as well
+    quiet: u32,
+}
+
+impl KittyCommand {
+    fn parse(control: &str) -> Self {
+        let mut cmd = Self {
+            action: 't',
+            format: 32,
+            medium: 'd',
+            width: 0,
+            height: 0,
+            compressed: false,
+            more: false,
+            id: 0,
+            number: 0,
+            quiet: 0,
+        };
+        for pair in control.split(',') {
+            let Some((key, value)) = pair.split_once('=') else { continue };
+            let num = || value.parse::<u32>().unwrap_or(0);
+            let flag = || value.chars().next().unwrap_or_default();
+            match key {
+                "a" => cmd.action = flag(),
+                "f" => cmd.format = num(),
+                "t" => cmd.medium = flag(),
+                "s" => cmd.width = num(),
+                "v" => cmd.height = num(),
+                "o" => cmd.compressed = value == "z",
+                "m" => cmd.more = value == "1",
+                "i" => cmd.id = num(),
+                "I" => cmd.number = num(),
+                "q" => cmd.quiet = num(),
+                _ => {}
+            }
+        }
+        cmd
+    }
+
+    /// Decode a complete payload into image bytes the browser can show:
+    /// PNG passes through, raw RGB(A) pixels are encoded as PNG.
+    fn decode(&self, payload: &[u8], max_size: usize) -> Result<Vec<u8>, &'static str> {
+        use std::io::Read;
+
+        if self.medium != 'd' {
+            return Err("EINVAL:only direct transmission is supported");
+        }
+        let mut data = base64_decode(payload).ok_or("EINVAL:bad base64 payload")?;
+        if self.compressed {
+            let mut inflated = Vec::new();
+            flate2::read::ZlibDecoder::new(&data[..])
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut inflated)
+                .map_err(|_| "EINVAL:bad zlib payload")?;
+            data = inflated;
+        }
+        if data.is_empty() {
+            return Err("ENODATA:no image data");
+        }
+        if data.len() > max_size {
+            return Err("EFBIG:image too large");
+        }
+        match self.format {
+            100 => Ok(data),
+            24 | 32 => {
+                let channels = self.format as usize / 8;
+                let pixels = (self.width as usize).checked_mul(self.height as usize);
+                if pixels == Some(0) || pixels.and_then(|n| n.checked_mul(channels)) != Some(data.len()) {
+                    return Err("EINVAL:pixel data does not match s and v");
+                }
+                let rgba = if channels == 4 {
+                    data
+                } else {
+                    data.chunks(3).flat_map(|p| [p[0], p[1], p[2], 0xff]).collect()
+                };
+                Ok(encode_png(self.width, self.height, &rgba))
+            }
+            _ => Err("EINVAL:unsupported format"),
+        }
+    }
+
+    /// The APC reply kitty would send for this command, or None when the
+    /// program didn't ask for one (no i=/I=) or silenced it with q=.
+    fn reply(&self, result: Result<(), &str>) -> Option<Vec<u8>> {
+        if self.id == 0 && self.number == 0 {
+            return None;
+        }
+        let message = match result {
+            Ok(()) if self.quiet == 0 => "OK",
+            Err(e) if self.quiet < 2 => e,
+            _ => return None,
+        };
+        let mut keys = Vec::new();
+        if self.id != 0 {
+            keys.push(format!("i={}", self.id));
+        }
+        if self.number != 0 {
+            keys.push(format!("I={}", self.number));
+        }
+        Some(format!("\x1b_G{};{}\x1b\\", keys.join(","), message).into_bytes())
+    }
+}
+
+/// A chunked transmission waiting for its final (m=0) chunk.
+struct KittyTransfer {
+    /// Keys from the first chunk; continuation chunks only carry m= and q=.
+    command: KittyCommand,
+    payload: Vec<u8>,
+    overflow: bool,
+}
+
+/// Kitty graphics decoder state carried across PTY reads.
+struct KittyGraphics {
+    transfer: Option<KittyTransfer>,
+    /// Images transmitted with a=t, by kitty image id, oldest first.
+    stored: VecDeque<(u32, Vec<u8>)>,
+    /// Largest decoded image.
+    max_size: usize,
+}
+
+impl KittyGraphics {
+    fn new(max_size: usize) -> Self {
+        Self {
+            transfer: None,
+            stored: VecDeque::new(),
+            max_size,
+        }
+    }
+
+    /// Strip kitty graphics commands from data. Returns (cleaned_data,
+    /// images, replies to write back to the program). An unterminated
+    /// command is passed through for the extractor's partial holdback.
+    fn extract(&mut self, data: &[u8]) -> (Vec<u8>, Vec<InlineImage>, Vec<Vec<u8>>) {
+        let mut cleaned = Vec::with_capacity(data.len());
+        let mut images = Vec::new();
+        let mut replies = Vec::new();
+        let mut i = 0;
+
+        while i < data.len() {
+            if data[i] == 0x1b && data[i..].starts_with(KITTY_PREFIX) {
+                let body_start = i + KITTY_PREFIX.len();
+                if let Some(len) = data[body_start..].windows(2).position(|w| w == b"\x1b\\") {
+                    let body = &data[body_start..body_start + len];
+                    let (control, payload) = match body.iter().position(|&b| b == b';') {
+                        Some(semi) => (&body[..semi], &body[semi + 1..]),
+                        None => (body, &[][..]),
+                    };
+                    let (image, reply) = self.command(&String::from_utf8_lossy(control), payload);
+                    if let Some(image) = image {
+                        cleaned.extend_from_slice(image.placeholder().as_bytes());
+                        images.push(image);
+                    }
+                    replies.extend(reply);
+                    i = body_start + len + 2;
+                    continue;
+                }
+            }
+            cleaned.push(data[i]);
+            i += 1;
+        }
+
+        (cleaned, images, replies)
+    }
+
+    /// Handle one APC command, which may be a chunk of a larger transfer.
+    fn command(&mut self, control: &str, payload: &[u8]) -> (Option<InlineImage>, Option<Vec<u8>>) {
+        let keys = KittyCommand::parse(control);
+        let mut transfer = self.transfer.take().unwrap_or_else(|| KittyTransfer {
+            command: keys.clone(),
+            payload: Vec::new(),
+            overflow: false,
+        });
+        // Largest base64 payload accumulated across chunks
+        let max_payload = self.max_size / 3 * 4 + 4;
+        if transfer.payload.len() + payload.len() <= max_payload {
+            transfer.payload.extend_from_slice(payload);
+        } else {
+            transfer.overflow = true;
+            transfer.payload = Vec::new();
+        }
+        if keys.more {
+            self.transfer = Some(transfer);
+            return (None, None);
+        }
+
+        let cmd = &transfer.command;
+        let decode = || {
+            if transfer.overflow {
+                Err("EFBIG:image too large")
+            } else {
+                cmd.decode(&transfer.payload, self.max_size)
+            }
+        };
+        match cmd.action {
+            'T' => match decode() {
+                Ok(bytes) => (Some(InlineImage::new(bytes)), cmd.reply(Ok(()))),
+                Err(e) => (None, cmd.reply(Err(e))),
+            },
+            't' => match decode() {
+                Ok(bytes) => {
+                    if cmd.id != 0 {
+                        self.store(cmd.id, bytes);
+                    }
+                    (None, cmd.reply(Ok(())))
+                }
+                Err(e) => (None, cmd.reply(Err(e))),
+            },
+            'p' => match self.stored.iter().find(|(id, _)| *id == cmd.id) {
+                Some((_, bytes)) => (Some(InlineImage::new(bytes.clone())), cmd.reply(Ok(()))),
+                None => (None, cmd.reply(Err("ENOENT:no such image"))),
+            },
+            'q' => (None, cmd.reply(decode().map(|_| ()))),
+            // Deletion and animation only concern kitty's own image store
+            _ => (None, None),
+        }
+    }
+
+    fn store(&mut self, id: u32, bytes: Vec<u8>) {
+        self.stored.retain(|(stored_id, _)| *stored_id != id);
+        self.stored.push_back((id, bytes));
+        let mut total: usize = self.stored.iter().map(|(_, b)| b.len()).sum();
+        while self.stored.len() > KITTY_STORED_MAX || total > KITTY_STORED_MAX_BYTES {
+            match self.stored.pop_front() {
+                Some((_, evicted)) => total -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encode 8-bit RGBA pixels as a PNG (no filtering, fast deflate).
+fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        let mut crc = flate2::Crc::new();
+        crc.update(kind);
+        crc.update(data);
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+
+    let stride = width as usize * 4;
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for row in rgba.chunks(stride.max(1)) {
+        raw.push(0); // filter type: None
+        raw.extend_from_slice(row);
+    }
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Compression::fast());
+    let _ = encoder.write_all(&raw);
+    let idat = encoder.finish().unwrap_or_default();
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit RGBA, deflate, no filter, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &ihdr);
+    chunk(&mut png, b"IDAT", &idat);
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// ── Sixel images ────────────────────────────────────────────────────
+//
+// Sixel graphics arrive as DCS <P1;P2;P3> q <sixel data> ST, often hundreds
+// of KB per image (gnuplot, lsix, chafa). They are decoded to RGBA, encoded
+// as PNG and sent as IMAGE frames with the usual placeholder, so the payload
+// never reaches the output buffer.
+
+/// VT340 default color registers 0-15, as RGB percentages.
+const SIXEL_DEFAULT_PALETTE: [[usize; 3]; 16] = [
+    [0, 0, 0],
+    [20, 20, 80],
+    [80, 13, 13],
+    [20, 80, 20],
+    [80, 20, 80],
+    [20, 80, 80],
+    [80, 80, 20],
+    [53, 53, 53],
+    [26, 26, 26],
+    [33, 33, 60],
+    [60, 26, 26],
+    [33, 60, 33],
+    [60, 33, 60],
+    [33, 60, 60],
+    [60, 60, 33],
+    [80, 80, 80],
+];
+
+/// Length of the DCS introducer (ESC P, numeric parameters, q) if `data`
+/// starts a sixel sequence.
+fn sixel_introducer(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(b"\x1bP") {
+        return None;
+    }
+    let params = data[2..].iter().take_while(|b| b.is_ascii_digit() || **b == b';').count();
+    (data.get(2 + params) == Some(&b'q')).then_some(3 + params)
+}
+
+/// Whether `data` (which starts with ESC) may be the start of a sixel
+/// sequence that hasn't fully arrived.
+fn could_be_sixel(data: &[u8]) -> bool {
+    if !data.starts_with(b"\x1bP") {
+        return false;
+    }
+    let params = data[2..].iter().take_while(|b| b.is_ascii_digit() || **b == b';').count();
+    matches!(data.get(2 + params), None | Some(&b'q'))
+}
+
+/// Extract sixel DCS sequences from data. Returns (cleaned_data, images).
+/// Other DCS sequences pass through untouched. Images whose RGBA would
+/// exceed `max_size` bytes are dropped.
+fn extract_sixel_images(data: &[u8], max_size: usize) -> (Vec<u8>, Vec<InlineImage>) {
+    let mut images = Vec::new();
+    let mut cleaned = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == 0x1b {
+            if let Some(intro) = sixel_introducer(&data[i..]) {
+                let body_start = i + intro;
+                if let Some(len) = data[body_start..].windows(2).position(|w| w == b"\x1b\\") {
+                    // P2 = 1: pixels left unpainted stay transparent
+                    let params = &data[i + 2..body_start - 1];
+                    let transparent = params.split(|&b| b == b';').nth(1) == Some(b"1");
+                    let decoded = decode_sixel(&data[body_start..body_start + len], transparent, max_size / 4);
+                    if let Some((width, height, rgba)) = decoded {
+                        let image = InlineImage::new(encode_png(width as u32, height as u32, &rgba));
+                        cleaned.extend_from_slice(image.placeholder().as_bytes());
+                        images.push(image);
+                    }
+                    i = body_start + len + 2;
+                    continue;
+                }
+            }
+        }
+        cleaned.push(data[i]);
+        i += 1;
+    }
+
+    (cleaned, images)
+}
+
+/// Read `;`-separated numeric parameters starting at `*i`, advancing it.
+fn sixel_params(data: &[u8], i: &mut usize) -> Vec<usize> {
+    let mut params = vec![0usize];
+    while let Some(&b) = data.get(*i) {
+        match b {
+            b'0'..=b'9' => {
+                let last = params.last_mut().unwrap();
+                *last = last.saturating_mul(10).saturating_add((b - b'0') as usize);
+            }
+            b';' => params.push(0),
+            _ => break,
+        }
+        *i += 1;
+    }
+    params
+}
+
+/// Convert a sixel percent component (0-100) to a byte.
+fn sixel_percent(value: usize) -> u8 {
+    ((value.min(100) * 255 + 50) / 100) as u8
+}
+
+/// Convert DEC HLS (hue 0 = blue, lightness and saturation 0-100) to RGB.
+fn sixel_hls(hue: usize, lightness: usize, saturation: usize) -> [u8; 3] {
+    // DEC puts blue at 0°, red at 120°, green at 240°
+    let h = ((hue + 240) % 360) as f64 / 60.0;
+    let l = lightness.min(100) as f64 / 100.0;
+    let s = saturation.min(100) as f64 / 100.0;
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    [r, g, b].map(|v| ((v + m) * 255.0).round() as u8)
+}
+
+/// Decode sixel data (after the `q`) to (width, height, RGBA pixels).
+/// None for empty images and ones over `max_pixels`.
+fn decode_sixel(data: &[u8], transparent: bool, max_pixels: usize) -> Option<(usize, usize, Vec<u8>)> {
+    let mut palette: Vec<[u8; 4]> = (0..256)
+        .map(|n| {
+            let [r, g, b] = SIXEL_DEFAULT_PALETTE.get(n).copied().unwrap_or([0, 0, 0]);
+            [sixel_percent(r), sixel_percent(g), sixel_percent(b), 0xff]
+        })
+        .collect();
+    let background = palette[0];
+    let mut color = palette[0];
+    // Painted runs: (x, band, count, sixel bits, color). Colors are taken at
+    // paint time, so redefining a register doesn't recolor earlier pixels.
+    let mut runs = Vec::new();
+    let (mut x, mut band) = (0usize, 0usize);
+    let (mut width, mut height) = (0usize, 0usize);
+    let mut i = 0;
+
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        let (bits, count) = match b {
+            b'"' => {
+                // Raster attributes: Pan;Pad;Ph;Pv
+                let params = sixel_params(data, &mut i);
+                if let (Some(&w), Some(&h)) = (params.get(2), params.get(3)) {
+                    if w.saturating_mul(h) > max_pixels {
+                        return None;
+                    }
+                    width = width.max(w);
+                    height = height.max(h);
+                }
+                continue;
+            }
+            b'#' => {
+                // Color: #Pc selects, #Pc;Pu;Px;Py;Pz defines (1 = HLS, 2 = RGB)
+                let params = sixel_params(data, &mut i);
+                let register = params[0] % palette.len();
+                if let [_, space, p1, p2, p3, ..] = params[..] {
+                    let [r, g, b] = match space {
+                        1 => sixel_hls(p1, p2, p3),
+                        _ => [sixel_percent(p1), sixel_percent(p2), sixel_percent(p3)],
+                    };
+                    palette[register] = [r, g, b, 0xff];
+                }
+                color = palette[register];
+                continue;
+            }
+            b'!' => {
+                // Repeat: !Pn <sixel>
+                let count = sixel_params(data, &mut i)[0].max(1);
+                match data.get(i) {
+                    Some(&c @ 0x3f..=0x7e) => {
+                        i += 1;
+                        (c - 0x3f, count)
+                    }
+                    _ => continue,
+                }
+            }
+            b'$' => {
+                x = 0;
+                continue;
+            }
+            b'-' => {
+                x = 0;
+                band += 1;
+                continue;
+            }
+            0x3f..=0x7e => (b - 0x3f, 1),
+            _ => continue,
+        };
+
+        if bits != 0 {
+            let right = x.saturating_add(count);
+            let bottom = band * 6 + 8 - bits.leading_zeros() as usize;
+            if right.max(width).saturating_mul(bottom.max(height)) > max_pixels {
+                return None;
+            }
+            width = width.max(right);
+            height = height.max(bottom);
+            runs.push((x, band, count, bits, color));
+        }
+        x = x.saturating_add(count);
+    }
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let fill = if transparent { [0; 4] } else { background };
+    let mut rgba = fill.repeat(width * height);
+    for (x, band, count, bits, color) in runs {
+        for bit in 0..6 {
+            let y = band * 6 + bit;
+            if bits & (1 << bit) == 0 || y >= height {
+                continue;
+            }
+            for px in x..(x + count).min(width) {
+                let at = (y * width + px) * 4;
+                rgba[at..at + 4].copy_from_slice(&color);
+            }
+        }
+    }
+    Some((width, height, rgba))
+}
+
+/// Results from a single OscExtractor::feed() call.
+#[derive(Default)]
+struct OscExtractResult {
+    /// Cleaned data with all recognized OSC sequences stripped.
+    cleaned: Vec<u8>,
+    /// Extracted clipboard texts (OSC 52).
+    clipboard_texts: Vec<String>,
+    /// Clipboard read queries (OSC 52 `?`).
+    clipboard_queries: Vec<ClipboardQuery>,
+    /// Extracted notification messages (OSC 9).
+    notifications: Vec<String>,
+    /// Extracted inline images (OSC 1337, kitty graphics and sixel).
+    inline_images: Vec<InlineImage>,
+    /// Replies to write back to the PTY (kitty graphics queries).
+    replies: Vec<Vec<u8>>,
+    /// Semantic prompt marks (OSC 133), at positions in `cleaned`.
+    prompt_marks: Vec<(usize, PromptMark)>,
+    /// Hyperlink boundaries (OSC 8, left in `cleaned`), at positions in it.
+    links: Vec<(usize, LinkEvent)>,
+}
+
+/// Room for a held-back sequence's introducer and parameters on top of
+/// its payload.
+const HOLDBACK_HEADER_MAX: usize = 4096;
+
+/// Sequences the extractor holds back until their terminator arrives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Holdback {
+    Osc,
+    Kitty,
+    Sixel,
+}
+
+/// Which held-back sequence `data` (starting with ESC) may begin, if any.
+/// Of the APCs only kitty graphics (`ESC _ G`) are held back.
+fn holdback_kind(data: &[u8]) -> Option<Holdback> {
+    match data.get(1)? {
+        b']' => Some(Holdback::Osc),
+        b'_' if data.starts_with(KITTY_PREFIX) || KITTY_PREFIX.starts_with(data) => Some(Holdback::Kitty),
+        b'P' if could_be_sixel(data) => Some(Holdback::Sixel),
+        _ => None,
+    }
+}
+
+/// Whether `data` contains a BEL or ST terminator.
+fn has_terminator(data: &[u8]) -> bool {
+    data.contains(&0x07) || data.windows(2).any(|w| w == b"\x1b\\")
+}
+
+/// Stateful OSC sequence extractor that handles sequences split across
+/// PTY read() boundaries. Buffers partial (unterminated) OSC sequences
+/// and prepends them to the next read's data.
+struct OscExtractor {
+    /// Bytes from a partial OSC sequence at the end of the previous read.
+    pending: Vec<u8>,
+    /// Most bytes `pending` may grow to before it is flushed unchanged.
+    pending_cap: usize,
+    /// Chunked kitty graphics transfers and stored images.
+    kitty: KittyGraphics,
+    clipboard_max_size: usize,
+    image_max_size: usize,
+}
+
+impl OscExtractor {
+    fn new(config: &Config) -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_cap: 0,
+            kitty: KittyGraphics::new(config.image_max_size),
+            clipboard_max_size: config.clipboard_max_size,
+            image_max_size: config.image_max_size,
+        }
+    }
+
+    /// Most bytes held back for one sequence: the largest payload we'd
+    /// extract from it, plus its header. Anything longer can't be one we
+    /// want, and holding it would stall output.
+    fn holdback_cap(&self, kind: Holdback) -> usize {
+        let payload = match kind {
+            // OSC 52 and OSC 1337 carry base64
+            Holdback::Osc => self.image_max_size.max(self.clipboard_max_size) / 3 * 4 + 4,
+            Holdback::Kitty => self.image_max_size / 3 * 4 + 4,
+            // Sixel decodes to at most image_max_size / 4 pixels; allow
+            // four bytes of colour and repeat commands for each
+            Holdback::Sixel => {
+                let max_pixels = self.image_max_size / 4;
+                max_pixels * 4
+            }
+        };
+        payload + HOLDBACK_HEADER_MAX
+    }
+
+    /// Feed a chunk of PTY output. Returns cleaned data and any extracted
+    /// OSC payloads. Partial OSC sequences at the end of `data` are buffered
+    /// internally and will be completed on the next call.
+    fn feed(&mut self, data: &[u8]) -> OscExtractResult {
+        // If we have pending bytes from a previous partial sequence,
+        // prepend them to form a combined buffer.
+        let combined: Vec<u8>;
+        let input = if self.pending.is_empty() {
+            data
+        } else {
+            // Until a terminator shows up, only the new bytes (and the last
+            // held one, which may be the ESC of an ST) need looking at
+            let scan_from = self.pending.len() - 1;
+            self.pending.extend_from_slice(data);
+            if !has_terminator(&self.pending[scan_from..]) && holdback_kind(&self.pending).is_some() {
+                if self.pending.len() <= self.pending_cap {
+                    return OscExtractResult::default();
+                }
+                log_warn!("unterminated escape sequence passed through", bytes = self.pending.len());
+                return OscExtractResult {
+                    cleaned: std::mem::take(&mut self.pending),
+                    ..OscExtractResult::default()
+                };
+            }
+            combined = std::mem::take(&mut self.pending);
+            &combined
+        };
+        // Every sequence handled here starts with ESC
+        if !input.contains(&0x1b) {
+            return OscExtractResult {
+                cleaned: input.to_vec(),
+                ..OscExtractResult::default()
+            };
+        }
+
+        // Run the extraction passes. OSC 133 goes last so mark positions
+        // are in the final cleaned data.
+        let (after_osc9, notifications) = extract_osc9_notifications(input);
+        let (after_osc52, clipboard_texts, clipboard_queries) = extract_osc52_clipboard(&after_osc9, self.clipboard_max_size);
+        let (after_osc1337, mut inline_images) = extract_osc1337_images(&after_osc52, self.image_max_size);
+        let (after_kitty, kitty_images, replies) = self.kitty.extract(&after_osc1337);
+        inline_images.extend(kitty_images);
+        let (after_sixel, sixel_images) = extract_sixel_images(&after_kitty, self.image_max_size);
+        inline_images.extend(sixel_images);
+        let (mut cleaned, prompt_marks) = extract_osc133_marks(&after_sixel);
+
+        // Check if `cleaned` ends with a partial (unterminated) OSC sequence.
+        // The extract functions pass incomplete sequences through to cleaned,
+        // so we need to find and stash any trailing partial for the next call.
+        if let Some(partial_start) = find_trailing_partial_osc(&cleaned) {
+            let cap = holdback_kind(&cleaned[partial_start..]).map_or(0, |kind| self.holdback_cap(kind));
+            if cleaned.len() - partial_start <= cap {
+                self.pending = cleaned.split_off(partial_start);
+                self.pending_cap = cap;
+            }
+        }
+        let links = scan_osc8_links(&cleaned);
+
+        OscExtractResult {
+            cleaned,
+            clipboard_texts,
+            clipboard_queries,
+            notifications,
+            inline_images,
+            replies,
+            prompt_marks,
+            links,
+        }
+    }
+}
+
+/// Scan data for a trailing partial OSC sequence (ESC ] ... without terminator).
+/// Returns the byte offset where the partial sequence starts, or None.
+///
+/// We look for the last ESC ] (0x1b 0x5d) and check whether it's followed
+/// by a BEL (0x07) or ST (ESC \) terminator. If not, it's a partial sequence
+/// that was passed through by the extract functions. Kitty graphics APCs
+/// (ESC _ G) and sixel DCS sequences are held back the same way.
+fn find_trailing_partial_osc(data: &[u8]) -> Option<usize> {
+    // Search backward for the last ESC (0x1b)
+    let mut i = data.len();
+    while i > 0 {
+        i -= 1;
+        if data[i] == 0x1b {
+            // Check if this could be the start of an OSC (ESC ]), kitty
+            // graphics APC (ESC _ G) or sixel DCS (ESC P ... q)
+            if holdback_kind(&data[i..]).is_some() {
+                // Found one. Check if there's a terminator after it.
+                // Scan forward from here looking for BEL or ESC\.
+                let mut j = i + 2;
+                while j < data.len() {
+                    if data[j] == 0x07 {
+                        // Found BEL terminator — this OSC is complete, not partial.
+                        return None;
+                    }
+                    if data[j] == 0x1b && j + 1 < data.len() && data[j + 1] == 0x5c {
+                        // Found ESC\ terminator — this OSC is complete.
+                        return None;
+                    }
+                    j += 1;
+                }
+                // No terminator found — this is a partial OSC sequence.
+                return Some(i);
+            }
+            // Lone ESC at the very end of data — do NOT buffer it.
+            // It's far more likely to be the start of CSI (ESC [) than
+            // OSC (ESC ]). Buffering it delays critical escape sequence
+            // data, causing visible rendering stalls in programs like fzf
+            // that go idle after their initial render. xterm.js's parser
+            // handles split escape sequences across write() calls correctly,
+            // so the ESC will be joined with the next chunk on the client.
+            if i + 1 == data.len() {
+                return None;
+            }
+        }
+    }
+    None
+}
+
+// ── Foreground process name resolution ──────────────────────────────
+
+/// Resolve a PID to its process name.
+/// On macOS, uses `proc_name()` from libproc.
+/// On Linux, reads `/proc/<pid>/comm`.
+/// Returns None if the process cannot be found (race: it may have exited).
+fn get_process_name(pid: libc::pid_t) -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        // libproc's proc_name: fills a buffer with the process name
+        let mut buf = [0u8; 256];
+        let len = unsafe {
+            libc::proc_name(
+                pid,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len() as u32,
+            )
+        };
+        if len > 0 {
+            return Some(
+                String::from_utf8_lossy(&buf[..len as usize])
+                    .trim_end_matches('\0')
+                    .to_string(),
+            );
+        }
+        None
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        // Linux: read /proc/<pid>/comm
+        std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    }
+}
+
+// ── Process CWD resolution ──────────────────────────────────────────
+
+/// Resolve a PID to its current working directory.
+/// On macOS, uses `proc_pidinfo(PROC_PIDVNODEPATHINFO)` from libproc.
+/// On Linux, reads `/proc/<pid>/cwd` symlink.
+/// Returns None if the process cannot be found or CWD cannot be resolved.
+fn get_process_cwd(pid: libc::pid_t) -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        // PROC_PIDVNODEPATHINFO returns vnode path info including CWD
+        const PROC_PIDVNODEPATHINFO: i32 = 9;
+        // struct proc_vnodepathinfo layout (from libproc.h):
+        //   pvi_cdir: struct vnode_info_path (offset 0, CWD path at +152)
+        //   pvi_rdir: struct vnode_info_path (root dir)
+        // Total struct size: 2352 bytes, CWD path at offset 152.
+        const STRUCT_SIZE: usize = 2352;
+        const CWD_PATH_OFFSET: usize = 152;
+        const MAXPATHLEN: usize = 1024;
+
+        let mut buf = vec![0u8; STRUCT_SIZE];
+        let ret = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                PROC_PIDVNODEPATHINFO,
+                0,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                STRUCT_SIZE as i32,
+            )
+        };
+        if ret == STRUCT_SIZE as i32 {
+            let path_bytes = &buf[CWD_PATH_OFFSET..CWD_PATH_OFFSET + MAXPATHLEN];
+            let end = path_bytes.iter().position(|&b| b == 0).unwrap_or(MAXPATHLEN);
+            if end > 0 {
+                return String::from_utf8_lossy(&path_bytes[..end])
+                    .to_string()
+                    .into();
+            }
+        }
+        None
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        // Linux: read /proc/<pid>/cwd symlink
+        std::fs::read_link(format!("/proc/{}/cwd", pid))
+            .ok()
+            .and_then(|p| p.to_str().map(|s| s.to_string()))
+    }
+}
+
+// ── Process resource usage ──────────────────────────────────────────
+
+/// CPU time and resident memory of one process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ProcessUsage {
+    /// User + system CPU time.
+    cpu_seconds: f64,
+    rss_bytes: u64,
+}
+
+/// Read a PID's CPU time and RSS.
+/// On macOS, uses `proc_pidinfo(PROC_PIDTASKINFO)` from libproc.
+/// On Linux, reads `/proc/<pid>/stat`.
+/// Returns None if the process cannot be found (race: it may have exited).
+fn get_process_usage(pid: libc::pid_t) -> Option<ProcessUsage> {
+    #[cfg(target_os = "macos")]
+    {
+        let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::proc_taskinfo>() as i32;
+        let ret = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                libc::PROC_PIDTASKINFO,
+                0,
+                &mut info as *mut libc::proc_taskinfo as *mut libc::c_void,
+                size,
+            )
+        };
+        if ret != size {
+            return None;
+        }
+        // Task times are in Mach absolute time units, not nanoseconds, on
+        // Apple silicon.
+        #[allow(deprecated)]
+        let nanos_per_tick = {
+            let mut timebase = libc::mach_timebase_info { numer: 0, denom: 0 };
+            if unsafe { libc::mach_timebase_info(&mut timebase) } == 0 && timebase.denom > 0 {
+                timebase.numer as f64 / timebase.denom as f64
+            } else {
+                1.0
+            }
+        };
+        let ticks = (info.pti_total_user + info.pti_total_system) as f64;
+        Some(ProcessUsage {
+            cpu_seconds: ticks * nanos_per_tick / 1e9,
+            rss_bytes: info.pti_resident_size,
+        })
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        parse_proc_stat(&stat, ticks_per_sec as f64, page_size as u64)
+    }
+}
+
+/// Parse `/proc/<pid>/stat`. The command name (field 2) may contain spaces
+/// and parentheses, so fields are counted from the last `)`.
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+fn parse_proc_stat(stat: &str, ticks_per_sec: f64, page_size: u64) -> Option<ProcessUsage> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // fields[0] is field 3 (state): utime is field 14, stime 15, rss 24
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    if ticks_per_sec <= 0.0 {
+        return None;
+    }
+    Some(ProcessUsage {
+        cpu_seconds: (field(14)? + field(15)?) as f64 / ticks_per_sec,
+        rss_bytes: field(24)? * page_size,
+    })
+}
+
+// ── Session metadata ────────────────────────────────────────────────
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SessionMeta {
+    id: String,
+    command: String,
+    args: Vec<String>,
+    cwd: String,
+    created_at: u64,
     last_activity: u64,
     status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     exit_code: Option<i32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    exited_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exited_at: Option<u64>,
+    cols: u16,
+    rows: u16,
+    pid: u32,
+    started_at: String,
+    total_bytes_written: f64,
+    last_active_at: String,
+    bytes_per_second: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// 1-minute bytes/sec rolling average
+    bps1: f64,
+    /// 5-minute bytes/sec rolling average
+    bps5: f64,
+    /// 15-minute bytes/sec rolling average
+    bps15: f64,
+    /// Name of the foreground process (None when shell itself is in foreground)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    foreground_process: Option<String>,
+    /// Directory of persisted output segments (RELAY_PERSIST_SCROLLBACK=1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrollback_dir: Option<String>,
+    /// Bound address of the `--ws-listen` WebSocket listener
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ws_listen: Option<String>,
+    /// Bound address or socket path of the `--metrics-listen` endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metrics_listen: Option<String>,
+    /// Matches recorded by `mark` watch rules, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    watch_marks: Vec<WatchMark>,
+    /// Most recent command from OSC 133 marks: running, or last finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_command: Option<CommandRecord>,
+}
+
+// ── Throughput metrics (1/5/15m) ────────────────────────────────────
+
+struct ThroughputSample {
+    instant: Instant,
+    bytes: usize,
+}
+
+struct ThroughputTracker {
+    samples: Vec<ThroughputSample>,
+}
+
+impl ThroughputTracker {
+    fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.samples.push(ThroughputSample {
+            instant: Instant::now(),
+            bytes,
+        });
+        // Prune samples older than 15 minutes
+        let cutoff = Instant::now() - Duration::from_secs(15 * 60);
+        self.samples.retain(|s| s.instant >= cutoff);
+    }
+
+    /// Compute average bytes/sec over the given window duration.
+    fn compute_bps(&self, window: Duration) -> f64 {
+        let now = Instant::now();
+        let cutoff = now - window;
+        let total_bytes: usize = self
+            .samples
+            .iter()
+            .filter(|s| s.instant >= cutoff)
+            .map(|s| s.bytes)
+            .sum();
+        let window_secs = window.as_secs_f64();
+        if window_secs <= 0.0 {
+            return 0.0;
+        }
+        total_bytes as f64 / window_secs
+    }
+
+    fn bps1(&self) -> f64 {
+        self.compute_bps(Duration::from_secs(60))
+    }
+
+    fn bps5(&self) -> f64 {
+        self.compute_bps(Duration::from_secs(300))
+    }
+
+    fn bps15(&self) -> f64 {
+        self.compute_bps(Duration::from_secs(900))
+    }
+}
+
+// ── Sparkline ring buffer (1-second bps1 history) ────────────────────
+
+const SPARKLINE_RING_CAP: usize = 3600; // 1 hour at 1s intervals
+
+struct SparklineRing {
+    buf: Vec<f64>,
+    head: usize,
+    len: usize,
+}
+
+impl SparklineRing {
+    /// `cap` must fit the u16 length prefix of `encode`.
+    fn new(cap: usize) -> Self {
+        Self {
+            buf: vec![0.0; cap],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Rebuild a ring from oldest-first values, keeping the newest `cap`.
+    fn from_values(cap: usize, values: &[f64]) -> Self {
+        let mut ring = Self::new(cap);
+        for &v in values {
+            ring.push(v);
+        }
+        ring
+    }
+
+    fn push(&mut self, value: f64) {
+        self.buf[self.head] = value;
+        self.head = (self.head + 1) % self.buf.len();
+        if self.len < self.buf.len() {
+            self.len += 1;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn to_vec(&self) -> Vec<f64> {
+        if self.len == 0 {
+            return Vec::new();
+        }
+        if self.len < self.buf.len() {
+            self.buf[..self.len].to_vec()
+        } else {
+            let mut result = Vec::with_capacity(self.len);
+            result.extend_from_slice(&self.buf[self.head..]);
+            result.extend_from_slice(&self.buf[..self.head]);
+            result
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let values = self.to_vec();
+        let mut out = Vec::with_capacity(2 + values.len() * 8);
+        out.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        for v in &values {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        out
+    }
+}
+
+// ── PTY helpers (using nix/libc) ────────────────────────────────────
+
+/// Spawn a child in a new PTY. Returns (master_fd, child_pid).
+///
+/// When `login` is true, argv[0] is set to `-<basename>` (e.g. `-zsh`),
+/// which is the standard Unix convention used by iTerm2, tmux, and
+/// Terminal.app to tell a shell to behave as a login shell. This causes
+/// the shell to source login profiles (/etc/zprofile, ~/.zprofile, etc.)
+/// without needing a wrapper shell like `zsh -l -c "exec zsh"`.
+fn spawn_pty(
+    command: &str,
+    args: &[String],
+    cols: u16,
+    rows: u16,
+    cwd: &str,
+    login: bool,
+) -> io::Result<(OwnedFd, libc::pid_t)> {
+    let mut winsize = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // Use forkpty
+    let mut master_fd: libc::c_int = -1;
+    let pid = unsafe {
+        libc::forkpty(
+            &mut master_fd as *mut libc::c_int,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut winsize as *mut libc::winsize,
+        )
+    };
+
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if pid == 0 {
+        // Child process
+        // Set TERM and TERM_PROGRAM so child processes (e.g. NeoVim) detect
+        // the correct terminal capabilities.  We use xterm.js in the browser,
+        // which doesn't support colon-separated SGR RGB (e.g. `38:2:R:G:B`).
+        // Leaving TERM_PROGRAM inherited from the parent (e.g. "iTerm.app")
+        // would cause NeoVim to emit colon-format colors that xterm.js v5
+        // misparses, zeroing the blue channel.
+        env::set_var("TERM", "xterm-256color");
+        env::set_var("TERM_PROGRAM", "relay-tty");
+        // Change directory
+        if env::set_current_dir(cwd).is_err() {
+            eprintln!("pty-host: failed to chdir to {}", cwd);
+        }
+
+        // Build argv for exec
+        let c_command = std::ffi::CString::new(command).unwrap_or_else(|_| {
+            eprintln!("pty-host: invalid command name");
+            process::exit(127);
+        });
+
+        // argv[0]: when login is true, use "-<basename>" convention (like iTerm2/tmux).
+        // This tells the shell to behave as a login shell without needing a wrapper.
+        let argv0 = if login {
+            let basename = std::path::Path::new(command)
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or(command);
+            std::ffi::CString::new(format!("-{}", basename)).unwrap_or_else(|_| c_command.clone())
+        } else {
+            c_command.clone()
+        };
+
+        let c_args: Vec<std::ffi::CString> = std::iter::once(argv0)
+            .chain(args.iter().map(|a| {
+                std::ffi::CString::new(a.as_str()).unwrap_or_else(|_| {
+                    eprintln!("pty-host: invalid argument");
+                    process::exit(127);
+                })
+            }))
+            .collect();
+
+        // execvp
+        let c_argv: Vec<*const libc::c_char> = c_args
+            .iter()
+            .map(|a| a.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
+
+        unsafe {
+            libc::execvp(c_command.as_ptr(), c_argv.as_ptr());
+        }
+        // If execvp returns, it failed
+        let err = io::Error::last_os_error();
+        eprintln!("pty-host: exec failed: {}", err);
+        process::exit(127);
+    }
+
+    // Parent process
+    // SAFETY: master_fd is valid from forkpty
+    let owned = unsafe { OwnedFd::from_raw_fd(master_fd) };
+    Ok((owned, pid))
+}
+
+/// Resize a PTY.
+///
+/// Sets the winsize via `TIOCSWINSZ` on the master fd, then explicitly sends
+/// `SIGWINCH` to the foreground process group on the slave side. The kernel
+/// *should* deliver SIGWINCH automatically from the ioctl, but some programs
+/// (notably NeoVim via libuv) don't always receive it reliably. Explicitly
+/// signaling the foreground pgrp — the same belt-and-suspenders approach used
+/// by tmux — ensures all well-behaved terminal applications redraw correctly.
+fn resize_pty(master_fd: RawFd, cols: u16, rows: u16) {
+    let ws = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe {
+        let ret = libc::ioctl(master_fd, libc::TIOCSWINSZ, &ws);
+        if ret < 0 {
+            log_warn!("TIOCSWINSZ failed", error = io::Error::last_os_error());
+            return;
+        }
+
+        // Explicitly send SIGWINCH to the foreground process group.
+        // TIOCSWINSZ should trigger this automatically via the kernel, but
+        // some programs (NeoVim/libuv) don't always catch the implicit signal.
+        // tcgetpgrp on the master fd returns the slave's foreground pgrp.
+        let fg_pgrp = libc::tcgetpgrp(master_fd);
+        if fg_pgrp > 0 {
+            libc::kill(-fg_pgrp, libc::SIGWINCH);
+        }
+    }
+}
+
+// ── Frame helpers ───────────────────────────────────────────────────
+
+fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read one frame's payload from a blocking stream.
+fn read_frame_blocking(stream: &mut impl io::Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    if payload.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty frame"));
+    }
+    Ok(payload)
+}
+
+// ── Shared state ────────────────────────────────────────────────────
+
+type ClientWriter = Arc<Mutex<OwnedWriteHalf>>;
+
+/// How full replays (first connect, stale offset) are produced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplayMode {
+    /// Synthesize the current screen + scrollback from the VT model.
+    Screen,
+    /// Replay the raw ring buffer, truncated at the last screen clear.
+    /// Escape hatch via `RELAY_REPLAY_MODE=bytes`.
+    Bytes,
+}
+
+impl ReplayMode {
+    fn from_env() -> Self {
+        match env::var("RELAY_REPLAY_MODE").as_deref() {
+            Ok("bytes") => ReplayMode::Bytes,
+            _ => ReplayMode::Screen,
+        }
+    }
+}
+
+struct SharedState {
+    output_buffer: OutputBuffer,
+    screen: Screen,
+    replay_mode: ReplayMode,
+    /// On-disk copy of the output stream, when persistence is enabled.
+    scrollback_store: Option<ScrollbackStore>,
+    /// asciicast v2 recording, when `--record`/`RELAY_RECORD` is set.
+    recorder: Option<AsciicastRecorder>,
+    /// Player position, in `--replay` mode.
+    playback: Option<PlaybackStatus>,
+    meta: SessionMeta,
+    meta_dirty: bool,
+    session_active: bool,
+    exit_code: Option<i32>,
+    throughput: ThroughputTracker,
+    title: Option<String>,
+    /// Tracks whether metrics are currently broadcasting (non-zero activity).
+    /// Stops broadcasting when all three bps values hit 0.
+    last_metrics_nonzero: bool,
+    sparkline: SparklineRing,
+    slow_clients: SlowClientPolicy,
+    watcher: Watcher,
+    commands: CommandTracker,
+    links: LinkIndex,
+    images: ImageStore,
+    clipboard: Clipboard,
+    config: Config,
+}
+
+impl SharedState {
+    /// Data for a full replay, according to the replay mode.
+    fn full_replay(&self) -> Vec<u8> {
+        match self.replay_mode {
+            ReplayMode::Screen => self.screen.snapshot(),
+            ReplayMode::Bytes => self.output_buffer.read(),
+        }
+    }
+
+    /// What a client at `offset` is missing: the delta since then, falling
+    /// back to persisted scrollback for offsets the ring buffer no longer
+    /// holds. Otherwise a full replay, with `true` if the client's cached
+    /// output must be discarded first.
+    fn catch_up(&self, offset: f64) -> (Vec<u8>, bool) {
+        if offset <= 0.0 {
+            return (self.full_replay(), false);
+        }
+        let delta = self.output_buffer.read_from(offset).or_else(|| {
+            let store = self.scrollback_store.as_ref()?;
+            let end = self.output_buffer.total_written as u64;
+            store.read_range(offset as u64, end).ok().flatten()
+        });
+        match delta {
+            Some(delta) => (delta, false),
+            None => (self.full_replay(), true),
+        }
+    }
+}
+
+// ── Peer authorization ──────────────────────────────────────────────
+
+/// What an accepted client may do. Ordered so `min` is the weaker role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Access {
+    /// Receives output but cannot type, resize, detach, clear or control
+    /// playback.
+    ReadOnly,
+    ReadWrite,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Access::ReadOnly => "read-only",
+            Access::ReadWrite => "read-write",
+        })
+    }
+}
+
+/// Who may use the session socket, checked against the peer's credentials
+/// (`SO_PEERCRED`/`getpeereid`) on accept. Our own UID always has full access.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerPolicy {
+    /// `--allow-uid`: further UIDs with full access.
+    allow_uids: Vec<u32>,
+    /// `--allow-gid`: primary GIDs with full access. Only the GID in the
+    /// peer's credentials is checked, not its supplementary groups.
+    allow_gids: Vec<u32>,
+    /// `--unlisted-peers read-only`: downgrade other peers instead of
+    /// rejecting them.
+    read_only_unlisted: bool,
+    /// `--require-token`: clients are viewers until they send `AUTH` with
+    /// the write token.
+    require_token: bool,
+    /// Capability token for read-write `AUTH`, also written to
+    /// `~/.relay-tty/sessions/<id>.token` (0600). Empty matches nothing.
+    write_token: String,
+}
+
+impl PeerPolicy {
+    fn access(&self, own_uid: u32, uid: u32, gid: u32) -> Option<Access> {
+        if uid == own_uid || self.allow_uids.contains(&uid) || self.allow_gids.contains(&gid) {
+            Some(Access::ReadWrite)
+        } else if self.read_only_unlisted {
+            Some(Access::ReadOnly)
+        } else {
+            None
+        }
+    }
+
+    /// Constant-time comparison against the write token.
+    fn token_matches(&self, token: &[u8]) -> bool {
+        let expected = self.write_token.as_bytes();
+        !expected.is_empty()
+            && expected.len() == token.len()
+            && expected.iter().zip(token).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    /// Socket file mode: as narrow as the policy allows. Owner-only with no
+    /// allowlist, group-writable for a single allowed GID (see
+    /// `socket_group`), and otherwise world-writable, in which case the
+    /// credential check is what keeps strangers out.
+    fn socket_mode(&self) -> u32 {
+        if self.socket_group().is_some() {
+            0o660
+        } else if self.read_only_unlisted || !self.allow_uids.is_empty() || !self.allow_gids.is_empty() {
+            0o666
+        } else {
+            0o600
+        }
+    }
+
+    /// Group to chgrp the socket to, when one allowed GID is all that needs
+    /// filesystem access.
+    fn socket_group(&self) -> Option<u32> {
+        match self.allow_gids.as_slice() {
+            [gid] if self.allow_uids.is_empty() && !self.read_only_unlisted => Some(*gid),
+            _ => None,
+        }
+    }
+}
+
+/// A connected client's access: the most its credentials allow, and the
+/// policy that decides its role within that.
+struct ClientAuth {
+    ceiling: Access,
+    policy: Arc<PeerPolicy>,
+}
+
+impl ClientAuth {
+    /// Role before the client sends `AUTH`.
+    fn initial(&self) -> Access {
+        if self.policy.require_token {
+            Access::ReadOnly
+        } else {
+            self.ceiling
+        }
+    }
+
+    /// Role requested by an `AUTH` frame: `[1B role][token]`. Read-write
+    /// needs the write token; anything else makes the client a viewer.
+    fn authenticate(&self, data: &[u8]) -> Access {
+        match data.split_first() {
+            Some((&AUTH_ROLE_READ_WRITE, token)) if self.policy.token_matches(token) => self.ceiling,
+            Some((&AUTH_ROLE_READ_WRITE, _)) => {
+                log_warn!("invalid auth token, client is read-only");
+                Access::ReadOnly
+            }
+            Some((&AUTH_ROLE_VIEWER, _)) => Access::ReadOnly,
+            _ => {
+                log_warn!("unknown auth role, client is read-only");
+                Access::ReadOnly
+            }
+        }
+    }
+}
+
+/// Generate a write token and store it where same-user clients can read it.
+fn issue_token(path: &Path) -> String {
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut bytes = [0u8; 16];
+    let token = fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map(|()| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let written = token.and_then(|token| {
+        let _ = fs::remove_file(path);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(token.as_bytes())?;
+        Ok(token)
+    });
+    written.unwrap_or_else(|e| {
+        log_error!("failed to issue auth token", error = e);
+        String::new()
+    })
+}
+
+/// Parse a comma-separated `--allow-uid`/`--allow-gid` value.
+fn parse_id_list(value: &str) -> Option<Vec<u32>> {
+    value.split(',').map(|id| id.trim().parse().ok()).collect()
+}
+
+fn restrict_socket(path: &Path, policy: &PeerPolicy) {
+    use std::os::unix::fs::PermissionsExt;
+    let mut mode = policy.socket_mode();
+    if let Some(gid) = policy.socket_group() {
+        if let Err(e) = std::os::unix::fs::chown(path, None, Some(gid)) {
+            // The group couldn't reach a 0660 socket; fall back to the
+            // credential check alone
+            log_warn!("failed to chgrp socket", gid = gid, error = e);
+            mode = 0o666;
+        }
+    }
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+        log_warn!("failed to chmod socket", error = e);
+    }
+}
+
+/// Decide what a newly accepted peer may do; `None` means drop it before
+/// it sees any output.
+fn authorize_peer(stream: &tokio::net::UnixStream, policy: &PeerPolicy) -> Option<Access> {
+    let cred = match stream.peer_cred() {
+        Ok(cred) => cred,
+        Err(e) => {
+            log_warn!("rejected peer with unknown credentials", error = e);
+            return None;
+        }
+    };
+    let access = policy.access(unsafe { libc::geteuid() }, cred.uid(), cred.gid());
+    match access {
+        None => log_warn!("rejected peer", uid = cred.uid(), gid = cred.gid()),
+        Some(Access::ReadOnly) => log_info!("peer limited to read-only", uid = cred.uid(), gid = cred.gid()),
+        Some(Access::ReadWrite) => {}
+    }
+    access
+}
+
+// ── WebSocket listener ──────────────────────────────────────────────
+//
+// `--ws-listen` lets browsers connect to pty-host directly, without the Node
+// bridge. Each binary WebSocket message is one payload exactly as framed on
+// the Unix socket, minus the 4-byte length prefix. A bridge task converts
+// between the two over a socketpair whose other end goes to `spawn_client`,
+// so WebSocket clients get the same handshake, replay and roles.
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WS_MAX_MESSAGE: usize = 1024 * 1024;
+const WS_MAX_REQUEST_HEAD: usize = 8192;
+const WS_HANDSHAKE_TIMEOUT_MS: u64 = 5_000;
+
+const WS_OP_CONTINUATION: u8 = 0x0;
+const WS_OP_TEXT: u8 = 0x1;
+const WS_OP_BINARY: u8 = 0x2;
+const WS_OP_CLOSE: u8 = 0x8;
+const WS_OP_PING: u8 = 0x9;
+const WS_OP_PONG: u8 = 0xA;
+
+/// Where WebSocket upgrades are accepted.
+#[derive(Default)]
+struct WsConfig {
+    /// `--ws-listen unix`: also accept `GET` upgrades on the session socket.
+    unix: bool,
+    /// `--ws-listen [host:]port`
+    tcp: Option<StdTcpListener>,
+}
+
+/// Bind a `--ws-listen` TCP address; a bare port listens on loopback.
+fn bind_ws_listener(spec: &str) -> StdTcpListener {
+    let addr = if spec.bytes().all(|b| b.is_ascii_digit()) {
+        format!("127.0.0.1:{}", spec)
+    } else {
+        spec.to_string()
+    };
+    StdTcpListener::bind(&addr).unwrap_or_else(|e| {
+        eprintln!("pty-host: failed to listen for WebSockets on {}: {}", addr, e);
+        process::exit(1);
+    })
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (acc, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *acc = acc.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (bytes, v) in out.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+/// Standard base64 with padding, the counterpart of `base64_decode`.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+fn websocket_accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key, WS_GUID).as_bytes()))
+}
+
+/// The parts of a WebSocket upgrade request pty-host cares about.
+#[derive(Debug, PartialEq)]
+struct WsRequest {
+    key: String,
+    /// `?token=` — the write token, for read-write access.
+    token: Option<String>,
+}
+
+/// Validate an HTTP/1.1 upgrade request head (without the blank line).
+fn parse_upgrade_request(head: &str) -> Result<WsRequest, &'static str> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some("GET"), Some(target), Some(version)) =
+        (request_line.next(), request_line.next(), request_line.next())
+    else {
+        return Err("not a GET request");
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err("unsupported HTTP version");
+    }
+    let token = target
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|pair| pair.strip_prefix("token=")))
+        .map(str::to_string);
+
+    let (mut upgrade, mut connection, mut version_ok, mut key) = (false, false, false, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "connection" => {
+                connection = value.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade"))
+            }
+            "sec-websocket-version" => version_ok = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if !upgrade || !connection {
+        return Err("missing WebSocket upgrade headers");
+    }
+    if !version_ok {
+        return Err("unsupported WebSocket version");
+    }
+    let key = key.ok_or("missing Sec-WebSocket-Key")?;
+    Ok(WsRequest { key, token })
+}
+
+/// Server frames are never masked or fragmented.
+fn encode_ws_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[derive(Debug, PartialEq)]
+enum WsEvent {
+    Message(Vec<u8>),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Incremental decoder for client→server frames.
+#[derive(Default)]
+struct WsDecoder {
+    buf: Vec<u8>,
+    /// Fragments of an unfinished message.
+    message: Vec<u8>,
+    fragmented: bool,
+}
+
+impl WsDecoder {
+    /// Feed raw bytes and return the complete events. An error means the
+    /// connection must be dropped.
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<WsEvent>, &'static str> {
+        self.buf.extend_from_slice(data);
+        let mut events = Vec::new();
+        while self.buf.len() >= 2 {
+            let fin = self.buf[0] & 0x80 != 0;
+            let opcode = self.buf[0] & 0x0F;
+            if self.buf[1] & 0x80 == 0 {
+                return Err("unmasked client frame");
+            }
+            let (len, header_len) = match self.buf[1] & 0x7F {
+                126 if self.buf.len() >= 4 => (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4),
+                127 if self.buf.len() >= 10 => (u64::from_be_bytes(self.buf[2..10].try_into().unwrap()), 10),
+                126 | 127 => break,
+                len => (len as u64, 2),
+            };
+            if len > WS_MAX_MESSAGE as u64 {
+                return Err("message too large");
+            }
+            let start = header_len + 4;
+            let end = start + len as usize;
+            if self.buf.len() < end {
+                break;
+            }
+            let mask: [u8; 4] = self.buf[header_len..start].try_into().unwrap();
+            let payload: Vec<u8> = self.buf[start..end]
+                .iter()
+                .zip(mask.iter().cycle())
+                .map(|(b, m)| b ^ m)
+                .collect();
+            self.buf.drain(..end);
+
+            match opcode {
+                WS_OP_CLOSE => {
+                    events.push(WsEvent::Close);
+                    break;
+                }
+                WS_OP_PING => events.push(WsEvent::Ping(payload)),
+                WS_OP_PONG => {}
+                WS_OP_TEXT | WS_OP_BINARY | WS_OP_CONTINUATION => {
+                    if (opcode == WS_OP_CONTINUATION) != self.fragmented {
+                        return Err("unexpected continuation frame");
+                    }
+                    if self.message.len() + payload.len() > WS_MAX_MESSAGE {
+                        return Err("message too large");
+                    }
+                    self.message.extend_from_slice(&payload);
+                    self.fragmented = !fin;
+                    if fin {
+                        events.push(WsEvent::Message(std::mem::take(&mut self.message)));
+                    }
+                }
+                _ => return Err("unknown opcode"),
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Read the upgrade request and answer it. Returns the request and any
+/// bytes the client sent after it, or `None` if the connection was refused.
+/// TCP peers have no credentials to check, so `token_required` makes them
+/// prove they can read the write token.
+async fn websocket_handshake<S>(
+    stream: &mut S,
+    policy: &PeerPolicy,
+    token_required: bool,
+) -> Option<(WsRequest, Vec<u8>)>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if data.len() > WS_MAX_REQUEST_HEAD {
+            return None;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let leftover = data.split_off(head_end + 4);
+
+    let refusal = match parse_upgrade_request(&head) {
+        Err(e) => {
+            log_warn!("bad WebSocket upgrade", error = e);
+            Some("400 Bad Request")
+        }
+        Ok(WsRequest { token: Some(ref token), .. }) if !policy.token_matches(token.as_bytes()) => {
+            log_warn!("WebSocket client sent an invalid token");
+            Some("403 Forbidden")
+        }
+        Ok(WsRequest { token: None, .. }) if token_required => Some("403 Forbidden"),
+        Ok(_) => None,
+    };
+    if let Some(status) = refusal {
+        let response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status);
+        let _ = stream.write_all(response.as_bytes()).await;
+        return None;
+    }
+    let request = parse_upgrade_request(&head).ok()?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept_key(&request.key)
+    );
+    stream.write_all(response.as_bytes()).await.ok()?;
+    Some((request, leftover))
+}
+
+/// Shuttle messages between an upgraded WebSocket and the client handler's
+/// end of a socketpair until either side closes.
+async fn bridge_websocket<S>(ws: S, leftover: Vec<u8>, token: Option<String>, host: tokio::net::UnixStream)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut ws_read, mut ws_write) = tokio::io::split(ws);
+    let (mut host_read, mut host_write) = host.into_split();
+
+    // A valid query token is presented to the handler as an AUTH frame.
+    if let Some(token) = token {
+        let mut auth = vec![WS_MSG_AUTH, AUTH_ROLE_READ_WRITE];
+        auth.extend_from_slice(token.as_bytes());
+        if host_write.write_all(&encode_frame(&auth)).await.is_err() {
+            return;
+        }
+    }
+
+    let mut decoder = WsDecoder::default();
+    let mut incoming = leftover;
+    let mut ws_buf = vec![0u8; 65536];
+    let mut host_buf = vec![0u8; 65536];
+    let mut host_pending: Vec<u8> = Vec::new();
+    loop {
+        let events = match decoder.feed(&incoming) {
+            Ok(events) => events,
+            Err(e) => {
+                log_warn!("dropping WebSocket client", error = e);
+                let _ = ws_write.write_all(&encode_ws_frame(WS_OP_CLOSE, &1002u16.to_be_bytes())).await;
+                return;
+            }
+        };
+        for event in events {
+            let result = match event {
+                // Empty payloads carry no type byte and would stall the handler.
+                WsEvent::Message(payload) if payload.is_empty() => Ok(()),
+                WsEvent::Message(payload) => host_write.write_all(&encode_frame(&payload)).await,
+                WsEvent::Ping(payload) => ws_write.write_all(&encode_ws_frame(WS_OP_PONG, &payload)).await,
+                WsEvent::Close => {
+                    let _ = ws_write.write_all(&encode_ws_frame(WS_OP_CLOSE, &[])).await;
+                    return;
+                }
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+
+        tokio::select! {
+            result = ws_read.read(&mut ws_buf) => match result {
+                Ok(0) | Err(_) => return,
+                Ok(n) => incoming = ws_buf[..n].to_vec(),
+            },
+            result = host_read.read(&mut host_buf) => {
+                let n = match result {
+                    Ok(0) | Err(_) => {
+                        let _ = ws_write.write_all(&encode_ws_frame(WS_OP_CLOSE, &1000u16.to_be_bytes())).await;
+                        return;
+                    }
+                    Ok(n) => n,
+                };
+                host_pending.extend_from_slice(&host_buf[..n]);
+                let mut consumed = 0;
+                while host_pending.len() - consumed >= 4 {
+                    let len = u32::from_be_bytes(host_pending[consumed..consumed + 4].try_into().unwrap()) as usize;
+                    let end = consumed + 4 + len;
+                    if host_pending.len() < end {
+                        break;
+                    }
+                    let frame = encode_ws_frame(WS_OP_BINARY, &host_pending[consumed + 4..end]);
+                    if ws_write.write_all(&frame).await.is_err() {
+                        return;
+                    }
+                    consumed = end;
+                }
+                host_pending.drain(..consumed);
+                incoming.clear();
+            }
+        }
+    }
+}
+
+/// Complete the upgrade on an accepted stream and attach it as a client.
+async fn serve_websocket<S>(
+    mut stream: S,
+    token_required: bool,
+    state: Arc<RwLock<SharedState>>,
+    broadcast_tx: broadcast::Sender<Vec<u8>>,
+    channels: ClientChannels,
+    auth: ClientAuth,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let handshake = websocket_handshake(&mut stream, &auth.policy, token_required);
+    let Ok(Some((request, leftover))) =
+        time::timeout(Duration::from_millis(WS_HANDSHAKE_TIMEOUT_MS), handshake).await
+    else {
+        return;
+    };
+    let (host, client) = match tokio::net::UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => {
+            log_error!("failed to create WebSocket bridge", error = e);
+            return;
+        }
+    };
+    spawn_client(client, "websocket", state, &broadcast_tx, channels, auth);
+    bridge_websocket(stream, leftover, request.token, host).await;
+}
+
+/// Accept WebSocket clients on the `--ws-listen` TCP port.
+/// Pause before retrying a failed accept. Errors like EMFILE persist until
+/// a descriptor frees up, so retrying at once would spin.
+const ACCEPT_RETRY_MS: u64 = 100;
+
+async fn accept_backoff(listener: &str, e: io::Error) {
+    log_warn!("accept failed", listener = listener, error = e);
+    time::sleep(Duration::from_millis(ACCEPT_RETRY_MS)).await;
+}
+
+async fn accept_websockets(
+    listener: tokio::net::TcpListener,
+    state: Arc<RwLock<SharedState>>,
+    broadcast_tx: broadcast::Sender<Vec<u8>>,
+    channels: ClientChannels,
+    policy: Arc<PeerPolicy>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                accept_backoff("websocket", e).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let auth = ClientAuth { ceiling: Access::ReadWrite, policy: Arc::clone(&policy) };
+        tokio::spawn(serve_websocket(
+            stream,
+            true,
+            Arc::clone(&state),
+            broadcast_tx.clone(),
+            channels.clone(),
+            auth,
+        ));
+    }
+}
+
+/// Hand an accepted Unix socket connection to `spawn_client`, or with
+/// `--ws-listen unix` to the WebSocket bridge if it opens with `GET`. A
+/// length-prefixed frame can never start with `G` (that would be a >1GB
+/// frame). Clients that stay silent for the RESUME window are plain clients.
+fn accept_unix_client(
+    stream: tokio::net::UnixStream,
+    ws_unix: bool,
+    state: Arc<RwLock<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Vec<u8>>,
+    channels: ClientChannels,
+    auth: ClientAuth,
+) {
+    if !ws_unix {
+        spawn_client(stream, "unix", state, broadcast_tx, channels, auth);
+        return;
+    }
+    let broadcast_tx = broadcast_tx.clone();
+    tokio::spawn(async move {
+        let peek = async {
+            loop {
+                stream.readable().await?;
+                let mut first = 0u8;
+                let peeked = stream.try_io(tokio::io::Interest::READABLE, || {
+                    let n = unsafe {
+                        libc::recv(stream.as_raw_fd(), &mut first as *mut u8 as *mut libc::c_void, 1, libc::MSG_PEEK)
+                    };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n)
+                    }
+                });
+                match peeked {
+                    Ok(n) => return Ok((n == 1).then_some(first)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        match time::timeout(Duration::from_millis(RESUME_TIMEOUT_MS), peek).await {
+            Ok(Ok(Some(b'G'))) => serve_websocket(stream, false, state, broadcast_tx, channels, auth).await,
+            Ok(Ok(None)) | Ok(Err(_)) => {}
+            _ => spawn_client(stream, "unix", state, &broadcast_tx, channels, auth),
+        }
+    });
+}
+
+// ── Metrics endpoint ────────────────────────────────────────────────
+//
+// With `--metrics-listen`, pty-host answers `GET /metrics` with an
+// OpenMetrics text exposition of the session: output throughput, clients,
+// buffer fill, lag, replays and the child's CPU and memory. Every sample
+// carries a `session` label so one Prometheus can scrape all of a machine's
+// sessions. Counters restart from zero in each process, including after a
+// live upgrade, which Prometheus treats like any other restart.
+
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const METRICS_REQUEST_TIMEOUT_MS: u64 = 5_000;
+const METRICS_MAX_REQUEST_HEAD: usize = 8192;
+/// Upper bounds of the `relay_replay_bytes` histogram buckets.
+const REPLAY_SIZE_BUCKETS: [usize; 8] = [1 << 10, 4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20, 4 << 20, 16 << 20];
+
+/// Client and replay counters that `SharedState` doesn't already keep.
+struct MetricsCounters {
+    /// Connected right now.
+    clients: AtomicU64,
+    connections: AtomicU64,
+    dropped_frames: AtomicU64,
+    resyncs: AtomicU64,
+    slow_disconnects: AtomicU64,
+    /// Indexed by `CatchUp`.
+    replays: [AtomicU64; 3],
+    replay_bytes: AtomicU64,
+    /// Per bucket, not cumulative; the last is everything larger.
+    replay_sizes: [AtomicU64; REPLAY_SIZE_BUCKETS.len() + 1],
+}
+
+static METRICS: MetricsCounters = MetricsCounters::new();
+
+impl MetricsCounters {
+    const fn new() -> Self {
+        Self {
+            clients: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+            slow_disconnects: AtomicU64::new(0),
+            replays: [const { AtomicU64::new(0) }; 3],
+            replay_bytes: AtomicU64::new(0),
+            replay_sizes: [const { AtomicU64::new(0) }; REPLAY_SIZE_BUCKETS.len() + 1],
+        }
+    }
+
+    fn count(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn replay(&self, mode: CatchUp, bytes: usize) {
+        Self::count(&self.replays[mode as usize], 1);
+        Self::count(&self.replay_bytes, bytes as u64);
+        let bucket = REPLAY_SIZE_BUCKETS.iter().position(|&max| bytes <= max).unwrap_or(REPLAY_SIZE_BUCKETS.len());
+        Self::count(&self.replay_sizes[bucket], 1);
+    }
+}
+
+/// Everything one scrape reports.
+struct MetricsSnapshot {
+    session: String,
+    output_bytes: f64,
+    /// 1, 5 and 15 minute averages.
+    bps: [f64; 3],
+    /// (used, capacity) for the main and alternate screen buffers.
+    buffers: [(usize, usize); 2],
+    clients: u64,
+    connections: u64,
+    dropped_frames: u64,
+    resyncs: u64,
+    slow_disconnects: u64,
+    replays: [u64; 3],
+    replay_bytes: u64,
+    replay_sizes: [u64; REPLAY_SIZE_BUCKETS.len() + 1],
+    child: Option<ProcessUsage>,
+}
+
+impl MetricsSnapshot {
+    fn collect(s: &SharedState, counters: &MetricsCounters, child: Option<ProcessUsage>) -> Self {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let buf = &s.output_buffer;
+        Self {
+            session: s.meta.id.clone(),
+            output_bytes: buf.total_written,
+            bps: [s.throughput.bps1(), s.throughput.bps5(), s.throughput.bps15()],
+            buffers: [(buf.main_size(), buf.max_size), (buf.alt_buf.len(), buf.alt_cap)],
+            clients: load(&counters.clients),
+            connections: load(&counters.connections),
+            dropped_frames: load(&counters.dropped_frames),
+            resyncs: load(&counters.resyncs),
+            slow_disconnects: load(&counters.slow_disconnects),
+            replays: counters.replays.each_ref().map(load),
+            replay_bytes: load(&counters.replay_bytes),
+            replay_sizes: counters.replay_sizes.each_ref().map(load),
+            child,
+        }
+    }
+
+    /// The OpenMetrics text exposition, `# EOF` included.
+    fn render(&self) -> String {
+        let mut out = Exposition { text: String::new(), session: escape_label_value(&self.session) };
+        out.family("relay_output_bytes", "counter", "PTY output since the session started.");
+        out.sample("relay_output_bytes_total", "", self.output_bytes);
+        out.family("relay_output_bytes_per_second", "gauge", "Average PTY output rate.");
+        for (window, bps) in ["1m", "5m", "15m"].iter().zip(self.bps) {
+            out.sample("relay_output_bytes_per_second", &format!("window=\"{}\"", window), bps);
+        }
+        out.family("relay_clients", "gauge", "Connected clients.");
+        out.sample("relay_clients", "", self.clients);
+        out.family("relay_client_connections", "counter", "Accepted client connections.");
+        out.sample("relay_client_connections_total", "", self.connections);
+
+        let screens = ["main", "alt"].iter().zip(self.buffers);
+        out.family("relay_buffer_bytes", "gauge", "Output held for replay.");
+        for (screen, (used, _)) in screens.clone() {
+            out.sample("relay_buffer_bytes", &format!("screen=\"{}\"", screen), used);
+        }
+        out.family("relay_buffer_capacity_bytes", "gauge", "Replay buffer limits.");
+        for (screen, (_, capacity)) in screens {
+            out.sample("relay_buffer_capacity_bytes", &format!("screen=\"{}\"", screen), capacity);
+        }
+
+        out.family("relay_dropped_frames", "counter", "Broadcast frames dropped for lagging clients.");
+        out.sample("relay_dropped_frames_total", "", self.dropped_frames);
+        out.family("relay_client_resyncs", "counter", "Lagging clients caught up with a replay.");
+        out.sample("relay_client_resyncs_total", "", self.resyncs);
+        out.family("relay_slow_client_disconnects", "counter", "Clients disconnected for lagging too often.");
+        out.sample("relay_slow_client_disconnects_total", "", self.slow_disconnects);
+
+        out.family("relay_replays", "counter", "Replays sent, by how the client was caught up.");
+        for (mode, n) in CatchUp::ALL.iter().zip(self.replays) {
+            out.sample("relay_replays_total", &format!("mode=\"{}\"", mode), n);
+        }
+        out.family("relay_replay_bytes", "histogram", "Replay sizes.");
+        let mut cumulative = 0;
+        for (i, n) in self.replay_sizes.iter().enumerate() {
+            cumulative += n;
+            let le = REPLAY_SIZE_BUCKETS.get(i).map_or("+Inf".to_string(), |max| max.to_string());
+            out.sample("relay_replay_bytes_bucket", &format!("le=\"{}\"", le), cumulative);
+        }
+        out.sample("relay_replay_bytes_count", "", cumulative);
+        out.sample("relay_replay_bytes_sum", "", self.replay_bytes);
+
+        if let Some(child) = self.child {
+            out.family("relay_child_cpu_seconds", "counter", "CPU time of the session's child process.");
+            out.sample("relay_child_cpu_seconds_total", "", child.cpu_seconds);
+            out.family("relay_child_resident_memory_bytes", "gauge", "Resident memory of the session's child process.");
+            out.sample("relay_child_resident_memory_bytes", "", child.rss_bytes);
+        }
+        out.text.push_str("# EOF\n");
+        out.text
+    }
+}
+
+/// OpenMetrics text being written; every sample gets the `session` label.
+struct Exposition {
+    text: String,
+    session: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.text.push_str(&format!("# TYPE {} {}\n# HELP {} {}\n", name, kind, name, help));
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: impl fmt::Display) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        self.text.push_str(&format!("{}{{session=\"{}\"{}{}}} {}\n", name, self.session, sep, labels, value));
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// `--metrics-listen` socket.
+enum MetricsListener {
+    Tcp(StdTcpListener),
+    /// The socket file is removed when the session ends.
+    Unix(StdUnixListener, PathBuf),
+}
+
+impl MetricsListener {
+    /// `[host:]port` (a bare port binds loopback) or a Unix socket path.
+    fn bind(spec: &str) -> io::Result<Self> {
+        if spec.contains('/') {
+            use std::os::unix::fs::PermissionsExt;
+            let path = PathBuf::from(spec);
+            let _ = fs::remove_file(&path);
+            let listener = StdUnixListener::bind(&path)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+            return Ok(MetricsListener::Unix(listener, path));
+        }
+        let addr = if spec.bytes().all(|b| b.is_ascii_digit()) {
+            format!("127.0.0.1:{}", spec)
+        } else {
+            spec.to_string()
+        };
+        Ok(MetricsListener::Tcp(StdTcpListener::bind(addr)?))
+    }
+
+    /// Published as `metricsListen` in the session JSON.
+    fn address(&self) -> Option<String> {
+        match self {
+            MetricsListener::Tcp(l) => l.local_addr().ok().map(|a| a.to_string()),
+            MetricsListener::Unix(_, path) => Some(path.to_string_lossy().into_owned()),
+        }
+    }
+
+    fn socket_path(&self) -> Option<PathBuf> {
+        match self {
+            MetricsListener::Tcp(_) => None,
+            MetricsListener::Unix(_, path) => Some(path.clone()),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MetricsListener::Tcp(l) => l.as_raw_fd(),
+            MetricsListener::Unix(l, _) => l.as_raw_fd(),
+        }
+    }
+}
+
+/// Bind `--metrics-listen`; `unix` means `~/.relay-tty/sockets/<id>.metrics`.
+fn bind_metrics_listener(spec: &str, sockets_dir: &Path, id: &str) -> MetricsListener {
+    let spec = match spec {
+        "unix" => sockets_dir.join(format!("{}.metrics", id)).to_string_lossy().into_owned(),
+        spec => spec.to_string(),
+    };
+    MetricsListener::bind(&spec).unwrap_or_else(|e| {
+        eprintln!("pty-host: failed to listen for metrics on {}: {}", spec, e);
+        process::exit(1);
+    })
+}
+
+/// Answer scrapes until the session ends. `child_pid` is None in replay mode.
+async fn serve_metrics(listener: MetricsListener, state: Arc<RwLock<SharedState>>, child_pid: Option<libc::pid_t>) {
+    match listener {
+        MetricsListener::Tcp(l) => {
+            l.set_nonblocking(true).ok();
+            let listener = tokio::net::TcpListener::from_std(l).unwrap();
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(answer_metrics_request(stream, Arc::clone(&state), child_pid));
+                    }
+                    Err(e) => accept_backoff("metrics", e).await,
+                }
+            }
+        }
+        MetricsListener::Unix(l, _) => {
+            l.set_nonblocking(true).ok();
+            let listener = UnixListener::from_std(l).unwrap();
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(answer_metrics_request(stream, Arc::clone(&state), child_pid));
+                    }
+                    Err(e) => accept_backoff("metrics", e).await,
+                }
+            }
+        }
+    }
+}
+
+async fn answer_metrics_request<S>(mut stream: S, state: Arc<RwLock<SharedState>>, child_pid: Option<libc::pid_t>)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let read_head = async {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            if data.len() > METRICS_MAX_REQUEST_HEAD {
+                return None;
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        Some(String::from_utf8_lossy(&data).into_owned())
+    };
+    let Ok(Some(head)) = time::timeout(Duration::from_millis(METRICS_REQUEST_TIMEOUT_MS), read_head).await else {
+        return;
+    };
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    let path = request_line.clone().nth(1).map(|target| target.split('?').next().unwrap_or(target));
+    let (status, body) = match (request_line.next(), path) {
+        (Some("GET"), Some("/metrics" | "/")) => {
+            let child = child_pid.and_then(get_process_usage);
+            let snapshot = MetricsSnapshot::collect(&*state.read().await, &METRICS, child);
+            ("200 OK", snapshot.render())
+        }
+        (Some("GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        METRICS_CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+// ── Live upgrade ────────────────────────────────────────────────────
+//
+// SIGUSR2 makes pty-host exec its own binary path again, so an upgraded
+// binary installed in place takes over the session. exec keeps our pid, so
+// the shell stays our child and the PTY master, listening socket and
+// recording file carry over as inherited fds; everything else travels in a
+// handoff file. Clients are disconnected and reconnect with RESUME.
+
+/// Bump when `Handoff` changes shape.
+const HANDOFF_VERSION: u32 = 1;
+
+/// Session state passed to the process that replaces us. On disk this is
+/// one JSON line followed by the main ring, alt buffer and screen snapshot
+/// as raw blobs, in that order.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Handoff {
+    version: u32,
+    master_fd: RawFd,
+    listener_fd: RawFd,
+    child_pid: libc::pid_t,
+    meta: SessionMeta,
+    title: Option<String>,
+    session_active: bool,
+    replay_bytes: bool,
+    sparkline: Vec<f64>,
+    /// Partial OSC sequence held back by the PTY reader.
+    osc_pending: Vec<u8>,
+    recorder: Option<RecorderHandoff>,
+    #[serde(default)]
+    peer_policy: PeerPolicy,
+    #[serde(default)]
+    ws_unix: bool,
+    #[serde(default)]
+    ws_listener_fd: Option<RawFd>,
+    #[serde(default)]
+    metrics_listener_fd: Option<RawFd>,
+    /// Set when the metrics listener is a Unix socket rather than TCP.
+    #[serde(default)]
+    metrics_socket: Option<PathBuf>,
+    #[serde(default)]
+    slow_clients: SlowClientPolicy,
+    #[serde(default)]
+    watches: Vec<WatchRule>,
+    #[serde(default)]
+    commands: CommandTracker,
+    #[serde(default)]
+    links: LinkIndex,
+    /// Stored images; their data follows the screen snapshot blob.
+    #[serde(default)]
+    images: Vec<ImageHandoff>,
+    /// Keeps image ids unique across the upgrade.
+    #[serde(default)]
+    next_image_id: u64,
+    #[serde(default)]
+    clipboard: Clipboard,
+    #[serde(default)]
+    config: Config,
+    buffer: BufferHandoff,
+    screen_len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferHandoff {
+    total_written: f64,
+    in_alt_screen: bool,
+    alt_content_start: f64,
+    pending_seq: Vec<u8>,
+    main_len: usize,
+    alt_len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageHandoff {
+    id: String,
+    mime: String,
+    len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecorderHandoff {
+    fd: RawFd,
+    elapsed: f64,
+    utf8_carry: Vec<u8>,
+}
+
+/// Where and how to re-exec on SIGUSR2.
+struct UpgradeTarget {
+    exe: PathBuf,
+    handoff_path: PathBuf,
+    master_fd: RawFd,
+    listener_fd: RawFd,
+    child_pid: libc::pid_t,
+    peer_policy: PeerPolicy,
+    ws_unix: bool,
+    ws_listener_fd: Option<RawFd>,
+    metrics_listener_fd: Option<RawFd>,
+    metrics_socket: Option<PathBuf>,
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn write_handoff(path: &Path, handoff: &Handoff, blobs: &[&[u8]]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    let mut header = serde_json::to_vec(handoff)?;
+    header.push(b'\n');
+    file.write_all(&header)?;
+    for blob in blobs {
+        file.write_all(blob)?;
+    }
+    Ok(())
+}
+
+/// Read and delete a handoff file. Returns the header and the blob section.
+fn read_handoff(path: &Path) -> io::Result<(Handoff, Vec<u8>)> {
+    let mut data = fs::read(path)?;
+    let _ = fs::remove_file(path);
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let newline = data
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("missing handoff header"))?;
+    let blobs = data.split_off(newline + 1);
+    let handoff: Handoff = serde_json::from_slice(&data[..newline])?;
+    if handoff.version != HANDOFF_VERSION {
+        return Err(invalid(&format!("unsupported handoff version {}", handoff.version)));
+    }
+    let images_len: usize = handoff.images.iter().map(|image| image.len).sum();
+    if blobs.len() != handoff.buffer.main_len + handoff.buffer.alt_len + handoff.screen_len + images_len {
+        return Err(invalid("truncated handoff file"));
+    }
+    Ok((handoff, blobs))
+}
+
+/// Hand the session to a fresh copy of the binary. Only returns if the
+/// handoff could not be written or the exec failed, in which case this
+/// process keeps serving the session.
+fn exec_upgrade(target: &UpgradeTarget, s: &mut SharedState, osc_pending: &[u8]) -> io::Error {
+    if let Some(store) = s.scrollback_store.as_mut() {
+        store.sync();
+    }
+    let main = s.output_buffer.read_main_raw();
+    let screen = s.screen.snapshot();
+    let recorder = s.recorder.as_ref().map(AsciicastRecorder::handoff);
+    let (images, image_data) = s.images.handoff();
+    let mut fds = vec![target.master_fd, target.listener_fd];
+    fds.extend(target.ws_listener_fd);
+    fds.extend(target.metrics_listener_fd);
+    fds.extend(recorder.as_ref().map(|r| r.fd));
+    let handoff = Handoff {
+        version: HANDOFF_VERSION,
+        master_fd: target.master_fd,
+        listener_fd: target.listener_fd,
+        child_pid: target.child_pid,
+        meta: s.meta.clone(),
+        title: s.title.clone(),
+        session_active: s.session_active,
+        replay_bytes: s.replay_mode == ReplayMode::Bytes,
+        sparkline: s.sparkline.to_vec(),
+        osc_pending: osc_pending.to_vec(),
+        recorder,
+        peer_policy: target.peer_policy.clone(),
+        ws_unix: target.ws_unix,
+        ws_listener_fd: target.ws_listener_fd,
+        metrics_listener_fd: target.metrics_listener_fd,
+        metrics_socket: target.metrics_socket.clone(),
+        slow_clients: s.slow_clients,
+        watches: s.watcher.rules(),
+        commands: s.commands.clone(),
+        links: s.links.clone(),
+        images,
+        next_image_id: IMAGE_COUNTER.load(Ordering::Relaxed),
+        clipboard: s.clipboard.clone(),
+        config: s.config,
+        buffer: s.output_buffer.handoff(),
+        screen_len: screen.len(),
+    };
+    let mut blobs: Vec<&[u8]> = vec![&main, &s.output_buffer.alt_buf, &screen];
+    blobs.extend(image_data);
+    if let Err(e) = write_handoff(&target.handoff_path, &handoff, &blobs) {
+        let _ = fs::remove_file(&target.handoff_path);
+        return e;
+    }
+
+    let err = match fds.iter().try_for_each(|&fd| set_cloexec(fd, false)) {
+        Ok(()) => {
+            use std::os::unix::process::CommandExt;
+            process::Command::new(&target.exe)
+                .arg("--handoff")
+                .arg(&target.handoff_path)
+                .arg(&s.meta.id)
+                .exec()
+        }
+        Err(e) => e,
+    };
+    for fd in fds {
+        let _ = set_cloexec(fd, true);
+    }
+    let _ = fs::remove_file(&target.handoff_path);
+    err
+}
+
+/// Rebuild a session from the handoff file written by `exec_upgrade`.
+fn adopt_handoff(path: &Path, id: &str) -> io::Result<Session> {
+    let (handoff, blobs) = read_handoff(path)?;
+    if handoff.meta.id != id {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "handoff is for another session"));
+    }
+    let mut fds = vec![handoff.master_fd, handoff.listener_fd];
+    fds.extend(handoff.ws_listener_fd);
+    fds.extend(handoff.metrics_listener_fd);
+    fds.extend(handoff.recorder.as_ref().map(|r| r.fd));
+    for &fd in &fds {
+        set_cloexec(fd, true)?;
+    }
+    let data_dir = PathBuf::from(env::var("HOME").unwrap_or_else(|_| "/".to_string())).join(".relay-tty");
+    log_init(&data_dir, id, &handoff.config);
+    log_info!("took over session after live upgrade", pid = process::id(), child = handoff.child_pid);
+
+    let (main, rest) = blobs.split_at(handoff.buffer.main_len);
+    let (alt, rest) = rest.split_at(handoff.buffer.alt_len);
+    let (snapshot, image_data) = rest.split_at(handoff.screen_len);
+    IMAGE_COUNTER.fetch_max(handoff.next_image_id, Ordering::Relaxed);
+    let output_buffer = OutputBuffer::from_handoff(&handoff.config, &handoff.buffer, main, alt);
+    let mut screen = Screen::new(handoff.meta.cols, handoff.meta.rows, handoff.config.scrollback_lines);
+    screen.feed(snapshot);
+
+    let scrollback_store = handoff.meta.scrollback_dir.as_ref().and_then(|dir| {
+        ScrollbackStore::open(PathBuf::from(dir), PERSIST_SEGMENT_SIZE, PERSIST_MAX_BYTES)
+            .map_err(|e| log_warn!("failed to reopen scrollback dir", error = e))
+            .ok()
+    });
+    let recorder = handoff
+        .recorder
+        .map(|r| AsciicastRecorder::adopt(unsafe { fs::File::from_raw_fd(r.fd) }, r));
+
+    let mut watcher = Watcher::default();
+    if let Err(e) = watcher.set_rules(handoff.watches) {
+        log_warn!("dropped watch rules on upgrade", error = e);
+    }
+
+    Ok(Session {
+        id: id.to_string(),
+        master_fd: unsafe { OwnedFd::from_raw_fd(handoff.master_fd) },
+        child_pid: handoff.child_pid,
+        listener: unsafe { StdUnixListener::from_raw_fd(handoff.listener_fd) },
+        osc_pending: handoff.osc_pending,
+        peer_policy: handoff.peer_policy,
+        ws: WsConfig {
+            unix: handoff.ws_unix,
+            tcp: handoff.ws_listener_fd.map(|fd| unsafe { StdTcpListener::from_raw_fd(fd) }),
+        },
+        metrics: handoff.metrics_listener_fd.map(|fd| match handoff.metrics_socket {
+            Some(path) => MetricsListener::Unix(unsafe { StdUnixListener::from_raw_fd(fd) }, path),
+            None => MetricsListener::Tcp(unsafe { StdTcpListener::from_raw_fd(fd) }),
+        }),
+        session_path: data_dir.join("sessions").join(format!("{}.json", id)),
+        socket_path: data_dir.join("sockets").join(format!("{}.sock", id)),
+        state: SharedState {
+            output_buffer,
+            screen,
+            replay_mode: if handoff.replay_bytes { ReplayMode::Bytes } else { ReplayMode::Screen },
+            scrollback_store,
+            recorder,
+            playback: None,
+            meta: handoff.meta,
+            meta_dirty: true,
+            session_active: handoff.session_active,
+            exit_code: None,
+            throughput: ThroughputTracker::new(),
+            title: handoff.title,
+            last_metrics_nonzero: false,
+            sparkline: SparklineRing::from_values(handoff.config.sparkline_ring_cap, &handoff.sparkline),
+            slow_clients: handoff.slow_clients,
+            watcher,
+            commands: handoff.commands,
+            links: handoff.links,
+            images: ImageStore::from_handoff(handoff.images, image_data),
+            clipboard: handoff.clipboard,
+            config: handoff.config,
+        },
+    })
+}
+
+// ── Recorded session replay ─────────────────────────────────────────
+
+/// An asciicast v2 recording loaded for `--replay`.
+struct Recording {
     cols: u16,
     rows: u16,
-    pid: u32,
-    started_at: String,
-    total_bytes_written: f64,
-    last_active_at: String,
-    bytes_per_second: f64,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
     title: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
-    /// 1-minute bytes/sec rolling average
-    bps1: f64,
-    /// 5-minute bytes/sec rolling average
-    bps5: f64,
-    /// 15-minute bytes/sec rolling average
-    bps15: f64,
-    /// Name of the foreground process (None when shell itself is in foreground)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    foreground_process: Option<String>,
+    events: Vec<RecordedEvent>,
+}
+
+struct RecordedEvent {
+    /// Seconds since the start of the recording.
+    time: f64,
+    kind: RecordedEventKind,
+}
+
+enum RecordedEventKind {
+    Output(Vec<u8>),
+    Resize(u16, u16),
+}
+
+/// Latest event time accepted in a recording, in seconds (about 31 years).
+/// Keeps every playback delay representable as a `Duration`.
+const RECORDING_MAX_TIME: f64 = 1e9;
+
+impl Recording {
+    /// Parse an asciicast v2 file. Input (`i`) and marker (`m`) events are
+    /// skipped; event times are forced to be non-decreasing.
+    fn parse_asciicast(content: &str) -> Result<Self, String> {
+        let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+        let header: serde_json::Value = lines
+            .next()
+            .ok_or("empty recording")
+            .and_then(|l| serde_json::from_str(l).map_err(|_| "invalid header"))?;
+        if header["version"] != 2 {
+            return Err("not an asciicast v2 recording".to_string());
+        }
+        let dimension = |key: &str| header[key].as_u64().filter(|&n| n > 0 && n <= u16::MAX as u64);
+        let (Some(cols), Some(rows)) = (dimension("width"), dimension("height")) else {
+            return Err("header is missing width/height".to_string());
+        };
+
+        let mut events = Vec::new();
+        let mut last_time = 0.0_f64;
+        for (i, line) in lines.enumerate() {
+            let event: (f64, String, String) = serde_json::from_str(line)
+                .map_err(|e| format!("invalid event on line {}: {}", i + 2, e))?;
+            let (time, code, data) = event;
+            if !(0.0..=RECORDING_MAX_TIME).contains(&time) {
+                return Err(format!("event time out of range on line {}: {}", i + 2, time));
+            }
+            let time = time.max(last_time);
+            last_time = time;
+            let kind = match code.as_str() {
+                "o" => RecordedEventKind::Output(data.into_bytes()),
+                "r" => {
+                    let Some((c, r)) = data.split_once('x') else { continue };
+                    match (c.parse::<u16>(), r.parse::<u16>()) {
+                        (Ok(c), Ok(r)) if c > 0 && r > 0 => RecordedEventKind::Resize(c, r),
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            events.push(RecordedEvent { time, kind });
+        }
+
+        Ok(Self {
+            cols: cols as u16,
+            rows: rows as u16,
+            command: header["command"].as_str().map(String::from),
+            title: header["title"].as_str().map(String::from),
+            events,
+        })
+    }
+
+    fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |e| e.time)
+    }
+}
+
+/// Client request to the player, carried by PLAYBACK_CONTROL:
+/// `[op u8]` or `[op u8][f64 BE]` for seek (seconds) and speed (multiplier).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlaybackControl {
+    Pause,
+    Play,
+    Seek(f64),
+    Speed(f64),
+}
+
+const PLAYBACK_MIN_SPEED: f64 = 0.0625;
+const PLAYBACK_MAX_SPEED: f64 = 64.0;
+
+impl PlaybackControl {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let arg = || {
+            data.get(1..9)
+                .map(|b| f64::from_be_bytes(b.try_into().unwrap()))
+                .filter(|v| v.is_finite())
+        };
+        match *data.first()? {
+            0x00 => Some(PlaybackControl::Pause),
+            0x01 => Some(PlaybackControl::Play),
+            0x02 => arg().map(|t| PlaybackControl::Seek(t.max(0.0))),
+            0x03 => arg()
+                .filter(|&x| x > 0.0)
+                .map(|x| PlaybackControl::Speed(x.clamp(PLAYBACK_MIN_SPEED, PLAYBACK_MAX_SPEED))),
+            _ => None,
+        }
+    }
+}
+
+/// Player position, broadcast as PLAYBACK_STATE on every change:
+/// `[paused u8][position f64][duration f64][speed f64]` (f64s BE, seconds).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PlaybackStatus {
+    paused: bool,
+    position: f64,
+    duration: f64,
+    speed: f64,
+}
+
+impl PlaybackStatus {
+    fn encode_frame(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(26);
+        msg.push(WS_MSG_PLAYBACK_STATE);
+        msg.push(self.paused as u8);
+        msg.extend_from_slice(&self.position.to_be_bytes());
+        msg.extend_from_slice(&self.duration.to_be_bytes());
+        msg.extend_from_slice(&self.speed.to_be_bytes());
+        encode_frame(&msg)
+    }
+}
+
+/// Feed recorded output into the session as if the PTY had produced it.
+fn replay_output(s: &mut SharedState, broadcast_tx: &broadcast::Sender<Vec<u8>>, data: &[u8]) {
+    if let Some(new_title) = parse_osc_title(data) {
+        if s.title.as_deref() != Some(&new_title) {
+            s.title = Some(new_title.clone());
+            s.meta.title = Some(new_title.clone());
+            let mut title_msg = vec![WS_MSG_TITLE];
+            title_msg.extend_from_slice(new_title.as_bytes());
+            let _ = broadcast_tx.send(encode_frame(&title_msg));
+        }
+    }
+
+    s.output_buffer.write(data);
+    s.screen.feed(data);
+    s.meta.total_bytes_written += data.len() as f64;
+    s.meta.last_activity = now_millis();
+    s.meta.last_active_at = iso_now();
+    s.meta_dirty = true;
+
+    let mut data_msg = Vec::with_capacity(1 + data.len());
+    data_msg.push(WS_MSG_DATA);
+    data_msg.extend_from_slice(data);
+    let _ = broadcast_tx.send(encode_frame(&data_msg));
+}
+
+fn replay_resize(s: &mut SharedState, broadcast_tx: &broadcast::Sender<Vec<u8>>, cols: u16, rows: u16) {
+    s.meta.cols = cols;
+    s.meta.rows = rows;
+    s.meta_dirty = true;
+    s.output_buffer.notify_resize();
+    s.screen.resize(cols, rows);
+
+    let mut resize_msg = vec![WS_MSG_RESIZE, 0, 0, 0, 0];
+    resize_msg[1..3].copy_from_slice(&cols.to_be_bytes());
+    resize_msg[3..5].copy_from_slice(&rows.to_be_bytes());
+    let _ = broadcast_tx.send(encode_frame(&resize_msg));
+}
+
+/// Jump to `target` seconds: rebuild the screen as it was at that point and
+/// send it to clients as a terminal reset followed by the synthesized
+/// screen, so every client redraws without player-specific code. Returns
+/// the index of the first event after `target`.
+async fn replay_seek(
+    state: &Arc<RwLock<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Vec<u8>>,
+    recording: &Recording,
+    target: f64,
+) -> usize {
+    let scrollback_lines = state.read().await.screen.scrollback_cap;
+    let mut screen = Screen::new(recording.cols, recording.rows, scrollback_lines);
+    let (mut cols, mut rows) = (recording.cols, recording.rows);
+    let next = recording.events.partition_point(|e| e.time <= target);
+    for event in &recording.events[..next] {
+        match event.kind {
+            RecordedEventKind::Output(ref data) => screen.feed(data),
+            RecordedEventKind::Resize(c, r) => {
+                screen.resize(c, r);
+                (cols, rows) = (c, r);
+            }
+        }
+    }
+
+    let mut s = state.write().await;
+    if (s.meta.cols, s.meta.rows) != (cols, rows) {
+        replay_resize(&mut s, broadcast_tx, cols, rows);
+    }
+    // RIS + ED 3: reset the terminal and drop its scrollback
+    let mut data = b"\x1bc\x1b[3J".to_vec();
+    data.extend_from_slice(&screen.snapshot());
+    replay_output(&mut s, broadcast_tx, &data);
+    next
+}
+
+/// Play the recording on its original timing, obeying PLAYBACK_CONTROL
+/// requests. Playback pauses at the end so clients can seek back.
+async fn run_player(
+    state: Arc<RwLock<SharedState>>,
+    broadcast_tx: broadcast::Sender<Vec<u8>>,
+    recording: Recording,
+    mut control_rx: mpsc::Receiver<PlaybackControl>,
+) {
+    let mut next = 0;
+    let mut status = PlaybackStatus {
+        paused: false,
+        position: 0.0,
+        duration: recording.duration(),
+        speed: 1.0,
+    };
+    publish_playback(&state, &broadcast_tx, status).await;
+
+    loop {
+        let next_time = recording.events.get(next).map(|e| e.time);
+        if next_time.is_none() && !status.paused {
+            status.paused = true;
+            status.position = status.duration;
+            publish_playback(&state, &broadcast_tx, status).await;
+        }
+        let wait = match next_time.filter(|_| !status.paused) {
+            Some(t) => match Duration::try_from_secs_f64(((t - status.position) / status.speed).max(0.0)) {
+                Ok(wait) => Some(wait),
+                Err(e) => {
+                    log_error!("playback paused: event time out of range", time = t, error = e);
+                    status.paused = true;
+                    publish_playback(&state, &broadcast_tx, status).await;
+                    None
+                }
+            },
+            None => None,
+        };
+        let waiting_since = Instant::now();
+
+        tokio::select! {
+            _ = time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                let event = &recording.events[next];
+                status.position = event.time;
+                next += 1;
+                let mut s = state.write().await;
+                match event.kind {
+                    RecordedEventKind::Output(ref data) => replay_output(&mut s, &broadcast_tx, data),
+                    RecordedEventKind::Resize(c, r) => replay_resize(&mut s, &broadcast_tx, c, r),
+                }
+            }
+            control = control_rx.recv() => {
+                let Some(control) = control else { return };
+                if let (Some(t), Some(_)) = (next_time, wait) {
+                    let elapsed = waiting_since.elapsed().as_secs_f64() * status.speed;
+                    status.position = (status.position + elapsed).min(t);
+                }
+                match control {
+                    PlaybackControl::Pause => status.paused = true,
+                    PlaybackControl::Play => {
+                        if next_time.is_none() {
+                            // Finished — play again from the start
+                            next = replay_seek(&state, &broadcast_tx, &recording, 0.0).await;
+                            status.position = 0.0;
+                        }
+                        status.paused = false;
+                    }
+                    PlaybackControl::Speed(speed) => status.speed = speed,
+                    PlaybackControl::Seek(target) => {
+                        let target = target.min(status.duration);
+                        next = replay_seek(&state, &broadcast_tx, &recording, target).await;
+                        status.position = target;
+                    }
+                }
+                publish_playback(&state, &broadcast_tx, status).await;
+            }
+        }
+    }
+}
+
+async fn publish_playback(
+    state: &Arc<RwLock<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Vec<u8>>,
+    status: PlaybackStatus,
+) {
+    state.write().await.playback = Some(status);
+    let _ = broadcast_tx.send(status.encode_frame());
+}
+
+/// `--replay <file> <id>`: serve a recording over the regular socket
+/// protocol, so existing clients work as a player. Runs until SIGTERM.
+async fn run_replay(
+    path: &str,
+    id: &str,
+    mut peer_policy: PeerPolicy,
+    ws: WsConfig,
+    metrics_listen: Option<String>,
+    slow_clients: SlowClientPolicy,
+    config: Config,
+) {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let data_dir = PathBuf::from(&home).join(".relay-tty");
+    log_init(&data_dir, id, &config);
+    let recording = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| Recording::parse_asciicast(&content))
+    {
+        Ok(recording) => recording,
+        Err(e) => {
+            log_error!("cannot replay", path = path, error = e);
+            process::exit(1);
+        }
+    };
+    log_info!("replaying recording", path = path, pid = process::id());
+
+    let sockets_dir = data_dir.join("sockets");
+    let sessions_dir = data_dir.join("sessions");
+    fs::create_dir_all(&sockets_dir).ok();
+    fs::create_dir_all(&sessions_dir).ok();
+    let socket_path = sockets_dir.join(format!("{}.sock", id));
+    let session_path = sessions_dir.join(format!("{}.json", id));
+    let token_path = sessions_dir.join(format!("{}.token", id));
+    let _ = fs::remove_file(&socket_path);
+    peer_policy.write_token = issue_token(&token_path);
+    let peer_policy = Arc::new(peer_policy);
+    let metrics = metrics_listen.map(|spec| bind_metrics_listener(&spec, &sockets_dir, id));
+    let metrics_socket = metrics.as_ref().and_then(MetricsListener::socket_path);
+
+    let now = now_millis();
+    let meta = SessionMeta {
+        id: id.to_string(),
+        command: recording.command.clone().unwrap_or_else(|| "replay".to_string()),
+        args: vec![],
+        cwd: env::current_dir()
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or(home),
+        created_at: now,
+        last_activity: now,
+        status: "running".to_string(),
+        exit_code: None,
+        exited_at: None,
+        cols: recording.cols,
+        rows: recording.rows,
+        pid: process::id(),
+        started_at: iso_now(),
+        total_bytes_written: 0.0,
+        last_active_at: iso_now(),
+        bytes_per_second: 0.0,
+        title: recording.title.clone(),
+        error: None,
+        bps1: 0.0,
+        bps5: 0.0,
+        bps15: 0.0,
+        foreground_process: None,
+        scrollback_dir: None,
+        ws_listen: ws.tcp.as_ref().and_then(|l| l.local_addr().ok()).map(|a| a.to_string()),
+        metrics_listen: metrics.as_ref().and_then(MetricsListener::address),
+        watch_marks: Vec::new(),
+        last_command: None,
+    };
+    atomic_write_json(&session_path, &meta);
+
+    let state = Arc::new(RwLock::new(SharedState {
+        output_buffer: OutputBuffer::new(config.buffer_size, config.alt_buffer_cap),
+        screen: Screen::new(recording.cols, recording.rows, config.scrollback_lines),
+        replay_mode: ReplayMode::Screen,
+        scrollback_store: None,
+        recorder: None,
+        playback: None,
+        title: recording.title.clone(),
+        meta,
+        meta_dirty: false,
+        session_active: true,
+        exit_code: None,
+        throughput: ThroughputTracker::new(),
+        last_metrics_nonzero: false,
+        sparkline: SparklineRing::new(config.sparkline_ring_cap),
+        slow_clients,
+        watcher: Watcher::default(),
+        commands: CommandTracker::default(),
+        links: LinkIndex::default(),
+        images: ImageStore::default(),
+        clipboard: Clipboard::default(),
+        config,
+    }));
+    let (broadcast_tx, _) = broadcast::channel::<Vec<u8>>(256);
+
+    let listener = match StdUnixListener::bind(&socket_path) {
+        Ok(l) => {
+            restrict_socket(&socket_path, &peer_policy);
+            l.set_nonblocking(true).ok();
+            UnixListener::from_std(l).unwrap()
+        }
+        Err(e) => {
+            log_error!("failed to bind socket", error = e);
+            process::exit(1);
+        }
+    };
+
+    // SIGTERM: mark the session exited and clean up
+    let state_sigterm = Arc::clone(&state);
+    let session_path_sigterm = session_path.clone();
+    let socket_path_sigterm = socket_path.clone();
+    tokio::spawn(async move {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to set up SIGTERM handler")
+            .recv()
+            .await;
+        log_info!("received SIGTERM, stopping replay");
+        let mut s = state_sigterm.write().await;
+        s.meta.status = "exited".to_string();
+        s.meta.exit_code = Some(0);
+        s.meta.exited_at = Some(now_millis());
+        atomic_write_json(&session_path_sigterm, &s.meta);
+        let _ = fs::remove_file(&socket_path_sigterm);
+        let _ = fs::remove_file(&token_path);
+        if let Some(path) = metrics_socket {
+            let _ = fs::remove_file(path);
+        }
+        process::exit(0);
+    });
+    if let Some(metrics) = metrics {
+        tokio::spawn(serve_metrics(metrics, Arc::clone(&state), None));
+    }
+
+    // Periodic JSON flush
+    let state_json = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(JSON_WRITE_INTERVAL_MS));
+        loop {
+            interval.tick().await;
+            let mut s = state_json.write().await;
+            if s.meta_dirty {
+                atomic_write_json(&session_path, &s.meta);
+                s.meta_dirty = false;
+            }
+        }
+    });
+
+    let (playback_tx, playback_rx) = mpsc::channel::<PlaybackControl>(16);
+    tokio::spawn(run_player(
+        Arc::clone(&state),
+        broadcast_tx.clone(),
+        recording,
+        playback_rx,
+    ));
+
+    // Input, resize, detach and clear have no PTY to act on — their
+    // receivers are dropped and client requests are discarded.
+    let channels = ClientChannels {
+        input_tx: mpsc::channel(1).0,
+        resize_tx: mpsc::channel(1).0,
+        detach_tx: mpsc::channel(1).0,
+        clear_tx: mpsc::channel(1).0,
+        playback_tx: Some(playback_tx),
+    };
+    if let Some(tcp) = ws.tcp {
+        tcp.set_nonblocking(true).ok();
+        tokio::spawn(accept_websockets(
+            tokio::net::TcpListener::from_std(tcp).unwrap(),
+            Arc::clone(&state),
+            broadcast_tx.clone(),
+            channels.clone(),
+            Arc::clone(&peer_policy),
+        ));
+    }
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Some(ceiling) = authorize_peer(&stream, &peer_policy) {
+                    let auth = ClientAuth { ceiling, policy: Arc::clone(&peer_policy) };
+                    accept_unix_client(stream, ws.unix, Arc::clone(&state), &broadcast_tx, channels.clone(), auth);
+                }
+            }
+            Err(e) => accept_backoff("session", e).await,
+        }
+    }
+}
+
+// ── Command line ────────────────────────────────────────────────────
+//
+// Subcommands: spawn, replay, attach, list, kill, export (see USAGE). Everything
+// after `--` (or the first non-option word) in `spawn` is the child's own
+// argv and passes through untouched.
+//
+// The pre-subcommand form `[options] <id> <cols> <rows> <cwd> <command>
+// [args...]`, where a `--login` anywhere in the child's args is taken by
+// pty-host, and `[options] --replay <file> <id>` are still accepted for
+// older callers. `--handoff <file> <id>` is internal (see `exec_upgrade`).
+
+const USAGE: &str = "\
+Usage: relay-pty-host spawn [options] [--id <id>] [--cols <n>] [--rows <n>] [--cwd <dir>]
+                            [--login] [--detach] [--] <command> [args...]
+       relay-pty-host replay [options] [--id <id>] <file>
+       relay-pty-host attach <id>
+       relay-pty-host list [--json]
+       relay-pty-host kill <id>
+       relay-pty-host export [--html] <id>
+Options: --record <file>  --allow-uid <uid,...>  --allow-gid <primary-gid,...>
+         --unlisted-peers reject|read-only  --require-token
+         --ws-listen [host:]port|unix  --metrics-listen [host:]port|unix|<path>
+         --slow-clients resync|disconnect  --max-resyncs <n>
+         --watch [notify:|exit:|mark:]<regex>  --clipboard-read  --set <key>=<value>";
+
+/// Options shared by `spawn` and `replay`.
+#[derive(Default)]
+struct HostOptions {
+    record_path: Option<String>,
+    peer_policy: PeerPolicy,
+    ws_unix: bool,
+    ws_tcp: Option<String>,
+    metrics_listen: Option<String>,
+    slow_clients: SlowClientPolicy,
+    watch_rules: Vec<WatchRule>,
+    clipboard_read: bool,
+    config_overrides: Vec<String>,
+}
+
+impl HostOptions {
+    /// Consume `flag` (and its value from `rest`). Ok(false) if `flag` is
+    /// not a host option.
+    fn parse_flag<'a>(&mut self, flag: &str, rest: &mut impl Iterator<Item = &'a String>) -> Result<bool, String> {
+        let mut value = || rest.next().cloned().ok_or_else(|| format!("{} needs a value", flag));
+        match flag {
+            "--record" => self.record_path = Some(value()?),
+            "--allow-uid" | "--allow-gid" => {
+                let list = value()?;
+                let ids = parse_id_list(&list).ok_or_else(|| format!("invalid {} list: {}", flag, list))?;
+                if flag == "--allow-uid" {
+                    self.peer_policy.allow_uids.extend(ids);
+                } else {
+                    self.peer_policy.allow_gids.extend(ids);
+                }
+            }
+            "--ws-listen" => match value()?.as_str() {
+                "unix" => self.ws_unix = true,
+                addr => self.ws_tcp = Some(addr.to_string()),
+            },
+            "--metrics-listen" => self.metrics_listen = Some(value()?),
+            "--slow-clients" => {
+                self.slow_clients.disconnect = match value()?.as_str() {
+                    "resync" => false,
+                    "disconnect" => true,
+                    other => return Err(format!("--slow-clients must be resync or disconnect, got {}", other)),
+                }
+            }
+            "--max-resyncs" => {
+                let max = value()?;
+                self.slow_clients.max_resyncs = max.parse().map_err(|_| format!("invalid --max-resyncs: {}", max))?;
+            }
+            "--watch" => self.watch_rules.push(WatchRule::parse_flag(&value()?)),
+            "--require-token" => self.peer_policy.require_token = true,
+            "--clipboard-read" => self.clipboard_read = true,
+            "--set" => self.config_overrides.push(value()?),
+            "--unlisted-peers" => {
+                self.peer_policy.read_only_unlisted = match value()?.as_str() {
+                    "reject" => false,
+                    "read-only" => true,
+                    other => return Err(format!("--unlisted-peers must be reject or read-only, got {}", other)),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
 }
 
-// ── Throughput metrics (1/5/15m) ────────────────────────────────────
-
-struct ThroughputSample {
-    instant: Instant,
-    bytes: usize,
+/// What to run for a new session.
+struct SpawnArgs {
+    /// None: pick a random one.
+    id: Option<String>,
+    cols: u16,
+    rows: u16,
+    /// Empty: $HOME.
+    cwd: String,
+    /// Start the command as a login shell (argv[0] = `-<basename>`).
+    login: bool,
+    /// Fork into the background once the socket is up and print the ID.
+    detach: bool,
+    /// The host options as given, passed on by `--detach`.
+    host_argv: Vec<String>,
+    command: String,
+    args: Vec<String>,
 }
 
-struct ThroughputTracker {
-    samples: Vec<ThroughputSample>,
+enum Invocation {
+    Spawn(HostOptions, SpawnArgs),
+    Replay { options: HostOptions, path: String, id: Option<String> },
+    Handoff { path: String, id: String },
+    Attach { id: String },
+    List { json: bool },
+    Kill { id: String },
+    Export { id: String, format: u8 },
+    Help,
 }
 
-impl ThroughputTracker {
-    fn new() -> Self {
-        Self {
-            samples: Vec::new(),
+/// Parse argv (without the program name).
+fn parse_args(args: &[String]) -> Result<Invocation, String> {
+    let mut rest = args.iter();
+    let Some(first) = args.first() else {
+        return Ok(Invocation::Help);
+    };
+    match first.as_str() {
+        "spawn" => {
+            rest.next();
+            parse_spawn(&mut rest)
         }
+        "replay" => {
+            rest.next();
+            let mut options = HostOptions::default();
+            let mut id = None;
+            let mut path = None;
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--id" => id = Some(rest.next().cloned().ok_or("--id needs a value")?),
+                    flag if flag.starts_with("--") => {
+                        if !options.parse_flag(flag, &mut rest)? {
+                            return Err(format!("unknown option {}", flag));
+                        }
+                    }
+                    _ if path.is_none() => path = Some(arg.clone()),
+                    _ => return Err(format!("unexpected argument {}", arg)),
+                }
+            }
+            let path = path.ok_or("replay needs a recording file")?;
+            Ok(Invocation::Replay { options, path, id })
+        }
+        "list" => match &args[1..] {
+            [] => Ok(Invocation::List { json: false }),
+            [flag] if flag == "--json" => Ok(Invocation::List { json: true }),
+            _ => Err("usage: list [--json]".to_string()),
+        },
+        "attach" => match &args[1..] {
+            [id] if !id.starts_with('-') => Ok(Invocation::Attach { id: id.clone() }),
+            _ => Err("usage: attach <id>".to_string()),
+        },
+        "kill" => match &args[1..] {
+            [id] if !id.starts_with('-') => Ok(Invocation::Kill { id: id.clone() }),
+            _ => Err("usage: kill <id>".to_string()),
+        },
+        "export" => match &args[1..] {
+            [flag, id] if flag == "--html" => Ok(Invocation::Export { id: id.clone(), format: EXPORT_FORMAT_HTML }),
+            [id] if !id.starts_with("--") => Ok(Invocation::Export { id: id.clone(), format: EXPORT_FORMAT_TEXT }),
+            _ => Err("usage: export [--html] <id>".to_string()),
+        },
+        "help" | "--help" | "-h" => Ok(Invocation::Help),
+        _ => parse_legacy(&mut rest),
     }
+}
 
-    fn record(&mut self, bytes: usize) {
-        self.samples.push(ThroughputSample {
-            instant: Instant::now(),
-            bytes,
-        });
-        // Prune samples older than 15 minutes
-        let cutoff = Instant::now() - Duration::from_secs(15 * 60);
-        self.samples.retain(|s| s.instant >= cutoff);
-    }
-
-    /// Compute average bytes/sec over the given window duration.
-    fn compute_bps(&self, window: Duration) -> f64 {
-        let now = Instant::now();
-        let cutoff = now - window;
-        let total_bytes: usize = self
-            .samples
-            .iter()
-            .filter(|s| s.instant >= cutoff)
-            .map(|s| s.bytes)
-            .sum();
-        let window_secs = window.as_secs_f64();
-        if window_secs <= 0.0 {
-            return 0.0;
+/// `spawn` options up to the command, which ends option parsing.
+fn parse_spawn<'a>(rest: &mut std::slice::Iter<'a, String>) -> Result<Invocation, String> {
+    let mut options = HostOptions::default();
+    let mut spawn = SpawnArgs {
+        id: None,
+        cols: 80,
+        rows: 24,
+        cwd: String::new(),
+        login: false,
+        detach: false,
+        host_argv: Vec::new(),
+        command: String::new(),
+        args: Vec::new(),
+    };
+    while let Some(arg) = rest.next() {
+        let mut value = |name: &str| rest.next().cloned().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--" => break,
+            "--id" => spawn.id = Some(value("--id")?),
+            "--cols" => spawn.cols = parse_dimension("--cols", &value("--cols")?)?,
+            "--rows" => spawn.rows = parse_dimension("--rows", &value("--rows")?)?,
+            "--cwd" => spawn.cwd = value("--cwd")?,
+            "--login" => spawn.login = true,
+            "--detach" => spawn.detach = true,
+            flag if flag.starts_with('-') => {
+                let before = rest.as_slice();
+                if !options.parse_flag(flag, rest)? {
+                    return Err(format!("unknown option {}", flag));
+                }
+                spawn.host_argv.push(flag.to_string());
+                spawn.host_argv.extend_from_slice(&before[..before.len() - rest.len()]);
+            }
+            command => {
+                spawn.command = command.to_string();
+                break;
+            }
         }
-        total_bytes as f64 / window_secs
     }
-
-    fn bps1(&self) -> f64 {
-        self.compute_bps(Duration::from_secs(60))
+    if spawn.command.is_empty() {
+        spawn.command = rest.next().cloned().ok_or("spawn needs a command")?;
     }
+    spawn.args = rest.cloned().collect();
+    Ok(Invocation::Spawn(options, spawn))
+}
 
-    fn bps5(&self) -> f64 {
-        self.compute_bps(Duration::from_secs(300))
+/// `[options] <id> <cols> <rows> <cwd> <command> [args...]`, or
+/// `[options] --replay <file> <id>`, or `--handoff <file> <id>`.
+fn parse_legacy<'a>(rest: &mut std::slice::Iter<'a, String>) -> Result<Invocation, String> {
+    let mut options = HostOptions::default();
+    let mut replay_path = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--replay" => replay_path = Some(rest.next().cloned().ok_or("--replay needs a value")?),
+            "--handoff" => {
+                let path = rest.next().cloned().ok_or("--handoff needs a value")?;
+                let id = rest.next().cloned().ok_or("--handoff needs a session ID")?;
+                return Ok(Invocation::Handoff { path, id });
+            }
+            flag if flag.starts_with("--") => {
+                if !options.parse_flag(flag, rest)? {
+                    return Err(format!("unknown option {}", flag));
+                }
+            }
+            _ => {
+                positional.push(arg.clone());
+                positional.extend(rest.cloned());
+                break;
+            }
+        }
     }
-
-    fn bps15(&self) -> f64 {
-        self.compute_bps(Duration::from_secs(900))
+    if let Some(path) = replay_path {
+        let [id] = positional.as_slice() else {
+            return Err("--replay needs a session ID".to_string());
+        };
+        return Ok(Invocation::Replay { options, path, id: Some(id.clone()) });
     }
+    let [id, cols, rows, cwd, command, args @ ..] = positional.as_slice() else {
+        return Err("missing arguments".to_string());
+    };
+    let login = args.iter().any(|a| a == "--login");
+    Ok(Invocation::Spawn(
+        options,
+        SpawnArgs {
+            id: Some(id.clone()),
+            cols: parse_dimension("cols", cols)?,
+            rows: parse_dimension("rows", rows)?,
+            cwd: cwd.clone(),
+            login,
+            detach: false,
+            host_argv: Vec::new(),
+            command: command.clone(),
+            args: args.iter().filter(|a| *a != "--login").cloned().collect(),
+        },
+    ))
 }
 
-// ── Sparkline ring buffer (1-second bps1 history) ────────────────────
+fn parse_dimension(name: &str, value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid {}: {}", name, value)),
+    }
+}
 
-const SPARKLINE_RING_CAP: usize = 3600; // 1 hour at 1s intervals
+/// Resolve the config for a new session, exiting on errors. A handed-over
+/// session keeps the config it was started with instead.
+fn load_config(overrides: &[String]) -> Config {
+    let data_dir = PathBuf::from(env::var("HOME").unwrap_or_else(|_| "/".to_string())).join(".relay-tty");
+    Config::load(&data_dir, overrides).unwrap_or_else(|e| {
+        eprintln!("pty-host: invalid config: {}", e);
+        process::exit(1);
+    })
+}
 
-struct SparklineRing {
-    buf: Box<[f64; SPARKLINE_RING_CAP]>,
-    head: usize,
-    len: usize,
+/// A new session ID: 8 hex digits, like the Node server's.
+fn random_session_id() -> io::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 4];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
 }
 
-impl SparklineRing {
-    fn new() -> Self {
-        Self {
-            buf: Box::new([0.0; SPARKLINE_RING_CAP]),
-            head: 0,
-            len: 0,
-        }
+/// `spawn --detach`: run the same spawn in a new session with stdio on
+/// /dev/null, wait for its socket, and print the session ID.
+fn run_detached(spawn: &SpawnArgs, exe: &Path) -> i32 {
+    use std::os::unix::process::CommandExt;
+
+    let id = match &spawn.id {
+        Some(id) => id.clone(),
+        None => match random_session_id() {
+            Ok(id) => id,
+            Err(e) => {
+                eprintln!("pty-host: failed to pick a session ID: {}", e);
+                return 1;
+            }
+        },
+    };
+    let mut cmd = process::Command::new(exe);
+    cmd.arg("spawn")
+        .args(&spawn.host_argv)
+        .args(["--id", &id, "--cols", &spawn.cols.to_string(), "--rows", &spawn.rows.to_string()])
+        .args(["--cwd", &spawn.cwd]);
+    if spawn.login {
+        cmd.arg("--login");
+    }
+    cmd.arg("--").arg(&spawn.command).args(&spawn.args);
+    cmd.stdin(process::Stdio::null()).stdout(process::Stdio::null()).stderr(process::Stdio::null());
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
     }
-
-    fn push(&mut self, value: f64) {
-        self.buf[self.head] = value;
-        self.head = (self.head + 1) % SPARKLINE_RING_CAP;
-        if self.len < SPARKLINE_RING_CAP {
-            self.len += 1;
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("pty-host: failed to start {}: {}", exe.display(), e);
+            return 1;
         }
-    }
-
-    fn len(&self) -> usize {
-        self.len
-    }
+    };
 
-    fn to_vec(&self) -> Vec<f64> {
-        if self.len == 0 {
-            return Vec::new();
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let socket_path = PathBuf::from(home).join(".relay-tty/sockets").join(format!("{}.sock", id));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if std::os::unix::net::UnixStream::connect(&socket_path).is_ok() {
+            println!("{}", id);
+            return 0;
         }
-        if self.len < SPARKLINE_RING_CAP {
-            self.buf[..self.len].to_vec()
-        } else {
-            let mut result = Vec::with_capacity(SPARKLINE_RING_CAP);
-            result.extend_from_slice(&self.buf[self.head..]);
-            result.extend_from_slice(&self.buf[..self.head]);
-            result
+        if let Ok(Some(status)) = child.try_wait() {
+            eprintln!("pty-host: session {} exited during startup ({})", id, status);
+            return 1;
         }
+        std::thread::sleep(Duration::from_millis(20));
     }
+    eprintln!("pty-host: timed out waiting for session {}", id);
+    1
+}
 
-    fn encode(&self) -> Vec<u8> {
-        let values = self.to_vec();
-        let mut out = Vec::with_capacity(2 + values.len() * 8);
-        out.extend_from_slice(&(values.len() as u16).to_be_bytes());
-        for v in &values {
-            out.extend_from_slice(&v.to_be_bytes());
-        }
-        out
-    }
+fn pid_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
 }
 
-// ── PTY helpers (using nix/libc) ────────────────────────────────────
+/// Read every session JSON in ~/.relay-tty/sessions, sorted by creation
+/// time. Unreadable files are skipped.
+fn load_sessions(sessions_dir: &Path) -> Vec<SessionMeta> {
+    let mut sessions: Vec<SessionMeta> = fs::read_dir(sessions_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| serde_json::from_slice(&fs::read(entry.path()).ok()?).ok())
+        .collect();
+    sessions.sort_by_key(|s| s.created_at);
+    sessions
+}
 
-/// Spawn a child in a new PTY. Returns (master_fd, child_pid).
-///
-/// When `login` is true, argv[0] is set to `-<basename>` (e.g. `-zsh`),
-/// which is the standard Unix convention used by iTerm2, tmux, and
-/// Terminal.app to tell a shell to behave as a login shell. This causes
-/// the shell to source login profiles (/etc/zprofile, ~/.zprofile, etc.)
-/// without needing a wrapper shell like `zsh -l -c "exec zsh"`.
-fn spawn_pty(
-    command: &str,
-    args: &[String],
-    cols: u16,
-    rows: u16,
-    cwd: &str,
-    login: bool,
-) -> io::Result<(OwnedFd, libc::pid_t)> {
-    let mut winsize = libc::winsize {
-        ws_row: rows,
-        ws_col: cols,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
-    };
+/// `list`: one line per session, or the session JSON with `--json`.
+/// Sessions marked running whose pty-host is gone are shown as dead.
+fn run_list(json: bool) -> i32 {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let sessions = load_sessions(&PathBuf::from(home).join(".relay-tty/sessions"));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&sessions).unwrap_or_default());
+        return 0;
+    }
+    if sessions.is_empty() {
+        eprintln!("No sessions");
+        return 0;
+    }
+    for s in &sessions {
+        let status = match (s.status.as_str(), s.exit_code) {
+            ("running", _) if !pid_alive(s.pid) => "dead".to_string(),
+            ("running", _) => "running".to_string(),
+            (_, Some(code)) => format!("exited({})", code),
+            (status, None) => status.to_string(),
+        };
+        let command = std::iter::once(&s.command).chain(&s.args).cloned().collect::<Vec<_>>().join(" ");
+        println!(
+            "{:<10} {:<10} {:>7} {:>4}x{:<4} {}{}",
+            s.id,
+            status,
+            s.pid,
+            s.cols,
+            s.rows,
+            command,
+            s.title.as_ref().map(|t| format!("  ({})", t)).unwrap_or_default()
+        );
+    }
+    0
+}
 
-    // Use forkpty
-    let mut master_fd: libc::c_int = -1;
-    let pid = unsafe {
-        libc::forkpty(
-            &mut master_fd as *mut libc::c_int,
-            std::ptr::null_mut(),
-            std::ptr::null_mut(),
-            &mut winsize as *mut libc::winsize,
-        )
+/// `kill`: SIGTERM the session's pty-host, which ends the child and marks
+/// the session exited.
+fn run_kill(id: &str) -> i32 {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let session_path = PathBuf::from(home).join(".relay-tty/sessions").join(format!("{}.json", id));
+    let meta: SessionMeta = match fs::read(&session_path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+    {
+        Ok(meta) => meta,
+        Err(e) => {
+            eprintln!("pty-host: no session {}: {}", id, e);
+            return 1;
+        }
     };
-
-    if pid < 0 {
-        return Err(io::Error::last_os_error());
+    if meta.status != "running" || !pid_alive(meta.pid) {
+        eprintln!("pty-host: session {} is not running", id);
+        return 1;
     }
+    if unsafe { libc::kill(meta.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        eprintln!("pty-host: failed to signal session {}: {}", id, io::Error::last_os_error());
+        return 1;
+    }
+    0
+}
 
-    if pid == 0 {
-        // Child process
-        // Set TERM and TERM_PROGRAM so child processes (e.g. NeoVim) detect
-        // the correct terminal capabilities.  We use xterm.js in the browser,
-        // which doesn't support colon-separated SGR RGB (e.g. `38:2:R:G:B`).
-        // Leaving TERM_PROGRAM inherited from the parent (e.g. "iTerm.app")
-        // would cause NeoVim to emit colon-format colors that xterm.js v5
-        // misparses, zeroing the blue channel.
-        env::set_var("TERM", "xterm-256color");
-        env::set_var("TERM_PROGRAM", "relay-tty");
-        // Change directory
-        if let Err(_) = env::set_current_dir(cwd) {
-            eprintln!("pty-host: failed to chdir to {}", cwd);
-        }
+// ── Attach client ───────────────────────────────────────────────────
+//
+// `relay-pty-host attach <id>` is a terminal client for machines without
+// Node, like cli/attach.ts: raw mode, HELLO + RESUME handshake, Ctrl+]
+// to detach. It tracks its byte offset, so a reconnect after a dropped
+// connection (or a live upgrade) resumes with a delta replay.
 
-        // Build argv for exec
-        let c_command = std::ffi::CString::new(command).unwrap_or_else(|_| {
-            eprintln!("pty-host: invalid command name");
-            process::exit(127);
-        });
+/// Ctrl+] detaches.
+const ATTACH_DETACH_KEY: u8 = 0x1d;
 
-        // argv[0]: when login is true, use "-<basename>" convention (like iTerm2/tmux).
-        // This tells the shell to behave as a login shell without needing a wrapper.
-        let argv0 = if login {
-            let basename = std::path::Path::new(command)
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or(command);
-            std::ffi::CString::new(format!("-{}", basename)).unwrap_or_else(|_| c_command.clone())
-        } else {
-            c_command.clone()
-        };
+/// Longest wait between reconnect attempts.
+const ATTACH_RETRY_MAX: Duration = Duration::from_secs(5);
 
-        let c_args: Vec<std::ffi::CString> = std::iter::once(argv0)
-            .chain(args.iter().map(|a| {
-                std::ffi::CString::new(a.as_str()).unwrap_or_else(|_| {
-                    eprintln!("pty-host: invalid argument");
-                    process::exit(127);
-                })
-            }))
-            .collect();
+/// Puts stdin in raw mode until dropped. None when stdin is not a tty.
+struct RawTerminal {
+    saved: libc::termios,
+}
 
-        // execvp
-        let c_argv: Vec<*const libc::c_char> = c_args
-            .iter()
-            .map(|a| a.as_ptr())
-            .chain(std::iter::once(std::ptr::null()))
-            .collect();
+impl RawTerminal {
+    fn enter() -> Option<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return None;
+            }
+            let mut raw = saved;
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+            Some(Self { saved })
+        }
+    }
+}
 
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
         unsafe {
-            libc::execvp(c_command.as_ptr(), c_argv.as_ptr());
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
         }
-        // If execvp returns, it failed
-        let err = io::Error::last_os_error();
-        eprintln!("pty-host: exec failed: {}", err);
-        process::exit(127);
     }
+}
 
-    // Parent process
-    // SAFETY: master_fd is valid from forkpty
-    let owned = unsafe { OwnedFd::from_raw_fd(master_fd) };
-    Ok((owned, pid))
+/// RESIZE for the local terminal size, if stdout is a terminal.
+fn attach_resize_message() -> Option<Vec<u8>> {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } != 0 || ws.ws_col == 0 {
+        return None;
+    }
+    let mut msg = vec![WS_MSG_RESIZE];
+    msg.extend_from_slice(&ws.ws_col.to_be_bytes());
+    msg.extend_from_slice(&ws.ws_row.to_be_bytes());
+    Some(msg)
 }
 
-/// Resize a PTY.
-///
-/// Sets the winsize via `TIOCSWINSZ` on the master fd, then explicitly sends
-/// `SIGWINCH` to the foreground process group on the slave side. The kernel
-/// *should* deliver SIGWINCH automatically from the ioctl, but some programs
-/// (notably NeoVim via libuv) don't always receive it reliably. Explicitly
-/// signaling the foreground pgrp — the same belt-and-suspenders approach used
-/// by tmux — ensures all well-behaved terminal applications redraw correctly.
-fn resize_pty(master_fd: RawFd, cols: u16, rows: u16) {
-    let ws = libc::winsize {
-        ws_row: rows,
-        ws_col: cols,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
-    };
-    unsafe {
-        let ret = libc::ioctl(master_fd, libc::TIOCSWINSZ, &ws);
-        if ret < 0 {
-            eprintln!(
-                "pty-host: TIOCSWINSZ failed: {}",
-                io::Error::last_os_error()
-            );
-            return;
+/// Remove and return the first complete frame's payload from `pending`.
+fn take_frame(pending: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u32::from_be_bytes(pending.get(..4)?.try_into().unwrap()) as usize;
+    if pending.len() < 4 + len {
+        return None;
+    }
+    let payload = pending[4..4 + len].to_vec();
+    pending.drain(..4 + len);
+    Some(payload)
+}
+
+enum AttachEnd {
+    Detached,
+    Exited(i32),
+    /// The session ended while we weren't connected; its exit code, if
+    /// the session JSON recorded one.
+    Ended(Option<i32>),
+    Lost,
+}
+
+/// Apply one server message: output goes to `out`, SYNC and DATA move
+/// `offset`. Some(end) when the session exited.
+fn handle_attach_frame(payload: &[u8], offset: &mut f64, out: &mut impl Write) -> Option<AttachEnd> {
+    let (&msg_type, data) = payload.split_first()?;
+    match msg_type {
+        WS_MSG_DATA => {
+            let _ = out.write_all(data);
+            *offset += data.len() as f64;
+        }
+        WS_MSG_BUFFER_REPLAY => {
+            let _ = out.write_all(data);
+        }
+        WS_MSG_BUFFER_REPLAY_GZ => {
+            let mut decoded = Vec::new();
+            if io::Read::read_to_end(&mut flate2::read::GzDecoder::new(data), &mut decoded).is_ok() {
+                let _ = out.write_all(&decoded);
+            }
         }
-
-        // Explicitly send SIGWINCH to the foreground process group.
-        // TIOCSWINSZ should trigger this automatically via the kernel, but
-        // some programs (NeoVim/libuv) don't always catch the implicit signal.
-        // tcgetpgrp on the master fd returns the slave's foreground pgrp.
-        let fg_pgrp = libc::tcgetpgrp(master_fd);
-        if fg_pgrp > 0 {
-            libc::kill(-fg_pgrp, libc::SIGWINCH);
+        WS_MSG_SYNC if data.len() >= 8 => {
+            let synced = f64::from_be_bytes(data[..8].try_into().unwrap());
+            // SYNC 0 ahead of a full replay: our offset fell out of the
+            // buffer, so reset the screen before it is redrawn.
+            if synced == 0.0 && *offset > 0.0 {
+                let _ = out.write_all(b"\x1bc");
+            }
+            *offset = synced;
         }
+        WS_MSG_EXIT if data.len() >= 4 => {
+            return Some(AttachEnd::Exited(i32::from_be_bytes(data[..4].try_into().unwrap())));
+        }
+        _ => {}
     }
+    None
 }
 
-// ── Frame helpers ───────────────────────────────────────────────────
-
-fn encode_frame(payload: &[u8]) -> Vec<u8> {
-    let len = payload.len() as u32;
-    let mut frame = Vec::with_capacity(4 + payload.len());
-    frame.extend_from_slice(&len.to_be_bytes());
-    frame.extend_from_slice(payload);
-    frame
+/// One connection's worth of attach: handshake, then relay until the
+/// connection drops, the session exits or the user detaches.
+async fn attach_connection(
+    stream: tokio::net::UnixStream,
+    input: &mut mpsc::Receiver<Vec<u8>>,
+    input_open: &mut bool,
+    winch: &mut tokio::signal::unix::Signal,
+    offset: &mut f64,
+) -> AttachEnd {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut hello = vec![WS_MSG_HELLO];
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello.extend_from_slice(&CAP_BUFFER_REPLAY_GZ.to_be_bytes());
+    let mut resume = vec![WS_MSG_RESUME];
+    resume.extend_from_slice(&offset.to_be_bytes());
+    let mut handshake = [encode_frame(&hello), encode_frame(&resume)].concat();
+    if let Some(resize) = attach_resize_message() {
+        handshake.extend_from_slice(&encode_frame(&resize));
+    }
+    if writer.write_all(&handshake).await.is_err() {
+        return AttachEnd::Lost;
+    }
+
+    let mut stdout = io::stdout();
+    let mut pending = Vec::new();
+    let mut buf = vec![0u8; 65536];
+    // Input waits for the handshake's SYNC, so the server never sees it
+    // in the same read as RESUME.
+    let mut synced = false;
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let n = match read {
+                    Ok(0) | Err(_) => return AttachEnd::Lost,
+                    Ok(n) => n,
+                };
+                pending.extend_from_slice(&buf[..n]);
+                while let Some(payload) = take_frame(&mut pending) {
+                    synced |= payload.first() == Some(&WS_MSG_SYNC);
+                    if let Some(end) = handle_attach_frame(&payload, offset, &mut stdout) {
+                        let _ = stdout.flush();
+                        return end;
+                    }
+                }
+                let _ = stdout.flush();
+            }
+            data = input.recv(), if *input_open && synced => {
+                let Some(data) = data else {
+                    // Piped input ran out; keep showing output
+                    *input_open = false;
+                    continue;
+                };
+                let detach = data.iter().position(|&b| b == ATTACH_DETACH_KEY);
+                let typed = &data[..detach.unwrap_or(data.len())];
+                let mut frames = Vec::new();
+                if !typed.is_empty() {
+                    frames.extend_from_slice(&encode_frame(&[&[WS_MSG_DATA], typed].concat()));
+                }
+                if detach.is_some() {
+                    frames.extend_from_slice(&encode_frame(&[WS_MSG_DETACH]));
+                }
+                let sent = writer.write_all(&frames).await;
+                if detach.is_some() {
+                    return AttachEnd::Detached;
+                }
+                if sent.is_err() {
+                    return AttachEnd::Lost;
+                }
+            }
+            _ = winch.recv() => {
+                if let Some(resize) = attach_resize_message() {
+                    let _ = writer.write_all(&encode_frame(&resize)).await;
+                }
+            }
+        }
+    }
 }
 
-// ── Shared state ────────────────────────────────────────────────────
+/// `attach`: relay the local terminal to a session until the user
+/// detaches or the session ends. Returns the exit code to use: the
+/// session's own if it exited, 1 if that is unknown.
+async fn run_attach(id: &str, config: &Config) -> i32 {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    let data_dir = PathBuf::from(home).join(".relay-tty");
+    let socket_path = data_dir.join("sockets").join(format!("{}.sock", id));
+    let session_path = data_dir.join("sessions").join(format!("{}.json", id));
+    // No pty-host (crash or reboot): show what the session persisted
+    if let Err(e) = tokio::net::UnixStream::connect(&socket_path).await {
+        match read_dead_scrollback(&data_dir, id, config.buffer_size as u64) {
+            Ok(Some(data)) => {
+                let mut stdout = io::stdout().lock();
+                // Leave the alt screen and reset attributes the output may have left on
+                let _ = stdout.write_all(&data).and_then(|_| stdout.write_all(b"\x1b[?1049l\x1b[0m\r\n"));
+                eprintln!("Session {} is no longer running; showing its saved output.", id);
+                return 0;
+            }
+            Ok(None) if !socket_path.exists() => {
+                eprintln!("Session {} not found", id);
+                return 1;
+            }
+            // A socket nobody listens on is left behind by a pty-host that died
+            Ok(None) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                eprintln!("Session {} is no longer running and saved no output", id);
+                return 1;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("pty-host: failed to read saved output of {}: {}", id, e);
+                return 1;
+            }
+        }
+    }
+    let Ok(mut winch) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change()) else {
+        eprintln!("pty-host: failed to watch for window size changes");
+        return 1;
+    };
+    let session_meta = || {
+        fs::read(&session_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<SessionMeta>(&data).ok())
+    };
+    // Still running: the socket is there and the session JSON says so
+    let session_running = || socket_path.exists() && session_meta().is_none_or(|meta| meta.status != "exited");
+
+    eprintln!("Attaching to {}. Ctrl+] to detach.", id);
+    let raw = RawTerminal::enter();
+    // A blocking reader thread, so a dropped connection never loses input
+    let (input_tx, mut input) = mpsc::channel::<Vec<u8>>(64);
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buf = [0u8; 4096];
+        while let Ok(n @ 1..) = io::Read::read(&mut stdin, &mut buf) {
+            if input_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
 
-type ClientWriter = Arc<Mutex<OwnedWriteHalf>>;
+    let mut input_open = true;
+    let mut offset = 0.0;
+    let mut retry = Duration::from_millis(500);
+    let mut reconnecting = false;
+    let end = loop {
+        // Refused means nobody listens on the socket: the pty-host is gone
+        let mut listening = true;
+        match tokio::net::UnixStream::connect(&socket_path).await {
+            Ok(stream) => {
+                retry = Duration::from_millis(500);
+                match attach_connection(stream, &mut input, &mut input_open, &mut winch, &mut offset).await {
+                    AttachEnd::Lost => {}
+                    end => break end,
+                }
+            }
+            Err(e) => listening = e.kind() != io::ErrorKind::ConnectionRefused,
+        }
+        if !listening || !session_running() {
+            eprint!("\r\nSession ended.\r\n");
+            break AttachEnd::Ended(session_meta().and_then(|meta| meta.exit_code));
+        }
+        if !reconnecting {
+            reconnecting = true;
+            eprint!("\r\nConnection lost. Reconnecting...\r\n");
+        }
+        time::sleep(retry).await;
+        retry = (retry * 3 / 2).min(ATTACH_RETRY_MAX);
+    };
+    drop(raw);
 
-struct SharedState {
-    output_buffer: OutputBuffer,
-    meta: SessionMeta,
-    meta_dirty: bool,
-    session_active: bool,
-    exit_code: Option<i32>,
-    throughput: ThroughputTracker,
-    title: Option<String>,
-    /// Tracks whether metrics are currently broadcasting (non-zero activity).
-    /// Stops broadcasting when all three bps values hit 0.
-    last_metrics_nonzero: bool,
-    sparkline: SparklineRing,
+    match end {
+        AttachEnd::Exited(code) | AttachEnd::Ended(Some(code)) => {
+            eprintln!("Process exited with code {}", code);
+            code
+        }
+        AttachEnd::Ended(None) => {
+            eprintln!("Process exited with code unknown");
+            1
+        }
+        _ => {
+            eprintln!("\nDetached from {}. Reattach: relay-pty-host attach {}", id, id);
+            0
+        }
+    }
 }
 
 // ── Main ────────────────────────────────────────────────────────────
@@ -1509,24 +6906,80 @@ struct SharedState {
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    // relay-pty-host <id> <cols> <rows> <cwd> <command> [args...]
-    if args.len() < 6 {
-        eprintln!("Usage: relay-pty-host <id> <cols> <rows> <cwd> <command> [args...]");
+    // Resolved now: once an upgrade replaces the file, /proc/self/exe
+    // points at the deleted old binary.
+    let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from(&args[0]));
+    let (options, spawn) = match parse_args(&args[1..]) {
+        Ok(Invocation::Spawn(options, spawn)) => (options, spawn),
+        Ok(Invocation::Replay { options, path, id }) => {
+            let id = id.unwrap_or_else(|| random_session_id().expect("failed to pick a session ID"));
+            let config = load_config(&options.config_overrides);
+            let ws = WsConfig {
+                unix: options.ws_unix,
+                tcp: options.ws_tcp.as_deref().map(bind_ws_listener),
+            };
+            run_replay(
+                &path,
+                &id,
+                options.peer_policy,
+                ws,
+                options.metrics_listen,
+                options.slow_clients,
+                config,
+            )
+            .await;
+            return;
+        }
+        Ok(Invocation::Handoff { path, id }) => match adopt_handoff(Path::new(&path), &id) {
+            Ok(session) => serve_session(session, exe).await,
+            Err(e) => {
+                eprintln!("pty-host: failed to take over session {}: {}", id, e);
+                process::exit(1);
+            }
+        },
+        Ok(Invocation::Attach { id }) => process::exit(run_attach(&id, &load_config(&[])).await),
+        Ok(Invocation::List { json }) => process::exit(run_list(json)),
+        Ok(Invocation::Kill { id }) => process::exit(run_kill(&id)),
+        Ok(Invocation::Export { id, format }) => process::exit(run_export(&id, format)),
+        Ok(Invocation::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("pty-host: {}", e);
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+    if spawn.detach {
+        process::exit(run_detached(&spawn, &exe));
+    }
+    let mut watcher = Watcher::default();
+    if let Err(e) = watcher.set_rules(options.watch_rules) {
+        eprintln!("pty-host: invalid --watch: {}", e);
         process::exit(1);
     }
+    let config = load_config(&options.config_overrides);
+    let record_path = options.record_path.or_else(|| env::var("RELAY_RECORD").ok().filter(|p| !p.is_empty()));
+    let metrics_listen = options
+        .metrics_listen
+        .or_else(|| env::var("RELAY_METRICS_LISTEN").ok().filter(|p| !p.is_empty()));
+    let mut peer_policy = options.peer_policy;
+    let slow_clients = options.slow_clients;
+    let clipboard_read = options.clipboard_read;
+    let ws = WsConfig {
+        unix: options.ws_unix,
+        tcp: options.ws_tcp.as_deref().map(bind_ws_listener),
+    };
 
-    let id = &args[1];
-    let cols: u16 = args[2].parse().unwrap_or(80);
-    let rows: u16 = args[3].parse().unwrap_or(24);
-    let cwd_arg = &args[4];
-    let command = &args[5];
-    let raw_args: Vec<String> = args[6..].to_vec();
-
-    // Check for --login flag: signals that spawn_pty should use the "-<basename>"
-    // argv[0] convention (like iTerm2/tmux) to make the shell a login shell.
-    // Strip --login from the args passed to the child process.
-    let login = raw_args.iter().any(|a| a == "--login");
-    let cmd_args: Vec<String> = raw_args.iter().filter(|a| *a != "--login").cloned().collect();
+    let id = &spawn.id.unwrap_or_else(|| random_session_id().expect("failed to pick a session ID"));
+    let (cols, rows) = (spawn.cols, spawn.rows);
+    let cwd_arg = &spawn.cwd;
+    let command = &spawn.command;
+    // --login: spawn_pty uses the "-<basename>" argv[0] convention (like
+    // iTerm2/tmux) to make the shell a login shell.
+    let login = spawn.login;
+    let cmd_args = spawn.args;
 
     // Display command from env vars (set by pty-manager for login-shell wrapping)
     let display_command = env::var("RELAY_ORIG_COMMAND").unwrap_or_else(|_| command.clone());
@@ -1534,9 +6987,15 @@ async fn main() {
         .ok()
         .and_then(|v| serde_json::from_str(&v).ok())
         .unwrap_or_else(|| cmd_args.clone());
+    let replay_mode = ReplayMode::from_env();
+    let persist_scrollback = env::var("RELAY_PERSIST_SCROLLBACK").is_ok_and(|v| v == "1");
     // Clean up env vars so they don't leak into the child process
     env::remove_var("RELAY_ORIG_COMMAND");
     env::remove_var("RELAY_ORIG_ARGS");
+    env::remove_var("RELAY_REPLAY_MODE");
+    env::remove_var("RELAY_PERSIST_SCROLLBACK");
+    env::remove_var("RELAY_RECORD");
+    env::remove_var("RELAY_METRICS_LISTEN");
 
     // Ensure RELAY_SESSION_ID is set so the child shell can identify its
     // session (e.g. `relay info`). For CLI spawns the parent already sets
@@ -1549,6 +7008,8 @@ async fn main() {
     let sessions_dir = data_dir.join("sessions");
     fs::create_dir_all(&sockets_dir).ok();
     fs::create_dir_all(&sessions_dir).ok();
+    log_init(&data_dir, id, &config);
+    let metrics = metrics_listen.map(|spec| bind_metrics_listener(&spec, &sockets_dir, id));
 
     let socket_path = sockets_dir.join(format!("{}.sock", id));
     let session_path = sessions_dir.join(format!("{}.json", id));
@@ -1566,10 +7027,7 @@ async fn main() {
     let (master_fd, child_pid) = match spawn_pty(command, &cmd_args, cols, rows, &cwd, login) {
         Ok(v) => v,
         Err(err) => {
-            eprintln!(
-                "pty-host: failed to spawn \"{}\": {}",
-                display_command, err
-            );
+            log_error!("failed to spawn", command = display_command, error = err);
             let now = now_millis();
             let error_meta = SessionMeta {
                 id: id.clone(),
@@ -1594,20 +7052,67 @@ async fn main() {
                 bps5: 0.0,
                 bps15: 0.0,
                 foreground_process: None,
+                scrollback_dir: None,
+                ws_listen: None,
+                metrics_listen: None,
+                watch_marks: Vec::new(),
+                last_command: None,
             };
             let _ = fs::write(&session_path, serde_json::to_string(&error_meta).unwrap());
             process::exit(127);
         }
     };
-
-    let master_raw_fd = master_fd.as_raw_fd();
-
-    // Ignore SIGHUP -- we're detached
-    unsafe {
-        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+    log_info!(
+        "session started",
+        pid = process::id(),
+        child = child_pid,
+        command = display_command,
+        cols = cols,
+        rows = rows,
+    );
+
+    // Open persisted scrollback. Output left by a previous pty-host for this
+    // session (crash, reboot) is restored so clients can still read it and
+    // RESUME from offsets they saw before the restart.
+    let scrollback_dir = sessions_dir.join(format!("{}.scrollback", id));
+    let scrollback_store = if persist_scrollback {
+        match ScrollbackStore::open(scrollback_dir.clone(), PERSIST_SEGMENT_SIZE, PERSIST_MAX_BYTES) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                log_warn!("failed to open scrollback dir", error = e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut output_buffer = OutputBuffer::new(config.buffer_size, config.alt_buffer_cap);
+    let mut screen = Screen::new(cols, rows, config.scrollback_lines);
+    if let Some(ref store) = scrollback_store {
+        match store.read_tail(config.buffer_size as u64) {
+            Ok((start, data)) => {
+                output_buffer.restore(start as f64, &data);
+                if replay_mode == ReplayMode::Screen {
+                    screen.feed(&data);
+                }
+            }
+            Err(e) => log_warn!("failed to restore scrollback", error = e),
+        }
     }
+    let restored_bytes = output_buffer.total_written;
+
+    let recorder = record_path.and_then(|path| {
+        let command_line = std::iter::once(&display_command)
+            .chain(&display_args)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        AsciicastRecorder::create(Path::new(&path), cols, rows, &command_line)
+            .map_err(|e| log_warn!("failed to create recording", path = path, error = e))
+            .ok()
+    });
 
-    // Write initial session metadata
+    // Initial session metadata (written once the socket is bound)
     let now = now_millis();
     let meta = SessionMeta {
         id: id.clone(),
@@ -1623,7 +7128,7 @@ async fn main() {
         rows,
         pid: process::id(),
         started_at: iso_now(),
-        total_bytes_written: 0.0,
+        total_bytes_written: restored_bytes,
         last_active_at: iso_now(),
         bytes_per_second: 0.0,
         title: None,
@@ -1632,12 +7137,30 @@ async fn main() {
         bps5: 0.0,
         bps15: 0.0,
         foreground_process: None,
+        scrollback_dir: scrollback_store
+            .as_ref()
+            .map(|_| scrollback_dir.to_string_lossy().into_owned()),
+        ws_listen: ws.tcp.as_ref().and_then(|l| l.local_addr().ok()).map(|a| a.to_string()),
+        metrics_listen: metrics.as_ref().and_then(MetricsListener::address),
+        watch_marks: Vec::new(),
+        last_command: None,
     };
-    let _ = fs::write(&session_path, serde_json::to_string(&meta).unwrap());
-
-    // Shared state
-    let state = Arc::new(RwLock::new(SharedState {
-        output_buffer: OutputBuffer::new(BUFFER_SIZE),
+    // Create Unix socket listener
+    let listener = StdUnixListener::bind(&socket_path)
+        .unwrap_or_else(|e| {
+            log_error!("failed to bind socket", error = e);
+            process::exit(1);
+        });
+    restrict_socket(&socket_path, &peer_policy);
+    peer_policy.write_token = issue_token(&sessions_dir.join(format!("{}.token", id)));
+
+    let state = SharedState {
+        output_buffer,
+        screen,
+        replay_mode,
+        scrollback_store,
+        recorder,
+        playback: None,
         meta,
         meta_dirty: false,
         session_active: true,
@@ -1645,15 +7168,83 @@ async fn main() {
         throughput: ThroughputTracker::new(),
         title: None,
         last_metrics_nonzero: false,
-        sparkline: SparklineRing::new(),
-    }));
+        sparkline: SparklineRing::new(config.sparkline_ring_cap),
+        slow_clients,
+        watcher,
+        commands: CommandTracker::default(),
+        links: LinkIndex::default(),
+        images: ImageStore::default(),
+        clipboard: Clipboard {
+            text: None,
+            allow_read: clipboard_read,
+        },
+        config,
+    };
+    serve_session(
+        Session {
+            id: id.clone(),
+            master_fd,
+            child_pid,
+            listener,
+            state,
+            osc_pending: Vec::new(),
+            peer_policy,
+            ws,
+            metrics,
+            session_path,
+            socket_path,
+        },
+        exe,
+    )
+    .await;
+}
+
+/// A running PTY session, freshly spawned or taken over from a previous
+/// pty-host through a live upgrade.
+struct Session {
+    id: String,
+    master_fd: OwnedFd,
+    child_pid: libc::pid_t,
+    listener: StdUnixListener,
+    state: SharedState,
+    /// Partial OSC sequence to complete on the next PTY read.
+    osc_pending: Vec<u8>,
+    peer_policy: PeerPolicy,
+    ws: WsConfig,
+    metrics: Option<MetricsListener>,
+    session_path: PathBuf,
+    socket_path: PathBuf,
+}
+
+/// Run the session until the child exits. Never returns.
+async fn serve_session(session: Session, exe: PathBuf) -> ! {
+    let Session {
+        id,
+        master_fd,
+        child_pid,
+        listener: std_listener,
+        state,
+        osc_pending,
+        peer_policy,
+        ws,
+        metrics,
+        session_path,
+        socket_path,
+    } = session;
+    let master_raw_fd = master_fd.as_raw_fd();
+
+    // Ignore SIGHUP -- we're detached
+    unsafe {
+        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+    }
+
+    atomic_write_json(&session_path, &state.meta);
+    let config = state.config;
+    let state = Arc::new(RwLock::new(state));
 
     // Broadcast channel for sending frames to all connected clients
     let (broadcast_tx, _) = broadcast::channel::<Vec<u8>>(256);
 
-    // Monotonic client ID counter for log messages
-    static CLIENT_COUNTER: AtomicU64 = AtomicU64::new(1);
-
     // Channel for input data from clients -> PTY
     let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(256);
 
@@ -1666,24 +7257,48 @@ async fn main() {
     // Channel for clear scrollback requests from clients
     let (clear_tx, mut clear_rx) = mpsc::channel::<()>(4);
 
-    // Create Unix socket listener
-    let std_listener = StdUnixListener::bind(&socket_path)
-        .unwrap_or_else(|e| {
-            eprintln!("pty-host: failed to bind socket: {}", e);
-            process::exit(1);
-        });
     std_listener.set_nonblocking(true).ok();
     let listener = UnixListener::from_std(std_listener).unwrap();
 
+    // ── SIGUSR2: live upgrade ───────────────────────────────────────
+    // The PTY read task performs the exec between reads, so no output is
+    // ever caught between the kernel buffer and the handoff.
+    let (upgrade_tx, mut upgrade_rx) = mpsc::channel::<()>(1);
+    tokio::spawn(async move {
+        let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())
+            .expect("Failed to set up SIGUSR2 handler");
+        while signal.recv().await.is_some() {
+            let _ = upgrade_tx.try_send(());
+        }
+    });
+    let upgrade_target = UpgradeTarget {
+        exe,
+        handoff_path: session_path.with_file_name(format!("{}.handoff", id)),
+        master_fd: master_raw_fd,
+        listener_fd: listener.as_raw_fd(),
+        child_pid,
+        peer_policy: peer_policy.clone(),
+        ws_unix: ws.unix,
+        ws_listener_fd: ws.tcp.as_ref().map(|l| l.as_raw_fd()),
+        metrics_listener_fd: metrics.as_ref().map(MetricsListener::as_raw_fd),
+        metrics_socket: metrics.as_ref().and_then(MetricsListener::socket_path),
+    };
+    let peer_policy = Arc::new(peer_policy);
+    let token_path = session_path.with_file_name(format!("{}.token", id));
+    let metrics_socket = metrics.as_ref().and_then(MetricsListener::socket_path);
+
     // ── SIGTERM handler ─────────────────────────────────────────────
     let state_sigterm = Arc::clone(&state);
     let session_path_sigterm = session_path.clone();
     let socket_path_sigterm = socket_path.clone();
+    let token_path_sigterm = token_path.clone();
+    let metrics_socket_sigterm = metrics_socket.clone();
     tokio::spawn(async move {
         tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
             .expect("Failed to set up SIGTERM handler")
             .recv()
             .await;
+        log_info!("received SIGTERM, stopping session", child = child_pid);
 
         // Kill child process
         unsafe {
@@ -1693,6 +7308,9 @@ async fn main() {
         // Update metadata
         {
             let mut s = state_sigterm.write().await;
+            if let Some(store) = s.scrollback_store.as_mut() {
+                store.sync();
+            }
             s.meta.status = "exited".to_string();
             s.meta.exit_code = Some(-1);
             s.meta.exited_at = Some(now_millis());
@@ -1700,6 +7318,10 @@ async fn main() {
         }
 
         let _ = fs::remove_file(&socket_path_sigterm);
+        let _ = fs::remove_file(&token_path_sigterm);
+        if let Some(path) = metrics_socket_sigterm {
+            let _ = fs::remove_file(path);
+        }
         process::exit(0);
     });
 
@@ -1708,6 +7330,7 @@ async fn main() {
     let state_pty = Arc::clone(&state);
     let broadcast_tx_pty = broadcast_tx.clone();
     let session_path_pty = session_path.clone();
+    let input_tx_pty = input_tx.clone();
     let mut pty_read_handle = tokio::spawn(async move {
         // Make PTY master non-blocking for tokio
         let flags = unsafe { libc::fcntl(master_raw_fd, libc::F_GETFL) };
@@ -1720,7 +7343,8 @@ async fn main() {
                 .expect("Failed to create AsyncFd for PTY master");
 
         let mut buf = vec![0u8; 65536];
-        let mut osc_extractor = OscExtractor::new();
+        let mut osc_extractor = OscExtractor::new(&config);
+        osc_extractor.pending = osc_pending;
 
         // Reusable accumulator for draining multiple reads per readability event.
         // Avoids per-read lock acquisition and broadcast overhead during bursts
@@ -1728,7 +7352,16 @@ async fn main() {
         let mut drain_buf: Vec<u8> = Vec::new();
 
         loop {
-            let ready = async_fd.readable().await;
+            let ready = tokio::select! {
+                ready = async_fd.readable() => ready,
+                Some(()) = upgrade_rx.recv() => {
+                    log_info!("received SIGUSR2, starting live upgrade");
+                    let mut s = state_pty.write().await;
+                    let err = exec_upgrade(&upgrade_target, &mut s, &osc_extractor.pending);
+                    log_error!("live upgrade failed", error = err);
+                    continue;
+                }
+            };
             match ready {
                 Ok(mut guard) => {
                     // Drain all available data from the PTY fd in a tight loop.
@@ -1815,21 +7448,28 @@ async fn main() {
                         clip_msg.extend_from_slice(clip_text.as_bytes());
                         let _ = broadcast_tx_pty.send(encode_frame(&clip_msg));
                     }
+                    if let Some(clip_text) = osc_result.clipboard_texts.last() {
+                        state_pty.write().await.clipboard.text = Some(clip_text.clone());
+                    }
+                    if !osc_result.clipboard_queries.is_empty() {
+                        let answers: Vec<Vec<u8>> = {
+                            let s = state_pty.read().await;
+                            osc_result.clipboard_queries.iter().filter_map(|q| s.clipboard.answer(q)).collect()
+                        };
+                        for answer in answers {
+                            let _ = input_tx_pty.send(answer).await;
+                        }
+                    }
+                    for reply in osc_result.replies {
+                        let _ = input_tx_pty.send(reply).await;
+                    }
                     let cleaned = osc_result.cleaned;
-                    for img in &osc_result.inline_images {
-                        // IMAGE message format:
-                        // [0x17][4B id_len BE][id UTF-8][mime UTF-8 NUL-terminated][raw image bytes]
-                        let id_bytes = img.id.as_bytes();
-                        let mime_bytes = img.mime.as_bytes();
-                        let msg_len = 1 + 4 + id_bytes.len() + mime_bytes.len() + 1 + img.data.len();
-                        let mut img_msg = Vec::with_capacity(msg_len);
-                        img_msg.push(WS_MSG_IMAGE);
-                        img_msg.extend_from_slice(&(id_bytes.len() as u32).to_be_bytes());
-                        img_msg.extend_from_slice(id_bytes);
-                        img_msg.extend_from_slice(mime_bytes);
-                        img_msg.push(0); // NUL terminator for MIME
-                        img_msg.extend_from_slice(&img.data);
-                        let _ = broadcast_tx_pty.send(encode_frame(&img_msg));
+                    if !osc_result.inline_images.is_empty() {
+                        let mut s = state_pty.write().await;
+                        for img in osc_result.inline_images {
+                            let _ = broadcast_tx_pty.send(encode_frame(&img.message()));
+                            s.images.insert(img);
+                        }
                     }
 
                     if !cleaned.is_empty() {
@@ -1839,7 +7479,25 @@ async fn main() {
                             let data_time = now_millis();
                             let byte_len = cleaned.len();
 
+                            let start = s.output_buffer.total_written;
+                            let hits = s.watcher.feed(&cleaned, start, Instant::now());
                             s.output_buffer.write(&cleaned);
+                            // Bytes mode replays the ring buffer; the screen is unused
+                            if s.replay_mode == ReplayMode::Screen {
+                                s.screen.feed(&cleaned);
+                            }
+                            if let Some(store) = s.scrollback_store.as_mut() {
+                                if let Err(e) = store.append(&cleaned) {
+                                    log_warn!("scrollback persistence disabled", error = e);
+                                    s.scrollback_store = None;
+                                }
+                            }
+                            if let Some(recorder) = s.recorder.as_mut() {
+                                if let Err(e) = recorder.output(&cleaned) {
+                                    log_warn!("recording stopped", error = e);
+                                    s.recorder = None;
+                                }
+                            }
                             s.meta.last_activity = data_time;
                             s.meta.total_bytes_written += byte_len as f64;
                             s.meta.last_active_at = iso_now();
@@ -1853,13 +7511,70 @@ async fn main() {
                                 let state_msg = vec![WS_MSG_SESSION_STATE, 0x01];
                                 let _ = broadcast_tx_pty.send(encode_frame(&state_msg));
                             }
+
+                            // Broadcast DATA to all clients. Done under the lock
+                            // so a lagging client's resync lines up exactly.
+                            let mut data_msg = Vec::with_capacity(1 + cleaned.len());
+                            data_msg.push(WS_MSG_DATA);
+                            data_msg.extend_from_slice(&cleaned);
+                            let _ = broadcast_tx_pty.send(encode_frame(&data_msg));
+
+                            // Watch rules
+                            for notif in &hits.notifications {
+                                let mut notif_msg = vec![WS_MSG_NOTIFICATION];
+                                notif_msg.extend_from_slice(notif.as_bytes());
+                                let _ = broadcast_tx_pty.send(encode_frame(&notif_msg));
+                            }
+                            if !hits.marks.is_empty() {
+                                let marks = &mut s.meta.watch_marks;
+                                marks.extend(hits.marks);
+                                let excess = marks.len().saturating_sub(WATCH_MAX_MARKS);
+                                marks.drain(..excess);
+                                // Immediate flush, like title changes
+                                atomic_write_json(&session_path_pty, &s.meta);
+                                s.meta_dirty = false;
+                            }
+                            if hits.exit {
+                                log_info!("exit watch rule matched, hanging up", child = child_pid);
+                                hang_up(master_raw_fd, child_pid);
+                            }
+                        }
+                    }
+
+                    // OSC 133 marks, now that the output they point into is
+                    // in the buffer
+                    if !osc_result.prompt_marks.is_empty() {
+                        let mut guard = state_pty.write().await;
+                        let s = &mut *guard;
+                        let base = s.output_buffer.total_written - cleaned.len() as f64;
+                        let now = now_millis();
+                        for &(pos, mark) in &osc_result.prompt_marks {
+                            let offset = base + pos as f64;
+                            if let Some(record) = s.commands.mark(mark, offset, now, &s.output_buffer) {
+                                let update = CommandsMessage { commands: vec![record] };
+                                let mut commands_msg = vec![WS_MSG_COMMANDS];
+                                commands_msg.extend_from_slice(&serde_json::to_vec(&update).unwrap_or_default());
+                                let _ = broadcast_tx_pty.send(encode_frame(&commands_msg));
+                            }
+                        }
+                        let last = s.commands.last().cloned();
+                        if s.meta.last_command != last {
+                            s.meta.last_command = last;
+                            // Immediate flush so watchers of the session file
+                            // see a command finish without polling delay
+                            atomic_write_json(&session_path_pty, &s.meta);
+                            s.meta_dirty = false;
                         }
+                    }
 
-                        // Broadcast DATA to all clients
-                        let mut data_msg = Vec::with_capacity(1 + cleaned.len());
-                        data_msg.push(WS_MSG_DATA);
-                        data_msg.extend_from_slice(&cleaned);
-                        let _ = broadcast_tx_pty.send(encode_frame(&data_msg));
+                    // OSC 8 link boundaries, likewise
+                    if !osc_result.links.is_empty() {
+                        let mut guard = state_pty.write().await;
+                        let s = &mut *guard;
+                        let base = s.output_buffer.total_written - cleaned.len() as f64;
+                        for (pos, event) in &osc_result.links {
+                            s.links.apply(base + *pos as f64, event, base, &s.output_buffer);
+                        }
                     }
 
                     if eof {
@@ -1884,6 +7599,7 @@ async fn main() {
         } else {
             -1
         };
+        log_info!("child exited", child = child_pid, code = code);
 
         // Broadcast EXIT
         let mut exit_msg = vec![WS_MSG_EXIT, 0, 0, 0, 0];
@@ -1893,6 +7609,9 @@ async fn main() {
         // Update metadata
         {
             let mut s = state_pty.write().await;
+            if let Some(store) = s.scrollback_store.as_mut() {
+                store.sync();
+            }
             s.exit_code = Some(code);
             s.meta.status = "exited".to_string();
             s.meta.exit_code = Some(code);
@@ -1936,6 +7655,13 @@ async fn main() {
             s.meta.rows = new_rows;
             s.meta_dirty = true;
             s.output_buffer.notify_resize();
+            s.screen.resize(new_cols, new_rows);
+            if let Some(recorder) = s.recorder.as_mut() {
+                if let Err(e) = recorder.resize(new_cols, new_rows) {
+                    log_warn!("recording stopped", error = e);
+                    s.recorder = None;
+                }
+            }
 
             // Broadcast RESIZE to all clients so read-only viewers stay in sync
             let mut resize_msg = vec![WS_MSG_RESIZE, 0, 0, 0, 0];
@@ -1955,6 +7681,7 @@ async fn main() {
             let fg_pgrp = unsafe { libc::tcgetpgrp(master_raw_fd) };
             if fg_pgrp > 0 && fg_pgrp != child_pid {
                 // Foreground process group differs from the shell — SIGHUP it
+                log_info!("detach requested, hanging up foreground job", pgrp = fg_pgrp);
                 unsafe {
                     libc::kill(-fg_pgrp, libc::SIGHUP);
                 }
@@ -1969,6 +7696,12 @@ async fn main() {
         while let Some(()) = clear_rx.recv().await {
             let mut s = state_clear.write().await;
             s.output_buffer.clear();
+            s.screen.clear_history();
+            if let Some(store) = s.scrollback_store.as_mut() {
+                if let Err(e) = store.clear() {
+                    log_warn!("failed to clear persisted scrollback", error = e);
+                }
+            }
             // Broadcast CLEAR_SCROLLBACK to all clients so they call term.clear()
             let clear_msg = vec![WS_MSG_CLEAR_SCROLLBACK];
             let _ = broadcast_tx_clear.send(encode_frame(&clear_msg));
@@ -2027,6 +7760,9 @@ async fn main() {
                 atomic_write_json(&session_path_json, &s.meta);
                 s.meta_dirty = false;
             }
+            if let Some(store) = s.scrollback_store.as_mut() {
+                store.sync();
+            }
             if s.exit_code.is_some() {
                 break;
             }
@@ -2045,7 +7781,7 @@ async fn main() {
                 break;
             }
             let idle_ms = now_millis() - s.meta.last_activity;
-            if s.session_active && idle_ms >= IDLE_TIMEOUT_MS {
+            if s.session_active && idle_ms >= config.idle_timeout_ms {
                 s.session_active = false;
                 s.meta_dirty = true;
                 let state_msg = vec![WS_MSG_SESSION_STATE, 0x00];
@@ -2115,106 +7851,381 @@ async fn main() {
     // ── Accept client connections ────────────────────────────────────
     let state_accept = Arc::clone(&state);
     let broadcast_tx_accept = broadcast_tx.clone();
+    let channels = ClientChannels {
+        input_tx,
+        resize_tx,
+        detach_tx,
+        clear_tx,
+        playback_tx: None,
+    };
+    if let Some(tcp) = ws.tcp {
+        tcp.set_nonblocking(true).ok();
+        tokio::spawn(accept_websockets(
+            tokio::net::TcpListener::from_std(tcp).unwrap(),
+            Arc::clone(&state),
+            broadcast_tx.clone(),
+            channels.clone(),
+            Arc::clone(&peer_policy),
+        ));
+    }
+    if let Some(metrics) = metrics {
+        tokio::spawn(serve_metrics(metrics, Arc::clone(&state), Some(child_pid)));
+    }
 
     loop {
         tokio::select! {
             result = listener.accept() => {
                 match result {
                     Ok((stream, _)) => {
-                        let (reader, writer) = stream.into_split();
-                        let writer = Arc::new(Mutex::new(writer));
-                        let state_client = Arc::clone(&state_accept);
-                        let input_tx = input_tx.clone();
-                        let resize_tx = resize_tx.clone();
-                        let mut broadcast_rx = broadcast_tx_accept.subscribe();
-                        let writer_broadcast = Arc::clone(&writer);
-                        let client_num = CLIENT_COUNTER.fetch_add(1, Ordering::Relaxed);
-
-                        // Spawn broadcast forwarder for this client
-                        let broadcast_handle = tokio::spawn(async move {
-                            let mut last_lag_log = tokio::time::Instant::now() - Duration::from_secs(10);
-                            let mut total_lagged: u64 = 0;
-                            loop {
-                                match broadcast_rx.recv().await {
-                                    Ok(frame) => {
-                                        let mut w = writer_broadcast.lock().await;
-                                        if w.write_all(&frame).await.is_err() {
-                                            break;
-                                        }
-                                    }
-                                    Err(broadcast::error::RecvError::Lagged(n)) => {
-                                        total_lagged += n;
-                                        let now = tokio::time::Instant::now();
-                                        if now.duration_since(last_lag_log) >= Duration::from_secs(5) {
-                                            eprintln!(
-                                                "pty-host: client {} dropped {} broadcast frame(s) (total: {})",
-                                                client_num, n, total_lagged
-                                            );
-                                            last_lag_log = now;
-                                        }
-                                        // Continue — client recovers on next frame
-                                    }
-                                    Err(broadcast::error::RecvError::Closed) => break,
-                                }
-                            }
-                        });
+                        if let Some(ceiling) = authorize_peer(&stream, &peer_policy) {
+                            let auth = ClientAuth { ceiling, policy: Arc::clone(&peer_policy) };
+                            accept_unix_client(stream, ws.unix, Arc::clone(&state_accept), &broadcast_tx_accept, channels.clone(), auth);
+                        }
+                    }
+                    Err(_) => continue,
+                }
+            }
+            exit_code = &mut pty_read_handle => {
+                // PTY process exited -- wait briefly for clients to receive exit frame
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let _ = fs::remove_file(&socket_path);
+                let _ = fs::remove_file(&token_path);
+                if let Some(path) = &metrics_socket {
+                    let _ = fs::remove_file(path);
+                }
+                let code = exit_code.unwrap_or(-1);
+                process::exit(if code >= 0 { 0 } else { 1 });
+            }
+        }
+    }
+}
+
+// ── Protocol negotiation ────────────────────────────────────────────
+//
+// A client may open with HELLO [u16 version][u32 capabilities] to declare
+// which optional message types it understands. pty-host answers with the
+// version and capabilities it will use, filters everything it sends by
+// them, and waits for RESUME instead of guessing after RESUME_TIMEOUT_MS.
+
+const PROTOCOL_VERSION: u16 = 1;
+
+// Capability bits, one per optional server→client message type. DATA,
+// RESIZE, EXIT, BUFFER_REPLAY, SYNC and HELLO are always sent.
+const CAP_BUFFER_REPLAY_GZ: u32 = 1 << 0;
+const CAP_TITLE: u32 = 1 << 1;
+const CAP_NOTIFICATION: u32 = 1 << 2;
+const CAP_SESSION_STATE: u32 = 1 << 3;
+const CAP_SESSION_METRICS: u32 = 1 << 4;
+const CAP_CLIPBOARD: u32 = 1 << 5;
+const CAP_IMAGE: u32 = 1 << 6;
+const CAP_SPARKLINE: u32 = 1 << 7;
+const CAP_PLAYBACK: u32 = 1 << 8;
+const CAP_COMMANDS: u32 = 1 << 9;
+
+const SERVER_CAPABILITIES: u32 = CAP_BUFFER_REPLAY_GZ
+    | CAP_TITLE
+    | CAP_NOTIFICATION
+    | CAP_SESSION_STATE
+    | CAP_SESSION_METRICS
+    | CAP_CLIPBOARD
+    | CAP_IMAGE
+    | CAP_SPARKLINE
+    | CAP_PLAYBACK
+    | CAP_COMMANDS;
+
+/// Clients that never send HELLO: what they got before HELLO existed,
+/// minus IMAGE, which older CLIs cannot parse. Types added since HELLO
+/// (COMMANDS) must be asked for.
+const LEGACY_CAPABILITIES: u32 = SERVER_CAPABILITIES & !CAP_IMAGE & !CAP_COMMANDS;
+
+/// The capability a message type needs, or 0 for the core types.
+fn required_capability(msg_type: u8) -> u32 {
+    match msg_type {
+        WS_MSG_BUFFER_REPLAY_GZ => CAP_BUFFER_REPLAY_GZ,
+        WS_MSG_TITLE => CAP_TITLE,
+        WS_MSG_NOTIFICATION => CAP_NOTIFICATION,
+        WS_MSG_SESSION_STATE => CAP_SESSION_STATE,
+        WS_MSG_SESSION_METRICS => CAP_SESSION_METRICS,
+        WS_MSG_CLIPBOARD => CAP_CLIPBOARD,
+        WS_MSG_IMAGE => CAP_IMAGE,
+        WS_MSG_SPARKLINE_HISTORY => CAP_SPARKLINE,
+        WS_MSG_PLAYBACK_STATE => CAP_PLAYBACK,
+        WS_MSG_COMMANDS => CAP_COMMANDS,
+        _ => 0,
+    }
+}
+
+fn accepts(capabilities: u32, msg_type: u8) -> bool {
+    let required = required_capability(msg_type);
+    capabilities & required == required
+}
+
+/// Answer a client HELLO. Returns the capabilities to use and the HELLO
+/// reply, or `None` if the payload is malformed.
+fn negotiate(data: &[u8]) -> Option<(u32, Vec<u8>)> {
+    if data.len() < 6 {
+        return None;
+    }
+    let version = u16::from_be_bytes([data[0], data[1]]).min(PROTOCOL_VERSION);
+    let capabilities = u32::from_be_bytes(data[2..6].try_into().unwrap()) & SERVER_CAPABILITIES;
+    let mut reply = Vec::with_capacity(7);
+    reply.push(WS_MSG_HELLO);
+    reply.extend_from_slice(&version.to_be_bytes());
+    reply.extend_from_slice(&capabilities.to_be_bytes());
+    Some((capabilities, reply))
+}
+
+// ── Slow clients ────────────────────────────────────────────────────
+//
+// A client that falls more than the broadcast channel's capacity behind
+// misses frames. Rather than let its terminal silently diverge, pty-host
+// tracks each client's offset and resyncs it from the ring buffer (or with
+// SYNC(0) and a full replay), or disconnects it per `--slow-clients`.
+
+/// Window for `--max-resyncs`.
+const RESYNC_WINDOW: Duration = Duration::from_secs(60);
+
+/// What to do with a client that falls behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SlowClientPolicy {
+    /// `--slow-clients disconnect`: drop lagging clients instead of
+    /// resyncing them. They reconnect and RESUME on their own.
+    disconnect: bool,
+    /// `--max-resyncs`: resyncs per minute before a client that keeps
+    /// falling behind is dropped. 0 means no limit.
+    max_resyncs: u32,
+}
+
+impl SlowClientPolicy {
+    /// Record a lag event; false if the client should be disconnected.
+    fn allow_resync(&self, recent: &mut VecDeque<Instant>, now: Instant) -> bool {
+        if self.disconnect {
+            return false;
+        }
+        if self.max_resyncs == 0 {
+            return true;
+        }
+        while recent.front().is_some_and(|&t| now.duration_since(t) >= RESYNC_WINDOW) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.max_resyncs as usize {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+}
+
+/// A client's output offset as the client itself computes it: the last
+/// SYNC it was sent plus the DATA bytes since. Updated only while holding
+/// the client's writer lock, so it matches what was actually written.
+struct ClientOffset(AtomicU64);
+
+impl ClientOffset {
+    /// No SYNC sent yet.
+    fn new() -> Self {
+        Self(AtomicU64::new(f64::NAN.to_bits()))
+    }
+
+    fn get(&self) -> Option<f64> {
+        let offset = f64::from_bits(self.0.load(Ordering::Relaxed));
+        (!offset.is_nan()).then_some(offset)
+    }
+
+    fn set(&self, offset: f64) {
+        self.0.store(offset.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Account for a broadcast frame just written to the client.
+    fn observe(&self, frame: &[u8]) {
+        match frame.get(4) {
+            Some(&WS_MSG_DATA) => {
+                if let Some(offset) = self.get() {
+                    self.set(offset + (frame.len() - 5) as f64);
+                }
+            }
+            Some(&WS_MSG_SYNC) if frame.len() >= 13 => {
+                self.set(f64::from_be_bytes(frame[5..13].try_into().unwrap()));
+            }
+            _ => {}
+        }
+    }
+}
+
+// ── Client handler ──────────────────────────────────────────────────
+
+/// Where client requests are routed.
+#[derive(Clone)]
+struct ClientChannels {
+    input_tx: mpsc::Sender<Vec<u8>>,
+    resize_tx: mpsc::Sender<(u16, u16)>,
+    detach_tx: mpsc::Sender<()>,
+    clear_tx: mpsc::Sender<()>,
+    /// Set only in `--replay` mode.
+    playback_tx: Option<mpsc::Sender<PlaybackControl>>,
+}
+
+/// Serve a newly accepted connection: forward broadcast frames to it and
+/// run the handshake + request loop. `transport` is only for the log.
+fn spawn_client(
+    stream: tokio::net::UnixStream,
+    transport: &'static str,
+    state: Arc<RwLock<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Vec<u8>>,
+    channels: ClientChannels,
+    auth: ClientAuth,
+) {
+    // Monotonic client ID counter for log messages
+    static CLIENT_COUNTER: AtomicU64 = AtomicU64::new(1);
 
-                        // Spawn client reader
-                        let writer_client = Arc::clone(&writer);
-                        let detach_tx = detach_tx.clone();
-                        let clear_tx = clear_tx.clone();
-                        tokio::spawn(async move {
-                            handle_client(reader, writer_client, state_client, input_tx, resize_tx, detach_tx, clear_tx).await;
-                            broadcast_handle.abort();
-                        });
+    let (reader, writer) = stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    let mut broadcast_rx = broadcast_tx.subscribe();
+    let writer_broadcast = Arc::clone(&writer);
+    let client_num = CLIENT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    log_info!("client connected", client = client_num, transport = transport, access = auth.initial());
+    MetricsCounters::count(&METRICS.clients, 1);
+    MetricsCounters::count(&METRICS.connections, 1);
+    // Updated by handle_client once the client sends HELLO
+    let capabilities = Arc::new(AtomicU32::new(LEGACY_CAPABILITIES));
+    let capabilities_broadcast = Arc::clone(&capabilities);
+    let offset = Arc::new(ClientOffset::new());
+    let offset_broadcast = Arc::clone(&offset);
+    let state_broadcast = Arc::clone(&state);
+
+    // Spawn broadcast forwarder for this client
+    let broadcast_handle = tokio::spawn(async move {
+        let mut last_lag_log = tokio::time::Instant::now() - Duration::from_secs(10);
+        let mut total_lagged: u64 = 0;
+        let mut recent_resyncs = VecDeque::new();
+        loop {
+            match broadcast_rx.recv().await {
+                Ok(frame) => {
+                    let msg_type = frame.get(4).copied().unwrap_or(WS_MSG_DATA);
+                    if !accepts(capabilities_broadcast.load(Ordering::Relaxed), msg_type) {
+                        continue;
+                    }
+                    let mut w = writer_broadcast.lock().await;
+                    if w.write_all(&frame).await.is_err() {
+                        break;
+                    }
+                    offset_broadcast.observe(&frame);
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    total_lagged += n;
+                    MetricsCounters::count(&METRICS.dropped_frames, n);
+                    let now = tokio::time::Instant::now();
+                    if now.duration_since(last_lag_log) >= Duration::from_secs(5) {
+                        log_warn!("client lagging", client = client_num, dropped = n, total = total_lagged);
+                        last_lag_log = now;
+                    }
+                    // Still in the handshake, whose replay covers the gap
+                    let Some(client_offset) = offset_broadcast.get() else { continue };
+                    let policy = state_broadcast.read().await.slow_clients;
+                    if !policy.allow_resync(&mut recent_resyncs, Instant::now()) {
+                        log_warn!("disconnecting slow client", client = client_num, total = total_lagged);
+                        MetricsCounters::count(&METRICS.slow_disconnects, 1);
+                        let _ = writer_broadcast.lock().await.shutdown().await;
+                        break;
                     }
-                    Err(_) => continue,
+                    // DATA is broadcast under the state write lock, so a
+                    // receiver created under the read lock starts right
+                    // where the catch-up data ends.
+                    let (data, reset, end) = {
+                        let s = state_broadcast.read().await;
+                        broadcast_rx = broadcast_rx.resubscribe();
+                        let (data, reset) = s.catch_up(client_offset);
+                        (data, reset, s.output_buffer.total_written)
+                    };
+                    if reset {
+                        send_cache_reset(&writer_broadcast).await;
+                    }
+                    let mode = if reset { CatchUp::CacheReset } else { CatchUp::Delta };
+                    log_info!("resyncing lagging client", client = client_num, mode = mode, bytes = data.len());
+                    MetricsCounters::count(&METRICS.resyncs, 1);
+                    METRICS.replay(mode, data.len());
+                    let caps = capabilities_broadcast.load(Ordering::Relaxed);
+                    send_replay(&writer_broadcast, &state_broadcast, &data, end, caps, &offset_broadcast).await;
                 }
-            }
-            exit_code = &mut pty_read_handle => {
-                // PTY process exited -- wait briefly for clients to receive exit frame
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                let _ = fs::remove_file(&socket_path);
-                let code = exit_code.unwrap_or(-1);
-                process::exit(if code >= 0 { 0 } else { 1 });
+                Err(broadcast::error::RecvError::Closed) => break,
             }
         }
-    }
-}
+    });
 
-// ── Client handler ──────────────────────────────────────────────────
+    // Spawn client reader
+    let connected_at = Instant::now();
+    tokio::spawn(async move {
+        handle_client(client_num, reader, writer, state, channels, auth, capabilities, offset).await;
+        broadcast_handle.abort();
+        METRICS.clients.fetch_sub(1, Ordering::Relaxed);
+        let secs = connected_at.elapsed().as_secs();
+        log_info!("client disconnected", client = client_num, connected_secs = secs);
+    });
+}
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_client(
+    client: u64,
     mut reader: tokio::net::unix::OwnedReadHalf,
     writer: ClientWriter,
     state: Arc<RwLock<SharedState>>,
-    input_tx: mpsc::Sender<Vec<u8>>,
-    resize_tx: mpsc::Sender<(u16, u16)>,
-    detach_tx: mpsc::Sender<()>,
-    clear_tx: mpsc::Sender<()>,
+    channels: ClientChannels,
+    auth: ClientAuth,
+    capabilities: Arc<AtomicU32>,
+    offset: Arc<ClientOffset>,
 ) {
     // Wait for RESUME or timeout for full replay
     let mut pending = Vec::new();
-    let mut resume_handled = false;
-
-    // Read initial data with timeout
-    let resume_result = tokio::time::timeout(
-        Duration::from_millis(RESUME_TIMEOUT_MS),
-        read_first_message(&mut reader, &mut pending),
-    )
-    .await;
+    let mut access = auth.initial();
+    let mut caps = LEGACY_CAPABILITIES;
+
+    // Read initial data. HELLO and AUTH may come first; the RESUME wait
+    // restarts after each, and after HELLO there is no timeout at all.
+    // Requests (RANGE_REQUEST, SEARCH_REQUEST, SPARKLINE_REQUEST, ...) are
+    // served as they arrive and restart the wait too, so clients can send
+    // them before resuming or without ever resuming.
+    let mut negotiated = false;
+    let resume_result = loop {
+        let first = read_first_message(&mut reader, &mut pending);
+        let result = if negotiated {
+            Ok(first.await)
+        } else {
+            tokio::time::timeout(Duration::from_millis(RESUME_TIMEOUT_MS), first).await
+        };
+        match result {
+            Ok(Some((WS_MSG_AUTH, data))) => {
+                access = auth.authenticate(&data);
+                log_info!("client authenticated", client = client, access = access);
+            }
+            Ok(Some((WS_MSG_HELLO, data))) if !negotiated => {
+                negotiated = true;
+                if let Some((negotiated_caps, reply)) = negotiate(&data) {
+                    log_debug!("client negotiated", client = client, caps = format!("{:#x}", negotiated_caps));
+                    caps = negotiated_caps;
+                    capabilities.store(caps, Ordering::Relaxed);
+                    let mut w = writer.lock().await;
+                    let _ = w.write_all(&encode_frame(&reply)).await;
+                }
+            }
+            Ok(Some((msg_type, data))) => {
+                if !handle_request(msg_type, &data, &writer, &state, access, caps).await {
+                    break Ok(Some((msg_type, data)));
+                }
+            }
+            other => break other,
+        }
+    };
 
-    match resume_result {
+    let (mode, replayed) = match resume_result {
+        Ok(Some((WS_MSG_RESUME, data))) => handle_resume(&writer, &state, &data, caps, &offset).await,
         Ok(Some((msg_type, data))) => {
-            if msg_type == WS_MSG_RESUME {
-                resume_handled = true;
-                handle_resume(&writer, &state, &data).await;
-            } else {
-                // Not a RESUME -- send full replay first, then process this message
-                send_full_replay(&writer, &state).await;
-                resume_handled = true;
-                process_client_message(msg_type, &data, &input_tx, &resize_tx, &detach_tx, &clear_tx).await;
+            // Not a RESUME -- send full replay first, then process this message
+            let replayed = send_full_replay(&writer, &state, caps, &offset).await;
+            if access == Access::ReadWrite {
+                process_client_message(msg_type, &data, &channels).await;
             }
+            (CatchUp::Full, replayed)
         }
         Ok(None) => {
             // Client disconnected
@@ -2222,19 +8233,25 @@ async fn handle_client(
         }
         Err(_) => {
             // Timeout -- send full replay
+            (CatchUp::Full, send_full_replay(&writer, &state, caps, &offset).await)
         }
-    }
-
-    if !resume_handled {
-        send_full_replay(&writer, &state).await;
-    }
+    };
+    METRICS.replay(mode, replayed);
+    log_info!(
+        "handshake complete",
+        client = client,
+        mode = mode,
+        bytes = replayed,
+        offset = offset.get().unwrap_or_default(),
+        negotiated = negotiated,
+    );
 
     // Send exit if already exited
     {
         let s = state.read().await;
         if let Some(code) = s.exit_code {
             let mut exit_msg = vec![WS_MSG_EXIT, 0, 0, 0, 0];
-            exit_msg[1..5].copy_from_slice(&(code as i32).to_be_bytes());
+            exit_msg[1..5].copy_from_slice(&code.to_be_bytes());
             let frame = encode_frame(&exit_msg);
             let mut w = writer.lock().await;
             let _ = w.write_all(&frame).await;
@@ -2270,19 +8287,57 @@ async fn handle_client(
             let msg_type = payload[0];
             let data = &payload[1..];
 
-            if msg_type == WS_MSG_SPARKLINE_REQUEST {
-                let s = state.read().await;
-                let mut resp = Vec::with_capacity(1 + 2 + s.sparkline.len() * 8);
-                resp.push(WS_MSG_SPARKLINE_HISTORY);
-                resp.extend_from_slice(&s.sparkline.encode());
-                let frame = encode_frame(&resp);
-                let mut w = writer.lock().await;
-                let _ = w.write_all(&frame).await;
-            } else {
-                process_client_message(msg_type, data, &input_tx, &resize_tx, &detach_tx, &clear_tx).await;
+            if msg_type == WS_MSG_AUTH {
+                access = auth.authenticate(data);
+                log_info!("client authenticated", client = client, access = access);
+            } else if !handle_request(msg_type, data, &writer, &state, access, caps).await
+                && access == Access::ReadWrite
+            {
+                process_client_message(msg_type, data, &channels).await;
+            }
+        }
+    }
+}
+
+/// Serve a request that is answered to this client alone (SEARCH_REQUEST,
+/// RANGE_REQUEST, EXPORT, ...). These may come before RESUME. Returns false
+/// if `msg_type` isn't one.
+async fn handle_request(
+    msg_type: u8,
+    data: &[u8],
+    writer: &ClientWriter,
+    state: &Arc<RwLock<SharedState>>,
+    access: Access,
+    caps: u32,
+) -> bool {
+    match msg_type {
+        WS_MSG_SPARKLINE_REQUEST => {
+            if !accepts(caps, WS_MSG_SPARKLINE_HISTORY) {
+                return true;
+            }
+            let s = state.read().await;
+            let mut resp = Vec::with_capacity(1 + 2 + s.sparkline.len() * 8);
+            resp.push(WS_MSG_SPARKLINE_HISTORY);
+            resp.extend_from_slice(&s.sparkline.encode());
+            let frame = encode_frame(&resp);
+            let mut w = writer.lock().await;
+            let _ = w.write_all(&frame).await;
+        }
+        WS_MSG_SEARCH_REQUEST => handle_search(writer, state, data).await,
+        WS_MSG_RANGE_REQUEST => handle_range(writer, state, data).await,
+        WS_MSG_EXPORT => handle_export(writer, state, data).await,
+        WS_MSG_LINKS => handle_links(writer, state).await,
+        WS_MSG_IMAGE_REQUEST => handle_image_request(writer, state, data).await,
+        WS_MSG_COMMANDS => handle_commands(writer, state).await,
+        WS_MSG_WATCH => handle_watch(writer, state, data, access).await,
+        WS_MSG_CLIPBOARD_SET => {
+            if access == Access::ReadWrite {
+                handle_clipboard_set(state, data).await;
             }
         }
+        _ => return false,
     }
+    true
 }
 
 async fn read_first_message(
@@ -2311,37 +8366,205 @@ async fn read_first_message(
     }
 }
 
-async fn handle_resume(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+/// How a client was brought up to date, for the log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CatchUp {
+    /// Only the output since the client's offset.
+    Delta,
+    /// The whole buffer: no RESUME, or a malformed one.
+    Full,
+    /// SYNC 0 and the whole buffer: the client's offset was already evicted.
+    CacheReset,
+}
+
+impl CatchUp {
+    const ALL: [CatchUp; 3] = [CatchUp::Delta, CatchUp::Full, CatchUp::CacheReset];
+}
+
+impl fmt::Display for CatchUp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CatchUp::Delta => "delta",
+            CatchUp::Full => "full",
+            CatchUp::CacheReset => "cache-reset",
+        })
+    }
+}
+
+/// Answer RESUME; returns how the client was caught up and the replay size.
+async fn handle_resume(
+    writer: &ClientWriter,
+    state: &Arc<RwLock<SharedState>>,
+    data: &[u8],
+    caps: u32,
+    offset: &ClientOffset,
+) -> (CatchUp, usize) {
     if data.len() < 8 {
         // Malformed RESUME -- send full replay
-        send_full_replay(writer, state).await;
-        return;
+        return (CatchUp::Full, send_full_replay(writer, state, caps, offset).await);
     }
 
     let client_offset = f64::from_be_bytes(data[..8].try_into().unwrap());
-
     let s = state.read().await;
-    if client_offset <= 0.0 {
-        // First connect -- full replay
-        let buf_data = s.output_buffer.read();
-        drop(s);
-        send_replay(writer, state, &buf_data).await;
+    let (buf_data, reset) = s.catch_up(client_offset);
+    let end = s.output_buffer.total_written;
+    drop(s);
+    if reset {
+        // Offset too old -- full replay with cache reset signal
+        send_cache_reset(writer).await;
+    }
+    send_replay(writer, state, &buf_data, end, caps, offset).await;
+    (if reset { CatchUp::CacheReset } else { CatchUp::Delta }, buf_data.len())
+}
+
+/// Answer a RANGE_REQUEST `[8B start][8B end]([4B max bytes])` with
+/// RANGE_DATA `[8B start][8B end][bytes]`, float64/uint32 BE.
+async fn handle_range(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+    if data.len() < 16 {
+        return;
+    }
+    let start = f64::from_be_bytes(data[..8].try_into().unwrap());
+    let end = f64::from_be_bytes(data[8..16].try_into().unwrap());
+    let max = match data.get(16..20) {
+        Some(max) => (u32::from_be_bytes(max.try_into().unwrap()) as usize).min(RANGE_MAX_BYTES),
+        None => RANGE_MAX_BYTES,
+    };
+    let (start, bytes) = state.read().await.output_buffer.read_range(start, end, max);
+
+    let mut msg = Vec::with_capacity(17 + bytes.len());
+    msg.push(WS_MSG_RANGE_DATA);
+    msg.extend_from_slice(&start.to_be_bytes());
+    msg.extend_from_slice(&(start + bytes.len() as f64).to_be_bytes());
+    msg.extend_from_slice(&bytes);
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
+}
+
+/// Answer an EXPORT `[1B format]` with EXPORT `[1B format][document]`.
+async fn handle_export(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+    let format = match data.first() {
+        Some(&EXPORT_FORMAT_HTML) => EXPORT_FORMAT_HTML,
+        _ => EXPORT_FORMAT_TEXT,
+    };
+    let (output, title) = {
+        let s = state.read().await;
+        let title = s.title.clone().unwrap_or_else(|| format!("relay-tty {}", s.meta.id));
+        (s.output_buffer.read_main(), title)
+    };
+    let doc = tokio::task::spawn_blocking(move || render_export(&output, format, &title))
+        .await
+        .unwrap_or_default();
+    let mut msg = Vec::with_capacity(2 + doc.len());
+    msg.extend_from_slice(&[WS_MSG_EXPORT, format]);
+    msg.extend_from_slice(doc.as_bytes());
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
+}
+
+/// Answer a COMMANDS request with the whole command history. Viewers may
+/// ask too: it only reads state.
+async fn handle_commands(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>) {
+    let mut msg = vec![WS_MSG_COMMANDS];
+    {
+        let s = state.read().await;
+        let history = CommandsMessage { commands: s.commands.all() };
+        msg.extend_from_slice(&serde_json::to_vec(&history).unwrap_or_default());
+    }
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
+}
+
+/// Answer a LINKS request with the link index. Viewers may ask too.
+async fn handle_links(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>) {
+    let mut msg = vec![WS_MSG_LINKS];
+    {
+        let s = state.read().await;
+        let index = LinksMessage { links: &s.links.links };
+        msg.extend_from_slice(&serde_json::to_vec(&index).unwrap_or_default());
+    }
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
+}
+
+/// Answer an IMAGE_REQUEST (the image id) with the stored image. Evicted
+/// or unknown ids get no reply. Viewers may ask too.
+async fn handle_image_request(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+    let id = String::from_utf8_lossy(data);
+    let msg = state.read().await.images.get(&id).map(InlineImage::message);
+    if let Some(msg) = msg {
+        let frame = encode_frame(&msg);
+        let mut w = writer.lock().await;
+        let _ = w.write_all(&frame).await;
+    }
+}
+
+/// Replace the session clipboard with a client's text (read-write clients
+/// only). Oversized or non-UTF-8 text is ignored.
+async fn handle_clipboard_set(state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+    let mut s = state.write().await;
+    if data.len() > s.config.clipboard_max_size {
+        return;
+    }
+    if let Ok(text) = std::str::from_utf8(data) {
+        s.clipboard.text = Some(text.to_string());
+    }
+}
+
+/// Answer a WATCH. A non-empty request `{"rules": [...]}` replaces the
+/// rule set (read-write clients only); either way the reply lists the rules
+/// in effect.
+async fn handle_watch(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8], access: Access) {
+    let mut reply = WatchReply::default();
+    // Only a ReadWrite client replacing rules needs the write lock; list
+    // queries and refused viewer edits must not stall the PTY read task.
+    if data.is_empty() {
+        reply.rules = state.read().await.watcher.rules();
+    } else if access != Access::ReadWrite {
+        reply.error = Some("viewers cannot change watch rules".to_string());
+        reply.rules = state.read().await.watcher.rules();
     } else {
-        // Try delta replay
-        match s.output_buffer.read_from(client_offset) {
-            Some(delta) => {
-                drop(s);
-                send_replay(writer, state, &delta).await;
+        match serde_json::from_slice::<WatchRequest>(data) {
+            Ok(req) => {
+                let mut s = state.write().await;
+                reply.error = s.watcher.set_rules(req.rules).err();
+                reply.rules = s.watcher.rules();
             }
-            None => {
-                // Offset too old -- full replay with cache reset signal
-                let buf_data = s.output_buffer.read();
-                drop(s);
-                send_cache_reset(writer).await;
-                send_replay(writer, state, &buf_data).await;
+            Err(e) => {
+                reply.error = Some(e.to_string());
+                reply.rules = state.read().await.watcher.rules();
             }
         }
     }
+    let mut msg = vec![WS_MSG_WATCH];
+    msg.extend_from_slice(&serde_json::to_vec(&reply).unwrap_or_default());
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
+}
+
+/// Answer a SEARCH_REQUEST. Viewers may search too: it only reads output.
+async fn handle_search(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, data: &[u8]) {
+    let results = match serde_json::from_slice::<SearchRequest>(data) {
+        Ok(req) => {
+            let (start, data) = state.read().await.output_buffer.snapshot();
+            tokio::task::spawn_blocking(move || search_output(&req, start, &data))
+                .await
+                .unwrap_or_default()
+        }
+        Err(e) => SearchResults {
+            error: Some(format!("invalid search request: {}", e)),
+            ..SearchResults::default()
+        },
+    };
+    let mut msg = vec![WS_MSG_SEARCH_RESULTS];
+    msg.extend_from_slice(&serde_json::to_vec(&results).unwrap_or_default());
+    let frame = encode_frame(&msg);
+    let mut w = writer.lock().await;
+    let _ = w.write_all(&frame).await;
 }
 
 /// Send SYNC(0.0) to tell the client its cached buffer is stale and must be discarded.
@@ -2354,14 +8577,31 @@ async fn send_cache_reset(writer: &ClientWriter) {
     let _ = w.write_all(&frame).await;
 }
 
-async fn send_full_replay(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>) {
+/// Replay the whole buffer; returns its size.
+async fn send_full_replay(
+    writer: &ClientWriter,
+    state: &Arc<RwLock<SharedState>>,
+    caps: u32,
+    offset: &ClientOffset,
+) -> usize {
     let s = state.read().await;
-    let buf_data = s.output_buffer.read();
+    let buf_data = s.full_replay();
+    let end = s.output_buffer.total_written;
     drop(s);
-    send_replay(writer, state, &buf_data).await;
+    send_replay(writer, state, &buf_data, end, caps, offset).await;
+    buf_data.len()
 }
 
-async fn send_replay(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, buf_data: &[u8]) {
+/// Send replay data followed by SYNC(`end`), the offset the data brings
+/// the client up to.
+async fn send_replay(
+    writer: &ClientWriter,
+    state: &Arc<RwLock<SharedState>>,
+    buf_data: &[u8],
+    end: f64,
+    caps: u32,
+    offset: &ClientOffset,
+) {
     // Send current dimensions before replay so clients render at the correct size.
     // Uses RESIZE (0x01) server→client: [type(1)][cols(2 BE)][rows(2 BE)].
     {
@@ -2382,7 +8622,8 @@ async fn send_replay(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, bu
     };
 
     if !cleaned.is_empty() {
-        if cleaned.len() >= GZIP_THRESHOLD {
+        let gzip_threshold = state.read().await.config.gzip_threshold;
+        if cleaned.len() >= gzip_threshold && accepts(caps, WS_MSG_BUFFER_REPLAY_GZ) {
             // Try gzip compression
             let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
             encoder.write_all(&cleaned).ok();
@@ -2412,21 +8653,30 @@ async fn send_replay(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, bu
             let mut w = writer.lock().await;
             let _ = w.write_all(&frame).await;
         }
+
+        // Resend the stored images the replayed placeholders refer to
+        if accepts(caps, WS_MSG_IMAGE) {
+            let s = state.read().await;
+            let mut w = writer.lock().await;
+            for image in s.images.referenced_in(&cleaned) {
+                let _ = w.write_all(&encode_frame(&image.message())).await;
+            }
+        }
     }
 
     // Send SYNC
     {
         let s = state.read().await;
-        let total = s.output_buffer.total_written;
         let mut sync_msg = vec![WS_MSG_SYNC; 9];
         sync_msg[0] = WS_MSG_SYNC;
-        sync_msg[1..9].copy_from_slice(&total.to_be_bytes());
+        sync_msg[1..9].copy_from_slice(&end.to_be_bytes());
         let frame = encode_frame(&sync_msg);
         let mut w = writer.lock().await;
         let _ = w.write_all(&frame).await;
+        offset.set(end);
 
         // Send current title
-        if let Some(ref title) = s.title {
+        if let Some(title) = s.title.as_ref().filter(|_| accepts(caps, WS_MSG_TITLE)) {
             let mut title_msg = vec![WS_MSG_TITLE];
             title_msg.extend_from_slice(title.as_bytes());
             let frame = encode_frame(&title_msg);
@@ -2434,37 +8684,40 @@ async fn send_replay(writer: &ClientWriter, state: &Arc<RwLock<SharedState>>, bu
         }
 
         // Send current activity state
-        let state_byte = if s.session_active { 0x01 } else { 0x00 };
-        let state_msg = vec![WS_MSG_SESSION_STATE, state_byte];
-        let frame = encode_frame(&state_msg);
-        let _ = w.write_all(&frame).await;
+        if accepts(caps, WS_MSG_SESSION_STATE) {
+            let state_byte = if s.session_active { 0x01 } else { 0x00 };
+            let state_msg = vec![WS_MSG_SESSION_STATE, state_byte];
+            let frame = encode_frame(&state_msg);
+            let _ = w.write_all(&frame).await;
+        }
+
+        // Send player position (--replay mode)
+        if let Some(playback) = s.playback.as_ref().filter(|_| accepts(caps, WS_MSG_PLAYBACK_STATE)) {
+            let _ = w.write_all(&playback.encode_frame()).await;
+        }
     }
 }
 
-async fn process_client_message(
-    msg_type: u8,
-    data: &[u8],
-    input_tx: &mpsc::Sender<Vec<u8>>,
-    resize_tx: &mpsc::Sender<(u16, u16)>,
-    detach_tx: &mpsc::Sender<()>,
-    clear_tx: &mpsc::Sender<()>,
-) {
+async fn process_client_message(msg_type: u8, data: &[u8], channels: &ClientChannels) {
     match msg_type {
         WS_MSG_DATA => {
-            let _ = input_tx.send(data.to_vec()).await;
+            let _ = channels.input_tx.send(data.to_vec()).await;
         }
-        WS_MSG_RESIZE => {
-            if data.len() >= 4 {
-                let new_cols = u16::from_be_bytes([data[0], data[1]]);
-                let new_rows = u16::from_be_bytes([data[2], data[3]]);
-                let _ = resize_tx.send((new_cols, new_rows)).await;
-            }
+        WS_MSG_RESIZE if data.len() >= 4 => {
+            let new_cols = u16::from_be_bytes([data[0], data[1]]);
+            let new_rows = u16::from_be_bytes([data[2], data[3]]);
+            let _ = channels.resize_tx.send((new_cols, new_rows)).await;
         }
         WS_MSG_DETACH => {
-            let _ = detach_tx.send(()).await;
+            let _ = channels.detach_tx.send(()).await;
         }
         WS_MSG_CLEAR_SCROLLBACK => {
-            let _ = clear_tx.send(()).await;
+            let _ = channels.clear_tx.send(()).await;
+        }
+        WS_MSG_PLAYBACK_CONTROL => {
+            if let (Some(tx), Some(control)) = (&channels.playback_tx, PlaybackControl::parse(data)) {
+                let _ = tx.send(control).await;
+            }
         }
         _ => {
             // Ignore other message types (RESUME handled separately)
@@ -2523,18 +8776,15 @@ fn days_to_date(days: i64) -> (i64, u32, u32) {
 /// Atomic JSON write: write to temp file then rename.
 fn atomic_write_json<T: Serialize>(path: &Path, value: &T) {
     let tmp_path = path.with_extension("json.tmp");
-    match serde_json::to_string(value) {
-        Ok(json) => {
-            if fs::write(&tmp_path, &json).is_ok() {
-                if fs::rename(&tmp_path, path).is_err() {
-                    // Fallback: direct write
-                    let _ = fs::write(path, &json);
-                }
-            } else {
+    if let Ok(json) = serde_json::to_string(value) {
+        if fs::write(&tmp_path, &json).is_ok() {
+            if fs::rename(&tmp_path, path).is_err() {
+                // Fallback: direct write
                 let _ = fs::write(path, &json);
             }
+        } else {
+            let _ = fs::write(path, &json);
         }
-        Err(_) => {}
     }
 }
 
@@ -2551,7 +8801,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_write_and_read() {
-        let mut buf = OutputBuffer::new(64);
+        let mut buf = OutputBuffer::new(64, ALT_BUFFER_CAP);
         buf.write(b"hello world");
         assert_eq!(buf.read(), b"hello world");
         assert_eq!(buf.size(), 11);
@@ -2560,7 +8810,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_wraps_around() {
-        let mut buf = OutputBuffer::new(16);
+        let mut buf = OutputBuffer::new(16, ALT_BUFFER_CAP);
         // Write 10 bytes
         buf.write(b"AAAAAAAAAA");
         // Write 10 more -- wraps around the 16-byte buffer
@@ -2582,7 +8832,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_read_from_delta() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"first chunk ");
         let offset_after_first = buf.total_written;
         buf.write(b"second chunk");
@@ -2593,7 +8843,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_read_from_fully_caught_up() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"data");
         let delta = buf.read_from(buf.total_written).unwrap();
         assert!(delta.is_empty());
@@ -2601,7 +8851,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_read_from_overwritten_returns_none() {
-        let mut buf = OutputBuffer::new(32);
+        let mut buf = OutputBuffer::new(32, ALT_BUFFER_CAP);
         buf.write(b"first write that fills buffer!!");
         let early_offset = 5.0;
         // Write enough to push beyond the buffer
@@ -2611,7 +8861,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_capacity_enforcement() {
-        let mut buf = OutputBuffer::new(64);
+        let mut buf = OutputBuffer::new(64, ALT_BUFFER_CAP);
         // Write more than capacity in one shot
         let big = vec![b'X'; 128];
         buf.write(&big);
@@ -2625,7 +8875,7 @@ mod tests {
 
     #[test]
     fn ring_buffer_empty_read() {
-        let buf = OutputBuffer::new(64);
+        let buf = OutputBuffer::new(64, ALT_BUFFER_CAP);
         assert!(buf.read().is_empty());
         assert_eq!(buf.size(), 0);
         assert_eq!(buf.total_written, 0.0);
@@ -2633,12 +8883,292 @@ mod tests {
 
     #[test]
     fn ring_buffer_exact_capacity_write() {
-        let mut buf = OutputBuffer::new(8);
+        let mut buf = OutputBuffer::new(8, ALT_BUFFER_CAP);
         buf.write(b"12345678");
         assert_eq!(buf.read_raw(), b"12345678");
         assert_eq!(buf.size(), 8);
     }
 
+    // ── Scrollback search tests ─────────────────────────────────────
+
+    fn search_request(query: &str) -> SearchRequest {
+        serde_json::from_str(&format!("{{\"id\":7,\"query\":{:?}}}", query)).unwrap()
+    }
+
+    fn search_buffer(req: &SearchRequest, buf: &OutputBuffer) -> SearchResults {
+        let (start, data) = buf.snapshot();
+        search_output(req, start, &data)
+    }
+
+    #[test]
+    fn strip_for_search_maps_text_to_stream_offsets() {
+        let stripped = strip_for_search(b"\x1b[31mred\x1b[0m\r\n\x1b]0;title\x07\xffok", 100.0);
+        assert_eq!(stripped.text, "red\nok");
+        assert_eq!(stripped.offset(0), 105.0);
+        assert_eq!(stripped.offset(2), 107.0);
+        assert_eq!(stripped.offset(3), 113.0, "newline after the dropped CR");
+        assert_eq!(stripped.offset(4), 125.0, "invalid UTF-8 is skipped");
+        assert_eq!(stripped.runs.len(), 3, "one run per stretch of kept bytes");
+    }
+
+    #[test]
+    fn search_output_finds_literal_matches_with_context() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"one\r\ntwo \x1b[1mNeedle\x1b[0m\r\nthree\r\nfour\r\n");
+        let results = search_buffer(&search_request("needle"), &buf);
+        assert_eq!(results.id, 7);
+        assert_eq!(results.matches.len(), 1);
+        let m = &results.matches[0];
+        assert_eq!((m.offset, m.length), (13.0, 6));
+        assert_eq!(m.line, "two Needle");
+        assert_eq!(m.before, ["one"]);
+        assert_eq!(m.after, ["three", "four"]);
+
+        let mut req = search_request("needle");
+        req.case_sensitive = true;
+        assert!(search_buffer(&req, &buf).matches.is_empty());
+    }
+
+    #[test]
+    fn search_output_supports_regex_limits_and_errors() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"err 1\nok\nerr 22\nerr 333\n");
+        let mut req = search_request(r"^err \d+$");
+        req.regex = true;
+        req.limit = 2;
+        let results = search_buffer(&req, &buf);
+        let lines: Vec<_> = results.matches.iter().map(|m| m.line.as_str()).collect();
+        assert_eq!(lines, ["err 1", "err 22"]);
+        assert!(results.truncated);
+
+        let literal = search_buffer(&search_request("d+"), &buf);
+        assert!(literal.matches.is_empty(), "literal queries are not regexes");
+
+        req.query = "(".to_string();
+        assert!(search_buffer(&req, &buf).error.is_some());
+    }
+
+    #[test]
+    fn search_output_covers_alt_screen() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"shell\r\n\x1b[?1049htui needle");
+        let results = search_buffer(&search_request("needle"), &buf);
+        assert_eq!(results.matches.len(), 1);
+        assert_eq!(results.matches[0].offset, 19.0);
+    }
+
+    #[test]
+    fn search_output_finds_matches_across_the_alt_screen_switch() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"shell nee\x1b[?1049hdle");
+        let results = search_buffer(&search_request("needle"), &buf);
+        assert_eq!(results.matches.len(), 1);
+        let m = &results.matches[0];
+        assert_eq!((m.offset, m.length), (6.0, 14));
+        assert_eq!(m.line, "shell needle");
+    }
+
+    #[test]
+    fn clip_line_keeps_window_around_match() {
+        let line = "x".repeat(2000) + "needle" + &"y".repeat(2000);
+        let clipped = clip_line(&line, 2000);
+        assert_eq!(clipped.len(), SEARCH_MAX_LINE);
+        assert!(clipped.contains("needle"));
+    }
+
+    // ── RANGE_REQUEST tests ─────────────────────────────────────────
+
+    #[test]
+    fn read_range_pages_back_through_lines() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"line1\r\nline2\r\nline3\r\n");
+        assert_eq!(buf.read_range(0.0, 0.0, 10), (14.0, b"line3\r\n".to_vec()));
+        assert_eq!(buf.read_range(0.0, 14.0, 10), (7.0, b"line2\r\n".to_vec()));
+        assert_eq!(buf.read_range(0.0, 7.0, 100), (0.0, b"line1\r\n".to_vec()));
+        assert_eq!(buf.read_range(3.0, 3.0, 100), (3.0, Vec::new()));
+    }
+
+    #[test]
+    fn read_range_never_splits_escape_sequences() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"ab\x1b[31mcd\x1b[0mef");
+        assert_eq!(buf.read_range(0.0, 0.0, 9), (9.0, b"\x1b[0mef".to_vec()));
+        assert_eq!(buf.read_range(0.0, 11.0, 100), (0.0, b"ab\x1b[31mcd".to_vec()));
+    }
+
+    #[test]
+    fn read_range_skips_partial_line_of_wrapped_ring() {
+        let mut buf = OutputBuffer::new(16, ALT_BUFFER_CAP);
+        buf.write(&[b"x".repeat(20), b"\nabc\n".to_vec()].concat());
+        assert_eq!(buf.read_range(0.0, 0.0, 100), (21.0, b"abc\n".to_vec()));
+    }
+
+    #[test]
+    fn read_range_pages_across_the_ring_wrap_point() {
+        let mut buf = OutputBuffer::new(16, ALT_BUFFER_CAP);
+        buf.write(b"0123456789
+aa
+bb
+cc
+");
+        // The ring holds offsets 4..20, stored wrapped at offset 16
+        assert_eq!(buf.read_range(0.0, 17.0, 6), (11.0, b"aa\nbb\n".to_vec()));
+        assert_eq!(buf.read_range(0.0, 0.0, 100), (11.0, b"aa\nbb\ncc\n".to_vec()));
+    }
+
+    #[test]
+    fn read_range_excludes_alt_screen() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"shell\n\x1b[?1049htui");
+        assert_eq!(buf.read_range(0.0, 0.0, 100), (0.0, b"shell\n\x1b[?1049h".to_vec()));
+    }
+
+    // ── Export tests ────────────────────────────────────────────────
+
+    #[test]
+    fn export_text_applies_line_editing_and_strips_escapes() {
+        let data = b"\x1b[1;31merror\x1b[0m: x\r\nprogress 10%\rprogress 100%\r\nab\x08c\x1b[K\r\n\x1b]0;t\x07a\tb\n";
+        let text = render_export(data, EXPORT_FORMAT_TEXT, "t");
+        assert_eq!(text, "error: x\nprogress 100%\nac\na       b\n");
+    }
+
+    #[test]
+    fn export_html_keeps_colors_and_escapes_text() {
+        let data = b"\x1b[1;31mfail\x1b[0m <a&b>\r\n\x1b[7mrev\x1b[38;5;196m!\x1b[0m";
+        let html = render_export(data, EXPORT_FORMAT_HTML, "build <1>");
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>build &lt;1&gt;</title>"));
+        assert!(html.contains("<span style=\"color:#cd0000;font-weight:bold;\">fail</span> &lt;a&amp;b&gt;\n"));
+        assert!(html.contains("<span style=\"color:#000000;background:#e5e5e5;\">rev</span>"));
+        assert!(html.contains("<span style=\"color:#000000;background:#ff0000;\">!</span>"));
+    }
+
+    #[test]
+    fn css_color_maps_palette_cube_and_grays() {
+        assert_eq!(css_color(Color::Default), None);
+        assert_eq!(css_color(Color::Indexed(9)).as_deref(), Some("#ff0000"));
+        assert_eq!(css_color(Color::Indexed(16)).as_deref(), Some("#000000"));
+        assert_eq!(css_color(Color::Indexed(196)).as_deref(), Some("#ff0000"));
+        assert_eq!(css_color(Color::Indexed(232)).as_deref(), Some("#080808"));
+        assert_eq!(css_color(Color::Rgb(1, 2, 255)).as_deref(), Some("#0102ff"));
+    }
+
+    // ── Command history tests ───────────────────────────────────────
+
+    #[test]
+    fn command_tracker_records_offsets_text_and_exit_codes() {
+        let mut output = OutputBuffer::new(4096, ALT_BUFFER_CAP);
+        let mut tracker = CommandTracker::default();
+        // `$ ` prompt at 0, command line at 2 (typed with a typo and a backspace)
+        output.write(b"$ mkae\x08\x08\x08ake\r\n");
+        assert!(tracker.mark(PromptMark::PromptStart, 0.0, 1000, &output).is_none());
+        assert!(tracker.mark(PromptMark::CommandStart, 2.0, 1000, &output).is_none());
+        let started = tracker.mark(PromptMark::OutputStart, 14.0, 1000, &output).unwrap();
+        assert_eq!(started.command.as_deref(), Some("make"));
+        assert_eq!(started.exit_code, None);
+        assert_eq!(tracker.last().unwrap().output_offset, Some(14.0));
+
+        output.write(b"error\r\n");
+        let finished = tracker.mark(PromptMark::CommandEnd(Some(2)), 21.0, 3500, &output).unwrap().clone();
+        assert_eq!((finished.id, finished.prompt_offset, finished.end_offset), (1, 0.0, Some(21.0)));
+        assert_eq!((finished.exit_code, finished.duration_ms), (Some(2), Some(2500)));
+
+        // Empty command line: D without C doesn't create a record
+        tracker.mark(PromptMark::PromptStart, 21.0, 4000, &output);
+        assert!(tracker.mark(PromptMark::CommandEnd(Some(0)), 23.0, 4000, &output).is_none());
+        assert_eq!(tracker.all().len(), 1);
+        assert_eq!(tracker.last(), Some(&finished));
+    }
+
+    #[test]
+    fn command_tracker_closes_unfinished_command_at_next_prompt() {
+        let output = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        let mut tracker = CommandTracker::default();
+        // No A or B: C alone still starts a command
+        tracker.mark(PromptMark::OutputStart, 5.0, 0, &output);
+        assert_eq!(tracker.all()[0].command, None);
+        tracker.mark(PromptMark::PromptStart, 9.0, 10, &output);
+        assert_eq!(tracker.history.len(), 1);
+        assert_eq!((tracker.history[0].end_offset, tracker.history[0].exit_code), (Some(9.0), None));
+        assert_eq!(tracker.current.as_ref().unwrap().id, 2);
+    }
+
+    // ── Link index tests ────────────────────────────────────────────
+
+    #[test]
+    fn link_index_records_text_and_offsets() {
+        let mut output = OutputBuffer::new(4096, ALT_BUFFER_CAP);
+        let mut index = LinkIndex::default();
+        output.write(b"$ ls\r\n");
+        let base = output.total_written;
+        let data = b"\x1b]8;;file:///a\x07\x1b[1ma\x1b[0m\x1b]8;;\x07 \x1b]8;;file:///b\x07b\r\n";
+        output.write(data);
+        for (pos, event) in scan_osc8_links(data) {
+            index.apply(base + pos as f64, &event, base, &output);
+        }
+        assert_eq!(index.links.len(), 1);
+        assert_eq!(index.links[0], LinkRecord { uri: "file:///a".into(), text: "a".into(), offset: 6.0 });
+        // The second link is still open; the next one closes it
+        let base = output.total_written;
+        output.write(b"\x1b]8;;https://x.io\x07");
+        index.apply(base, &LinkEvent::Open { uri: "https://x.io".into(), text_start: 19 }, base, &output);
+        assert_eq!(index.links[1].text, "b");
+        assert_eq!(index.open.as_ref().unwrap().0, "https://x.io");
+    }
+
+    // ── Watch tests ─────────────────────────────────────────────────
+
+    fn watcher(specs: &[&str]) -> Watcher {
+        let mut watcher = Watcher::default();
+        watcher.set_rules(specs.iter().map(|s| WatchRule::parse_flag(s)).collect()).unwrap();
+        watcher
+    }
+
+    #[test]
+    fn watch_rule_parses_action_prefix() {
+        assert_eq!(WatchRule::parse_flag("FAILED").action, WatchAction::Notify);
+        assert_eq!(WatchRule::parse_flag("exit:^done$").pattern, "^done$");
+        assert_eq!(WatchRule::parse_flag("mark:a:b").action, WatchAction::Mark);
+        assert_eq!(WatchRule::parse_flag("mark:a:b").pattern, "a:b");
+        assert_eq!(WatchRule::parse_flag("(?i)error: x").pattern, "(?i)error: x");
+    }
+
+    #[test]
+    fn watcher_matches_stripped_lines_across_chunks() {
+        let mut w = watcher(&["FAIL(ED)?", "mark:ok$"]);
+        let now = Instant::now();
+        let hits = w.feed(b"test a ... ok\r\ntest b ... \x1b[3", 100.0, now);
+        assert_eq!(hits.marks.len(), 1);
+        assert_eq!(hits.marks[0].offset, 100.0);
+        assert!(hits.notifications.is_empty());
+        // The split CSI doesn't leak into the text; the line fires once
+        let hits = w.feed(b"1mFAIL", 129.0, now);
+        assert_eq!(hits.notifications, vec!["test b ... FAIL".to_string()]);
+        let hits = w.feed(b"ED\x1b[0m\n", 135.0, now);
+        assert!(hits.notifications.is_empty());
+        assert!(w.line.is_empty());
+    }
+
+    #[test]
+    fn watcher_fires_on_unterminated_prompt_and_honours_cooldown() {
+        let mut w = watcher(&["exit:password:"]);
+        let now = Instant::now();
+        assert!(w.feed(b"[sudo] password: ", 0.0, now).exit);
+        assert!(!w.feed(b"\npassword: ", 17.0, now).exit);
+        assert!(w.feed(b"\npassword: ", 28.0, now + WATCH_COOLDOWN).exit);
+    }
+
+    #[test]
+    fn watcher_rejects_bad_rules_and_keeps_old_ones() {
+        let mut w = watcher(&["x"]);
+        assert!(w.set_rules(vec![WatchRule::parse_flag("(")]).is_err());
+        assert!(w.set_rules(vec![WatchRule::parse_flag("")]).is_err());
+        assert_eq!(w.rules(), vec![WatchRule::parse_flag("x")]);
+        let rule: WatchRule = serde_json::from_str(r#"{"pattern":"y","action":"mark"}"#).unwrap();
+        assert_eq!(rule.action, WatchAction::Mark);
+        assert_eq!(rule.message, None);
+    }
+
     // ── sanitize_start tests ────────────────────────────────────────
 
     #[test]
@@ -2934,7 +9464,7 @@ mod tests {
         // Minimal inline image: ESC ] 1337 ; File=inline=1 : <base64> BEL
         // base64 of "PNG" = "UE5H"
         let input = b"before\x1b]1337;File=inline=1:UE5H\x07after";
-        let (cleaned, images) = extract_osc1337_images(input);
+        let (cleaned, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert_eq!(images.len(), 1);
         assert_eq!(images[0].data, b"PNG");
         // Cleaned should have before, placeholder, and after
@@ -2948,7 +9478,7 @@ mod tests {
     fn osc1337_inline_image_st() {
         // Using ESC\ terminator
         let input = b"\x1b]1337;File=inline=1:UE5H\x1b\\";
-        let (_, images) = extract_osc1337_images(input);
+        let (_, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert_eq!(images.len(), 1);
         assert_eq!(images[0].data, b"PNG");
     }
@@ -2957,7 +9487,7 @@ mod tests {
     fn osc1337_not_inline_stripped() {
         // Non-inline image (inline=0 or absent) — should NOT be extracted
         let input = b"\x1b]1337;File=name=test.png:UE5H\x07";
-        let (cleaned, images) = extract_osc1337_images(input);
+        let (cleaned, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert!(images.is_empty());
         // Non-inline sequence is preserved in output (passed through)
         assert_eq!(cleaned, input.to_vec());
@@ -2966,7 +9496,7 @@ mod tests {
     #[test]
     fn osc1337_with_name_and_size() {
         let input = b"\x1b]1337;File=name=chart.png;size=100;inline=1:UE5H\x07";
-        let (_, images) = extract_osc1337_images(input);
+        let (_, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert_eq!(images.len(), 1);
         assert_eq!(images[0].data, b"PNG");
     }
@@ -2974,7 +9504,7 @@ mod tests {
     #[test]
     fn osc1337_no_match() {
         let input = b"just plain text";
-        let (cleaned, images) = extract_osc1337_images(input);
+        let (cleaned, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert!(images.is_empty());
         assert_eq!(cleaned, input.to_vec());
     }
@@ -2983,7 +9513,7 @@ mod tests {
     fn osc1337_incomplete_preserved() {
         // Incomplete OSC 1337 (no terminator) — should be preserved
         let input = b"\x1b]1337;File=inline=1:UE5H";
-        let (cleaned, images) = extract_osc1337_images(input);
+        let (cleaned, images) = extract_osc1337_images(input, IMAGE_MAX_SIZE);
         assert!(images.is_empty());
         assert_eq!(cleaned, input.to_vec());
     }
@@ -2992,8 +9522,8 @@ mod tests {
     fn osc1337_unique_ids() {
         let input1 = b"\x1b]1337;File=inline=1:UE5H\x07";
         let input2 = b"\x1b]1337;File=inline=1:UE5H\x07";
-        let (_, imgs1) = extract_osc1337_images(input1);
-        let (_, imgs2) = extract_osc1337_images(input2);
+        let (_, imgs1) = extract_osc1337_images(input1, IMAGE_MAX_SIZE);
+        let (_, imgs2) = extract_osc1337_images(input2, IMAGE_MAX_SIZE);
         assert_ne!(imgs1[0].id, imgs2[0].id);
     }
 
@@ -3016,7 +9546,7 @@ mod tests {
     #[test]
     fn osc_extractor_single_chunk() {
         // Complete OSC 9 in one chunk works the same as before
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
         let result = ext.feed(b"before\x1b]9;hello\x07after");
         assert_eq!(result.notifications.len(), 1);
         assert_eq!(result.notifications[0], "hello");
@@ -3026,7 +9556,7 @@ mod tests {
     #[test]
     fn osc_extractor_split_osc9() {
         // OSC 9 notification split across two reads
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
 
         // First read: starts the OSC but no terminator
         let r1 = ext.feed(b"before\x1b]9;hel");
@@ -3043,7 +9573,7 @@ mod tests {
     #[test]
     fn osc_extractor_split_osc52() {
         // OSC 52 clipboard split across two reads
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
 
         // "hello" base64 = "aGVsbG8="
         let r1 = ext.feed(b"data\x1b]52;c;aGVs");
@@ -3056,10 +9586,31 @@ mod tests {
         assert_eq!(r2.cleaned, b"more");
     }
 
+    #[test]
+    fn osc52_queries_are_answered_only_when_allowed() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let r = ext.feed(b"a\x1b]52;c;?\x07b\x1b]52;p;?\x1b\\c");
+        assert_eq!(r.cleaned, b"abc");
+        assert_eq!(
+            r.clipboard_queries,
+            vec![
+                ClipboardQuery { selection: "c".to_string(), terminator: "\x07" },
+                ClipboardQuery { selection: "p".to_string(), terminator: "\x1b\\" },
+            ]
+        );
+
+        let mut clipboard = Clipboard::default();
+        assert_eq!(clipboard.answer(&r.clipboard_queries[0]), None);
+        clipboard.allow_read = true;
+        assert_eq!(clipboard.answer(&r.clipboard_queries[0]).unwrap(), b"\x1b]52;c;\x07");
+        clipboard.text = Some("hello".to_string());
+        assert_eq!(clipboard.answer(&r.clipboard_queries[1]).unwrap(), b"\x1b]52;p;aGVsbG8=\x1b\\");
+    }
+
     #[test]
     fn osc_extractor_split_osc1337() {
         // OSC 1337 image split across two reads
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
 
         // "PNG" base64 = "UE5H"
         let r1 = ext.feed(b"text\x1b]1337;File=inline=1:UE");
@@ -3074,10 +9625,170 @@ mod tests {
         assert!(cleaned_str.ends_with("rest"));
     }
 
+    #[test]
+    fn kitty_graphics_direct_png() {
+        // "PNG" base64 = "UE5H"
+        let mut ext = OscExtractor::new(&Config::default());
+        let r = ext.feed(b"a\x1b_Ga=T,f=100;UE5H\x1b\\b");
+        assert_eq!(r.inline_images.len(), 1);
+        assert_eq!(r.inline_images[0].data, b"PNG");
+        assert!(r.replies.is_empty()); // no i= → no reply
+        let cleaned = String::from_utf8_lossy(&r.cleaned);
+        assert!(cleaned.starts_with("a\x1b[2;3m[image: img-"));
+        assert!(cleaned.ends_with("]\x1b[0m\r\nb"));
+    }
+
+    #[test]
+    fn kitty_graphics_chunked_across_reads() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let r1 = ext.feed(b"\x1b_Ga=T,f=100,i=7,m=1;UE\x1b\\\x1b_Gm=");
+        assert!(r1.inline_images.is_empty());
+        assert!(r1.cleaned.is_empty());
+        let r2 = ext.feed(b"0;5H\x1b\\done");
+        assert_eq!(r2.inline_images.len(), 1);
+        assert_eq!(r2.inline_images[0].data, b"PNG");
+        assert_eq!(r2.replies, vec![b"\x1b_Gi=7;OK\x1b\\".to_vec()]);
+        assert!(String::from_utf8_lossy(&r2.cleaned).ends_with("\r\ndone"));
+    }
+
+    #[test]
+    fn kitty_graphics_raw_rgb_encodes_png() {
+        // One red 1x1 RGB pixel: [ff 00 00] = "/wAA"
+        let mut kitty = KittyGraphics::new(IMAGE_MAX_SIZE);
+        let (_, images, _) = kitty.extract(b"\x1b_Ga=T,f=24,s=1,v=1;/wAA\x1b\\");
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].mime, "image/png");
+        let png = &images[0].data;
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 1, 0, 0, 0, 1]);
+
+        // Dimensions that don't match the data are an error
+        let (cleaned, images, replies) = kitty.extract(b"\x1b_Ga=T,f=24,s=2,v=1,i=3;/wAA\x1b\\");
+        assert!(images.is_empty() && cleaned.is_empty());
+        assert_eq!(replies, vec![b"\x1b_Gi=3;EINVAL:pixel data does not match s and v\x1b\\".to_vec()]);
+    }
+
+    #[test]
+    fn kitty_graphics_query_transmit_and_put() {
+        let mut kitty = KittyGraphics::new(IMAGE_MAX_SIZE);
+        // Support probe: answered, nothing displayed
+        let (cleaned, images, replies) = kitty.extract(b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\");
+        assert!(cleaned.is_empty() && images.is_empty());
+        assert_eq!(replies, vec![b"\x1b_Gi=31;OK\x1b\\".to_vec()]);
+        // Other media are refused
+        let (_, _, replies) = kitty.extract(b"\x1b_Gi=32,a=q,t=f;L3RtcC94\x1b\\");
+        assert_eq!(replies, vec![b"\x1b_Gi=32;EINVAL:only direct transmission is supported\x1b\\".to_vec()]);
+
+        // a=t stores quietly, a=p displays it, unknown ids are reported
+        let (_, images, replies) = kitty.extract(b"\x1b_Ga=t,f=100,i=5,q=1;UE5H\x1b\\");
+        assert!(images.is_empty() && replies.is_empty());
+        let (_, images, _) = kitty.extract(b"\x1b_Ga=p,i=5\x1b\\\x1b_Ga=p,i=5\x1b\\");
+        assert_eq!(images.len(), 2);
+        assert_ne!(images[0].id, images[1].id);
+        let (_, images, replies) = kitty.extract(b"\x1b_Ga=p,i=6\x1b\\");
+        assert!(images.is_empty());
+        assert_eq!(replies, vec![b"\x1b_Gi=6;ENOENT:no such image\x1b\\".to_vec()]);
+    }
+
+    #[test]
+    fn kitty_graphics_leaves_other_apc_alone() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let r = ext.feed(b"\x1b_Xother\x1b\\text");
+        assert_eq!(r.cleaned, b"\x1b_Xother\x1b\\text");
+        let r = ext.feed(b"split\x1b_Ga=T,f=10");
+        assert_eq!(r.cleaned, b"split");
+    }
+
+    #[test]
+    fn sixel_decodes_colors_repeats_and_bands() {
+        // 3 red pixels in the top row, then one HLS blue (hue 0) pixel filling
+        // the first column of the second band
+        let data = b"#1;2;100;0;0#1!3@-#2;1;0;50;100#2~";
+        let (width, height, rgba) = decode_sixel(data, false, IMAGE_MAX_SIZE / 4).unwrap();
+        assert_eq!((width, height), (3, 12));
+        let px = |x: usize, y: usize| &rgba[(y * width + x) * 4..(y * width + x) * 4 + 4];
+        assert_eq!(px(2, 0), [255, 0, 0, 255]);
+        assert_eq!(px(0, 1), [0, 0, 0, 255]); // background
+        assert_eq!(px(0, 6), [0, 0, 255, 255]);
+        assert_eq!(px(0, 11), [0, 0, 255, 255]);
+        assert_eq!(px(1, 6), [0, 0, 0, 255]);
+
+        let (_, _, rgba) = decode_sixel(b"#1;2;100;0;0#1@\"1;1;2;1", true, IMAGE_MAX_SIZE / 4).unwrap();
+        assert_eq!(&rgba[4..8], [0, 0, 0, 0]); // raster width, unpainted
+        assert!(decode_sixel(b"#1;2;100;0;0", false, IMAGE_MAX_SIZE / 4).is_none());
+        assert!(decode_sixel(b"!99999999~", false, IMAGE_MAX_SIZE / 4).is_none());
+    }
+
+    #[test]
+    fn osc_extractor_sixel_split_across_reads() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let r1 = ext.feed(b"plot\x1bP0;1");
+        assert_eq!(r1.cleaned, b"plot");
+        let r2 = ext.feed(b";0q\"1;1;4;6#0;2;0;100;0#0!4~");
+        assert!(r2.cleaned.is_empty() && r2.inline_images.is_empty());
+        let r3 = ext.feed(b"\x1b\\done");
+        assert_eq!(r3.inline_images.len(), 1);
+        let png = &r3.inline_images[0].data;
+        assert_eq!(&png[16..24], &[0, 0, 0, 4, 0, 0, 0, 6]);
+        assert!(String::from_utf8_lossy(&r3.cleaned).ends_with("\r\ndone"));
+
+        // Other DCS sequences are left alone and not held back
+        let r = ext.feed(b"\x1bP+q544e\x1b\\\x1bP$q");
+        assert_eq!(r.cleaned, b"\x1bP+q544e\x1b\\\x1bP$q");
+    }
+
+    #[test]
+    fn osc_extractor_flushes_oversized_sixel() {
+        let config = Config {
+            image_max_size: 64,
+            ..Config::default()
+        };
+        let mut ext = OscExtractor::new(&config);
+        let cap = ext.holdback_cap(Holdback::Sixel);
+        assert_eq!(ext.feed(b"\x1bPq#0;2;0;100;0").cleaned, b"");
+        let chunk = vec![b'~'; 1024];
+        let mut fed = 0;
+        let flushed = loop {
+            let r = ext.feed(&chunk);
+            fed += chunk.len();
+            if !r.cleaned.is_empty() {
+                break r.cleaned;
+            }
+        };
+        assert!(fed > cap - 1024 && fed <= cap + 1024);
+        assert!(flushed.starts_with(b"\x1bPq#0;2;0;100;0~"));
+        assert_eq!(flushed.len(), 15 + fed, "flushed unchanged");
+    }
+
+    #[test]
+    fn image_store_evicts_and_finds_placeholders() {
+        let mut store = ImageStore::default();
+        let images: Vec<InlineImage> = (0..IMAGE_STORE_MAX + 1).map(|_| InlineImage::new(b"PNG".to_vec())).collect();
+        let first = images[0].placeholder();
+        let second = images[1].placeholder();
+        let last = images[IMAGE_STORE_MAX].placeholder();
+        for image in images {
+            store.insert(image);
+        }
+        assert_eq!(store.images.len(), IMAGE_STORE_MAX);
+        assert_eq!(store.bytes, IMAGE_STORE_MAX * 3);
+
+        // The evicted first image is skipped; repeats are sent once
+        let replay = format!("{first}{last}text{second}{last}");
+        let found: Vec<String> = store.referenced_in(replay.as_bytes()).iter().map(|i| i.id.clone()).collect();
+        assert_eq!(found.len(), 2);
+        assert!(last.contains(&found[0]) && second.contains(&found[1]));
+
+        let (headers, data) = store.handoff();
+        let restored = ImageStore::from_handoff(headers, &data.concat());
+        assert_eq!(restored.images.len(), IMAGE_STORE_MAX);
+        assert_eq!(restored.get(&found[0]).unwrap().data, b"PNG");
+    }
+
     #[test]
     fn osc_extractor_no_partial() {
         // Plain data with no OSC sequences
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
         let r = ext.feed(b"just plain text");
         assert!(r.notifications.is_empty());
         assert!(r.clipboard_texts.is_empty());
@@ -3089,7 +9800,7 @@ mod tests {
     fn osc_extractor_esc_at_end() {
         // Lone ESC at end of data — passed through (not buffered).
         // xterm.js handles split escape sequences across write() calls.
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
         let r1 = ext.feed(b"data\x1b");
         assert_eq!(r1.cleaned, b"data\x1b");
 
@@ -3101,7 +9812,7 @@ mod tests {
     #[test]
     fn osc_extractor_complete_then_partial() {
         // One complete OSC followed by a partial in the same chunk
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
         let r1 = ext.feed(b"\x1b]9;first\x07mid\x1b]9;sec");
         assert_eq!(r1.notifications.len(), 1);
         assert_eq!(r1.notifications[0], "first");
@@ -3120,7 +9831,7 @@ mod tests {
         // starts a new partial OSC (ESC ] from pending would have joined
         // it in the old behavior). Now r2 passes through as-is and r3
         // completes independently.
-        let mut ext = OscExtractor::new();
+        let mut ext = OscExtractor::new(&Config::default());
         let r1 = ext.feed(b"a\x1b");
         assert_eq!(r1.cleaned, b"a\x1b");
 
@@ -3132,6 +9843,35 @@ mod tests {
         assert_eq!(r3.cleaned, b"lo\x07b");
     }
 
+    #[test]
+    fn osc_extractor_osc133_marks_positions_in_cleaned_output() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let r1 = ext.feed(b"\x1b]133;A\x07$ \x1b]133;B\x1b\\ls\r\n\x1b]9;hi\x07\x1b]133;C\x07out\r\n\x1b]133;D;");
+        assert_eq!(r1.cleaned, b"$ ls\r\nout\r\n");
+        assert_eq!(
+            r1.prompt_marks,
+            vec![(0, PromptMark::PromptStart), (2, PromptMark::CommandStart), (6, PromptMark::OutputStart)]
+        );
+        let r2 = ext.feed(b"127\x07\x1b]133;D\x07\x1b]133;Z\x07");
+        assert!(r2.cleaned.is_empty());
+        assert_eq!(r2.prompt_marks, vec![(0, PromptMark::CommandEnd(Some(127))), (0, PromptMark::CommandEnd(None))]);
+    }
+
+    #[test]
+    fn osc_extractor_indexes_osc8_links_without_stripping() {
+        let mut ext = OscExtractor::new(&Config::default());
+        let data = b"see \x1b]8;id=1;file:///a;b\x1b\\a;b\x1b]8;;\x1b\\ and \x1b]8;;https://x.io\x07";
+        let r1 = ext.feed(data);
+        assert_eq!(r1.cleaned, data);
+        let open = LinkEvent::Open { uri: "file:///a;b".into(), text_start: 26 };
+        assert_eq!(r1.links, vec![(4, open), (29, LinkEvent::Close), (41, LinkEvent::Open { uri: "https://x.io".into(), text_start: 59 })]);
+        // A split sequence is indexed once complete
+        let r2 = ext.feed(b"x.io\x1b]8");
+        assert!(r2.links.is_empty());
+        let r3 = ext.feed(b";;\x07");
+        assert_eq!(r3.links, vec![(0, LinkEvent::Close)]);
+    }
+
     #[test]
     fn find_trailing_partial_osc_complete() {
         // Complete OSC — no partial
@@ -3144,6 +9884,33 @@ mod tests {
         assert_eq!(find_trailing_partial_osc(b"before\x1b]9;msg"), Some(6));
     }
 
+    #[test]
+    fn find_trailing_partial_osc_holds_back_only_kitty_apcs() {
+        assert_eq!(find_trailing_partial_osc(b"img\x1b_Ga=T;AAAA"), Some(3));
+        assert_eq!(find_trailing_partial_osc(b"img\x1b_"), Some(3));
+        assert_eq!(find_trailing_partial_osc(b"cat\x1b_binary junk"), None);
+    }
+
+    #[test]
+    fn osc_extractor_flushes_sequences_over_the_holdback_cap() {
+        let config = Config {
+            image_max_size: 3,
+            ..Config::default()
+        };
+        let mut ext = OscExtractor::new(&config);
+        let cap = ext.holdback_cap(Holdback::Kitty);
+        let chunk = vec![b'A'; cap / 2];
+        assert_eq!(ext.feed(b"\x1b_Ga=T;").cleaned, b"");
+        assert_eq!(ext.feed(&chunk).cleaned, b"", "held while under the cap");
+        let r = ext.feed(&chunk);
+        assert_eq!(r.cleaned, [&b"\x1b_Ga=T;"[..], &chunk, &chunk].concat(), "flushed unchanged");
+        assert_eq!(ext.feed(b"\x1b\\after").cleaned, b"\x1b\\after");
+
+        // Data that arrives over the cap in one read isn't held at all
+        let big = [&b"x\x1b_G;"[..], &vec![b'A'; cap]].concat();
+        assert_eq!(ext.feed(&big).cleaned, big);
+    }
+
     #[test]
     fn find_trailing_partial_osc_lone_esc() {
         // Lone ESC at end — no longer buffered (passed through)
@@ -3245,6 +10012,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::same_item_push)]
     fn gzip_large_data_round_trip() {
         // Generate repetitive data that compresses well
         let mut original = Vec::with_capacity(8192);
@@ -3357,6 +10125,20 @@ mod tests {
         assert_eq!(WS_MSG_SESSION_METRICS, 0x14);
         assert_eq!(WS_MSG_IMAGE, 0x17);
         assert_eq!(WS_MSG_CLEAR_SCROLLBACK, 0x23);
+        assert_eq!(WS_MSG_PLAYBACK_CONTROL, 0x24);
+        assert_eq!(WS_MSG_PLAYBACK_STATE, 0x25);
+        assert_eq!(WS_MSG_AUTH, 0x26);
+        assert_eq!(WS_MSG_HELLO, 0x27);
+        assert_eq!(WS_MSG_SEARCH_REQUEST, 0x28);
+        assert_eq!(WS_MSG_SEARCH_RESULTS, 0x29);
+        assert_eq!(WS_MSG_RANGE_REQUEST, 0x2a);
+        assert_eq!(WS_MSG_RANGE_DATA, 0x2b);
+        assert_eq!(WS_MSG_EXPORT, 0x2c);
+        assert_eq!(WS_MSG_WATCH, 0x2d);
+        assert_eq!(WS_MSG_COMMANDS, 0x2e);
+        assert_eq!(WS_MSG_LINKS, 0x2f);
+        assert_eq!(WS_MSG_IMAGE_REQUEST, 0x30);
+        assert_eq!(WS_MSG_CLIPBOARD_SET, 0x31);
     }
 
     // ── SessionMeta serialization tests ─────────────────────────────
@@ -3386,6 +10168,11 @@ mod tests {
             bps5: 0.0,
             bps15: 0.0,
             foreground_process: None,
+            scrollback_dir: None,
+            ws_listen: None,
+            metrics_listen: None,
+            watch_marks: Vec::new(),
+            last_command: None,
         };
         let json = serde_json::to_string(&meta).unwrap();
         // camelCase fields
@@ -3426,6 +10213,11 @@ mod tests {
             bps5: 50.0,
             bps15: 25.0,
             foreground_process: None,
+            scrollback_dir: None,
+            ws_listen: None,
+            metrics_listen: None,
+            watch_marks: Vec::new(),
+            last_command: None,
         };
         let json = serde_json::to_string(&meta).unwrap();
         assert!(json.contains("\"exitCode\":0"));
@@ -3459,6 +10251,11 @@ mod tests {
             bps5: 0.0,
             bps15: 0.0,
             foreground_process: Some("vim".into()),
+            scrollback_dir: None,
+            ws_listen: None,
+            metrics_listen: None,
+            watch_marks: Vec::new(),
+            last_command: None,
         };
         let json = serde_json::to_string(&meta).unwrap();
         assert!(json.contains("\"foregroundProcess\":\"vim\""));
@@ -3496,6 +10293,23 @@ mod tests {
         assert!(cwd.is_none(), "should return None for invalid PID");
     }
 
+    #[test]
+    fn get_process_usage_reads_current_process() {
+        let usage = get_process_usage(std::process::id() as libc::pid_t).expect("own usage");
+        assert!(usage.rss_bytes > 0, "{:?}", usage);
+        assert!(get_process_usage(-1).is_none());
+    }
+
+    #[test]
+    fn parse_proc_stat_counts_fields_after_the_command_name() {
+        // comm "(a) b)" has a space and a parenthesis of its own
+        let stat = "42 ((a) b)) S 1 42 42 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 1 0 100 10000000 300 \
+                    18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 17 0 0 0 0 0 0";
+        let usage = parse_proc_stat(stat, 100.0, 4096).unwrap();
+        assert_eq!(usage, ProcessUsage { cpu_seconds: 3.0, rss_bytes: 300 * 4096 });
+        assert!(parse_proc_stat("42 (sh) S 1", 100.0, 4096).is_none(), "truncated");
+    }
+
     // ── days_to_date tests ──────────────────────────────────────────
 
     #[test]
@@ -3673,7 +10487,7 @@ mod tests {
 
     #[test]
     fn alt_screen_enter_exit_basic() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"normal text");
         buf.write(b"\x1b[?1049h"); // enter alt
         assert!(buf.in_alt_screen);
@@ -3692,7 +10506,7 @@ mod tests {
 
     #[test]
     fn alt_screen_read_includes_alt_content() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"main ");
         buf.write(b"\x1b[?1049h");
         buf.write(b"alt stuff");
@@ -3705,7 +10519,7 @@ mod tests {
 
     #[test]
     fn alt_screen_notify_resize_clears_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"\x1b[?1049h");
         buf.write(b"old redraw content that should be discarded");
         let old_total = buf.total_written;
@@ -3725,7 +10539,7 @@ mod tests {
 
     #[test]
     fn alt_screen_notify_resize_noop_when_normal() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"normal text");
         let before = buf.total_written;
         buf.notify_resize();
@@ -3735,7 +10549,7 @@ mod tests {
 
     #[test]
     fn alt_screen_total_written_monotonic() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"hello"); // 5
         assert_eq!(buf.total_written, 5.0);
         buf.write(b"\x1b[?1049h"); // 8 bytes for the sequence
@@ -3750,7 +10564,7 @@ mod tests {
 
     #[test]
     fn alt_screen_read_from_delta_in_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"main stuff");
         let offset = buf.total_written;
         buf.write(b"\x1b[?1049h");
@@ -3764,7 +10578,7 @@ mod tests {
 
     #[test]
     fn alt_screen_read_from_within_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"\x1b[?1049h");
         buf.write(b"first alt");
         let offset = buf.total_written;
@@ -3776,7 +10590,7 @@ mod tests {
 
     #[test]
     fn alt_screen_read_from_caught_up() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"\x1b[?1049h");
         buf.write(b"content");
         let delta = buf.read_from(buf.total_written).unwrap();
@@ -3785,7 +10599,7 @@ mod tests {
 
     #[test]
     fn alt_screen_size_includes_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"main"); // 4
         buf.write(b"\x1b[?1049h"); // 8
         buf.write(b"alt!"); // 4
@@ -3795,7 +10609,7 @@ mod tests {
 
     #[test]
     fn alt_screen_size_normal_no_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"just normal");
         assert_eq!(buf.size(), 11);
         assert_eq!(buf.alt_buf.len(), 0);
@@ -3803,7 +10617,7 @@ mod tests {
 
     #[test]
     fn alt_buffer_cap_enforced() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"\x1b[?1049h");
         // Write more than ALT_BUFFER_CAP
         let big = vec![b'X'; ALT_BUFFER_CAP + 1000];
@@ -3813,7 +10627,7 @@ mod tests {
 
     #[test]
     fn alt_screen_multiple_resizes() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"\x1b[?1049h");
         buf.write(b"redraw1");
         buf.notify_resize();
@@ -3830,7 +10644,7 @@ mod tests {
 
     #[test]
     fn alt_screen_enter_exit_enter() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"normal");
         buf.write(b"\x1b[?1049h");
         buf.write(b"alt1");
@@ -3850,7 +10664,7 @@ mod tests {
     #[test]
     fn alt_screen_cross_write_boundary() {
         // Split the ESC[?1049h sequence across two writes
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"before\x1b[?10");
         assert!(buf.scanner.is_mid_sequence());
         buf.write(b"49hafter");
@@ -3863,7 +10677,7 @@ mod tests {
     #[test]
     fn fast_path_no_esc_bytes() {
         // Plain text with no ESC bytes should take fast path
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"plain text no escapes here");
         assert_eq!(buf.total_written, 26.0);
         assert_eq!(buf.read(), b"plain text no escapes here");
@@ -3872,7 +10686,7 @@ mod tests {
 
     #[test]
     fn read_truncates_at_screen_clear_normal_mode() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         // Even in normal mode, read() truncates at last screen clear
         // (this is how Claude Code works — no alt screen, just clearTerminal())
         buf.write(b"before\x1b[2Jafter");
@@ -3886,7 +10700,7 @@ mod tests {
     #[test]
     fn read_truncates_at_screen_clear_alt_mode() {
         // Alt screen with ED(2) — read() truncates combined output
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"main-content\x1b[?1049h");
         buf.write(b"frame1\x1b[2J\x1b[3J\x1b[Hframe2");
         let replay = buf.read();
@@ -3901,7 +10715,7 @@ mod tests {
     fn read_truncates_at_last_clear_terminal() {
         // Simulates Claude Code (ink) behavior: no alt screen, just
         // clearTerminal() = ESC[2J ESC[3J ESC[H on every render
-        let mut buf = OutputBuffer::new(4096);
+        let mut buf = OutputBuffer::new(4096, ALT_BUFFER_CAP);
         buf.write(b"shell prompt stuff\r\n$ claude\r\n");
         // First render: clearTerminal + frame
         buf.write(b"\x1b[2J\x1b[3J\x1b[Hframe1-full-screen-content");
@@ -3922,7 +10736,7 @@ mod tests {
     #[test]
     fn read_no_truncation_without_clears() {
         // Plain shell with no screen clears — full replay preserved
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"$ ls\r\nfile1 file2\r\n$ pwd\r\n/home/user\r\n");
         assert_eq!(buf.read(), b"$ ls\r\nfile1 file2\r\n$ pwd\r\n/home/user\r\n");
     }
@@ -3930,7 +10744,7 @@ mod tests {
     #[test]
     fn read_from_delta_not_truncated() {
         // Delta replay (read_from) should NOT truncate — client is live
-        let mut buf = OutputBuffer::new(4096);
+        let mut buf = OutputBuffer::new(4096, ALT_BUFFER_CAP);
         buf.write(b"before");
         let offset = buf.total_written;
         buf.write(b"\x1b[2Jafter-clear");
@@ -3939,10 +10753,30 @@ mod tests {
         assert_eq!(delta, b"\x1b[2Jafter-clear");
     }
 
+    #[test]
+    fn slice_matches_read_from_across_wrap_and_alt_screen() {
+        let mut buf = OutputBuffer::new(16, ALT_BUFFER_CAP);
+        buf.write(b"0123456789abcdefghij"); // wraps
+        let total = buf.total_written;
+        for start in 4..20 {
+            for end in start..=20 {
+                let expected = buf.read_from(start as f64).unwrap()[..end - start].to_vec();
+                assert_eq!(buf.slice(start as f64, end as f64).unwrap(), expected);
+            }
+        }
+        assert!(buf.slice(3.0, 10.0).is_none());
+        assert_eq!(buf.slice(18.0, total + 10.0).unwrap(), b"ij");
+
+        buf.write(b"\x1b[?1049hALT");
+        let alt_start = buf.alt_content_start;
+        assert_eq!(buf.slice(18.0, alt_start + 2.0).unwrap(), b"ij\x1b[?1049hAL");
+        assert_eq!(buf.slice(alt_start + 1.0, buf.total_written).unwrap(), b"LT");
+    }
+
     #[test]
     fn existing_ring_buffer_unchanged_without_alt() {
         // Verify existing ring buffer behavior is preserved
-        let mut buf = OutputBuffer::new(16);
+        let mut buf = OutputBuffer::new(16, ALT_BUFFER_CAP);
         buf.write(b"AAAAAAAAAA"); // 10
         buf.write(b"BBBBBBBBBB"); // 10 more, wraps
         let data = buf.read_raw();
@@ -3953,7 +10787,7 @@ mod tests {
 
     #[test]
     fn existing_read_from_delta_without_alt() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"first chunk ");
         let offset = buf.total_written;
         buf.write(b"second chunk");
@@ -3963,7 +10797,7 @@ mod tests {
 
     #[test]
     fn existing_read_from_overwritten() {
-        let mut buf = OutputBuffer::new(32);
+        let mut buf = OutputBuffer::new(32, ALT_BUFFER_CAP);
         buf.write(b"first write that fills buffer!!");
         let early = 5.0;
         buf.write(b"second write that overwrites everything!!!");
@@ -3974,14 +10808,14 @@ mod tests {
 
     #[test]
     fn sparkline_ring_empty() {
-        let ring = SparklineRing::new();
+        let ring = SparklineRing::new(SPARKLINE_RING_CAP);
         assert_eq!(ring.len(), 0);
         assert!(ring.to_vec().is_empty());
     }
 
     #[test]
     fn sparkline_ring_push_and_read() {
-        let mut ring = SparklineRing::new();
+        let mut ring = SparklineRing::new(SPARKLINE_RING_CAP);
         ring.push(1.0);
         ring.push(2.0);
         ring.push(3.0);
@@ -3991,7 +10825,7 @@ mod tests {
 
     #[test]
     fn sparkline_ring_wraps_at_capacity() {
-        let mut ring = SparklineRing::new();
+        let mut ring = SparklineRing::new(SPARKLINE_RING_CAP);
         for i in 0..SPARKLINE_RING_CAP {
             ring.push(i as f64);
         }
@@ -4006,9 +10840,15 @@ mod tests {
         assert_eq!(v[SPARKLINE_RING_CAP - 1], 99999.0);
     }
 
+    #[test]
+    fn sparkline_ring_keeps_newest_values_for_its_cap() {
+        let ring = SparklineRing::from_values(3, &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(ring.to_vec(), vec![3.0, 4.0, 5.0]);
+    }
+
     #[test]
     fn sparkline_ring_encode_decode() {
-        let mut ring = SparklineRing::new();
+        let mut ring = SparklineRing::new(SPARKLINE_RING_CAP);
         ring.push(1.5);
         ring.push(2.5);
         ring.push(0.0);
@@ -4024,7 +10864,7 @@ mod tests {
 
     #[test]
     fn output_buffer_clear_resets_buffer() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"hello world this is some data");
         assert!(buf.total_written > 0.0);
         let tw_before = buf.total_written;
@@ -4043,7 +10883,7 @@ mod tests {
 
     #[test]
     fn output_buffer_clear_while_in_alt_screen() {
-        let mut buf = OutputBuffer::new(1024);
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
         buf.write(b"normal content");
         // Manually enter alt screen state for testing
         buf.write(b"\x1b[?1049h");
@@ -4056,4 +10896,1000 @@ mod tests {
         assert!(!buf.in_alt_screen);
         assert!(buf.alt_buf.is_empty());
     }
+
+    // ── ScrollbackStore tests ───────────────────────────────────────
+
+    fn segment_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn scrollback_store_rolls_segments_by_offset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("s.scrollback");
+        let mut store = ScrollbackStore::open(dir.clone(), 10, 1000).unwrap();
+        store.append(b"0123456789abcdef").unwrap();
+        store.append(b"ghij").unwrap();
+
+        assert_eq!(
+            segment_files(&dir),
+            vec!["0000000000000000.seg", "000000000000000a.seg"]
+        );
+        assert_eq!(store.end_offset(), 20);
+        assert_eq!(
+            store.read_range(8, 20).unwrap().unwrap(),
+            b"89abcdefghij".to_vec()
+        );
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&dir.join("000000000000000a.seg")), 0o600);
+    }
+
+    #[test]
+    fn scrollback_store_evicts_oldest_segments_over_cap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("s.scrollback");
+        let mut store = ScrollbackStore::open(dir.clone(), 10, 25).unwrap();
+        store.append(&[b'x'; 40]).unwrap();
+
+        assert_eq!(store.start_offset(), 20);
+        assert_eq!(store.end_offset(), 40);
+        assert_eq!(segment_files(&dir).len(), 2);
+        assert!(store.read_range(5, 40).unwrap().is_none(), "evicted offset");
+        assert_eq!(store.read_range(20, 40).unwrap().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn scrollback_store_reopen_continues_at_end_offset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("s.scrollback");
+        {
+            let mut store = ScrollbackStore::open(dir.clone(), 8, 1000).unwrap();
+            store.append(b"hello world").unwrap();
+            store.sync();
+        }
+        let mut store = ScrollbackStore::open(dir.clone(), 8, 1000).unwrap();
+        assert_eq!(store.end_offset(), 11);
+        store.append(b"!!").unwrap();
+        assert_eq!(store.read_tail(6).unwrap(), (7, b"orld!!".to_vec()));
+    }
+
+    #[test]
+    fn scrollback_store_discards_segments_before_gap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("s.scrollback");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0000000000000000.seg"), b"abc").unwrap();
+        fs::write(dir.join("0000000000000010.seg"), b"def").unwrap();
+
+        let store = ScrollbackStore::open(dir.clone(), 8, 1000).unwrap();
+        assert_eq!(store.start_offset(), 16);
+        assert_eq!(store.end_offset(), 19);
+        assert_eq!(segment_files(&dir), vec!["0000000000000010.seg"]);
+    }
+
+    #[test]
+    fn read_dead_scrollback_leaves_segments_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sessions").join("dead.scrollback");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0000000000000000.seg"), b"abc").unwrap();
+        fs::write(dir.join("0000000000000010.seg"), b"def").unwrap();
+
+        let data = read_dead_scrollback(tmp.path(), "dead", 100).unwrap();
+        assert_eq!(data, Some(b"def".to_vec()));
+        assert_eq!(segment_files(&dir).len(), 2, "stale segment kept");
+        assert_eq!(read_dead_scrollback(tmp.path(), "missing", 100).unwrap(), None);
+    }
+
+    #[test]
+    fn scrollback_store_clear_keeps_offset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("s.scrollback");
+        let mut store = ScrollbackStore::open(dir.clone(), 8, 1000).unwrap();
+        store.append(b"secret output").unwrap();
+        store.clear().unwrap();
+
+        assert_eq!(segment_files(&dir), vec!["000000000000000d.seg"]);
+        store.append(b"new").unwrap();
+        drop(store);
+        let store = ScrollbackStore::open(dir, 8, 1000).unwrap();
+        assert_eq!(store.read_tail(100).unwrap(), (13, b"new".to_vec()));
+    }
+
+    #[test]
+    fn output_buffer_restore_continues_offsets() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.restore(500.0, b"restored\n");
+        assert_eq!(buf.total_written, 509.0);
+        assert_eq!(buf.read_from(505.0).unwrap(), b"red\n".to_vec());
+        assert!(buf.read_from(100.0).is_none());
+    }
+
+    // ── asciicast recorder tests ────────────────────────────────────
+
+    #[test]
+    fn incomplete_utf8_suffix_detects_split_chars() {
+        assert_eq!(incomplete_utf8_suffix(b"abc"), 0);
+        assert_eq!(incomplete_utf8_suffix("é".as_bytes()), 0);
+        assert_eq!(incomplete_utf8_suffix(&"é".as_bytes()[..1]), 1);
+        assert_eq!(incomplete_utf8_suffix(&"€".as_bytes()[..2]), 2);
+        assert_eq!(incomplete_utf8_suffix(&"😀".as_bytes()[..3]), 3);
+        assert_eq!(incomplete_utf8_suffix(b"ab\xff"), 0);
+    }
+
+    #[test]
+    fn asciicast_recorder_writes_header_and_events() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("rec.cast");
+        let mut rec = AsciicastRecorder::create(&path, 80, 24, "bash -l").unwrap();
+        rec.output(b"hello\r\n").unwrap();
+        // "€" split across two chunks is emitted whole in the second event
+        rec.output(&"€".as_bytes()[..1]).unwrap();
+        rec.output(&"€".as_bytes()[1..]).unwrap();
+        rec.resize(100, 30).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["command"], "bash -l");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "hello\r\n");
+        assert_eq!(lines[2][2], "€");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "100x30");
+        assert!(lines[3][0].as_f64().unwrap() >= lines[1][0].as_f64().unwrap());
+    }
+
+    // ── Replay tests ────────────────────────────────────────────────
+
+    #[test]
+    fn parse_asciicast_reads_output_and_resize_events() {
+        let cast = concat!(
+            "{\"version\": 2, \"width\": 100, \"height\": 30, \"title\": \"demo\"}\n",
+            "[0.5, \"o\", \"hello\\r\\n\"]\n",
+            "[0.7, \"i\", \"ignored input\"]\n",
+            "[1.25, \"r\", \"120x40\"]\n",
+            "[1.0, \"o\", \"late\"]\n",
+        );
+        let rec = Recording::parse_asciicast(cast).unwrap();
+        assert_eq!((rec.cols, rec.rows), (100, 30));
+        assert_eq!(rec.title.as_deref(), Some("demo"));
+        assert_eq!(rec.events.len(), 3);
+        assert!(matches!(rec.events[0].kind, RecordedEventKind::Output(ref d) if d == b"hello\r\n"));
+        assert!(matches!(rec.events[1].kind, RecordedEventKind::Resize(120, 40)));
+        // Out-of-order timestamps are clamped to keep time moving forward
+        assert_eq!(rec.events[2].time, 1.25);
+        assert_eq!(rec.duration(), 1.25);
+    }
+
+    #[test]
+    fn parse_asciicast_rejects_other_versions() {
+        assert!(Recording::parse_asciicast("{\"version\": 1, \"width\": 80, \"height\": 24}").is_err());
+        assert!(Recording::parse_asciicast("").is_err());
+        assert!(Recording::parse_asciicast("{\"version\": 2}").is_err());
+    }
+
+    #[test]
+    fn parse_asciicast_rejects_out_of_range_times() {
+        let header = "{\"version\": 2, \"width\": 80, \"height\": 24}\n";
+        for time in ["1e300", "-1", "2e9"] {
+            let cast = format!("{}[{}, \"o\", \"x\"]\n", header, time);
+            let err = Recording::parse_asciicast(&cast).err().expect(time);
+            assert!(err.contains("out of range on line 2"), "{}", err);
+        }
+        assert!(Recording::parse_asciicast(&format!("{}[1e9, \"o\", \"x\"]\n", header)).is_ok());
+    }
+
+    #[test]
+    fn playback_control_parse() {
+        let with_arg = |op: u8, v: f64| {
+            let mut d = vec![op];
+            d.extend_from_slice(&v.to_be_bytes());
+            d
+        };
+        assert_eq!(PlaybackControl::parse(&[0x00]), Some(PlaybackControl::Pause));
+        assert_eq!(PlaybackControl::parse(&[0x01]), Some(PlaybackControl::Play));
+        assert_eq!(PlaybackControl::parse(&with_arg(0x02, 12.5)), Some(PlaybackControl::Seek(12.5)));
+        assert_eq!(PlaybackControl::parse(&with_arg(0x02, -3.0)), Some(PlaybackControl::Seek(0.0)));
+        assert_eq!(PlaybackControl::parse(&with_arg(0x03, 1000.0)), Some(PlaybackControl::Speed(64.0)));
+        assert_eq!(PlaybackControl::parse(&with_arg(0x03, 0.0)), None);
+        assert_eq!(PlaybackControl::parse(&with_arg(0x02, f64::NAN)), None);
+        assert_eq!(PlaybackControl::parse(&[0x02]), None);
+        assert_eq!(PlaybackControl::parse(&[0x09]), None);
+        assert_eq!(PlaybackControl::parse(&[]), None);
+    }
+
+    #[test]
+    fn playback_status_frame_layout() {
+        let status = PlaybackStatus {
+            paused: true,
+            position: 1.5,
+            duration: 10.0,
+            speed: 2.0,
+        };
+        let frame = status.encode_frame();
+        assert_eq!(&frame[..4], &26u32.to_be_bytes());
+        assert_eq!(frame[4], WS_MSG_PLAYBACK_STATE);
+        assert_eq!(frame[5], 1);
+        assert_eq!(f64::from_be_bytes(frame[6..14].try_into().unwrap()), 1.5);
+        assert_eq!(f64::from_be_bytes(frame[14..22].try_into().unwrap()), 10.0);
+        assert_eq!(f64::from_be_bytes(frame[22..30].try_into().unwrap()), 2.0);
+    }
+
+    // ── Peer authorization tests ────────────────────────────────────
+
+    #[test]
+    fn peer_policy_default_admits_only_own_uid() {
+        let policy = PeerPolicy::default();
+        assert_eq!(policy.access(1000, 1000, 1000), Some(Access::ReadWrite));
+        assert_eq!(policy.access(1000, 1001, 1000), None);
+        assert_eq!(policy.access(1000, 0, 0), None);
+        assert_eq!(policy.socket_mode(), 0o600);
+    }
+
+    #[test]
+    fn peer_policy_allowlists_uids_and_gids() {
+        let policy = PeerPolicy {
+            allow_uids: vec![1001],
+            allow_gids: vec![50],
+            ..PeerPolicy::default()
+        };
+        assert_eq!(policy.access(1000, 1001, 1001), Some(Access::ReadWrite));
+        assert_eq!(policy.access(1000, 1002, 50), Some(Access::ReadWrite));
+        assert_eq!(policy.access(1000, 1002, 1002), None);
+        assert_eq!((policy.socket_mode(), policy.socket_group()), (0o666, None));
+    }
+
+    #[test]
+    fn peer_policy_socket_mode_is_as_narrow_as_the_allowlist() {
+        let uids = PeerPolicy {
+            allow_uids: vec![1001],
+            ..PeerPolicy::default()
+        };
+        assert_eq!((uids.socket_mode(), uids.socket_group()), (0o666, None));
+        let gid = PeerPolicy {
+            allow_gids: vec![50],
+            ..PeerPolicy::default()
+        };
+        assert_eq!((gid.socket_mode(), gid.socket_group()), (0o660, Some(50)));
+        let gids = PeerPolicy {
+            allow_gids: vec![50, 51],
+            ..PeerPolicy::default()
+        };
+        assert_eq!((gids.socket_mode(), gids.socket_group()), (0o666, None));
+    }
+
+    #[test]
+    fn peer_policy_downgrades_unlisted_peers_to_read_only() {
+        let policy = PeerPolicy {
+            read_only_unlisted: true,
+            ..PeerPolicy::default()
+        };
+        assert_eq!(policy.access(1000, 1000, 1000), Some(Access::ReadWrite));
+        assert_eq!(policy.access(1000, 1002, 1002), Some(Access::ReadOnly));
+        assert_eq!((policy.socket_mode(), policy.socket_group()), (0o666, None));
+    }
+
+    #[test]
+    fn parse_id_list_accepts_comma_separated_ids() {
+        assert_eq!(parse_id_list("501"), Some(vec![501]));
+        assert_eq!(parse_id_list("501, 502"), Some(vec![501, 502]));
+        assert_eq!(parse_id_list("501,bob"), None);
+        assert_eq!(parse_id_list(""), None);
+    }
+
+    // ── Command line tests ──────────────────────────────────────────
+
+    fn argv(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_spawn_flags_and_child_argv() {
+        let Ok(Invocation::Spawn(options, spawn)) = parse_args(&argv(
+            "spawn --id ab12 --cols 120 --rows 40 --cwd /tmp --login --require-token --watch exit:ERR -- zsh --login -i",
+        )) else {
+            panic!("expected spawn");
+        };
+        assert_eq!(spawn.id.as_deref(), Some("ab12"));
+        assert_eq!((spawn.cols, spawn.rows, spawn.cwd.as_str()), (120, 40, "/tmp"));
+        assert!(spawn.login && !spawn.detach);
+        assert_eq!(spawn.command, "zsh");
+        assert_eq!(spawn.args, argv("--login -i"), "the child's own --login is kept");
+        assert!(options.peer_policy.require_token);
+        assert_eq!(spawn.host_argv, argv("--require-token --watch exit:ERR"));
+
+        // The first non-option word starts the command, without `--`
+        let Ok(Invocation::Spawn(_, spawn)) = parse_args(&argv("spawn vim --clean")) else {
+            panic!("expected spawn");
+        };
+        assert_eq!((spawn.id, spawn.cols, spawn.rows), (None, 80, 24));
+        assert_eq!((spawn.command.as_str(), spawn.args), ("vim", argv("--clean")));
+    }
+
+    #[test]
+    fn parse_args_rejects_bad_input() {
+        for bad in [
+            "spawn",
+            "spawn --",
+            "spawn --cols wide bash",
+            "spawn --rows 0 bash",
+            "spawn --bogus bash",
+            "spawn --id",
+            "spawn --slow-clients sometimes bash",
+            "replay",
+            "kill",
+            "list --all",
+            "export --html",
+            "ab12 80 24 /tmp",
+            "ab12 eighty 24 /tmp bash",
+        ] {
+            assert!(parse_args(&argv(bad)).is_err(), "{}", bad);
+        }
+        assert!(matches!(parse_args(&[]), Ok(Invocation::Help)));
+    }
+
+    #[test]
+    fn parse_args_accepts_legacy_forms() {
+        let Ok(Invocation::Spawn(options, spawn)) =
+            parse_args(&argv("--clipboard-read ab12 100 30 /tmp zsh --login -i"))
+        else {
+            panic!("expected spawn");
+        };
+        assert!(options.clipboard_read);
+        assert_eq!(spawn.id.as_deref(), Some("ab12"));
+        assert_eq!((spawn.cols, spawn.rows), (100, 30));
+        assert!(spawn.login);
+        assert_eq!(spawn.args, argv("-i"));
+
+        let Ok(Invocation::Replay { path, id, .. }) = parse_args(&argv("--replay demo.cast ab12")) else {
+            panic!("expected replay");
+        };
+        assert_eq!((path.as_str(), id.as_deref()), ("demo.cast", Some("ab12")));
+        let Ok(Invocation::Handoff { path, id }) = parse_args(&argv("--handoff ab12.handoff ab12")) else {
+            panic!("expected handoff");
+        };
+        assert_eq!((path.as_str(), id.as_str()), ("ab12.handoff", "ab12"));
+    }
+
+    #[test]
+    fn parse_args_subcommands() {
+        assert!(matches!(parse_args(&argv("list --json")), Ok(Invocation::List { json: true })));
+        assert!(matches!(parse_args(&argv("kill ab12")), Ok(Invocation::Kill { id }) if id == "ab12"));
+        assert!(matches!(
+            parse_args(&argv("export --html ab12")),
+            Ok(Invocation::Export { format: EXPORT_FORMAT_HTML, .. })
+        ));
+        let Ok(Invocation::Replay { path, id, .. }) = parse_args(&argv("replay --id ab12 demo.cast")) else {
+            panic!("expected replay");
+        };
+        assert_eq!((path.as_str(), id.as_deref()), ("demo.cast", Some("ab12")));
+    }
+
+    // ── Attach client tests ─────────────────────────────────────────
+
+    #[test]
+    fn take_frame_waits_for_whole_frames() {
+        let mut pending = [encode_frame(b"\x00ab"), encode_frame(b"\x00cd")[..5].to_vec()].concat();
+        assert_eq!(take_frame(&mut pending), Some(b"\x00ab".to_vec()));
+        assert_eq!(take_frame(&mut pending), None);
+        pending.extend_from_slice(b"cd");
+        assert_eq!(take_frame(&mut pending), Some(b"\x00cd".to_vec()));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn attach_frames_write_output_and_track_offset() {
+        let mut out = Vec::new();
+        let mut offset = 0.0;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+        gz.write_all(b"replayed ").unwrap();
+        let replay = [&[WS_MSG_BUFFER_REPLAY_GZ][..], &gz.finish().unwrap()].concat();
+        assert!(handle_attach_frame(&replay, &mut offset, &mut out).is_none());
+        let sync = [&[WS_MSG_SYNC][..], &9f64.to_be_bytes()].concat();
+        handle_attach_frame(&sync, &mut offset, &mut out);
+        handle_attach_frame(b"\x00live", &mut offset, &mut out);
+        handle_attach_frame(b"\x04ignored title", &mut offset, &mut out);
+        assert_eq!(out, b"replayed live");
+        assert_eq!(offset, 13.0);
+
+        // A cache reset clears the screen before the full replay
+        handle_attach_frame(&[WS_MSG_SYNC, 0, 0, 0, 0, 0, 0, 0, 0], &mut offset, &mut out);
+        assert!(out.ends_with(b"\x1bc"));
+        assert_eq!(offset, 0.0);
+
+        let exit = [&[WS_MSG_EXIT][..], &3i32.to_be_bytes()].concat();
+        assert!(matches!(handle_attach_frame(&exit, &mut offset, &mut out), Some(AttachEnd::Exited(3))));
+    }
+
+    // ── Config tests ────────────────────────────────────────────────
+
+    #[test]
+    fn parse_size_accepts_suffixes_and_separators() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("1_048_576"), Some(1024 * 1024));
+        assert_eq!(parse_size("64KB"), Some(64 * 1024));
+        assert_eq!(parse_size("100MB"), Some(100 * 1024 * 1024));
+        assert_eq!(parse_size("1 gib"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("10XB"), None);
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size("-1"), None);
+    }
+
+    #[test]
+    fn config_file_sets_known_keys_and_rejects_others() {
+        let mut config = Config::default();
+        config
+            .apply_file("# build logs\nbuffer_size = \"100MB\"\n\nidle_timeout_ms = 30_000 # quieter\n")
+            .unwrap();
+        assert_eq!(config.buffer_size, 100 * 1024 * 1024);
+        assert_eq!(config.idle_timeout_ms, 30_000);
+        assert_eq!(config.gzip_threshold, GZIP_THRESHOLD, "unset keys keep defaults");
+        config.apply_file("scrollback_lines = 1_000\n").unwrap();
+        assert_eq!(config.scrollback_lines, 1000);
+
+        let err = Config::default().apply_file("buffer_size = \"1MB\"\nscrollback = 5\n").unwrap_err();
+        assert!(err.starts_with("unknown key scrollback"), "{}", err);
+        assert!(Config::default().apply_file("buffer_size\n").is_err());
+        assert!(Config::default().apply_file("idle_timeout_ms = \"1MB\"\n").is_err());
+        let err = Config::default().apply_file("\nbuffer_size = 1MB\n").unwrap_err();
+        assert!(err.starts_with("line 2: "), "not TOML: {}", err);
+    }
+
+    #[test]
+    fn config_file_is_parsed_as_toml() {
+        let mut config = Config::default();
+        config
+            .apply_file("log_level = 'debug' # single quotes\nalt_buffer_cap = \"4MB\" # a # in a comment\n")
+            .unwrap();
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.alt_buffer_cap, 4 * 1024 * 1024);
+
+        let err = Config::default().apply_file("log_level = \"#debug\"\n").unwrap_err();
+        assert!(err.contains("#debug"), "a # inside quotes is part of the value: {}", err);
+        let err = Config::default().apply_file("[limits]\nbuffer_size = \"1MB\"\n").unwrap_err();
+        assert!(err.starts_with("limits must be"), "{}", err);
+        assert!(Config::default().apply_file("idle_timeout_ms = 1.5\n").is_err());
+    }
+
+    #[test]
+    fn config_load_layers_file_then_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Config::load(dir.path(), &[]).unwrap(), Config::default(), "no file is fine");
+
+        fs::write(dir.path().join("pty-host.toml"), "buffer_size = \"100MB\"\nalt_buffer_cap = \"1MB\"\n").unwrap();
+        let config = Config::load(dir.path(), &["buffer_size=1MB".to_string()]).unwrap();
+        assert_eq!(config.buffer_size, 1024 * 1024, "--set beats the file");
+        assert_eq!(config.alt_buffer_cap, 1024 * 1024);
+
+        assert!(Config::load(dir.path(), &["buffer_size".to_string()]).is_err());
+        assert!(Config::load(dir.path(), &["buffer_size=1KB".to_string()]).is_err(), "validated");
+    }
+
+    #[test]
+    fn config_validate_bounds() {
+        assert!(Config::default().validate().is_ok());
+        let bad = [
+            Config { buffer_size: 1024, ..Config::default() },
+            Config { idle_timeout_ms: 0, ..Config::default() },
+            Config { clipboard_max_size: 0, ..Config::default() },
+            Config { sparkline_ring_cap: 0, ..Config::default() },
+            Config { sparkline_ring_cap: 70_000, ..Config::default() },
+            Config { log_max_size: 0, ..Config::default() },
+            Config { scrollback_lines: 2_000_000, ..Config::default() },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    // ── Metrics tests ───────────────────────────────────────────────
+
+    fn metrics_snapshot(counters: &MetricsCounters, child: Option<ProcessUsage>) -> MetricsSnapshot {
+        let mut buf = OutputBuffer::new(1024 * 1024, ALT_BUFFER_CAP);
+        buf.write(b"hello");
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            session: "abc".to_string(),
+            output_bytes: buf.total_written,
+            bps: [1.5, 0.5, 0.0],
+            buffers: [(buf.main_size(), buf.max_size), (0, ALT_BUFFER_CAP)],
+            clients: load(&counters.clients),
+            connections: load(&counters.connections),
+            dropped_frames: load(&counters.dropped_frames),
+            resyncs: load(&counters.resyncs),
+            slow_disconnects: load(&counters.slow_disconnects),
+            replays: counters.replays.each_ref().map(load),
+            replay_bytes: load(&counters.replay_bytes),
+            replay_sizes: counters.replay_sizes.each_ref().map(load),
+            child,
+        }
+    }
+
+    #[test]
+    fn metrics_counters_bucket_replay_sizes() {
+        let counters = MetricsCounters::new();
+        counters.replay(CatchUp::Delta, 0);
+        counters.replay(CatchUp::Delta, 1024);
+        counters.replay(CatchUp::Full, 1025);
+        counters.replay(CatchUp::CacheReset, 64 << 20);
+        let sizes: Vec<u64> = counters.replay_sizes.iter().map(|n| n.load(Ordering::Relaxed)).collect();
+        assert_eq!(sizes, [2, 1, 0, 0, 0, 0, 0, 0, 1]);
+        let replays: Vec<u64> = counters.replays.iter().map(|n| n.load(Ordering::Relaxed)).collect();
+        assert_eq!(replays, [2, 1, 1]);
+        assert_eq!(counters.replay_bytes.load(Ordering::Relaxed), 2049 + (64 << 20));
+    }
+
+    #[test]
+    fn metrics_render_as_openmetrics() {
+        let counters = MetricsCounters::new();
+        MetricsCounters::count(&counters.clients, 2);
+        MetricsCounters::count(&counters.dropped_frames, 7);
+        counters.replay(CatchUp::Delta, 100);
+        counters.replay(CatchUp::Full, 5000);
+        let child = ProcessUsage { cpu_seconds: 1.25, rss_bytes: 4096 };
+        let text = metrics_snapshot(&counters, Some(child)).render();
+
+        for line in [
+            "# TYPE relay_output_bytes counter",
+            "relay_output_bytes_total{session=\"abc\"} 5",
+            "relay_output_bytes_per_second{session=\"abc\",window=\"1m\"} 1.5",
+            "relay_clients{session=\"abc\"} 2",
+            "relay_buffer_bytes{session=\"abc\",screen=\"main\"} 5",
+            "relay_buffer_capacity_bytes{session=\"abc\",screen=\"main\"} 1048576",
+            "relay_dropped_frames_total{session=\"abc\"} 7",
+            "relay_replays_total{session=\"abc\",mode=\"cache-reset\"} 0",
+            "# TYPE relay_replay_bytes histogram",
+            "relay_replay_bytes_bucket{session=\"abc\",le=\"1024\"} 1",
+            "relay_replay_bytes_bucket{session=\"abc\",le=\"4096\"} 1",
+            "relay_replay_bytes_bucket{session=\"abc\",le=\"16384\"} 2",
+            "relay_replay_bytes_bucket{session=\"abc\",le=\"+Inf\"} 2",
+            "relay_replay_bytes_count{session=\"abc\"} 2",
+            "relay_replay_bytes_sum{session=\"abc\"} 5100",
+            "relay_child_cpu_seconds_total{session=\"abc\"} 1.25",
+            "relay_child_resident_memory_bytes{session=\"abc\"} 4096",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        assert!(text.ends_with("\n# EOF\n"));
+
+        let text = metrics_snapshot(&counters, None).render();
+        assert!(!text.contains("relay_child_"), "no child in replay mode");
+        assert_eq!(escape_label_value("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+    }
+
+    // ── Logging tests ───────────────────────────────────────────────
+
+    #[test]
+    fn log_level_parses_and_orders() {
+        assert_eq!(LogLevel::parse("debug"), Some(LogLevel::Debug));
+        assert_eq!(LogLevel::parse("WARN"), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::parse("trace"), None);
+        assert!(LogLevel::Error < LogLevel::Warn && LogLevel::Info < LogLevel::Debug);
+
+        let mut config = Config::default();
+        assert_eq!(config.log_level, LogLevel::Info);
+        config.set("log_level", "debug").unwrap();
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert!(config.set("log_level", "loud").is_err());
+    }
+
+    #[test]
+    fn log_records_are_logfmt() {
+        let line = format_record(
+            "2025-01-01T00:00:00.000Z",
+            LogLevel::Info,
+            "client connected",
+            &[("client", &3), ("access", &Access::ReadOnly), ("path", &"/tmp/a b"), ("title", &"")],
+        );
+        assert_eq!(
+            line,
+            "time=2025-01-01T00:00:00.000Z level=info msg=\"client connected\" client=3 access=read-only path=\"/tmp/a b\" title=\"\"\n"
+        );
+        assert_eq!(logfmt_value("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+
+    #[test]
+    fn log_file_rotates_past_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("abc.log");
+        let mut log = LogFile::open(&path, 100).unwrap();
+        for n in 0..10 {
+            log.append(&format!("{:039}\n", n));
+        }
+        let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{:039}\n{:039}\n", 8, 9));
+        assert_eq!(fs::read_to_string(numbered(1)).unwrap(), format!("{:039}\n{:039}\n", 6, 7));
+        assert!(numbered(LOG_KEEP).exists());
+        assert!(!numbered(LOG_KEEP + 1).exists(), "only LOG_KEEP old files are kept");
+
+        // Reopening appends instead of truncating
+        LogFile::open(&path, 100).unwrap().append("x\n");
+        assert!(fs::read_to_string(&path).unwrap().ends_with("9\nx\n"));
+    }
+
+    #[test]
+    fn osc_extractor_uses_configured_limits() {
+        let config = Config {
+            clipboard_max_size: 4,
+            image_max_size: 4,
+            ..Config::default()
+        };
+        let mut ext = OscExtractor::new(&config);
+        // "hello" is over the 4-byte clipboard limit; "hi" is not
+        let result = ext.feed(b"\x1b]52;c;aGVsbG8=\x07\x1b]52;c;aGk=\x07");
+        assert_eq!(result.clipboard_texts, vec!["hi"]);
+        // 5 decoded bytes is over the 4-byte image limit
+        let result = ext.feed(b"\x1b]1337;File=inline=1:aGVsbG8=\x07");
+        assert!(result.inline_images.is_empty());
+    }
+
+    // ── Protocol negotiation tests ──────────────────────────────────
+
+    #[test]
+    fn negotiate_caps_version_and_capabilities() {
+        let mut hello = 7u16.to_be_bytes().to_vec();
+        hello.extend_from_slice(&(CAP_IMAGE | CAP_TITLE | 1 << 31).to_be_bytes());
+        let (caps, reply) = negotiate(&hello).unwrap();
+        assert_eq!(caps, CAP_IMAGE | CAP_TITLE, "unknown bits are dropped");
+        assert_eq!(reply[0], WS_MSG_HELLO);
+        assert_eq!(u16::from_be_bytes([reply[1], reply[2]]), PROTOCOL_VERSION);
+        assert_eq!(u32::from_be_bytes(reply[3..7].try_into().unwrap()), caps);
+        assert!(negotiate(&[0, 1, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn capabilities_gate_optional_message_types() {
+        for core in [WS_MSG_DATA, WS_MSG_RESIZE, WS_MSG_EXIT, WS_MSG_BUFFER_REPLAY, WS_MSG_SYNC, WS_MSG_HELLO] {
+            assert!(accepts(0, core));
+        }
+        assert!(!accepts(0, WS_MSG_BUFFER_REPLAY_GZ));
+        assert!(!accepts(CAP_TITLE, WS_MSG_IMAGE));
+        assert!(accepts(CAP_IMAGE, WS_MSG_IMAGE));
+        assert!(!accepts(LEGACY_CAPABILITIES, WS_MSG_IMAGE), "pre-HELLO clients never get images");
+        assert!(accepts(LEGACY_CAPABILITIES, WS_MSG_SESSION_METRICS));
+        assert!(!accepts(LEGACY_CAPABILITIES, WS_MSG_COMMANDS));
+    }
+
+    // ── Slow client tests ───────────────────────────────────────────
+
+    #[test]
+    fn slow_client_policy_limits_resyncs_per_window() {
+        let mut recent = VecDeque::new();
+        let now = Instant::now();
+        let unlimited = SlowClientPolicy::default();
+        assert!((0..100).all(|_| unlimited.allow_resync(&mut recent, now)));
+
+        let disconnect = SlowClientPolicy { disconnect: true, max_resyncs: 0 };
+        assert!(!disconnect.allow_resync(&mut VecDeque::new(), now));
+
+        let mut recent = VecDeque::new();
+        let limited = SlowClientPolicy { disconnect: false, max_resyncs: 2 };
+        assert!(limited.allow_resync(&mut recent, now));
+        assert!(limited.allow_resync(&mut recent, now));
+        assert!(!limited.allow_resync(&mut recent, now));
+        assert!(limited.allow_resync(&mut recent, now + RESYNC_WINDOW), "old resyncs expire");
+    }
+
+    #[test]
+    fn client_offset_follows_sync_and_data() {
+        let offset = ClientOffset::new();
+        let data = encode_frame(&[WS_MSG_DATA, b'a', b'b', b'c']);
+        offset.observe(&data);
+        assert_eq!(offset.get(), None, "DATA before the first SYNC is ignored");
+
+        let mut sync = vec![WS_MSG_SYNC];
+        sync.extend_from_slice(&100.0f64.to_be_bytes());
+        offset.observe(&encode_frame(&sync));
+        offset.observe(&data);
+        offset.observe(&encode_frame(&[WS_MSG_TITLE, b'x']));
+        assert_eq!(offset.get(), Some(103.0));
+    }
+
+    // ── Client role tests ───────────────────────────────────────────
+
+    fn client_auth(ceiling: Access, require_token: bool) -> ClientAuth {
+        ClientAuth {
+            ceiling,
+            policy: Arc::new(PeerPolicy {
+                require_token,
+                write_token: "s3cret".to_string(),
+                ..PeerPolicy::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn client_role_defaults_to_credentials_unless_token_required() {
+        assert_eq!(client_auth(Access::ReadWrite, false).initial(), Access::ReadWrite);
+        assert_eq!(client_auth(Access::ReadOnly, false).initial(), Access::ReadOnly);
+        assert_eq!(client_auth(Access::ReadWrite, true).initial(), Access::ReadOnly);
+    }
+
+    #[test]
+    fn auth_frame_selects_role() {
+        let auth = client_auth(Access::ReadWrite, true);
+        assert_eq!(auth.authenticate(b"\x01s3cret"), Access::ReadWrite);
+        assert_eq!(auth.authenticate(b"\x01wrong!"), Access::ReadOnly);
+        assert_eq!(auth.authenticate(b"\x01s3cre"), Access::ReadOnly);
+        assert_eq!(auth.authenticate(b"\x00"), Access::ReadOnly);
+        assert_eq!(auth.authenticate(b"\x00s3cret"), Access::ReadOnly);
+        assert_eq!(auth.authenticate(b"\x07"), Access::ReadOnly);
+        assert_eq!(auth.authenticate(b""), Access::ReadOnly);
+    }
+
+    #[test]
+    fn auth_token_cannot_exceed_peer_credentials() {
+        let auth = client_auth(Access::ReadOnly, false);
+        assert_eq!(auth.authenticate(b"\x01s3cret"), Access::ReadOnly);
+    }
+
+    #[test]
+    fn empty_write_token_matches_nothing() {
+        let policy = PeerPolicy::default();
+        assert!(!policy.token_matches(b""));
+    }
+
+    // ── WebSocket tests ─────────────────────────────────────────────
+
+    /// A client frame with a fixed mask.
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = encode_ws_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let mut out = frame[..header_len].to_vec();
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        out
+    }
+
+    #[test]
+    fn sha1_and_base64_match_known_values() {
+        let hex = |digest: [u8; 20]| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        for (input, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg==")] {
+            assert_eq!(base64_encode(input.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded.as_bytes()).unwrap(), input.as_bytes());
+        }
+        // RFC 6455 section 1.3
+        assert_eq!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn upgrade_request_requires_websocket_headers() {
+        let request = "GET /ws?cols=80&token=abc123 HTTP/1.1\r\nHost: localhost\r\nUpgrade: WebSocket\r\n\
+                       Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nsec-websocket-key: k==";
+        assert_eq!(
+            parse_upgrade_request(request),
+            Ok(WsRequest { key: "k==".to_string(), token: Some("abc123".to_string()) })
+        );
+        let no_token = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                        Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: k==";
+        assert_eq!(parse_upgrade_request(no_token).unwrap().token, None);
+
+        assert!(parse_upgrade_request("POST / HTTP/1.1\r\nUpgrade: websocket").is_err());
+        assert!(parse_upgrade_request("GET / HTTP/1.1\r\nHost: localhost").is_err());
+        let old_version = no_token.replace("Version: 13", "Version: 8");
+        assert!(parse_upgrade_request(&old_version).is_err());
+        let no_key = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13";
+        assert!(parse_upgrade_request(no_key).is_err());
+    }
+
+    #[test]
+    fn ws_frame_lengths_use_shortest_encoding() {
+        assert_eq!(encode_ws_frame(WS_OP_BINARY, b"hi"), vec![0x82, 2, b'h', b'i']);
+        let medium = encode_ws_frame(WS_OP_BINARY, &[0; 300]);
+        assert_eq!(&medium[..4], &[0x82, 126, 0x01, 0x2C]);
+        assert_eq!(medium.len(), 304);
+        let large = encode_ws_frame(WS_OP_BINARY, &[0; 70_000]);
+        assert_eq!(&large[..10], &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+        assert_eq!(large.len(), 70_010);
+    }
+
+    #[test]
+    fn ws_decoder_unmasks_reassembles_and_handles_control_frames() {
+        let mut decoder = WsDecoder::default();
+        let frame = masked_frame(true, WS_OP_BINARY, b"\x00ls\r");
+        // Split mid-header and mid-payload
+        assert_eq!(decoder.feed(&frame[..1]).unwrap(), vec![]);
+        assert_eq!(decoder.feed(&frame[1..5]).unwrap(), vec![]);
+        assert_eq!(decoder.feed(&frame[5..]).unwrap(), vec![WsEvent::Message(b"\x00ls\r".to_vec())]);
+
+        let mut bytes = masked_frame(false, WS_OP_BINARY, b"\x00ab");
+        bytes.extend(masked_frame(true, WS_OP_PING, b"p"));
+        bytes.extend(masked_frame(true, WS_OP_CONTINUATION, b"cd"));
+        bytes.extend(masked_frame(true, WS_OP_BINARY, &[7; 200]));
+        bytes.extend(masked_frame(true, WS_OP_CLOSE, &[]));
+        assert_eq!(
+            decoder.feed(&bytes).unwrap(),
+            vec![
+                WsEvent::Ping(b"p".to_vec()),
+                WsEvent::Message(b"\x00abcd".to_vec()),
+                WsEvent::Message(vec![7; 200]),
+                WsEvent::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn ws_decoder_rejects_protocol_violations() {
+        assert!(WsDecoder::default().feed(&encode_ws_frame(WS_OP_BINARY, b"x")).is_err());
+        assert!(WsDecoder::default().feed(&masked_frame(true, WS_OP_CONTINUATION, b"x")).is_err());
+        let oversized = [0x82, 0xFF, 0, 0, 0, 0, 0x7F, 0, 0, 0];
+        assert!(WsDecoder::default().feed(&oversized).is_err());
+    }
+
+    // ── Live upgrade tests ──────────────────────────────────────────
+
+    fn test_meta() -> SessionMeta {
+        SessionMeta {
+            id: "abc123".into(),
+            command: "bash".into(),
+            args: vec![],
+            cwd: "/tmp".into(),
+            created_at: 1000,
+            last_activity: 1000,
+            status: "running".into(),
+            exit_code: None,
+            exited_at: None,
+            cols: 80,
+            rows: 24,
+            pid: 1234,
+            started_at: "2026-01-01T00:00:00.000Z".into(),
+            total_bytes_written: 5.0,
+            last_active_at: "2026-01-01T00:00:00.000Z".into(),
+            bytes_per_second: 0.0,
+            title: Some("vim".into()),
+            error: None,
+            bps1: 0.0,
+            bps5: 0.0,
+            bps15: 0.0,
+            foreground_process: None,
+            scrollback_dir: None,
+            ws_listen: None,
+            metrics_listen: None,
+            watch_marks: Vec::new(),
+            last_command: None,
+        }
+    }
+
+    fn handoff_round_trip(buf: &OutputBuffer) -> OutputBuffer {
+        let handoff = buf.handoff();
+        let config = Config {
+            buffer_size: buf.max_size,
+            alt_buffer_cap: buf.alt_cap,
+            ..Config::default()
+        };
+        OutputBuffer::from_handoff(&config, &handoff, &buf.read_main_raw(), &buf.alt_buf)
+    }
+
+    #[test]
+    fn output_buffer_handoff_preserves_wrapped_ring() {
+        let mut buf = OutputBuffer::new(64, ALT_BUFFER_CAP);
+        for i in 0..20 {
+            buf.write(format!("line {}\r\n", i).as_bytes());
+        }
+        assert!(buf.filled);
+        let restored = handoff_round_trip(&buf);
+        assert_eq!(restored.total_written, buf.total_written);
+        assert_eq!(restored.read(), buf.read());
+        let offset = buf.total_written - 20.0;
+        assert_eq!(restored.read_from(offset), buf.read_from(offset));
+        assert_eq!(restored.read_from(0.0), None);
+    }
+
+    #[test]
+    fn output_buffer_handoff_preserves_alt_screen_and_pending_sequence() {
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"$ vim\r\n\x1b[?1049h");
+        buf.write(b"editor contents\x1b[?10");
+        assert!(buf.scanner.is_mid_sequence());
+        let mut restored = handoff_round_trip(&buf);
+        assert!(restored.in_alt_screen);
+        assert_eq!(restored.alt_content_start, buf.alt_content_start);
+        assert_eq!(restored.read_from(0.0), buf.read_from(0.0));
+
+        // The held-back bytes complete the exit sequence after the handoff
+        buf.write(b"49l$ ");
+        restored.write(b"49l$ ");
+        assert!(!restored.in_alt_screen);
+        assert_eq!(restored.total_written, buf.total_written);
+        assert_eq!(restored.read(), buf.read());
+    }
+
+    #[test]
+    fn handoff_file_round_trip_and_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.handoff");
+        let mut buf = OutputBuffer::new(1024, ALT_BUFFER_CAP);
+        buf.write(b"hello");
+        let handoff = |screen_len| Handoff {
+            version: HANDOFF_VERSION,
+            master_fd: 7,
+            listener_fd: 8,
+            child_pid: 1234,
+            meta: test_meta(),
+            title: Some("vim".to_string()),
+            session_active: true,
+            replay_bytes: false,
+            sparkline: vec![1.0, 2.0],
+            osc_pending: b"\x1b]0;ti".to_vec(),
+            recorder: None,
+            peer_policy: PeerPolicy {
+                allow_uids: vec![501],
+                ..PeerPolicy::default()
+            },
+            ws_unix: true,
+            ws_listener_fd: Some(9),
+            metrics_listener_fd: Some(10),
+            metrics_socket: Some(PathBuf::from("/tmp/abc.metrics")),
+            slow_clients: SlowClientPolicy { disconnect: false, max_resyncs: 3 },
+            watches: vec![WatchRule::parse_flag("exit:FAILED")],
+            commands: CommandTracker::default(),
+            links: LinkIndex::default(),
+            images: Vec::new(),
+            next_image_id: 0,
+            clipboard: Clipboard::default(),
+            config: Config {
+                buffer_size: 1024 * 1024,
+                ..Config::default()
+            },
+            buffer: buf.handoff(),
+            screen_len,
+        };
+
+        write_handoff(&path, &handoff(3), &[b"hello", b"", b"scr"]).unwrap();
+        let (read, blobs) = read_handoff(&path).unwrap();
+        assert!(!path.exists(), "handoff file is removed once read");
+        assert_eq!((read.master_fd, read.listener_fd, read.child_pid), (7, 8, 1234));
+        assert_eq!(read.title.as_deref(), Some("vim"));
+        assert_eq!(read.osc_pending, b"\x1b]0;ti");
+        assert_eq!(read.peer_policy.allow_uids, vec![501]);
+        assert_eq!((read.ws_unix, read.ws_listener_fd), (true, Some(9)));
+        assert_eq!(read.metrics_listener_fd, Some(10));
+        assert_eq!(read.metrics_socket.as_deref(), Some(Path::new("/tmp/abc.metrics")));
+        assert_eq!(read.slow_clients.max_resyncs, 3);
+        assert_eq!(read.watches[0].action, WatchAction::Exit);
+        assert_eq!(read.buffer.total_written, 5.0);
+        assert_eq!(read.config.buffer_size, 1024 * 1024);
+        assert_eq!(blobs, b"helloscr");
+
+        write_handoff(&path, &handoff(10), &[b"hello", b"", b"scr"]).unwrap();
+        assert!(read_handoff(&path).is_err(), "truncated blobs are rejected");
+
+        let mut future = handoff(0);
+        future.version = HANDOFF_VERSION + 1;
+        write_handoff(&path, &future, &[b"hello"]).unwrap();
+        assert!(read_handoff(&path).is_err(), "unknown versions are rejected");
+    }
+
+    #[test]
+    fn sparkline_from_values_keeps_order() {
+        let ring = SparklineRing::from_values(SPARKLINE_RING_CAP, &[1.0, 2.0, 3.0]);
+        assert_eq!(ring.to_vec(), vec![1.0, 2.0, 3.0]);
+    }
 }
+
@@ -0,0 +1,2213 @@
+//! VT screen model: a server-side terminal emulator that tracks the grid,
+//! scrollback and modes so a full replay can be synthesized from the
+//! current state instead of the raw byte history.
+
+use std::collections::VecDeque;
+
+/// Default `scrollback_lines`: lines kept in the screen model's scrollback.
+pub(crate) const SCROLLBACK_LINES: usize = 10_000;
+
+/// Cell attribute flags (SGR).
+pub(crate) const ATTR_BOLD: u16 = 1 << 0;
+pub(crate) const ATTR_DIM: u16 = 1 << 1;
+pub(crate) const ATTR_ITALIC: u16 = 1 << 2;
+pub(crate) const ATTR_UNDERLINE: u16 = 1 << 3;
+const ATTR_BLINK: u16 = 1 << 4;
+pub(crate) const ATTR_INVERSE: u16 = 1 << 5;
+pub(crate) const ATTR_HIDDEN: u16 = 1 << 6;
+pub(crate) const ATTR_STRIKE: u16 = 1 << 7;
+pub(crate) const ATTR_OVERLINE: u16 = 1 << 8;
+
+/// Flags that make an otherwise blank cell visible.
+const ATTR_VISIBLE_ON_BLANK: u16 = ATTR_UNDERLINE | ATTR_INVERSE | ATTR_STRIKE | ATTR_OVERLINE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Attrs {
+    pub(crate) fg: Color,
+    pub(crate) bg: Color,
+    pub(crate) flags: u16,
+}
+
+impl Attrs {
+    /// Append the SGR sequence that selects these attributes from scratch.
+    /// Always starts with `0` so the result is independent of prior state.
+    /// RGB colors use the semicolon form — xterm.js misparses `38:2:R:G:B`.
+    fn write_sgr(&self, out: &mut Vec<u8>) {
+        let mut sgr = String::from("\x1b[0");
+        const FLAG_CODES: [(u16, &str); 9] = [
+            (ATTR_BOLD, ";1"),
+            (ATTR_DIM, ";2"),
+            (ATTR_ITALIC, ";3"),
+            (ATTR_UNDERLINE, ";4"),
+            (ATTR_BLINK, ";5"),
+            (ATTR_INVERSE, ";7"),
+            (ATTR_HIDDEN, ";8"),
+            (ATTR_STRIKE, ";9"),
+            (ATTR_OVERLINE, ";53"),
+        ];
+        for (flag, code) in FLAG_CODES {
+            if self.flags & flag != 0 {
+                sgr.push_str(code);
+            }
+        }
+        match self.fg {
+            Color::Default => {}
+            Color::Indexed(n) if n < 8 => sgr.push_str(&format!(";{}", 30 + n as u16)),
+            Color::Indexed(n) if n < 16 => sgr.push_str(&format!(";{}", 90 + n as u16 - 8)),
+            Color::Indexed(n) => sgr.push_str(&format!(";38;5;{}", n)),
+            Color::Rgb(r, g, b) => sgr.push_str(&format!(";38;2;{};{};{}", r, g, b)),
+        }
+        match self.bg {
+            Color::Default => {}
+            Color::Indexed(n) if n < 8 => sgr.push_str(&format!(";{}", 40 + n as u16)),
+            Color::Indexed(n) if n < 16 => sgr.push_str(&format!(";{}", 100 + n as u16 - 8)),
+            Color::Indexed(n) => sgr.push_str(&format!(";48;5;{}", n)),
+            Color::Rgb(r, g, b) => sgr.push_str(&format!(";48;2;{};{};{}", r, g, b)),
+        }
+        sgr.push('m');
+        out.extend_from_slice(sgr.as_bytes());
+    }
+
+    /// Apply an SGR (`CSI ... m`) parameter list.
+    pub(crate) fn apply_sgr(&mut self, params: &[Vec<u16>]) {
+        if params.is_empty() {
+            *self = Attrs::default();
+            return;
+        }
+        let attrs = self;
+        let mut i = 0;
+        while i < params.len() {
+            let group = &params[i];
+            let code = group.first().copied().unwrap_or(0);
+            match code {
+                0 => *attrs = Attrs::default(),
+                1 => attrs.flags |= ATTR_BOLD,
+                2 => attrs.flags |= ATTR_DIM,
+                3 => attrs.flags |= ATTR_ITALIC,
+                4 => {
+                    // 4:0 turns underline off; other styles map to plain underline
+                    if group.get(1) == Some(&0) {
+                        attrs.flags &= !ATTR_UNDERLINE;
+                    } else {
+                        attrs.flags |= ATTR_UNDERLINE;
+                    }
+                }
+                5 | 6 => attrs.flags |= ATTR_BLINK,
+                7 => attrs.flags |= ATTR_INVERSE,
+                8 => attrs.flags |= ATTR_HIDDEN,
+                9 => attrs.flags |= ATTR_STRIKE,
+                21 => attrs.flags |= ATTR_UNDERLINE,
+                22 => attrs.flags &= !(ATTR_BOLD | ATTR_DIM),
+                23 => attrs.flags &= !ATTR_ITALIC,
+                24 => attrs.flags &= !ATTR_UNDERLINE,
+                25 => attrs.flags &= !ATTR_BLINK,
+                27 => attrs.flags &= !ATTR_INVERSE,
+                28 => attrs.flags &= !ATTR_HIDDEN,
+                29 => attrs.flags &= !ATTR_STRIKE,
+                30..=37 => attrs.fg = Color::Indexed((code - 30) as u8),
+                39 => attrs.fg = Color::Default,
+                40..=47 => attrs.bg = Color::Indexed((code - 40) as u8),
+                49 => attrs.bg = Color::Default,
+                53 => attrs.flags |= ATTR_OVERLINE,
+                55 => attrs.flags &= !ATTR_OVERLINE,
+                90..=97 => attrs.fg = Color::Indexed((code - 90 + 8) as u8),
+                100..=107 => attrs.bg = Color::Indexed((code - 100 + 8) as u8),
+                38 | 48 | 58 => {
+                    let (color, consumed) = parse_extended_color(params, i);
+                    if let Some(color) = color {
+                        match code {
+                            38 => attrs.fg = color,
+                            48 => attrs.bg = color,
+                            _ => {} // underline color isn't tracked
+                        }
+                    }
+                    i += consumed;
+                    continue;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Combining marks kept per cell; further marks on the same cell are dropped.
+const MAX_COMBINING: usize = 2;
+
+/// Distinct OSC 8 hyperlinks tracked per screen; later links are shown
+/// as plain text on replay.
+const LINKS_MAX: usize = 4096;
+
+/// Longest OSC string the screen collects. Longer ones (images, clipboard
+/// writes) are skipped without being dispatched.
+const OSC_MAX: usize = 4096;
+
+/// Append the OSC 8 sequence that switches to hyperlink `id` (1-based index
+/// into `links`), or ends the current link when `id` is 0.
+fn write_link(links: &[String], id: u16, out: &mut Vec<u8>) {
+    out.extend_from_slice(b"\x1b]8;");
+    match id.checked_sub(1).and_then(|i| links.get(i as usize)) {
+        Some(link) => out.extend_from_slice(link.as_bytes()),
+        None => out.push(b';'),
+    }
+    out.extend_from_slice(b"\x1b\\");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cell {
+    ch: char,
+    /// 1 = normal, 2 = leading half of a wide char, 0 = trailing half.
+    width: u8,
+    /// Zero-width combining characters attached to `ch`, `'\0'` if unused.
+    combining: [char; MAX_COMBINING],
+    attrs: Attrs,
+    /// OSC 8 hyperlink, an index into `Screen::links` plus one; 0 = none.
+    link: u16,
+}
+
+impl Cell {
+    fn blank(attrs: Attrs) -> Self {
+        Self {
+            ch: ' ',
+            width: 1,
+            combining: ['\0'; MAX_COMBINING],
+            attrs,
+            link: 0,
+        }
+    }
+
+    fn has_combining(&self) -> bool {
+        self.combining[0] != '\0'
+    }
+
+    /// Append the cell's text (base character plus combining marks).
+    fn write_text(&self, out: &mut Vec<u8>) {
+        if self.ch.is_ascii() && !self.has_combining() {
+            out.push(self.ch as u8);
+            return;
+        }
+        let mut utf8 = [0u8; 4];
+        out.extend_from_slice(self.ch.encode_utf8(&mut utf8).as_bytes());
+        for &mark in self.combining.iter().take_while(|&&c| c != '\0') {
+            out.extend_from_slice(mark.encode_utf8(&mut utf8).as_bytes());
+        }
+    }
+
+    /// A cell that renders as nothing — a plain space with no visible background.
+    fn is_blank(&self) -> bool {
+        self.ch == ' '
+            && !self.has_combining()
+            && self.link == 0
+            && matches!(self.attrs.bg, Color::Default)
+            && self.attrs.flags & ATTR_VISIBLE_ON_BLANK == 0
+    }
+}
+
+/// Set every cell in `cells` to `blank`. Copies in doubling blocks, which
+/// stays a handful of memmoves per row even in unoptimized builds; this
+/// runs for every line scrolled.
+fn fill_cells(cells: &mut [Cell], blank: Cell) {
+    let Some(first) = cells.first_mut() else {
+        return;
+    };
+    *first = blank;
+    let mut filled = 1;
+    while filled < cells.len() {
+        let n = filled.min(cells.len() - filled);
+        cells.copy_within(..n, filled);
+        filled += n;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Row {
+    cells: Vec<Cell>,
+    /// Soft-wrapped: the line continues on the next row.
+    wrapped: bool,
+    /// Cells from here on are default blanks. Writes to `cells` must raise
+    /// it (see `touch`); it lets scrolling a short line skip the rest.
+    used: usize,
+}
+
+impl Row {
+    fn new(cols: usize, attrs: Attrs) -> Self {
+        Self {
+            cells: vec![Cell::blank(attrs); cols],
+            wrapped: false,
+            used: if attrs == Attrs::default() { 0 } else { cols },
+        }
+    }
+
+    /// Note that cells before `end` may have been written.
+    fn touch(&mut self, end: usize) {
+        self.used = self.used.max(end.min(self.cells.len()));
+    }
+
+    /// Set `cells[range]` to `blank`.
+    fn fill(&mut self, range: std::ops::Range<usize>, blank: Cell) {
+        if blank != Cell::blank(Attrs::default()) {
+            fill_cells(&mut self.cells[range.clone()], blank);
+            self.touch(range.end);
+        } else if range.start < self.used {
+            // Cells past `used` are already default blanks
+            fill_cells(&mut self.cells[range.start..range.end.min(self.used)], blank);
+            if range.end >= self.used {
+                self.used = range.start;
+            }
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.cells[..self.used].iter().all(Cell::is_blank)
+    }
+
+    fn resize(&mut self, cols: usize) {
+        self.cells.resize(cols, Cell::blank(Attrs::default()));
+        self.used = self.used.min(cols);
+        // Don't leave the leading half of a wide char dangling at the edge
+        if let Some(last) = self.cells.last_mut() {
+            if last.width == 2 {
+                *last = Cell::blank(last.attrs);
+            }
+        }
+    }
+
+    /// Serialize this row as a self-contained escape stream (starts and
+    /// ends with default attributes and no hyperlink). Trailing blank cells
+    /// are trimmed unless the row is soft-wrapped, in which case every
+    /// column is emitted so the next printable character wraps naturally.
+    fn serialize(&self, links: &[String], out: &mut Vec<u8>) {
+        let mut end = if self.wrapped { self.cells.len() } else { self.used };
+        if !self.wrapped {
+            while end > 0 && self.cells[end - 1].is_blank() {
+                end -= 1;
+            }
+        }
+        let mut pen = Attrs::default();
+        let mut link = 0;
+        for cell in &self.cells[..end] {
+            if cell.width == 0 {
+                continue; // trailing half of a wide char — emitted with its lead
+            }
+            if cell.attrs != pen {
+                cell.attrs.write_sgr(out);
+                pen = cell.attrs;
+            }
+            if cell.link != link {
+                write_link(links, cell.link, out);
+                link = cell.link;
+            }
+            cell.write_text(out);
+        }
+        if link != 0 {
+            write_link(links, 0, out);
+        }
+        if pen != Attrs::default() {
+            out.extend_from_slice(b"\x1b[0m");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Charset {
+    Ascii,
+    /// DEC Special Graphics (line drawing), selected by `ESC ( 0`.
+    DecSpecial,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    x: usize,
+    y: usize,
+    attrs: Attrs,
+    /// Cursor sits past the last column; the next print wraps first.
+    pending_wrap: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    cursor: Cursor,
+    origin_mode: bool,
+    charsets: [Charset; 2],
+    active_charset: usize,
+}
+
+/// Terminal modes that affect how future output is interpreted or how
+/// the client behaves, and so must be restored on replay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Modes {
+    autowrap: bool,
+    origin: bool,
+    insert: bool,
+    newline: bool,
+    cursor_visible: bool,
+    app_cursor: bool,
+    app_keypad: bool,
+    bracketed_paste: bool,
+    focus_events: bool,
+    /// Active mouse tracking mode (1000/1002/1003), 0 when off.
+    mouse_tracking: u16,
+    /// Active mouse encoding (1005/1006/1015), 0 for the default X10 encoding.
+    mouse_encoding: u16,
+    /// DECSCUSR cursor style, 0 = terminal default.
+    cursor_style: u16,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            autowrap: true,
+            origin: false,
+            insert: false,
+            newline: false,
+            cursor_visible: true,
+            app_cursor: false,
+            app_keypad: false,
+            bracketed_paste: false,
+            focus_events: false,
+            mouse_tracking: 0,
+            mouse_encoding: 0,
+            cursor_style: 0,
+        }
+    }
+}
+
+/// Escape sequence parser state (a trimmed-down DEC/VT500 state machine).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VtState {
+    Ground,
+    Escape,
+    Csi,
+    /// Malformed CSI — swallow bytes until the final byte.
+    CsiIgnore,
+    Osc,
+    /// DCS, SOS, PM and APC strings — content is discarded.
+    String,
+}
+
+/// Server-side terminal emulator. Tracks the cell grid, cursor, modes,
+/// scroll region and a bounded scrollback so that a full replay can be
+/// synthesized from the current screen instead of the raw byte log.
+///
+/// Scrollback lines are stored pre-serialized: once a line scrolls off the
+/// top it never changes, and the escape-stream form is far smaller than
+/// a row of cells.
+pub(crate) struct Screen {
+    cols: usize,
+    rows: usize,
+    main: Vec<Row>,
+    alt: Vec<Row>,
+    in_alt: bool,
+    scrollback: VecDeque<Vec<u8>>,
+    pub(crate) scrollback_cap: usize,
+    cursor: Cursor,
+    /// Main-screen cursor saved by mode 1049 while the alt screen is active.
+    alt_saved_cursor: Option<SavedCursor>,
+    saved_main: Option<SavedCursor>,
+    saved_alt: Option<SavedCursor>,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    modes: Modes,
+    tabs: Vec<bool>,
+    charsets: [Charset; 2],
+    active_charset: usize,
+    last_printed: Option<char>,
+    /// OSC 8 hyperlinks seen so far, as their `params;URI` text.
+    links: Vec<String>,
+    /// Hyperlink applied to printed cells (see `Cell::link`).
+    link: u16,
+    // Parser state
+    state: VtState,
+    params: Vec<u8>,
+    intermediates: Vec<u8>,
+    /// Payload of the OSC being parsed, up to `OSC_MAX` bytes plus one
+    /// that marks it as too long.
+    osc: Vec<u8>,
+    string_esc: bool,
+    utf8_buf: [u8; 4],
+    utf8_len: usize,
+    utf8_need: usize,
+}
+
+impl Screen {
+    pub(crate) fn new(cols: u16, rows: u16, scrollback_cap: usize) -> Self {
+        let cols = (cols as usize).max(1);
+        let rows = (rows as usize).max(1);
+        Self {
+            cols,
+            rows,
+            main: vec![Row::new(cols, Attrs::default()); rows],
+            alt: vec![Row::new(cols, Attrs::default()); rows],
+            in_alt: false,
+            scrollback: VecDeque::new(),
+            scrollback_cap,
+            cursor: Cursor {
+                x: 0,
+                y: 0,
+                attrs: Attrs::default(),
+                pending_wrap: false,
+            },
+            alt_saved_cursor: None,
+            saved_main: None,
+            saved_alt: None,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            modes: Modes::default(),
+            tabs: default_tabs(cols),
+            charsets: [Charset::Ascii; 2],
+            active_charset: 0,
+            last_printed: None,
+            links: Vec::new(),
+            link: 0,
+            state: VtState::Ground,
+            params: Vec::with_capacity(32),
+            intermediates: Vec::with_capacity(4),
+            osc: Vec::new(),
+            string_esc: false,
+            utf8_buf: [0; 4],
+            utf8_len: 0,
+            utf8_need: 0,
+        }
+    }
+
+    fn grid(&self) -> &Vec<Row> {
+        if self.in_alt {
+            &self.alt
+        } else {
+            &self.main
+        }
+    }
+
+    fn grid_mut(&mut self) -> &mut Vec<Row> {
+        if self.in_alt {
+            &mut self.alt
+        } else {
+            &mut self.main
+        }
+    }
+
+    /// Blank cell using the current background (xterm's BCE behavior).
+    fn erase_cell(&self) -> Cell {
+        Cell::blank(Attrs {
+            bg: self.cursor.attrs.bg,
+            ..Attrs::default()
+        })
+    }
+
+    // ── Input ───────────────────────────────────────────────────────
+
+    /// Feed PTY output through the parser.
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            let byte = data[i];
+            // Runs of plain ASCII text skip the per-byte state machine
+            if (0x20..=0x7e).contains(&byte)
+                && self.state == VtState::Ground
+                && self.utf8_need == 0
+                && !self.modes.insert
+                && self.charsets[self.active_charset] == Charset::Ascii
+            {
+                let end = data[i..]
+                    .iter()
+                    .position(|&b| !(0x20..=0x7e).contains(&b))
+                    .map_or(data.len(), |n| i + n);
+                self.print_ascii(&data[i..end]);
+                i = end;
+                continue;
+            }
+            self.feed_byte(byte);
+            i += 1;
+        }
+    }
+
+    fn feed_byte(&mut self, byte: u8) {
+        // CAN and SUB abort any sequence in progress
+        if byte == 0x18 || byte == 0x1a {
+            self.state = VtState::Ground;
+            self.utf8_need = 0;
+            return;
+        }
+
+        match self.state {
+            VtState::Ground => self.ground(byte),
+            VtState::Escape => self.escape(byte),
+            VtState::Csi => self.csi(byte),
+            VtState::CsiIgnore => {
+                if byte == 0x1b {
+                    self.enter_escape();
+                } else if (0x40..=0x7e).contains(&byte) {
+                    self.state = VtState::Ground;
+                }
+            }
+            VtState::Osc | VtState::String => {
+                if self.string_esc {
+                    self.string_esc = false;
+                    if byte == b'\\' {
+                        if self.state == VtState::Osc {
+                            self.osc_dispatch();
+                        }
+                        self.state = VtState::Ground;
+                    } else {
+                        // ESC followed by something else starts a new sequence
+                        self.enter_escape();
+                        self.escape(byte);
+                    }
+                } else if byte == 0x1b {
+                    self.string_esc = true;
+                } else if byte == 0x07 && self.state == VtState::Osc {
+                    self.osc_dispatch();
+                    self.state = VtState::Ground;
+                } else if self.state == VtState::Osc && self.osc.len() <= OSC_MAX {
+                    self.osc.push(byte);
+                }
+            }
+        }
+    }
+
+    fn enter_escape(&mut self) {
+        self.state = VtState::Escape;
+        self.intermediates.clear();
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if self.utf8_need > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.utf8_buf[self.utf8_len] = byte;
+                self.utf8_len += 1;
+                if self.utf8_len == self.utf8_need {
+                    self.utf8_need = 0;
+                    let ch = std::str::from_utf8(&self.utf8_buf[..self.utf8_len])
+                        .ok()
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or('\u{fffd}');
+                    self.print(ch);
+                }
+                return;
+            }
+            // Truncated sequence — emit a replacement and reprocess this byte
+            self.utf8_need = 0;
+            self.print('\u{fffd}');
+        }
+
+        match byte {
+            0x1b => self.enter_escape(),
+            0x00..=0x1f => self.execute(byte),
+            0x7f => {}
+            0x20..=0x7e => self.print(byte as char),
+            0xc2..=0xf4 => {
+                self.utf8_buf[0] = byte;
+                self.utf8_len = 1;
+                self.utf8_need = match byte {
+                    0xc2..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    _ => 4,
+                };
+            }
+            _ => self.print('\u{fffd}'),
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        match byte {
+            0x20..=0x2f => self.intermediates.push(byte),
+            b'[' if self.intermediates.is_empty() => {
+                self.state = VtState::Csi;
+                self.params.clear();
+            }
+            b']' if self.intermediates.is_empty() => {
+                self.state = VtState::Osc;
+                self.osc.clear();
+                self.string_esc = false;
+            }
+            b'P' | b'X' | b'^' | b'_' if self.intermediates.is_empty() => {
+                self.state = VtState::String;
+                self.string_esc = false;
+            }
+            0x1b => self.intermediates.clear(),
+            0x00..=0x1f => self.execute(byte),
+            0x30..=0x7e => {
+                self.esc_dispatch(byte);
+                self.state = VtState::Ground;
+            }
+            _ => self.state = VtState::Ground,
+        }
+    }
+
+    fn csi(&mut self, byte: u8) {
+        match byte {
+            0x1b => self.enter_escape(),
+            0x00..=0x1f => self.execute(byte),
+            0x20..=0x3f => {
+                if self.params.len() >= 64 {
+                    self.state = VtState::CsiIgnore;
+                } else {
+                    self.params.push(byte);
+                }
+            }
+            0x40..=0x7e => {
+                self.csi_dispatch(byte);
+                self.state = VtState::Ground;
+            }
+            _ => self.state = VtState::CsiIgnore,
+        }
+    }
+
+    // ── Printing and C0 controls ────────────────────────────────────
+
+    fn print(&mut self, ch: char) {
+        let ch = if self.charsets[self.active_charset] == Charset::DecSpecial {
+            dec_special_graphics(ch)
+        } else {
+            ch
+        };
+        let width = unicode_width::UnicodeWidthChar::width(ch).unwrap_or(0);
+        if width == 0 {
+            self.attach_combining(ch);
+            return;
+        }
+        self.last_printed = Some(ch);
+
+        if self.cursor.pending_wrap && self.modes.autowrap {
+            let y = self.cursor.y;
+            self.grid_mut()[y].wrapped = true;
+            self.cursor.x = 0;
+            self.linefeed();
+        }
+        self.cursor.pending_wrap = false;
+
+        if width == 2 && self.cursor.x + 1 >= self.cols {
+            if self.cols < 2 {
+                return;
+            }
+            if self.modes.autowrap {
+                // Wide char doesn't fit — blank the last column and wrap early
+                let (x, y) = (self.cursor.x, self.cursor.y);
+                let blank = self.erase_cell();
+                self.grid_mut()[y].cells[x] = blank;
+                self.grid_mut()[y].touch(x + 1);
+                self.grid_mut()[y].wrapped = true;
+                self.cursor.x = 0;
+                self.linefeed();
+            } else {
+                self.cursor.x = self.cols - 2;
+            }
+        }
+
+        if self.modes.insert {
+            self.insert_chars(width);
+        }
+
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        self.clear_wide_overlap(y, x);
+        if width == 2 {
+            self.clear_wide_overlap(y, x + 1);
+        }
+        let (attrs, link) = (self.cursor.attrs, self.link);
+        let row = &mut self.grid_mut()[y];
+        row.cells[x] = Cell {
+            ch,
+            width: width as u8,
+            link,
+            ..Cell::blank(attrs)
+        };
+        if width == 2 {
+            row.cells[x + 1] = Cell {
+                width: 0,
+                link,
+                ..Cell::blank(attrs)
+            };
+        }
+        row.touch(x + width);
+
+        self.cursor.x += width;
+        if self.cursor.x >= self.cols {
+            self.cursor.x = self.cols - 1;
+            self.cursor.pending_wrap = self.modes.autowrap;
+        }
+    }
+
+    /// Print a run of printable ASCII: the same as `print` for each byte,
+    /// a row segment at a time. Only valid with the ASCII charset and
+    /// insert mode off.
+    fn print_ascii(&mut self, mut run: &[u8]) {
+        while !run.is_empty() {
+            if self.cursor.pending_wrap && self.modes.autowrap {
+                let y = self.cursor.y;
+                self.grid_mut()[y].wrapped = true;
+                self.cursor.x = 0;
+                self.linefeed();
+            }
+            self.cursor.pending_wrap = false;
+
+            let (x, y) = (self.cursor.x, self.cursor.y);
+            let n = run.len().min(self.cols - x);
+            // Only the ends of the segment can split a wide char
+            self.clear_wide_overlap(y, x);
+            if n > 1 {
+                self.clear_wide_overlap(y, x + n - 1);
+            }
+            let mut cell = Cell {
+                link: self.link,
+                ..Cell::blank(self.cursor.attrs)
+            };
+            let row = &mut self.grid_mut()[y];
+            for (dst, &b) in row.cells[x..x + n].iter_mut().zip(run) {
+                cell.ch = b as char;
+                *dst = cell;
+            }
+            row.touch(x + n);
+            self.last_printed = Some(run[n - 1] as char);
+
+            self.cursor.x += n;
+            if self.cursor.x >= self.cols {
+                self.cursor.x = self.cols - 1;
+                self.cursor.pending_wrap = self.modes.autowrap;
+            }
+            run = &run[n..];
+        }
+    }
+
+    /// Overwriting either half of a wide char blanks the other half.
+    fn clear_wide_overlap(&mut self, y: usize, x: usize) {
+        let cols = self.cols;
+        let row = &mut self.grid_mut()[y];
+        match row.cells[x].width {
+            0 if x > 0 => {
+                let attrs = row.cells[x - 1].attrs;
+                row.cells[x - 1] = Cell::blank(attrs);
+            }
+            2 if x + 1 < cols => {
+                let attrs = row.cells[x + 1].attrs;
+                row.cells[x + 1] = Cell::blank(attrs);
+            }
+            _ => {}
+        }
+    }
+
+    fn attach_combining(&mut self, ch: char) {
+        let y = self.cursor.y;
+        let mut x = if self.cursor.pending_wrap {
+            self.cursor.x
+        } else if self.cursor.x > 0 {
+            self.cursor.x - 1
+        } else {
+            return;
+        };
+        if self.grid()[y].cells[x].width == 0 && x > 0 {
+            x -= 1;
+        }
+        let row = &mut self.grid_mut()[y];
+        row.touch(x + 1);
+        if let Some(slot) = row.cells[x].combining.iter_mut().find(|c| **c == '\0') {
+            *slot = ch;
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            0x08 => {
+                // BS
+                self.cursor.pending_wrap = false;
+                self.cursor.x = self.cursor.x.saturating_sub(1);
+            }
+            0x09 => self.tab_forward(1),
+            0x0a..=0x0c => {
+                // LF, VT, FF
+                self.linefeed();
+                if self.modes.newline {
+                    self.cursor.x = 0;
+                }
+            }
+            0x0d => {
+                self.cursor.x = 0;
+                self.cursor.pending_wrap = false;
+            }
+            0x0e => self.active_charset = 1, // SO
+            0x0f => self.active_charset = 0, // SI
+            _ => {}
+        }
+    }
+
+    fn linefeed(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.y == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.y + 1 < self.rows {
+            self.cursor.y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.y == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.y > 0 {
+            self.cursor.y -= 1;
+        }
+    }
+
+    fn tab_forward(&mut self, n: usize) {
+        self.cursor.pending_wrap = false;
+        for _ in 0..n {
+            let next = (self.cursor.x + 1..self.cols).find(|&x| self.tabs[x]);
+            self.cursor.x = next.unwrap_or(self.cols - 1);
+        }
+    }
+
+    fn tab_backward(&mut self, n: usize) {
+        self.cursor.pending_wrap = false;
+        for _ in 0..n {
+            let prev = (0..self.cursor.x).rev().find(|&x| self.tabs[x]);
+            self.cursor.x = prev.unwrap_or(0);
+        }
+    }
+
+    // ── Scrolling ───────────────────────────────────────────────────
+
+    /// Scroll the region up by `n` lines. On the main screen with the
+    /// region anchored at the top, lines leaving the screen enter scrollback.
+    fn scroll_up(&mut self, n: usize) {
+        let (top, bottom, cols) = (self.scroll_top, self.scroll_bottom, self.cols);
+        let n = n.min(bottom - top + 1);
+        let blank = self.erase_cell();
+        for _ in 0..n {
+            if top == 0 && !self.in_alt {
+                let row = std::mem::replace(&mut self.main[0], Row::new(0, Attrs::default()));
+                self.push_scrollback(&row);
+                self.main[0] = row;
+            }
+            // Recycle the departing row as the new blank line
+            let grid = self.grid_mut();
+            grid[top..=bottom].rotate_left(1);
+            grid[bottom].fill(0..cols, blank);
+            grid[bottom].wrapped = false;
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let n = n.min(bottom - top + 1);
+        let blank = Row::new(self.cols, self.erase_cell().attrs);
+        for _ in 0..n {
+            self.grid_mut().remove(bottom);
+            self.grid_mut().insert(top, blank.clone());
+        }
+    }
+
+    fn push_scrollback(&mut self, row: &Row) {
+        if self.scrollback_cap == 0 {
+            return;
+        }
+        // Reuse the evicted line's allocation once scrollback is full
+        let mut line = if self.scrollback.len() >= self.scrollback_cap {
+            self.scrollback.pop_front().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        line.clear();
+        row.serialize(&self.links, &mut line);
+        if !row.wrapped {
+            line.extend_from_slice(b"\r\n");
+        }
+        self.scrollback.push_back(line);
+    }
+
+    // ── OSC dispatch ────────────────────────────────────────────────
+
+    /// Act on a complete OSC string. Only OSC 8 hyperlinks affect the
+    /// screen; the rest are left to the client.
+    fn osc_dispatch(&mut self) {
+        if self.osc.len() > OSC_MAX {
+            return;
+        }
+        let Some(rest) = self.osc.strip_prefix(b"8;") else {
+            return;
+        };
+        // OSC 8 ; params ; URI — an empty URI ends the link
+        let Some(sep) = rest.iter().position(|&b| b == b';') else {
+            return;
+        };
+        if sep + 1 == rest.len() {
+            self.link = 0;
+            return;
+        }
+        let link = String::from_utf8_lossy(rest).into_owned();
+        self.link = match self.links.iter().position(|l| *l == link) {
+            Some(i) => i as u16 + 1,
+            None if self.links.len() < LINKS_MAX => {
+                self.links.push(link);
+                self.links.len() as u16
+            }
+            None => 0,
+        };
+    }
+
+    // ── ESC dispatch ────────────────────────────────────────────────
+
+    fn esc_dispatch(&mut self, byte: u8) {
+        match (self.intermediates.first().copied(), byte) {
+            (None, b'7') => self.save_cursor(),
+            (None, b'8') => self.restore_cursor(),
+            (None, b'D') => self.linefeed(),
+            (None, b'E') => {
+                self.linefeed();
+                self.cursor.x = 0;
+            }
+            (None, b'M') => self.reverse_index(),
+            (None, b'H') => self.tabs[self.cursor.x] = true,
+            (None, b'c') => self.full_reset(),
+            (None, b'=') => self.modes.app_keypad = true,
+            (None, b'>') => self.modes.app_keypad = false,
+            (Some(b'('), b'0') => self.charsets[0] = Charset::DecSpecial,
+            (Some(b'('), _) => self.charsets[0] = Charset::Ascii,
+            (Some(b')'), b'0') => self.charsets[1] = Charset::DecSpecial,
+            (Some(b')'), _) => self.charsets[1] = Charset::Ascii,
+            (Some(b'#'), b'8') => {
+                // DECALN: fill the screen with 'E'
+                for row in self.grid_mut().iter_mut() {
+                    for cell in row.cells.iter_mut() {
+                        *cell = Cell {
+                            ch: 'E',
+                            ..Cell::blank(Attrs::default())
+                        };
+                    }
+                    row.used = row.cells.len();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        let saved = SavedCursor {
+            cursor: self.cursor,
+            origin_mode: self.modes.origin,
+            charsets: self.charsets,
+            active_charset: self.active_charset,
+        };
+        if self.in_alt {
+            self.saved_alt = Some(saved);
+        } else {
+            self.saved_main = Some(saved);
+        }
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = if self.in_alt {
+            self.saved_alt
+        } else {
+            self.saved_main
+        };
+        match saved {
+            Some(saved) => {
+                self.cursor = saved.cursor;
+                self.cursor.x = self.cursor.x.min(self.cols - 1);
+                self.cursor.y = self.cursor.y.min(self.rows - 1);
+                self.modes.origin = saved.origin_mode;
+                self.charsets = saved.charsets;
+                self.active_charset = saved.active_charset;
+            }
+            None => {
+                self.cursor.x = 0;
+                self.cursor.y = 0;
+                self.cursor.attrs = Attrs::default();
+                self.cursor.pending_wrap = false;
+            }
+        }
+    }
+
+    fn full_reset(&mut self) {
+        let scrollback = std::mem::take(&mut self.scrollback);
+        *self = Screen::new(self.cols as u16, self.rows as u16, self.scrollback_cap);
+        self.scrollback = scrollback;
+    }
+
+    fn soft_reset(&mut self) {
+        self.modes = Modes {
+            // DECSTR leaves these alone
+            bracketed_paste: self.modes.bracketed_paste,
+            focus_events: self.modes.focus_events,
+            mouse_tracking: self.modes.mouse_tracking,
+            mouse_encoding: self.modes.mouse_encoding,
+            cursor_style: self.modes.cursor_style,
+            ..Modes::default()
+        };
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows - 1;
+        self.cursor.attrs = Attrs::default();
+        self.cursor.pending_wrap = false;
+        self.charsets = [Charset::Ascii; 2];
+        self.active_charset = 0;
+        if self.in_alt {
+            self.saved_alt = None;
+        } else {
+            self.saved_main = None;
+        }
+    }
+
+    // ── CSI dispatch ────────────────────────────────────────────────
+
+    fn csi_dispatch(&mut self, final_byte: u8) {
+        // Split the collected bytes into private marker, params and intermediates
+        let raw = std::mem::take(&mut self.params);
+        let private = raw.first().copied().filter(|b| (b'<'..=b'?').contains(b));
+        let body = if private.is_some() { &raw[1..] } else { &raw[..] };
+        let param_end = body
+            .iter()
+            .position(|b| (0x20..=0x2f).contains(b))
+            .unwrap_or(body.len());
+        let intermediate = body.get(param_end).copied();
+        let params = parse_csi_params(&body[..param_end]);
+        self.params = raw;
+        self.params.clear();
+
+        // Single-value param accessor: missing or zero → default
+        let p = |i: usize, default: usize| -> usize {
+            match params.get(i).and_then(|g| g.first()) {
+                Some(&v) if v > 0 => v as usize,
+                _ => default,
+            }
+        };
+
+        match (private, intermediate, final_byte) {
+            (None, None, b'@') => self.insert_chars(p(0, 1)),
+            (None, None, b'A') => self.cursor_up(p(0, 1)),
+            (None, None, b'B') | (None, None, b'e') => self.cursor_down(p(0, 1)),
+            (None, None, b'C') | (None, None, b'a') => self.cursor_forward(p(0, 1)),
+            (None, None, b'D') => self.cursor_backward(p(0, 1)),
+            (None, None, b'E') => {
+                self.cursor_down(p(0, 1));
+                self.cursor.x = 0;
+            }
+            (None, None, b'F') => {
+                self.cursor_up(p(0, 1));
+                self.cursor.x = 0;
+            }
+            (None, None, b'G') | (None, None, b'`') => self.set_cursor_x(p(0, 1) - 1),
+            (None, None, b'H') | (None, None, b'f') => self.cursor_position(p(0, 1), p(1, 1)),
+            (None, None, b'I') => self.tab_forward(p(0, 1)),
+            (None, None, b'J') => self.erase_display(p(0, 0)),
+            (None, None, b'K') => self.erase_line(p(0, 0)),
+            (None, None, b'L') => self.insert_lines(p(0, 1)),
+            (None, None, b'M') => self.delete_lines(p(0, 1)),
+            (None, None, b'P') => self.delete_chars(p(0, 1)),
+            (None, None, b'S') => self.scroll_region_up(p(0, 1)),
+            (None, None, b'T') => self.scroll_down(p(0, 1)),
+            (None, None, b'X') => self.erase_chars(p(0, 1)),
+            (None, None, b'Z') => self.tab_backward(p(0, 1)),
+            (None, None, b'b') => {
+                if let Some(ch) = self.last_printed {
+                    for _ in 0..p(0, 1).min(self.cols * self.rows) {
+                        self.print(ch);
+                    }
+                }
+            }
+            (None, None, b'd') => self.cursor_position(p(0, 1), self.cursor.x + 1),
+            (None, None, b'g') => match p(0, 0) {
+                0 => self.tabs[self.cursor.x] = false,
+                3 => self.tabs.iter_mut().for_each(|t| *t = false),
+                _ => {}
+            },
+            (None, None, b'h') => self.set_ansi_modes(&params, true),
+            (None, None, b'l') => self.set_ansi_modes(&params, false),
+            (Some(b'?'), None, b'h') => self.set_private_modes(&params, true),
+            (Some(b'?'), None, b'l') => self.set_private_modes(&params, false),
+            (None, None, b'm') => self.cursor.attrs.apply_sgr(&params),
+            (None, None, b'r') => self.set_scroll_region(p(0, 1), p(1, self.rows)),
+            (None, None, b's') => self.save_cursor(),
+            (None, None, b'u') => self.restore_cursor(),
+            (None, Some(b'!'), b'p') => self.soft_reset(),
+            (None, Some(b' '), b'q') => self.modes.cursor_style = p(0, 0) as u16,
+            _ => {} // DSR, DA, window ops, etc. don't affect the screen
+        }
+    }
+
+    fn origin_offset(&self) -> usize {
+        if self.modes.origin {
+            self.scroll_top
+        } else {
+            0
+        }
+    }
+
+    fn cursor_up(&mut self, n: usize) {
+        let min = if self.cursor.y >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        };
+        self.cursor.y = self.cursor.y.saturating_sub(n).max(min);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn cursor_down(&mut self, n: usize) {
+        let max = if self.cursor.y <= self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows - 1
+        };
+        self.cursor.y = (self.cursor.y + n).min(max);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn cursor_forward(&mut self, n: usize) {
+        self.set_cursor_x(self.cursor.x + n);
+    }
+
+    fn cursor_backward(&mut self, n: usize) {
+        self.set_cursor_x(self.cursor.x.saturating_sub(n));
+    }
+
+    fn set_cursor_x(&mut self, x: usize) {
+        self.cursor.x = x.min(self.cols - 1);
+        self.cursor.pending_wrap = false;
+    }
+
+    /// CUP with 1-based coordinates, honoring origin mode.
+    fn cursor_position(&mut self, row: usize, col: usize) {
+        let (min_y, max_y) = if self.modes.origin {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.cursor.y = (min_y + row.max(1) - 1).min(max_y);
+        self.set_cursor_x(col.max(1) - 1);
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let blank = self.erase_cell();
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for row in self.grid_mut()[y + 1..].iter_mut() {
+                    *row = Row::new(row.cells.len(), blank.attrs);
+                }
+            }
+            1 => {
+                for row in self.grid_mut()[..y].iter_mut() {
+                    *row = Row::new(row.cells.len(), blank.attrs);
+                }
+                self.grid_mut()[y].fill(0..x + 1, blank);
+            }
+            2 => {
+                for row in self.grid_mut().iter_mut() {
+                    *row = Row::new(row.cells.len(), blank.attrs);
+                }
+            }
+            3 => self.scrollback.clear(),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let blank = self.erase_cell();
+        let (x, y, cols) = (self.cursor.x, self.cursor.y, self.cols);
+        let row = &mut self.grid_mut()[y];
+        match mode {
+            0 => {
+                row.fill(x..cols, blank);
+                row.wrapped = false;
+            }
+            1 => row.fill(0..x + 1, blank),
+            2 => {
+                row.fill(0..cols, blank);
+                row.wrapped = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_chars(&mut self, n: usize) {
+        let blank = self.erase_cell();
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let end = (x + n).min(self.cols);
+        self.grid_mut()[y].fill(x..end, blank);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn insert_chars(&mut self, n: usize) {
+        let blank = self.erase_cell();
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let cols = self.cols;
+        let row = &mut self.grid_mut()[y];
+        let n = n.min(cols - x);
+        row.cells.truncate(cols - n);
+        row.cells.splice(x..x, std::iter::repeat_n(blank, n));
+        // Everything from the cursor on shifted right
+        if row.used > x {
+            row.used = (row.used + n).min(cols);
+        }
+        if blank != Cell::blank(Attrs::default()) {
+            row.touch(x + n);
+        }
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let blank = self.erase_cell();
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let cols = self.cols;
+        let row = &mut self.grid_mut()[y];
+        let n = n.min(cols - x);
+        row.cells.drain(x..x + n);
+        row.cells.resize(cols, blank);
+        if blank != Cell::blank(Attrs::default()) {
+            row.touch(cols);
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        let y = self.cursor.y;
+        if y < self.scroll_top || y > self.scroll_bottom {
+            return;
+        }
+        let bottom = self.scroll_bottom;
+        let n = n.min(bottom - y + 1);
+        let blank = Row::new(self.cols, self.erase_cell().attrs);
+        for _ in 0..n {
+            self.grid_mut().remove(bottom);
+            self.grid_mut().insert(y, blank.clone());
+        }
+        self.cursor.x = 0;
+        self.cursor.pending_wrap = false;
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        let y = self.cursor.y;
+        if y < self.scroll_top || y > self.scroll_bottom {
+            return;
+        }
+        let bottom = self.scroll_bottom;
+        let n = n.min(bottom - y + 1);
+        let blank = Row::new(self.cols, self.erase_cell().attrs);
+        for _ in 0..n {
+            self.grid_mut().remove(y);
+            self.grid_mut().insert(bottom, blank.clone());
+        }
+        self.cursor.x = 0;
+        self.cursor.pending_wrap = false;
+    }
+
+    /// SU: scrolls the region without feeding scrollback (matches xterm.js).
+    fn scroll_region_up(&mut self, n: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let n = n.min(bottom - top + 1);
+        let blank = Row::new(self.cols, self.erase_cell().attrs);
+        for _ in 0..n {
+            self.grid_mut().remove(top);
+            self.grid_mut().insert(bottom, blank.clone());
+        }
+    }
+
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let bottom = bottom.min(self.rows);
+        if top >= bottom {
+            return;
+        }
+        self.scroll_top = top - 1;
+        self.scroll_bottom = bottom - 1;
+        self.cursor_position(1, 1);
+    }
+
+    fn set_ansi_modes(&mut self, params: &[Vec<u16>], on: bool) {
+        for group in params {
+            match group.first() {
+                Some(4) => self.modes.insert = on,
+                Some(20) => self.modes.newline = on,
+                _ => {}
+            }
+        }
+    }
+
+    fn set_private_modes(&mut self, params: &[Vec<u16>], on: bool) {
+        for group in params {
+            let Some(&mode) = group.first() else { continue };
+            match mode {
+                1 => self.modes.app_cursor = on,
+                6 => {
+                    self.modes.origin = on;
+                    self.cursor_position(1, 1);
+                }
+                7 => self.modes.autowrap = on,
+                25 => self.modes.cursor_visible = on,
+                47 | 1047 => self.switch_screen(on, false, mode == 1047),
+                1048 => {
+                    if on {
+                        self.save_cursor();
+                    } else {
+                        self.restore_cursor();
+                    }
+                }
+                1049 => self.switch_screen(on, true, true),
+                1000 | 1002 | 1003 => {
+                    self.modes.mouse_tracking = if on { mode } else { 0 };
+                }
+                1004 => self.modes.focus_events = on,
+                1005 | 1006 | 1015 => {
+                    self.modes.mouse_encoding = if on { mode } else { 0 };
+                }
+                2004 => self.modes.bracketed_paste = on,
+                _ => {}
+            }
+        }
+    }
+
+    /// Enter or leave the alternate screen. Mode 1049 also saves/restores
+    /// the cursor; modes 1047/1049 clear the alt screen.
+    fn switch_screen(&mut self, to_alt: bool, save_cursor: bool, clear: bool) {
+        if to_alt == self.in_alt {
+            return;
+        }
+        if to_alt {
+            if save_cursor {
+                self.alt_saved_cursor = Some(SavedCursor {
+                    cursor: self.cursor,
+                    origin_mode: self.modes.origin,
+                    charsets: self.charsets,
+                    active_charset: self.active_charset,
+                });
+            }
+            self.in_alt = true;
+            if clear {
+                let attrs = self.erase_cell().attrs;
+                for row in self.alt.iter_mut() {
+                    *row = Row::new(self.cols, attrs);
+                }
+            }
+        } else {
+            self.in_alt = false;
+            if save_cursor {
+                if let Some(saved) = self.alt_saved_cursor.take() {
+                    self.cursor = saved.cursor;
+                    self.modes.origin = saved.origin_mode;
+                    self.charsets = saved.charsets;
+                    self.active_charset = saved.active_charset;
+                }
+            }
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    // ── Resize and clear ────────────────────────────────────────────
+
+    /// Resize the grid. Columns are truncated or padded (no reflow — the
+    /// client reflows its own scrollback). Rows evicted from the top of the
+    /// main screen go to scrollback.
+    pub(crate) fn resize(&mut self, cols: u16, rows: u16) {
+        let cols = (cols as usize).max(1);
+        let rows = (rows as usize).max(1);
+        if cols == self.cols && rows == self.rows {
+            return;
+        }
+
+        // The main screen's cursor is parked in the 1049 save slot while
+        // the alt screen is active
+        let main_cursor_y = if self.in_alt {
+            self.alt_saved_cursor.map_or(self.rows - 1, |s| s.cursor.y)
+        } else {
+            self.cursor.y
+        };
+        let alt_cursor_y = if self.in_alt { self.cursor.y } else { self.rows - 1 };
+        let (main_y, evicted) = resize_grid(&mut self.main, cols, rows, main_cursor_y);
+        for row in &evicted {
+            self.push_scrollback(row);
+        }
+        let (alt_y, _) = resize_grid(&mut self.alt, cols, rows, alt_cursor_y);
+        if self.in_alt {
+            self.cursor.y = alt_y;
+            if let Some(saved) = self.alt_saved_cursor.as_mut() {
+                saved.cursor.y = main_y;
+            }
+        } else {
+            self.cursor.y = main_y;
+        }
+
+        self.cols = cols;
+        self.rows = rows;
+        self.cursor.x = self.cursor.x.min(cols - 1);
+        self.cursor.y = self.cursor.y.min(rows - 1);
+        self.cursor.pending_wrap = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.tabs = default_tabs(cols);
+        for saved in [
+            &mut self.saved_main,
+            &mut self.saved_alt,
+            &mut self.alt_saved_cursor,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            saved.cursor.x = saved.cursor.x.min(cols - 1);
+            saved.cursor.y = saved.cursor.y.min(rows - 1);
+        }
+    }
+
+    /// Mirror xterm.js `term.clear()`: drop scrollback and make the cursor
+    /// line the first line of the screen.
+    pub(crate) fn clear_history(&mut self) {
+        self.scrollback.clear();
+        if self.in_alt {
+            return;
+        }
+        let y = self.cursor.y;
+        let cols = self.cols;
+        self.main.drain(..y);
+        self.main
+            .iter_mut()
+            .skip(1)
+            .for_each(|row| *row = Row::new(cols, Attrs::default()));
+        self.main.resize(self.rows, Row::new(cols, Attrs::default()));
+        self.cursor.y = 0;
+    }
+
+    // ── Snapshot ────────────────────────────────────────────────────
+
+    /// Synthesize an escape stream that rebuilds the current scrollback,
+    /// screen contents, cursor and modes on a fresh terminal of the same size.
+    pub(crate) fn snapshot(&self) -> Vec<u8> {
+        let scrollback_len: usize = self.scrollback.iter().map(Vec::len).sum();
+        let mut out = Vec::with_capacity(scrollback_len + self.cols * self.rows + 256);
+
+        // Scrollback, then the main screen. Printing the main rows with
+        // CRLF pushes the scrollback lines off the top of the client's
+        // screen and into its own scrollback.
+        for line in &self.scrollback {
+            out.extend_from_slice(line);
+        }
+        let main_end = if self.scrollback.is_empty() {
+            // Nothing to push into scrollback — trailing blank rows are free
+            self.main
+                .iter()
+                .rposition(|r| !r.is_blank())
+                .map_or(0, |i| i + 1)
+        } else {
+            self.main.len()
+        };
+        for (i, row) in self.main[..main_end].iter().enumerate() {
+            row.serialize(&self.links, &mut out);
+            if i + 1 < main_end && !row.wrapped {
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+
+        if self.in_alt {
+            // Park the main cursor where the app's exit from the alt screen
+            // will restore it, then draw the alt screen row by row.
+            let main_cursor = self
+                .alt_saved_cursor
+                .map_or(self.cursor, |saved| saved.cursor);
+            out.extend_from_slice(
+                format!("\x1b[{};{}H\x1b[?1049h", main_cursor.y + 1, main_cursor.x + 1).as_bytes(),
+            );
+            for (i, row) in self.alt.iter().enumerate() {
+                if !row.is_blank() {
+                    out.extend_from_slice(format!("\x1b[{}H", i + 1).as_bytes());
+                    row.serialize(&self.links, &mut out);
+                }
+            }
+        }
+
+        // Scroll region (DECSTBM homes the cursor, so it goes before CUP)
+        if self.scroll_top != 0 || self.scroll_bottom != self.rows - 1 {
+            out.extend_from_slice(
+                format!("\x1b[{};{}r", self.scroll_top + 1, self.scroll_bottom + 1).as_bytes(),
+            );
+        }
+
+        // Saved cursor (DECSC) for the active screen. Positioned before
+        // origin mode is restored so the coordinates are absolute.
+        let saved = if self.in_alt {
+            self.saved_alt
+        } else {
+            self.saved_main
+        };
+        if let Some(saved) = saved {
+            out.extend_from_slice(
+                format!("\x1b[{};{}H", saved.cursor.y + 1, saved.cursor.x + 1).as_bytes(),
+            );
+            saved.cursor.attrs.write_sgr(&mut out);
+            out.extend_from_slice(b"\x1b7\x1b[0m");
+        }
+
+        self.write_modes(&mut out);
+
+        // Character sets
+        if self.charsets[0] == Charset::DecSpecial {
+            out.extend_from_slice(b"\x1b(0");
+        }
+        if self.charsets[1] == Charset::DecSpecial {
+            out.extend_from_slice(b"\x1b)0");
+        }
+        if self.active_charset == 1 {
+            out.push(0x0e);
+        }
+
+        // Cursor position. Absolute positioning can't express a pending
+        // wrap, so re-print the last cell to put the cursor in that state.
+        let y = self.cursor.y + 1 - self.origin_offset();
+        if self.cursor.pending_wrap {
+            let row = &self.grid()[self.cursor.y];
+            let mut x = self.cols - 1;
+            if row.cells[x].width == 0 && x > 0 {
+                x -= 1;
+            }
+            let cell = &row.cells[x];
+            out.extend_from_slice(format!("\x1b[{};{}H", y, x + 1).as_bytes());
+            cell.attrs.write_sgr(&mut out);
+            if cell.link != 0 {
+                write_link(&self.links, cell.link, &mut out);
+            }
+            cell.write_text(&mut out);
+            if cell.link != 0 {
+                write_link(&self.links, 0, &mut out);
+            }
+        } else {
+            out.extend_from_slice(format!("\x1b[{};{}H", y, self.cursor.x + 1).as_bytes());
+        }
+
+        // Current pen
+        if self.cursor.attrs != Attrs::default() {
+            self.cursor.attrs.write_sgr(&mut out);
+        } else if self.cursor.pending_wrap {
+            out.extend_from_slice(b"\x1b[0m");
+        }
+        if self.link != 0 {
+            write_link(&self.links, self.link, &mut out);
+        }
+
+        out
+    }
+
+    /// Append sequences that restore every non-default mode.
+    fn write_modes(&self, out: &mut Vec<u8>) {
+        let m = &self.modes;
+        let mut private: Vec<String> = Vec::new();
+        let mut private_off: Vec<String> = Vec::new();
+        if m.app_cursor {
+            private.push("1".into());
+        }
+        if m.origin {
+            private.push("6".into());
+        }
+        if !m.autowrap {
+            private_off.push("7".into());
+        }
+        if !m.cursor_visible {
+            private_off.push("25".into());
+        }
+        if m.mouse_tracking != 0 {
+            private.push(m.mouse_tracking.to_string());
+        }
+        if m.focus_events {
+            private.push("1004".into());
+        }
+        if m.mouse_encoding != 0 {
+            private.push(m.mouse_encoding.to_string());
+        }
+        if m.bracketed_paste {
+            private.push("2004".into());
+        }
+        if !private.is_empty() {
+            out.extend_from_slice(format!("\x1b[?{}h", private.join(";")).as_bytes());
+        }
+        if !private_off.is_empty() {
+            out.extend_from_slice(format!("\x1b[?{}l", private_off.join(";")).as_bytes());
+        }
+        if m.insert {
+            out.extend_from_slice(b"\x1b[4h");
+        }
+        if m.newline {
+            out.extend_from_slice(b"\x1b[20h");
+        }
+        if m.app_keypad {
+            out.extend_from_slice(b"\x1b=");
+        }
+        if m.cursor_style != 0 {
+            out.extend_from_slice(format!("\x1b[{} q", m.cursor_style).as_bytes());
+        }
+    }
+}
+
+/// Resize a grid to `cols` x `rows`. When shrinking, blank rows below the
+/// cursor are dropped first, then rows are evicted from the top.
+/// Returns the adjusted cursor row and the evicted rows (oldest first).
+fn resize_grid(grid: &mut Vec<Row>, cols: usize, rows: usize, cursor_y: usize) -> (usize, Vec<Row>) {
+    let mut cursor_y = cursor_y;
+    let mut evicted = Vec::new();
+    for row in grid.iter_mut() {
+        row.resize(cols);
+    }
+    while grid.len() > rows {
+        if cursor_y + 1 < grid.len() && grid.last().is_some_and(Row::is_blank) {
+            grid.pop();
+        } else {
+            evicted.push(grid.remove(0));
+            cursor_y = cursor_y.saturating_sub(1);
+        }
+    }
+    while grid.len() < rows {
+        grid.push(Row::new(cols, Attrs::default()));
+    }
+    (cursor_y, evicted)
+}
+
+/// Default tab stops every 8 columns.
+fn default_tabs(cols: usize) -> Vec<bool> {
+    (0..cols).map(|x| x > 0 && x % 8 == 0).collect()
+}
+
+/// Parse CSI parameter bytes into groups: `;` separates params, `:`
+/// separates sub-params within a param. Empty params parse as 0.
+pub(crate) fn parse_csi_params(data: &[u8]) -> Vec<Vec<u16>> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.split(|&b| b == b';')
+        .map(|group| {
+            group
+                .split(|&b| b == b':')
+                .map(|sub| {
+                    sub.iter()
+                        .filter(|b| b.is_ascii_digit())
+                        .fold(0u16, |acc, &b| acc.saturating_mul(10).saturating_add((b - b'0') as u16))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Parse an extended SGR color (38/48/58) starting at `params[i]`.
+/// Handles both the colon form (`38:2:R:G:B`, `38:2::R:G:B`, `38:5:N`)
+/// and the semicolon form (`38;2;R;G;B`, `38;5;N`).
+/// Returns the color and the number of param groups consumed.
+fn parse_extended_color(params: &[Vec<u16>], i: usize) -> (Option<Color>, usize) {
+    let group = &params[i];
+    if group.len() > 1 {
+        // Colon form: everything lives in this group
+        let color = match group[1] {
+            5 => group.get(2).map(|&n| Color::Indexed(n.min(255) as u8)),
+            2 => {
+                // Optional colorspace id: 38:2:<cs>:R:G:B
+                let rgb = if group.len() >= 6 { &group[3..6] } else { group.get(2..5).unwrap_or(&[]) };
+                if rgb.len() == 3 {
+                    Some(Color::Rgb(rgb[0].min(255) as u8, rgb[1].min(255) as u8, rgb[2].min(255) as u8))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        return (color, 1);
+    }
+
+    let value = |j: usize| params.get(j).and_then(|g| g.first()).copied();
+    match value(i + 1) {
+        Some(5) => (value(i + 2).map(|n| Color::Indexed(n.min(255) as u8)), 3),
+        Some(2) => match (value(i + 2), value(i + 3), value(i + 4)) {
+            (Some(r), Some(g), Some(b)) => {
+                (Some(Color::Rgb(r.min(255) as u8, g.min(255) as u8, b.min(255) as u8)), 5)
+            }
+            _ => (None, params.len() - i),
+        },
+        _ => (None, 2),
+    }
+}
+
+/// Map a character through the DEC Special Graphics charset (line drawing).
+fn dec_special_graphics(ch: char) -> char {
+    match ch {
+        '`' => '◆',
+        'a' => '▒',
+        'f' => '°',
+        'g' => '±',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => ch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OutputBuffer, ALT_BUFFER_CAP, BUFFER_SIZE};
+
+    /// Render each screen row as a trimmed string (for assertions).
+    fn screen_lines(screen: &Screen) -> Vec<String> {
+        screen
+            .grid()
+            .iter()
+            .map(|row| {
+                let text: String = row
+                    .cells
+                    .iter()
+                    .filter(|c| c.width != 0)
+                    .map(|c| c.ch)
+                    .collect();
+                text.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    /// Replay a snapshot into a fresh screen and assert the state matches.
+    fn assert_snapshot_round_trip(screen: &Screen) {
+        let snapshot = screen.snapshot();
+        let mut replayed = Screen::new(screen.cols as u16, screen.rows as u16, SCROLLBACK_LINES);
+        replayed.feed(&snapshot);
+
+        assert_eq!(replayed.in_alt, screen.in_alt, "alt screen state");
+        assert_eq!(replayed.scrollback, screen.scrollback, "scrollback");
+        for (grid, orig) in [(&replayed.main, &screen.main), (&replayed.alt, &screen.alt)] {
+            if std::ptr::eq(orig, &screen.alt) && !screen.in_alt {
+                continue; // inactive alt screen content isn't replayed
+            }
+            for (y, (a, b)) in grid.iter().zip(orig.iter()).enumerate() {
+                assert_eq!(a.cells, b.cells, "row {} cells", y);
+            }
+        }
+        assert_eq!(replayed.cursor.x, screen.cursor.x, "cursor x");
+        assert_eq!(replayed.cursor.y, screen.cursor.y, "cursor y");
+        assert_eq!(replayed.cursor.pending_wrap, screen.cursor.pending_wrap, "pending wrap");
+        assert_eq!(replayed.cursor.attrs, screen.cursor.attrs, "pen");
+        assert_eq!(replayed.modes, screen.modes, "modes");
+        assert_eq!(replayed.scroll_top, screen.scroll_top, "scroll top");
+        assert_eq!(replayed.scroll_bottom, screen.scroll_bottom, "scroll bottom");
+        assert_eq!(replayed.charsets, screen.charsets, "charsets");
+    }
+
+    #[test]
+    fn screen_prints_text_and_moves_cursor() {
+        let mut screen = Screen::new(20, 5, 100);
+        screen.feed(b"hello\r\nworld");
+        assert_eq!(screen_lines(&screen)[..2], ["hello", "world"]);
+        assert_eq!((screen.cursor.x, screen.cursor.y), (5, 1));
+    }
+
+    #[test]
+    fn screen_autowraps_and_marks_row_wrapped() {
+        let mut screen = Screen::new(5, 3, 100);
+        screen.feed(b"abcdefg");
+        assert_eq!(screen_lines(&screen)[..2], ["abcde", "fg"]);
+        assert!(screen.main[0].wrapped);
+        assert!(!screen.main[1].wrapped);
+    }
+
+    #[test]
+    fn screen_pending_wrap_at_last_column() {
+        let mut screen = Screen::new(5, 3, 100);
+        screen.feed(b"abcde");
+        assert_eq!(screen.cursor.x, 4);
+        assert!(screen.cursor.pending_wrap);
+        // CR clears the pending wrap without moving to the next line
+        screen.feed(b"\rX");
+        assert_eq!(screen_lines(&screen)[0], "Xbcde");
+        assert_eq!(screen.cursor.y, 0);
+    }
+
+    #[test]
+    fn screen_scrolls_lines_into_scrollback() {
+        let mut screen = Screen::new(10, 3, 100);
+        screen.feed(b"one\r\ntwo\r\nthree\r\nfour\r\nfive");
+        assert_eq!(screen_lines(&screen), ["three", "four", "five"]);
+        let scrollback: Vec<&[u8]> = screen.scrollback.iter().map(Vec::as_slice).collect();
+        assert_eq!(scrollback, [&b"one\r\n"[..], &b"two\r\n"[..]]);
+    }
+
+    #[test]
+    fn screen_scrollback_is_capped() {
+        let mut screen = Screen::new(10, 2, 3);
+        for i in 0..20 {
+            screen.feed(format!("line{}\r\n", i).as_bytes());
+        }
+        assert_eq!(screen.scrollback.len(), 3);
+        assert_eq!(screen.scrollback[0], b"line16\r\n");
+    }
+
+    #[test]
+    fn screen_scroll_region_does_not_feed_scrollback() {
+        let mut screen = Screen::new(10, 5, 100);
+        screen.feed(b"header\x1b[2;5r\x1b[5;1H");
+        screen.feed(b"a\r\nb\r\nc\r\nd\r\ne\r\nf");
+        assert!(screen.scrollback.is_empty());
+        assert_eq!(screen_lines(&screen)[0], "header");
+        assert_eq!(screen_lines(&screen)[4], "f");
+    }
+
+    #[test]
+    fn screen_cursor_addressed_redraws_replace_frames() {
+        // TUIs that redraw by moving the cursor (no ESC[2J) must not
+        // accumulate dead frames in the replay.
+        let mut screen = Screen::new(40, 10, 100);
+        for frame in 0..500 {
+            screen.feed(format!("\x1b[H\x1b[2Kframe {}\x1b[3;1Hstatus {}", frame, frame).as_bytes());
+        }
+        let snapshot = String::from_utf8(screen.snapshot()).unwrap();
+        assert!(snapshot.contains("frame 499"));
+        assert!(snapshot.contains("status 499"));
+        assert!(!snapshot.contains("frame 498"));
+        assert!(snapshot.len() < 200, "snapshot too large: {} bytes", snapshot.len());
+    }
+
+    #[test]
+    fn screen_erase_in_line_and_display() {
+        let mut screen = Screen::new(10, 3, 100);
+        screen.feed(b"aaaaaaaaaa\r\nbbbbbbbbbb\r\ncccccccccc");
+        screen.feed(b"\x1b[2;5H\x1b[K");
+        assert_eq!(screen_lines(&screen), ["aaaaaaaaaa", "bbbb", "cccccccccc"]);
+        screen.feed(b"\x1b[1K");
+        assert_eq!(screen_lines(&screen)[1], "");
+        screen.feed(b"\x1b[J");
+        assert_eq!(screen_lines(&screen), ["aaaaaaaaaa", "", ""]);
+        screen.feed(b"\x1b[2J");
+        assert_eq!(screen_lines(&screen), ["", "", ""]);
+    }
+
+    #[test]
+    fn screen_ed3_clears_scrollback() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.feed(b"a\r\nb\r\nc\r\nd");
+        assert!(!screen.scrollback.is_empty());
+        screen.feed(b"\x1b[2J\x1b[3J\x1b[H");
+        assert!(screen.scrollback.is_empty());
+    }
+
+    #[test]
+    fn screen_insert_and_delete_chars() {
+        let mut screen = Screen::new(10, 1, 100);
+        screen.feed(b"abcdef\x1b[1;3H\x1b[2@");
+        assert_eq!(screen_lines(&screen)[0], "ab  cdef");
+        screen.feed(b"\x1b[3P");
+        assert_eq!(screen_lines(&screen)[0], "abdef");
+        screen.feed(b"\x1b[2X");
+        assert_eq!(screen_lines(&screen)[0], "ab  f");
+    }
+
+    #[test]
+    fn screen_rows_track_written_width() {
+        let assert_used = |screen: &Screen| {
+            for (y, row) in screen.main.iter().enumerate() {
+                assert!(
+                    row.cells[row.used..].iter().all(|c| *c == Cell::blank(Attrs::default())),
+                    "row {} has cells past used={}",
+                    y,
+                    row.used
+                );
+            }
+        };
+        let mut screen = Screen::new(10, 3, 100);
+        for step in [
+            &b"abc"[..],
+            b"\x1b[41m\x1b[K\x1b[0m",
+            b"\r\nxy\x1b[1;1H\x1b[4@",
+            b"\x1b[2;1H\x1b[44m\x1b[3P\x1b[0m",
+            b"\x1b[3;8He\xcc\x81",
+            b"\x1b[3;1H\x1b[2K\r\n\r\n\r\n",
+            b"\x1b#8\x1b[2J",
+        ] {
+            screen.feed(step);
+            assert_used(&screen);
+        }
+        assert_snapshot_round_trip(&screen);
+        screen.resize(5, 3);
+        assert_used(&screen);
+    }
+
+    #[test]
+    fn screen_insert_and_delete_lines() {
+        let mut screen = Screen::new(10, 4, 100);
+        screen.feed(b"1\r\n2\r\n3\r\n4\x1b[2;1H\x1b[L");
+        assert_eq!(screen_lines(&screen), ["1", "", "2", "3"]);
+        screen.feed(b"\x1b[2M");
+        assert_eq!(screen_lines(&screen), ["1", "3", "", ""]);
+        assert!(screen.scrollback.is_empty());
+    }
+
+    #[test]
+    fn screen_sgr_attributes() {
+        let mut screen = Screen::new(20, 1, 100);
+        screen.feed(b"\x1b[1;31ma\x1b[38;5;200mb\x1b[38;2;1;2;3mc\x1b[48:2::4:5:6md\x1b[0me");
+        let cells = &screen.main[0].cells;
+        assert_eq!(cells[0].attrs.flags, ATTR_BOLD);
+        assert_eq!(cells[0].attrs.fg, Color::Indexed(1));
+        assert_eq!(cells[1].attrs.fg, Color::Indexed(200));
+        assert_eq!(cells[2].attrs.fg, Color::Rgb(1, 2, 3));
+        assert_eq!(cells[3].attrs.bg, Color::Rgb(4, 5, 6));
+        assert_eq!(cells[4].attrs, Attrs::default());
+    }
+
+    #[test]
+    fn screen_sgr_serializes_semicolon_rgb() {
+        let attrs = Attrs {
+            fg: Color::Rgb(10, 20, 30),
+            bg: Color::Indexed(9),
+            flags: ATTR_BOLD | ATTR_UNDERLINE,
+        };
+        let mut out = Vec::new();
+        attrs.write_sgr(&mut out);
+        assert_eq!(out, b"\x1b[0;1;4;38;2;10;20;30;101m");
+    }
+
+    #[test]
+    fn screen_alt_screen_preserves_main_content() {
+        let mut screen = Screen::new(20, 3, 100);
+        screen.feed(b"$ vim\r\n");
+        screen.feed(b"\x1b[?1049h\x1b[Hediting");
+        assert!(screen.in_alt);
+        assert_eq!(screen_lines(&screen)[0], "editing");
+        screen.feed(b"\x1b[?1049l");
+        assert!(!screen.in_alt);
+        assert_eq!(screen_lines(&screen)[0], "$ vim");
+        assert_eq!((screen.cursor.x, screen.cursor.y), (0, 1));
+    }
+
+    #[test]
+    fn screen_alt_screen_scrolling_skips_scrollback() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.feed(b"\x1b[?1049h");
+        screen.feed(b"a\r\nb\r\nc\r\nd");
+        assert!(screen.scrollback.is_empty());
+    }
+
+    #[test]
+    fn screen_wide_chars_occupy_two_cells() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.feed("a界b".as_bytes());
+        let cells = &screen.main[0].cells;
+        assert_eq!(cells[1].ch, '界');
+        assert_eq!(cells[1].width, 2);
+        assert_eq!(cells[2].width, 0);
+        assert_eq!(cells[3].ch, 'b');
+        assert_eq!(screen.cursor.x, 4);
+    }
+
+    #[test]
+    fn screen_wide_char_wraps_early_at_edge() {
+        let mut screen = Screen::new(4, 2, 100);
+        screen.feed("abc界".as_bytes());
+        assert_eq!(screen_lines(&screen), ["abc", "界"]);
+        assert!(screen.main[0].wrapped);
+    }
+
+    #[test]
+    fn screen_utf8_split_across_feeds() {
+        let mut screen = Screen::new(10, 1, 100);
+        let bytes = "é".as_bytes();
+        screen.feed(&bytes[..1]);
+        screen.feed(&bytes[1..]);
+        assert_eq!(screen_lines(&screen)[0], "é");
+    }
+
+    #[test]
+    fn screen_escape_split_across_feeds() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.feed(b"ab\x1b[");
+        screen.feed(b"2;3Hc");
+        assert_eq!(screen_lines(&screen)[1], "  c");
+    }
+
+    #[test]
+    fn screen_combining_chars_attach_to_previous_cell() {
+        let mut screen = Screen::new(10, 1, 100);
+        screen.feed("e\u{301}x".as_bytes());
+        assert_eq!(screen.main[0].cells[0].combining, ['\u{301}', '\0']);
+        assert_eq!(screen.main[0].cells[1].ch, 'x');
+    }
+
+    #[test]
+    fn screen_dec_line_drawing() {
+        let mut screen = Screen::new(10, 1, 100);
+        screen.feed(b"\x1b(0lqk\x1b(Bq");
+        assert_eq!(screen_lines(&screen)[0], "┌─┐q");
+    }
+
+    #[test]
+    fn screen_ignores_osc_and_dcs_payloads() {
+        let mut screen = Screen::new(20, 1, 100);
+        screen.feed(b"a\x1b]0;title\x07b\x1bPq#0;2;0;0;0\x1b\\c");
+        assert_eq!(screen_lines(&screen)[0], "abc");
+    }
+
+    #[test]
+    fn screen_tracks_osc8_links() {
+        let mut screen = Screen::new(20, 1, 100);
+        screen.feed(b"a\x1b]8;id=x;https://a.example/\x1b\\bc\x1b]8;;\x07d\x1b]8;;https://a.example/\x07e");
+        let links: Vec<u16> = screen.main[0].cells[..5].iter().map(|c| c.link).collect();
+        assert_eq!(links, [0, 1, 1, 0, 2]);
+        assert_eq!(screen.links, ["id=x;https://a.example/", ";https://a.example/"]);
+    }
+
+    #[test]
+    fn snapshot_keeps_osc8_links() {
+        let mut screen = Screen::new(20, 3, 100);
+        screen.feed(b"see \x1b]8;;https://example.com/docs\x1b\\\x1b[4mthe docs\x1b[0m\x1b]8;;\x1b\\ here\r\n");
+        screen.feed(b"\x1b]8;;https://example.com/a\x07one\r\n\r\n\r\n");
+        screen.feed(b"\x1b]8;;https://example.com/b\x07two");
+        assert_snapshot_round_trip(&screen);
+
+        let snapshot = String::from_utf8(screen.snapshot()).unwrap();
+        assert!(snapshot.starts_with("see \x1b[0;4m\x1b]8;;https://example.com/docs\x1b\\the docs\x1b[0m\x1b]8;;\x1b\\ here"));
+        assert!(snapshot.contains("\x1b]8;;https://example.com/a\x1b\\one\x1b]8;;\x1b\\\r\n"));
+
+        // The open link carries on into whatever is printed next
+        let mut replayed = Screen::new(20, 3, 100);
+        replayed.feed(snapshot.as_bytes());
+        replayed.feed(b"!");
+        let cell = &replayed.main[2].cells[3];
+        assert_eq!(replayed.links[cell.link as usize - 1], ";https://example.com/b");
+    }
+
+    #[test]
+    fn screen_tab_stops() {
+        let mut screen = Screen::new(20, 1, 100);
+        screen.feed(b"a\tb\tc");
+        assert_eq!(screen.main[0].cells[8].ch, 'b');
+        assert_eq!(screen.main[0].cells[16].ch, 'c');
+    }
+
+    #[test]
+    fn screen_resize_shrink_pushes_rows_to_scrollback() {
+        let mut screen = Screen::new(10, 4, 100);
+        screen.feed(b"1\r\n2\r\n3\r\n4");
+        screen.resize(10, 2);
+        assert_eq!(screen_lines(&screen), ["3", "4"]);
+        assert_eq!(screen.scrollback.len(), 2);
+        assert_eq!(screen.cursor.y, 1);
+    }
+
+    #[test]
+    fn screen_resize_shrink_drops_blank_rows_below_cursor() {
+        let mut screen = Screen::new(10, 4, 100);
+        screen.feed(b"prompt");
+        screen.resize(5, 2);
+        assert_eq!(screen_lines(&screen), ["promp", ""]);
+        assert!(screen.scrollback.is_empty());
+        assert_eq!((screen.cursor.x, screen.cursor.y), (4, 0));
+    }
+
+    #[test]
+    fn screen_clear_history_keeps_cursor_line() {
+        let mut screen = Screen::new(10, 3, 100);
+        screen.feed(b"a\r\nb\r\nc\r\nd\r\n$ ");
+        screen.clear_history();
+        assert!(screen.scrollback.is_empty());
+        assert_eq!(screen_lines(&screen), ["$", "", ""]);
+        assert_eq!((screen.cursor.x, screen.cursor.y), (2, 0));
+    }
+
+    #[test]
+    fn screen_full_reset_keeps_scrollback() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.feed(b"a\r\nb\r\nc\x1b[?25l\x1bc");
+        assert_eq!(screen.scrollback.len(), 1);
+        assert_eq!(screen_lines(&screen), ["", ""]);
+        assert!(screen.modes.cursor_visible);
+    }
+
+    #[test]
+    fn snapshot_empty_screen() {
+        let screen = Screen::new(80, 24, 100);
+        assert_eq!(screen.snapshot(), b"\x1b[1;1H");
+    }
+
+    #[test]
+    fn snapshot_round_trip_shell_session() {
+        let mut screen = Screen::new(20, 5, 100);
+        for i in 0..12 {
+            screen.feed(format!("$ echo {}\r\n\x1b[32m{}\x1b[0m\r\n", i, i).as_bytes());
+        }
+        screen.feed(b"$ a long command that wraps around\r\n$ ");
+        assert_snapshot_round_trip(&screen);
+    }
+
+    #[test]
+    fn snapshot_round_trip_alt_screen_tui() {
+        let mut screen = Screen::new(30, 6, 100);
+        screen.feed(b"$ htop\r\n");
+        screen.feed(b"\x1b[?1049h\x1b[?25l\x1b[?1h\x1b=\x1b[?1002h\x1b[?1006h\x1b[2;5r");
+        screen.feed(b"\x1b[1;1H\x1b[7m CPU \x1b[0m\x1b[3;1H\x1b[44m  bar  \x1b[0m\x1b[6;1Hfooter");
+        screen.feed(b"\x1b[4;10H\x1b[1;33m");
+        assert_snapshot_round_trip(&screen);
+
+        // The main screen must survive the client leaving the alt screen
+        let mut replayed = Screen::new(30, 6, 100);
+        replayed.feed(&screen.snapshot());
+        replayed.feed(b"\x1b[?1049l");
+        assert_eq!(screen_lines(&replayed)[0], "$ htop");
+        assert_eq!((replayed.cursor.x, replayed.cursor.y), (0, 1));
+    }
+
+    #[test]
+    fn snapshot_round_trip_pending_wrap_and_modes() {
+        let mut screen = Screen::new(8, 3, 100);
+        screen.feed(b"\x1b[?2004h\x1b[4h\x1b[5 q\x1b(0\x1b[31mabcdefgh");
+        assert!(screen.cursor.pending_wrap);
+        assert_snapshot_round_trip(&screen);
+    }
+
+    #[test]
+    fn snapshot_round_trip_wide_and_combining() {
+        let mut screen = Screen::new(6, 3, 100);
+        screen.feed("界界界界e\u{301}\x1b[41m \x1b[0m".as_bytes());
+        assert_snapshot_round_trip(&screen);
+    }
+
+    #[test]
+    fn snapshot_round_trip_saved_cursor() {
+        let mut screen = Screen::new(10, 4, 100);
+        screen.feed(b"\x1b[3;4H\x1b[1m\x1b7\x1b[0m\x1b[1;1Hx");
+        assert_snapshot_round_trip(&screen);
+        let mut replayed = Screen::new(10, 4, 100);
+        replayed.feed(&screen.snapshot());
+        replayed.feed(b"\x1b8");
+        assert_eq!((replayed.cursor.x, replayed.cursor.y), (3, 2));
+        assert_eq!(replayed.cursor.attrs.flags, ATTR_BOLD);
+    }
+
+    #[test]
+    fn snapshot_is_smaller_than_byte_log_for_redraws() {
+        let mut screen = Screen::new(80, 24, SCROLLBACK_LINES);
+        let mut buf = OutputBuffer::new(BUFFER_SIZE, ALT_BUFFER_CAP);
+        for frame in 0..2000 {
+            let data = format!("\x1b[H\x1b[1;32mspinner {}\x1b[0m\x1b[10;1Hprogress {}%", frame, frame % 100);
+            screen.feed(data.as_bytes());
+            buf.write(data.as_bytes());
+        }
+        assert!(screen.snapshot().len() * 100 < buf.read().len());
+    }
+
+    #[test]
+    fn parse_csi_params_groups_and_subparams() {
+        assert_eq!(parse_csi_params(b""), Vec::<Vec<u16>>::new());
+        assert_eq!(parse_csi_params(b"1;;3"), vec![vec![1], vec![0], vec![3]]);
+        assert_eq!(parse_csi_params(b"38:2::1:2:3"), vec![vec![38, 2, 0, 1, 2, 3]]);
+    }
+}
@@ -20,6 +20,7 @@ pub const WS_MSG_EXIT: u8 = 0x02;
 pub const WS_MSG_BUFFER_REPLAY: u8 = 0x03;
 pub const WS_MSG_TITLE: u8 = 0x04;
 pub const WS_MSG_NOTIFICATION: u8 = 0x05;
+pub const WS_MSG_IMAGE: u8 = 0x17;
 pub const WS_MSG_RESUME: u8 = 0x10;
 pub const WS_MSG_SYNC: u8 = 0x11;
 pub const WS_MSG_SESSION_STATE: u8 = 0x12;
@@ -27,6 +28,38 @@ pub const WS_MSG_BUFFER_REPLAY_GZ: u8 = 0x13;
 pub const WS_MSG_SESSION_METRICS: u8 = 0x14;
 pub const WS_MSG_SPARKLINE_REQUEST: u8 = 0x18;
 pub const WS_MSG_SPARKLINE_HISTORY: u8 = 0x19;
+pub const WS_MSG_PLAYBACK_CONTROL: u8 = 0x24;
+pub const WS_MSG_PLAYBACK_STATE: u8 = 0x25;
+pub const WS_MSG_AUTH: u8 = 0x26;
+pub const WS_MSG_HELLO: u8 = 0x27;
+pub const WS_MSG_SEARCH_REQUEST: u8 = 0x28;
+pub const WS_MSG_SEARCH_RESULTS: u8 = 0x29;
+pub const WS_MSG_RANGE_REQUEST: u8 = 0x2a;
+pub const WS_MSG_RANGE_DATA: u8 = 0x2b;
+pub const WS_MSG_EXPORT: u8 = 0x2c;
+pub const WS_MSG_WATCH: u8 = 0x2d;
+pub const WS_MSG_COMMANDS: u8 = 0x2e;
+pub const WS_MSG_LINKS: u8 = 0x2f;
+pub const WS_MSG_IMAGE_REQUEST: u8 = 0x30;
+pub const WS_MSG_CLIPBOARD_SET: u8 = 0x31;
+
+// AUTH roles
+pub const AUTH_ROLE_VIEWER: u8 = 0x00;
+pub const AUTH_ROLE_READ_WRITE: u8 = 0x01;
+
+// HELLO capability bits
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const CAP_BUFFER_REPLAY_GZ: u32 = 1 << 0;
+pub const CAP_TITLE: u32 = 1 << 1;
+pub const CAP_SESSION_STATE: u32 = 1 << 3;
+pub const CAP_IMAGE: u32 = 1 << 6;
+pub const CAP_COMMANDS: u32 = 1 << 9;
+
+// PLAYBACK_CONTROL ops
+pub const PLAYBACK_PAUSE: u8 = 0x00;
+pub const PLAYBACK_PLAY: u8 = 0x01;
+pub const PLAYBACK_SEEK: u8 = 0x02;
+pub const PLAYBACK_SPEED: u8 = 0x03;
 
 // ── Frame encoding/decoding ─────────────────────────────────────────
 
@@ -122,6 +155,37 @@ impl SocketClient {
         self.send_frame(WS_MSG_SPARKLINE_REQUEST, &[])
     }
 
+    /// Send a PLAYBACK_CONTROL frame; `arg` is the seek position or speed.
+    pub fn send_playback_control(&mut self, op: u8, arg: Option<f64>) -> io::Result<()> {
+        let mut data = vec![op];
+        if let Some(v) = arg {
+            data.extend_from_slice(&v.to_be_bytes());
+        }
+        self.send_frame(WS_MSG_PLAYBACK_CONTROL, &data)
+    }
+
+    /// Send an AUTH frame requesting `role`, with the write token if any.
+    pub fn send_auth(&mut self, role: u8, token: &str) -> io::Result<()> {
+        let mut data = vec![role];
+        data.extend_from_slice(token.as_bytes());
+        self.send_frame(WS_MSG_AUTH, &data)
+    }
+
+    /// Send a HELLO frame declaring a protocol version and capability bits.
+    pub fn send_hello(&mut self, version: u16, capabilities: u32) -> io::Result<()> {
+        let mut data = version.to_be_bytes().to_vec();
+        data.extend_from_slice(&capabilities.to_be_bytes());
+        self.send_frame(WS_MSG_HELLO, &data)
+    }
+
+    /// Send a RANGE_REQUEST frame: [8B start][8B end][4B max bytes].
+    pub fn send_range_request(&mut self, start: f64, end: f64, max: u32) -> io::Result<()> {
+        let mut data = start.to_be_bytes().to_vec();
+        data.extend_from_slice(&end.to_be_bytes());
+        data.extend_from_slice(&max.to_be_bytes());
+        self.send_frame(WS_MSG_RANGE_REQUEST, &data)
+    }
+
     /// Read the next frame. Returns None on timeout or disconnect.
     pub fn recv_frame(&mut self) -> Option<Frame> {
         use std::io::Read;
@@ -210,6 +274,161 @@ impl SocketClient {
     }
 }
 
+// ── WsClient ────────────────────────────────────────────────────────
+
+/// Minimal blocking WebSocket client for `--ws-listen`. Each binary message
+/// is one unprefixed payload: `[msg_type][data]`.
+pub struct WsClient<S: io::Read + io::Write> {
+    stream: S,
+    read_buf: Vec<u8>,
+}
+
+/// The sample key from RFC 6455 and the accept value it must produce.
+const WS_TEST_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const WS_TEST_ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+/// Open a WebSocket on `target` over an already-connected stream (with a
+/// read timeout set). On refusal, returns the HTTP status line.
+fn ws_handshake<S: io::Read + io::Write>(mut stream: S, target: &str) -> Result<WsClient<S>, String> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+        target, WS_TEST_KEY
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return Err("connection closed during handshake".to_string()),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let status = head.lines().next().unwrap_or("").to_string();
+    if !status.contains(" 101 ") {
+        return Err(status);
+    }
+    assert!(head.contains(WS_TEST_ACCEPT), "bad Sec-WebSocket-Accept in {:?}", head);
+    Ok(WsClient {
+        stream,
+        read_buf: data[head_end + 4..].to_vec(),
+    })
+}
+
+/// Connect to a `--ws-listen` TCP address.
+pub fn ws_connect_tcp(addr: &str, target: &str) -> Result<WsClient<std::net::TcpStream>, String> {
+    let stream = std::net::TcpStream::connect(addr).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(Duration::from_millis(200))).ok();
+    ws_handshake(stream, target)
+}
+
+/// Upgrade a connection to the session socket (`--ws-listen unix`).
+pub fn ws_connect_unix(socket_path: &Path, target: &str) -> Result<WsClient<UnixStream>, String> {
+    let stream = UnixStream::connect(socket_path).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(Duration::from_millis(200))).ok();
+    ws_handshake(stream, target)
+}
+
+impl<S: io::Read + io::Write> WsClient<S> {
+    /// Send one masked binary message: [msg_type][data].
+    pub fn send_message(&mut self, msg_type: u8, data: &[u8]) -> io::Result<()> {
+        let mask = [0xA1, 0xB2, 0xC3, 0xD4];
+        let len = data.len() + 1;
+        let mut frame = vec![0x82];
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        let payload = std::iter::once(&msg_type).chain(data);
+        frame.extend(payload.zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        self.stream.write_all(&frame)
+    }
+
+    pub fn send_resume(&mut self, offset: f64) -> io::Result<()> {
+        self.send_message(WS_MSG_RESUME, &offset.to_be_bytes())
+    }
+
+    pub fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_message(WS_MSG_DATA, data)
+    }
+
+    /// Read the next binary message before `deadline`. Returns None on
+    /// timeout, close or disconnect.
+    pub fn recv_frame(&mut self, deadline: Instant) -> Option<Frame> {
+        loop {
+            if self.read_buf.len() >= 2 {
+                assert_eq!(self.read_buf[1] & 0x80, 0, "server frames must not be masked");
+                let (len, header_len) = match self.read_buf[1] {
+                    126 if self.read_buf.len() >= 4 => {
+                        (u16::from_be_bytes([self.read_buf[2], self.read_buf[3]]) as usize, 4)
+                    }
+                    127 if self.read_buf.len() >= 10 => {
+                        (u64::from_be_bytes(self.read_buf[2..10].try_into().unwrap()) as usize, 10)
+                    }
+                    126 | 127 => (usize::MAX, 0),
+                    len => (len as usize, 2),
+                };
+                if len != usize::MAX && self.read_buf.len() >= header_len + len {
+                    let opcode = self.read_buf[0] & 0x0F;
+                    let payload = self.read_buf[header_len..header_len + len].to_vec();
+                    self.read_buf.drain(..header_len + len);
+                    match opcode {
+                        0x2 if !payload.is_empty() => {
+                            return Some(Frame {
+                                msg_type: payload[0],
+                                data: payload[1..].to_vec(),
+                            })
+                        }
+                        0x8 => return None,
+                        _ => continue,
+                    }
+                }
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            let mut buf = [0u8; 65536];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return None,
+                Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Wait for a specific message type, discarding others.
+    pub fn wait_for_message(&mut self, target_type: u8, timeout: Duration) -> Option<Frame> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.recv_frame(deadline) {
+                Some(frame) if frame.msg_type == target_type => return Some(frame),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+    }
+
+    /// Collect all messages that arrive within a timeout.
+    pub fn collect_frames(&mut self, timeout: Duration) -> Vec<Frame> {
+        let deadline = Instant::now() + timeout;
+        let mut frames = Vec::new();
+        while let Some(frame) = self.recv_frame(deadline) {
+            frames.push(frame);
+        }
+        frames
+    }
+}
+
 // ── Spawn helpers ───────────────────────────────────────────────────
 
 /// Find the compiled pty-host binary.
@@ -247,6 +466,71 @@ pub fn spawn_pty_host_with_env(
     command: &str,
     args: &[&str],
     env_vars: &[(&str, &str)],
+) -> io::Result<PtyHostHandle> {
+    #[allow(deprecated)]
+    let home_dir = tempfile::tempdir()?.into_path();
+    let session_id = format!("test{:08x}", rand_u32());
+    spawn_pty_host_at(&home_dir, &session_id, command, args, env_vars)
+}
+
+/// Spawn with leading command-line options (e.g. `["--allow-uid", "501"]`).
+pub fn spawn_pty_host_with_flags(
+    flags: &[&str],
+    command: &str,
+    args: &[&str],
+) -> io::Result<PtyHostHandle> {
+    #[allow(deprecated)]
+    let home_dir = tempfile::tempdir()?.into_path();
+    let session_id = format!("test{:08x}", rand_u32());
+    spawn_binary(&home_dir, &session_id, flags, command, args, &[])
+}
+
+/// Spawn into an existing HOME with a fixed session ID — used to restart a
+/// session over the files a previous pty-host left behind.
+pub fn spawn_pty_host_at(
+    home_dir: &Path,
+    session_id: &str,
+    command: &str,
+    args: &[&str],
+    env_vars: &[(&str, &str)],
+) -> io::Result<PtyHostHandle> {
+    spawn_binary(home_dir, session_id, &[], command, args, env_vars)
+}
+
+/// Spawn `relay-pty-host --replay <cast> <id>` to serve a recording.
+pub fn spawn_replay_host(cast_path: &Path) -> io::Result<PtyHostHandle> {
+    #[allow(deprecated)]
+    let home_dir = tempfile::tempdir()?.into_path();
+    let session_id = format!("test{:08x}", rand_u32());
+    let cast = cast_path.to_str().expect("non-UTF-8 cast path");
+    spawn_binary(&home_dir, &session_id, &["--replay", cast], "", &[], &[])
+}
+
+/// Run a one-shot subcommand (e.g. `export`) against sessions in `home_dir`.
+pub fn run_subcommand(home_dir: &Path, args: &[&str]) -> io::Result<std::process::Output> {
+    Command::new(binary_path()).args(args).env("HOME", home_dir).output()
+}
+
+/// Start a long-running subcommand (e.g. `attach`) with piped stdio.
+pub fn start_subcommand(home_dir: &Path, args: &[&str]) -> io::Result<std::process::Child> {
+    Command::new(binary_path())
+        .args(args)
+        .env("HOME", home_dir)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+}
+
+/// Run `spawn` with `flags` before the command, or `replay` when `flags`
+/// is `["--replay", <file>]`.
+fn spawn_binary(
+    home_dir: &Path,
+    session_id: &str,
+    flags: &[&str],
+    command: &str,
+    args: &[&str],
+    env_vars: &[(&str, &str)],
 ) -> io::Result<PtyHostHandle> {
     let bin = binary_path();
     if !bin.exists() {
@@ -256,10 +540,7 @@ pub fn spawn_pty_host_with_env(
         ));
     }
 
-    #[allow(deprecated)]
-    let home_dir = tempfile::tempdir()?.into_path();
     let data_dir = home_dir.join(".relay-tty");
-    let session_id = format!("test{:08x}", rand_u32());
 
     let socket_dir = data_dir.join("sockets");
     let session_dir = data_dir.join("sessions");
@@ -270,17 +551,18 @@ pub fn spawn_pty_host_with_env(
     let session_path = session_dir.join(format!("{}.json", session_id));
 
     let mut cmd = Command::new(&bin);
-    cmd.arg(&session_id)
-        .arg("80") // cols
-        .arg("24") // rows
-        .arg("/tmp") // cwd
-        .arg(command);
-    for arg in args {
-        cmd.arg(arg);
+    if let Some(cast) = flags.strip_prefix(&["--replay"]) {
+        cmd.arg("replay").args(["--id", session_id]).args(cast);
+    } else {
+        cmd.arg("spawn")
+            .args(flags)
+            .args(["--id", session_id, "--cols", "80", "--rows", "24", "--cwd", "/tmp", "--"])
+            .arg(command)
+            .args(args);
     }
 
     // Set HOME to our temp dir so pty-host uses it for ~/.relay-tty/
-    cmd.env("HOME", &home_dir);
+    cmd.env("HOME", home_dir);
 
     for (k, v) in env_vars {
         cmd.env(k, v);
@@ -307,8 +589,8 @@ pub fn spawn_pty_host_with_env(
         child,
         socket_path,
         session_path,
-        home_dir,
-        session_id,
+        home_dir: home_dir.to_path_buf(),
+        session_id: session_id.to_string(),
     })
 }
 
@@ -318,6 +600,54 @@ pub fn connect(socket_path: &Path) -> io::Result<SocketClient> {
     Ok(SocketClient::new(stream))
 }
 
+/// Connect as another user, so the pty-host sees that UID/GID in the peer
+/// credentials. Only the connecting thread switches identity, so this needs
+/// root and Linux's per-thread credentials. The socket's parent directories
+/// must be searchable by that user.
+#[cfg(target_os = "linux")]
+pub fn connect_as(socket_path: &Path, uid: u32, gid: u32) -> io::Result<SocketClient> {
+    let path = socket_path.to_path_buf();
+    let stream = std::thread::spawn(move || {
+        // Raw syscalls: the libc wrappers would change every thread's IDs
+        unsafe {
+            if libc::syscall(libc::SYS_setresgid, gid, gid, gid) != 0
+                || libc::syscall(libc::SYS_setresuid, uid, uid, uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        UnixStream::connect(path)
+    })
+    .join()
+    .expect("connect thread panicked")?;
+    Ok(SocketClient::new(stream))
+}
+
+/// Whether the tests run as root (needed for `connect_as`).
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Concatenate the terminal output carried by DATA, BUFFER_REPLAY and
+/// BUFFER_REPLAY_GZ frames (gzip payloads are decompressed).
+pub fn collect_output(frames: &[Frame]) -> String {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mut all_data = Vec::new();
+    for frame in frames {
+        match frame.msg_type {
+            WS_MSG_DATA | WS_MSG_BUFFER_REPLAY => all_data.extend_from_slice(&frame.data),
+            WS_MSG_BUFFER_REPLAY_GZ => {
+                let mut decoder = GzDecoder::new(&frame.data[..]);
+                decoder.read_to_end(&mut all_data).ok();
+            }
+            _ => {}
+        }
+    }
+    String::from_utf8_lossy(&all_data).into_owned()
+}
+
 /// Read and parse the session JSON file.
 pub fn read_session_json(session_path: &Path) -> io::Result<serde_json::Value> {
     let content = fs::read_to_string(session_path)?;
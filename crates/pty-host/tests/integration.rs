@@ -527,6 +527,201 @@ fn replay_gzip_decompresses_correctly() {
     );
 }
 
+#[test]
+fn replay_synthesizes_screen_for_cursor_redraws() {
+    // A TUI that redraws in place with cursor moves (no ESC[2J) — the full
+    // replay should contain only the final frame.
+    let handle = spawn_pty_host(
+        "/bin/sh",
+        &["-c", "for i in 1 2 3 4 5; do printf '\\033[Hredraw_frame_%s' $i; done; sleep 2"],
+    )
+    .expect("failed to spawn");
+
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let replay = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    assert!(
+        replay.contains("redraw_frame_5"),
+        "Replay should contain the final frame: {:?}",
+        replay
+    );
+    assert!(
+        !replay.contains("redraw_frame_1"),
+        "Replay should not contain dead frames: {:?}",
+        replay
+    );
+}
+
+#[test]
+fn replay_mode_bytes_replays_raw_buffer() {
+    let handle = spawn_pty_host_with_env(
+        "/bin/sh",
+        &["-c", "for i in 1 2 3; do printf '\\033[Hraw_frame_%s' $i; done; sleep 2"],
+        &[("RELAY_REPLAY_MODE", "bytes")],
+    )
+    .expect("failed to spawn");
+
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let replay = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    assert!(
+        replay.contains("raw_frame_1") && replay.contains("raw_frame_3"),
+        "Byte replay should contain every frame: {:?}",
+        replay
+    );
+}
+
+// ── Configuration tests ─────────────────────────────────────────────
+
+#[test]
+fn config_file_and_env_size_the_output_buffer() {
+    #[allow(deprecated)]
+    let home_dir = tempfile::tempdir().unwrap().into_path();
+    let data_dir = home_dir.join(".relay-tty");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(data_dir.join("pty-host.toml"), "buffer_size = \"1MB\"\nidle_timeout_ms = 5000\n").unwrap();
+
+    // The env var beats the file: 64KB of scrollback, not 1MB
+    let handle = spawn_pty_host_at(
+        &home_dir,
+        "testconfig",
+        "/bin/sh",
+        &["-c", "head -c 200000 /dev/zero | tr '\\0' x; sleep 2"],
+        &[("RELAY_REPLAY_MODE", "bytes"), ("RELAY_BUFFER_SIZE", "64KB")],
+    )
+    .expect("failed to spawn");
+
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let replay = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    let xs = replay.bytes().filter(|&b| b == b'x').count();
+    assert!(xs > 32 * 1024 && xs <= 64 * 1024, "replay holds {} bytes of output", xs);
+}
+
+#[test]
+fn invalid_config_override_is_rejected() {
+    let home_dir = tempfile::tempdir().unwrap();
+    let output = run_subcommand(
+        home_dir.path(),
+        &["--set", "buffer_size=tiny", "testbadcfg", "80", "24", "/tmp", "/bin/true"],
+    )
+    .expect("failed to run");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid size for buffer_size"), "{}", stderr);
+}
+
+// ── Logging tests ───────────────────────────────────────────────────
+
+#[test]
+fn session_log_records_clients_and_handshakes() {
+    let handle = spawn_pty_host_with_env("/bin/sh", &["-c", "echo hello; sleep 5"], &[("RELAY_LOG_LEVEL", "debug")])
+        .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut resumed = connect(&handle.socket_path).expect("connect failed");
+    resumed.send_hello(1, 0).expect("send_hello failed");
+    resumed.send_resume(0.0).expect("send_resume failed");
+    resumed.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    drop(resumed);
+
+    // Never sends RESUME, so it gets a full replay after the timeout
+    let mut silent = connect(&handle.socket_path).expect("connect failed");
+    silent.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    drop(silent);
+    std::thread::sleep(Duration::from_millis(300));
+
+    let log_path = handle.home_dir.join(".relay-tty/logs").join(format!("{}.log", handle.session_id));
+    let log = std::fs::read_to_string(&log_path).expect("no session log");
+    for line in log.lines() {
+        assert!(line.starts_with("time=") && line.contains(" level="), "not logfmt: {}", line);
+    }
+    assert!(log.contains("level=info msg=\"session started\""), "{}", log);
+    assert!(log.contains("msg=\"client connected\" client=1 transport=unix access=read-write"), "{}", log);
+    assert!(log.contains("level=debug msg=\"client negotiated\" client=1"), "{}", log);
+    assert!(log.contains("msg=\"handshake complete\" client=1 mode=delta"), "{}", log);
+    assert!(log.contains("msg=\"handshake complete\" client=2 mode=full"), "{}", log);
+    assert!(log.contains("msg=\"client disconnected\" client=1"), "{}", log);
+    assert!(log.contains("msg=\"client disconnected\" client=2"), "{}", log);
+}
+
+// ── Metrics endpoint tests ──────────────────────────────────────────
+
+/// Send a GET and return (status line, body).
+fn http_get<S: std::io::Read + std::io::Write>(mut stream: S, path: &str) -> (String, String) {
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").expect("no header end");
+    assert!(head.contains("Content-Type: application/openmetrics-text"), "{}", head);
+    (head.lines().next().unwrap_or_default().to_string(), body.to_string())
+}
+
+#[test]
+fn metrics_endpoint_reports_session_activity() {
+    use std::net::TcpStream;
+    let handle = spawn_pty_host_with_flags(&["--metrics-listen", "0"], "/bin/sh", &["-c", "echo metered; sleep 5"])
+        .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(300));
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+
+    let addr = published_address(&handle, "metricsListen");
+    let (status, body) = http_get(TcpStream::connect(&addr).unwrap(), "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let session = format!("session=\"{}\"", handle.session_id);
+    for line in [
+        format!("relay_clients{{{}}} 1", session),
+        format!("relay_client_connections_total{{{}}} 1", session),
+        format!("relay_replays_total{{{},mode=\"delta\"}} 1", session),
+        format!("relay_replay_bytes_count{{{}}} 1", session),
+    ] {
+        assert!(body.lines().any(|l| l == line), "missing {:?} in\n{}", line, body);
+    }
+    let sample = |name: &str| -> f64 {
+        let prefix = format!("{}{{{}}} ", name, session);
+        let line = body.lines().find(|l| l.starts_with(&prefix)).unwrap_or_else(|| panic!("no {}", name));
+        line[prefix.len()..].parse().unwrap()
+    };
+    assert!(sample("relay_output_bytes_total") >= "metered".len() as f64);
+    assert!(sample("relay_child_resident_memory_bytes") > 0.0);
+    assert!(body.ends_with("# EOF\n"));
+
+    let (status, _) = http_get(TcpStream::connect(&addr).unwrap(), "/other");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+}
+
+#[test]
+fn metrics_endpoint_on_unix_socket_is_removed_on_exit() {
+    use std::os::unix::net::UnixStream;
+    let mut handle = spawn_pty_host_with_flags(&["--metrics-listen", "unix"], "/bin/sleep", &["10"])
+        .expect("failed to spawn");
+    let path = published_address(&handle, "metricsListen");
+    assert_eq!(
+        std::path::Path::new(&path),
+        handle.home_dir.join(format!(".relay-tty/sockets/{}.metrics", handle.session_id))
+    );
+    let (status, body) = http_get(UnixStream::connect(&path).unwrap(), "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.contains("relay_clients{"), "{}", body);
+
+    unsafe {
+        libc::kill(handle.child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    handle.child.wait().unwrap();
+    assert!(!std::path::Path::new(&path).exists(), "metrics socket left behind");
+}
+
 // ── Environment inheritance tests ───────────────────────────────────
 
 #[test]
@@ -742,3 +937,1142 @@ fn sync_offset_is_valid_f64() {
         offset
     );
 }
+
+// ── Scrollback persistence tests ────────────────────────────────────
+
+/// Simulate a crash: SIGKILL the pty-host so nothing gets cleaned up, then
+/// start a new one for the same session over the files it left behind.
+fn crash_and_restart(handle: &mut PtyHostHandle, command: &str, args: &[&str]) -> PtyHostHandle {
+    handle.child.kill().expect("kill failed");
+    handle.child.wait().ok();
+    std::fs::remove_file(&handle.socket_path).ok();
+    spawn_pty_host_at(
+        &handle.home_dir,
+        &handle.session_id,
+        command,
+        args,
+        &[("RELAY_PERSIST_SCROLLBACK", "1")],
+    )
+    .expect("failed to respawn")
+}
+
+#[test]
+fn persisted_scrollback_survives_restart() {
+    let mut handle = spawn_pty_host_with_env(
+        "/bin/sh",
+        &["-c", "echo output_before_crash && sleep 30"],
+        &[("RELAY_PERSIST_SCROLLBACK", "1")],
+    )
+    .expect("failed to spawn");
+
+    // Wait for the output to reach the scrollback segment
+    std::thread::sleep(Duration::from_millis(500));
+
+    let meta = read_session_json(&handle.session_path).expect("failed to read session JSON");
+    let dir = meta["scrollbackDir"].as_str().expect("scrollbackDir missing");
+    assert!(std::path::Path::new(dir).is_dir(), "scrollback dir should exist");
+
+    let restarted = crash_and_restart(&mut handle, "/bin/sh", &["-c", "sleep 30"]);
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut client = connect(&restarted.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let replay = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    assert!(
+        replay.contains("output_before_crash"),
+        "Restarted session should replay persisted output: {:?}",
+        replay
+    );
+
+    let meta = read_session_json(&restarted.session_path).expect("failed to read session JSON");
+    assert!(
+        meta["totalBytesWritten"].as_f64().unwrap() > 0.0,
+        "totalBytesWritten should continue from the persisted offset"
+    );
+}
+
+#[test]
+fn persisted_scrollback_serves_resume_delta_after_restart() {
+    let mut handle = spawn_pty_host_with_env(
+        "/bin/sh",
+        &["-c", "echo first_part && sleep 1 && echo second_part && sleep 30"],
+        &[("RELAY_PERSIST_SCROLLBACK", "1")],
+    )
+    .expect("failed to spawn");
+
+    std::thread::sleep(Duration::from_millis(300));
+
+    // Remember the offset a client had seen before the crash
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let sync = client
+        .wait_for_message(WS_MSG_SYNC, Duration::from_secs(2))
+        .expect("no SYNC received");
+    let offset = f64::from_be_bytes(sync.data[..8].try_into().unwrap());
+    assert!(offset > 0.0);
+    drop(client);
+
+    std::thread::sleep(Duration::from_millis(1500));
+    let restarted = crash_and_restart(&mut handle, "/bin/sh", &["-c", "sleep 30"]);
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut client = connect(&restarted.socket_path).expect("connect failed");
+    client.send_resume(offset).expect("send_resume failed");
+    let frames = client.collect_frames(Duration::from_secs(1));
+    let replay = collect_output(&frames);
+    assert!(
+        replay.contains("second_part"),
+        "Delta should contain output after the old offset: {:?}",
+        replay
+    );
+    assert!(
+        !replay.contains("first_part"),
+        "Delta should not repeat output before the old offset: {:?}",
+        replay
+    );
+}
+
+#[test]
+fn dead_session_output_is_readable_without_respawning() {
+    let mut handle = spawn_pty_host_with_env(
+        "/bin/sh",
+        &["-c", "echo output_before_crash && sleep 30"],
+        &[("RELAY_PERSIST_SCROLLBACK", "1")],
+    )
+    .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    // Crash: the stale socket file is left behind
+    handle.child.kill().expect("kill failed");
+    handle.child.wait().ok();
+    assert!(handle.socket_path.exists());
+
+    let output = run_subcommand(&handle.home_dir, &["export", &handle.session_id]).expect("export failed");
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("output_before_crash"), "text: {:?}", text);
+
+    let output = run_subcommand(&handle.home_dir, &["attach", &handle.session_id]).expect("attach failed");
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("output_before_crash"));
+
+    // Nothing was respawned
+    assert!(connect(&handle.socket_path).is_err(), "no pty-host should be serving the session");
+}
+
+#[test]
+fn attach_to_crashed_session_without_scrollback_fails() {
+    let mut handle = spawn_pty_host("/bin/sh", &["-c", "sleep 30"]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(300));
+    handle.child.kill().expect("kill failed");
+    handle.child.wait().ok();
+    assert!(handle.socket_path.exists(), "the stale socket file is left behind");
+
+    let output = run_subcommand(&handle.home_dir, &["attach", &handle.session_id]).expect("attach failed");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no longer running"), "stderr: {}", stderr);
+}
+
+#[test]
+fn scrollback_not_persisted_by_default() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo hi && sleep 2"]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let meta = read_session_json(&handle.session_path).expect("failed to read session JSON");
+    assert!(meta.get("scrollbackDir").is_none());
+    let dir = handle
+        .home_dir
+        .join(format!(".relay-tty/sessions/{}.scrollback", handle.session_id));
+    assert!(!dir.exists(), "no scrollback dir without RELAY_PERSIST_SCROLLBACK");
+}
+
+// ── Recording tests ─────────────────────────────────────────────────
+
+#[test]
+fn record_env_writes_asciicast_with_resize_events() {
+    let cast_dir = tempfile::tempdir().expect("tempdir");
+    let cast_path = cast_dir.path().join("session.cast");
+    let handle = spawn_pty_host_with_env(
+        "/bin/sh",
+        &["-c", "echo recorded_output && sleep 3"],
+        &[("RELAY_RECORD", cast_path.to_str().unwrap())],
+    )
+    .expect("failed to spawn");
+
+    std::thread::sleep(Duration::from_millis(300));
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client
+        .wait_for_message(WS_MSG_SYNC, Duration::from_secs(2))
+        .expect("no SYNC received");
+    client.send_resize(100, 30).expect("send_resize failed");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let content = std::fs::read_to_string(&cast_path).expect("recording missing");
+    let lines: Vec<serde_json::Value> = content
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("invalid asciicast line"))
+        .collect();
+    assert_eq!(lines[0]["version"], 2);
+    assert_eq!(lines[0]["width"], 80);
+    assert_eq!(lines[0]["height"], 24);
+
+    let output: String = lines[1..]
+        .iter()
+        .filter(|e| e[1] == "o")
+        .map(|e| e[2].as_str().unwrap().to_string())
+        .collect();
+    assert!(output.contains("recorded_output"), "output events: {:?}", output);
+    assert!(
+        lines[1..].iter().any(|e| e[1] == "r" && e[2] == "100x30"),
+        "expected a resize event: {}",
+        content
+    );
+}
+
+// ── Replay server tests ─────────────────────────────────────────────
+
+fn write_cast(dir: &std::path::Path, events: &[(f64, &str)]) -> std::path::PathBuf {
+    let mut content = String::from("{\"version\": 2, \"width\": 80, \"height\": 24}\n");
+    for (time, text) in events {
+        content.push_str(&format!("[{}, \"o\", {}]\n", time, serde_json::to_string(text).unwrap()));
+    }
+    let path = dir.join("session.cast");
+    std::fs::write(&path, content).expect("write cast");
+    path
+}
+
+fn playback_state(frame: &Frame) -> (bool, f64, f64, f64) {
+    let f = |i: usize| f64::from_be_bytes(frame.data[i..i + 8].try_into().unwrap());
+    (frame.data[0] != 0, f(1), f(9), f(17))
+}
+
+#[test]
+fn replay_emits_output_on_recorded_timing() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let cast = write_cast(dir.path(), &[(0.1, "first_event\r\n"), (1.5, "second_event\r\n")]);
+    let handle = spawn_replay_host(&cast).expect("failed to spawn replay");
+
+    let started = std::time::Instant::now();
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    // Note when each event first shows up
+    let mut output = String::new();
+    let mut first_at = None;
+    let mut saw_playback_state = false;
+    while !output.contains("second_event") {
+        let frame = client.recv_frame().expect("replay ended before second event");
+        saw_playback_state |= frame.msg_type == WS_MSG_PLAYBACK_STATE;
+        output.push_str(&collect_output(std::slice::from_ref(&frame)));
+        if first_at.is_none() && output.contains("first_event") {
+            first_at = Some(started.elapsed());
+        }
+    }
+    let second_at = started.elapsed();
+
+    let first_at = first_at.expect("first event never arrived");
+    assert!(first_at < Duration::from_millis(1000), "first event at {:?}", first_at);
+    assert!(second_at >= Duration::from_millis(1300), "second event at {:?}", second_at);
+    assert!(saw_playback_state, "handshake should include PLAYBACK_STATE");
+
+    // Session is discoverable like a live one
+    let meta = read_session_json(&handle.session_path).expect("failed to read session JSON");
+    assert_eq!(meta["status"], "running");
+}
+
+#[test]
+fn replay_pause_seek_and_speed_controls() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let cast = write_cast(
+        dir.path(),
+        &[(0.0, "alpha\r\n"), (5.0, "beta\r\n"), (10.0, "gamma\r\n")],
+    );
+    let handle = spawn_replay_host(&cast).expect("failed to spawn replay");
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client
+        .wait_for_message(WS_MSG_SYNC, Duration::from_secs(2))
+        .expect("no SYNC received");
+
+    client.send_playback_control(PLAYBACK_PAUSE, None).unwrap();
+    client.send_playback_control(PLAYBACK_SEEK, Some(7.0)).unwrap();
+    let frames = client.collect_frames(Duration::from_millis(500));
+    let text = collect_output(&frames);
+    assert!(text.contains("beta"), "seek should redraw the screen at 7s: {:?}", text);
+    assert!(!text.contains("gamma"), "seek should not play past 7s: {:?}", text);
+    let state = frames
+        .iter()
+        .rev()
+        .find(|f| f.msg_type == WS_MSG_PLAYBACK_STATE)
+        .map(playback_state)
+        .expect("no PLAYBACK_STATE after seek");
+    assert_eq!(state, (true, 7.0, 10.0, 1.0));
+
+    // 3s of recording left — at 50x it finishes almost immediately
+    client.send_playback_control(PLAYBACK_SPEED, Some(50.0)).unwrap();
+    client.send_playback_control(PLAYBACK_PLAY, None).unwrap();
+    let frames = client.collect_frames(Duration::from_millis(800));
+    assert!(collect_output(&frames).contains("gamma"));
+    let state = frames
+        .iter()
+        .rev()
+        .find(|f| f.msg_type == WS_MSG_PLAYBACK_STATE)
+        .map(playback_state)
+        .expect("no PLAYBACK_STATE at end");
+    assert_eq!(state, (true, 10.0, 10.0, 50.0), "playback should pause at the end");
+}
+
+// ── Live upgrade tests ──────────────────────────────────────────────
+
+#[test]
+fn sigusr2_upgrade_keeps_child_and_offsets() {
+    let mut handle = spawn_pty_host(
+        "/bin/sh",
+        &["-c", "echo before_upgrade && sleep 2 && echo after_upgrade && sleep 2 && exit 3"],
+    )
+    .expect("failed to spawn");
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let sync = client
+        .wait_for_message(WS_MSG_SYNC, Duration::from_secs(2))
+        .expect("no SYNC received");
+    let offset = f64::from_be_bytes(sync.data[..8].try_into().unwrap());
+    assert!(offset > 0.0);
+
+    let pid = handle.child.id();
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGUSR2);
+    }
+
+    // The exec closes client connections; the same process keeps running
+    while client.recv_frame().is_some() {}
+    assert!(handle.child.try_wait().unwrap().is_none(), "pty-host should survive the upgrade");
+
+    let mut client = connect(&handle.socket_path).expect("reconnect failed");
+    client.send_resume(offset).expect("send_resume failed");
+    let mut frames = Vec::new();
+    let mut exit_code = None;
+    while let Some(frame) = client.recv_frame() {
+        if frame.msg_type == WS_MSG_EXIT {
+            exit_code = Some(i32::from_be_bytes(frame.data[..4].try_into().unwrap()));
+            break;
+        }
+        frames.push(frame);
+    }
+    let output = collect_output(&frames);
+    assert!(output.contains("after_upgrade"), "missing output after upgrade: {:?}", output);
+    assert!(!output.contains("before_upgrade"), "delta should start at the old offset: {:?}", output);
+    assert_eq!(exit_code, Some(3), "exit status of the inherited child should be reported");
+
+    let meta = read_session_json(&handle.session_path).expect("failed to read session JSON");
+    assert_eq!(meta["pid"].as_u64(), Some(pid as u64));
+    let handoff = handle
+        .home_dir
+        .join(format!(".relay-tty/sessions/{}.handoff", handle.session_id));
+    assert!(!handoff.exists(), "handoff file should be consumed");
+}
+
+// ── Peer authorization tests ────────────────────────────────────────
+
+const OTHER_UID: u32 = 65534;
+
+/// Let other users reach the socket inside the (0700) temp HOME.
+fn open_home_dir(handle: &PtyHostHandle) {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&handle.home_dir, std::fs::Permissions::from_mode(0o711)).unwrap();
+}
+
+#[test]
+fn socket_is_owner_only_by_default() {
+    use std::os::unix::fs::PermissionsExt;
+    let handle = spawn_pty_host("/bin/sh", &["-c", "sleep 5"]).expect("failed to spawn");
+    let mode = std::fs::metadata(&handle.socket_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    if is_root() {
+        open_home_dir(&handle);
+        let err = connect_as(&handle.socket_path, OTHER_UID, OTHER_UID)
+            .err()
+            .expect("other users should not be able to connect");
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+}
+
+#[test]
+fn unlisted_peer_is_rejected() {
+    if !is_root() {
+        eprintln!("skipping: connecting as another user needs root");
+        return;
+    }
+    // An allowlist opens the socket up, so the credential check decides
+    let handle = spawn_pty_host_with_flags(&["--allow-uid", "4242"], "/bin/sh", &["-c", "echo secret_output && sleep 5"])
+        .expect("failed to spawn");
+    open_home_dir(&handle);
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut client = connect_as(&handle.socket_path, OTHER_UID, OTHER_UID).expect("connect failed");
+    let frames = client.collect_frames(Duration::from_secs(1));
+    assert!(frames.is_empty(), "rejected peer should be disconnected without output");
+}
+
+#[test]
+fn allowlisted_peer_gets_full_access() {
+    if !is_root() {
+        eprintln!("skipping: connecting as another user needs root");
+        return;
+    }
+    let uid = OTHER_UID.to_string();
+    let handle = spawn_pty_host_with_flags(&["--allow-uid", &uid], "/bin/cat", &[]).expect("failed to spawn");
+    open_home_dir(&handle);
+
+    let mut client = connect_as(&handle.socket_path, OTHER_UID, OTHER_UID).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    client.send_data(b"from_allowed_peer\r").expect("send failed");
+    let output = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    assert!(output.contains("from_allowed_peer"), "input should reach the PTY: {:?}", output);
+}
+
+#[test]
+fn allowlisted_group_reaches_group_owned_socket() {
+    if !is_root() {
+        eprintln!("skipping: connecting as another user needs root");
+        return;
+    }
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let handle = spawn_pty_host_with_flags(&["--allow-gid", "4242"], "/bin/cat", &[]).expect("failed to spawn");
+    open_home_dir(&handle);
+    let meta = std::fs::metadata(&handle.socket_path).unwrap();
+    assert_eq!((meta.permissions().mode() & 0o777, meta.gid()), (0o660, 4242));
+
+    let mut client = connect_as(&handle.socket_path, OTHER_UID, 4242).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    assert!(
+        connect_as(&handle.socket_path, OTHER_UID, OTHER_UID).is_err(),
+        "users outside the group cannot reach the socket"
+    );
+}
+
+#[test]
+fn unlisted_peer_downgraded_to_read_only() {
+    if !is_root() {
+        eprintln!("skipping: connecting as another user needs root");
+        return;
+    }
+    let handle = spawn_pty_host_with_flags(&["--unlisted-peers", "read-only"], "/bin/cat", &[])
+        .expect("failed to spawn");
+    open_home_dir(&handle);
+
+    let mut viewer = connect_as(&handle.socket_path, OTHER_UID, OTHER_UID).expect("connect failed");
+    viewer.send_resume(0.0).expect("send_resume failed");
+    viewer.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    viewer.send_data(b"typed_by_viewer\r").expect("send failed");
+    viewer.send_resize(100, 40).expect("send failed");
+
+    let mut owner = connect(&handle.socket_path).expect("connect failed");
+    owner.send_resume(0.0).expect("send_resume failed");
+    owner.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    owner.send_data(b"typed_by_owner\r").expect("send failed");
+
+    let frames = viewer.collect_frames(Duration::from_secs(1));
+    let output = collect_output(&frames);
+    assert!(output.contains("typed_by_owner"), "viewer should see output: {:?}", output);
+    assert!(!output.contains("typed_by_viewer"), "viewer input should be dropped: {:?}", output);
+    assert!(
+        !frames.iter().any(|f| f.msg_type == WS_MSG_RESIZE),
+        "viewer resize should be dropped"
+    );
+}
+
+// ── Client role tests ───────────────────────────────────────────────
+
+/// Whether `text` typed by `client` comes back from `cat`, as seen by `observer`.
+fn input_echoes(client: &mut SocketClient, observer: &mut SocketClient, text: &str) -> bool {
+    client.send_data(format!("{}\r", text).as_bytes()).expect("send failed");
+    collect_output(&observer.collect_frames(Duration::from_millis(800))).contains(text)
+}
+
+fn handshake(client: &mut SocketClient) {
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+}
+
+#[test]
+fn viewer_role_drops_input_and_resize() {
+    let handle = spawn_pty_host("/bin/cat", &[]).expect("failed to spawn");
+
+    let mut viewer = connect(&handle.socket_path).expect("connect failed");
+    viewer.send_auth(AUTH_ROLE_VIEWER, "").expect("send_auth failed");
+    handshake(&mut viewer);
+    let mut writer = connect(&handle.socket_path).expect("connect failed");
+    handshake(&mut writer);
+
+    assert!(!input_echoes(&mut viewer, &mut writer, "typed_by_viewer"));
+    viewer.send_resize(100, 40).expect("send failed");
+    let frames = writer.collect_frames(Duration::from_millis(500));
+    assert!(!frames.iter().any(|f| f.msg_type == WS_MSG_RESIZE), "viewer resize should be dropped");
+
+    assert!(input_echoes(&mut writer, &mut viewer, "typed_by_writer"), "viewer should still see output");
+}
+
+#[test]
+fn require_token_makes_clients_viewers_until_auth() {
+    let handle = spawn_pty_host_with_flags(&["--require-token"], "/bin/cat", &[]).expect("failed to spawn");
+    let token_path = handle
+        .home_dir
+        .join(format!(".relay-tty/sessions/{}.token", handle.session_id));
+    let token = std::fs::read_to_string(&token_path).expect("token file missing");
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&token_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let mut anonymous = connect(&handle.socket_path).expect("connect failed");
+    handshake(&mut anonymous);
+    let mut forged = connect(&handle.socket_path).expect("connect failed");
+    forged.send_auth(AUTH_ROLE_READ_WRITE, "not-the-token").expect("send_auth failed");
+    handshake(&mut forged);
+    let mut owner = connect(&handle.socket_path).expect("connect failed");
+    owner.send_auth(AUTH_ROLE_READ_WRITE, &token).expect("send_auth failed");
+    handshake(&mut owner);
+
+    assert!(!input_echoes(&mut anonymous, &mut owner, "from_anonymous"));
+    assert!(!input_echoes(&mut forged, &mut owner, "from_forged"));
+    assert!(input_echoes(&mut owner, &mut anonymous, "from_owner"));
+
+    // AUTH is also honoured after the handshake
+    anonymous.send_auth(AUTH_ROLE_READ_WRITE, &token).expect("send_auth failed");
+    assert!(input_echoes(&mut anonymous, &mut owner, "late_auth"));
+}
+
+// ── WebSocket listener tests ────────────────────────────────────────
+
+fn read_token(handle: &PtyHostHandle) -> String {
+    let token_path = handle
+        .home_dir
+        .join(format!(".relay-tty/sessions/{}.token", handle.session_id));
+    std::fs::read_to_string(token_path).expect("token file missing")
+}
+
+/// The bound `--ws-listen` address, as published in the session JSON.
+fn ws_listen_addr(handle: &PtyHostHandle) -> String {
+    published_address(handle, "wsListen")
+}
+
+/// A listener address from the session JSON, once it has been written.
+fn published_address(handle: &PtyHostHandle, key: &str) -> String {
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    loop {
+        let meta = read_session_json(&handle.session_path).ok();
+        if let Some(addr) = meta.as_ref().and_then(|m| m[key].as_str()) {
+            return addr.to_string();
+        }
+        assert!(std::time::Instant::now() < deadline, "{} missing from session JSON", key);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn websocket_tcp_listener_serves_session() {
+    let handle = spawn_pty_host_with_flags(&["--ws-listen", "127.0.0.1:0"], "/bin/cat", &[])
+        .expect("failed to spawn");
+    let addr = ws_listen_addr(&handle);
+    let token = read_token(&handle);
+
+    let refused = ws_connect_tcp(&addr, "/").err().expect("tokenless TCP client should be refused");
+    assert!(refused.contains("403"), "unexpected refusal: {}", refused);
+    let refused = ws_connect_tcp(&addr, "/?token=wrong").err().expect("bad token should be refused");
+    assert!(refused.contains("403"), "unexpected refusal: {}", refused);
+
+    let mut ws = ws_connect_tcp(&addr, &format!("/ws?token={}", token)).expect("upgrade failed");
+    ws.send_resume(0.0).expect("send_resume failed");
+    ws.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC over WebSocket");
+
+    let mut observer = connect(&handle.socket_path).expect("connect failed");
+    observer.send_resume(0.0).expect("send_resume failed");
+    observer.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+
+    ws.send_data(b"typed_over_ws\r").expect("send failed");
+    let output = collect_output(&observer.collect_frames(Duration::from_millis(800)));
+    assert!(output.contains("typed_over_ws"), "WebSocket input should reach the PTY: {:?}", output);
+    observer.send_data(b"typed_over_socket\r").expect("send failed");
+    let output = collect_output(&ws.collect_frames(Duration::from_millis(800)));
+    assert!(output.contains("typed_over_socket"), "WebSocket client should get output: {:?}", output);
+
+    // The TCP listener survives a live upgrade
+    unsafe {
+        libc::kill(handle.child.id() as libc::pid_t, libc::SIGUSR2);
+    }
+    while observer.recv_frame().is_some() {}
+    let mut ws = ws_connect_tcp(&addr, &format!("/?token={}", token)).expect("upgrade after SIGUSR2 failed");
+    ws.send_resume(0.0).expect("send_resume failed");
+    ws.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC after live upgrade");
+}
+
+#[test]
+fn websocket_upgrade_on_unix_socket() {
+    let handle = spawn_pty_host_with_flags(&["--ws-listen", "unix"], "/bin/cat", &[]).expect("failed to spawn");
+    let meta = read_session_json(&handle.session_path).expect("failed to read session JSON");
+    assert!(meta.get("wsListen").is_none(), "no TCP address to publish");
+
+    // Same-user peers need no token on the socket
+    let mut ws = ws_connect_unix(&handle.socket_path, "/").expect("upgrade failed");
+    ws.send_resume(0.0).expect("send_resume failed");
+    ws.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC over WebSocket");
+
+    // Length-prefixed clients share the socket, including silent ones
+    let mut plain = connect(&handle.socket_path).expect("connect failed");
+    plain.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC for plain client");
+
+    ws.send_data(b"typed_over_unix_ws\r").expect("send failed");
+    let output = collect_output(&plain.collect_frames(Duration::from_millis(800)));
+    assert!(output.contains("typed_over_unix_ws"), "WebSocket input should reach the PTY: {:?}", output);
+}
+
+// ── Protocol negotiation tests ──────────────────────────────────────
+
+#[test]
+fn hello_negotiates_capabilities_and_waits_for_resume() {
+    // Compressible output well past the gzip threshold, and a title
+    let handle = spawn_pty_host(
+        "/bin/sh",
+        &["-c", "printf '\\033]0;negotiated\\007'; yes hello_line | head -2000; sleep 30"],
+    )
+    .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut plain = connect(&handle.socket_path).expect("connect failed");
+    plain.send_hello(PROTOCOL_VERSION, 1 << 30).expect("send_hello failed");
+    let hello = plain.recv_frame().expect("no HELLO reply");
+    assert_eq!(hello.msg_type, WS_MSG_HELLO);
+    assert_eq!(u16::from_be_bytes([hello.data[0], hello.data[1]]), PROTOCOL_VERSION);
+    assert_eq!(u32::from_be_bytes(hello.data[2..6].try_into().unwrap()), 0);
+
+    // No 100ms guess after HELLO: nothing is replayed until RESUME
+    let early = plain.collect_frames(Duration::from_millis(400));
+    assert!(early.is_empty(), "replay sent before RESUME");
+    plain.send_resume(0.0).expect("send_resume failed");
+    let frames = plain.collect_frames(Duration::from_millis(800));
+    assert!(frames.iter().any(|f| f.msg_type == WS_MSG_SYNC));
+    for unwanted in [WS_MSG_BUFFER_REPLAY_GZ, WS_MSG_TITLE, WS_MSG_SESSION_STATE] {
+        assert!(!frames.iter().any(|f| f.msg_type == unwanted), "undeclared type {:#x} sent", unwanted);
+    }
+    assert!(collect_output(&frames).contains("hello_line"));
+
+    let mut rich = connect(&handle.socket_path).expect("connect failed");
+    rich.send_hello(PROTOCOL_VERSION, CAP_BUFFER_REPLAY_GZ | CAP_TITLE | CAP_SESSION_STATE)
+        .expect("send_hello failed");
+    rich.send_resume(0.0).expect("send_resume failed");
+    let frames = rich.collect_frames(Duration::from_millis(800));
+    for wanted in [WS_MSG_HELLO, WS_MSG_BUFFER_REPLAY_GZ, WS_MSG_TITLE, WS_MSG_SESSION_STATE] {
+        assert!(frames.iter().any(|f| f.msg_type == wanted), "declared type {:#x} missing", wanted);
+    }
+}
+
+// ── Slow client tests ───────────────────────────────────────────────
+
+/// A line of flood output as it comes through the PTY (`\n` → `\r\n`).
+const SLOW_LINE: &str = "slow_line\r\n";
+const SLOW_LINES: usize = 100_000;
+
+fn flood_command() -> String {
+    // One write per line, so the output arrives as many small frames
+    format!("sleep 1; i=0; while [ $i -lt {} ]; do echo slow_line; i=$((i+1)); done; sleep 30", SLOW_LINES)
+}
+
+#[test]
+fn lagging_client_is_resynced_without_gaps() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", &flood_command()]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+
+    // Stop reading while the flood overruns the broadcast channel
+    std::thread::sleep(Duration::from_secs(4));
+
+    let expected = SLOW_LINE.len() * SLOW_LINES;
+    let mut frames = Vec::new();
+    let mut offset = 0.0;
+    let mut syncs = 0;
+    let deadline = std::time::Instant::now() + Duration::from_secs(20);
+    while offset < expected as f64 && std::time::Instant::now() < deadline {
+        let Some(frame) = client.recv_frame() else { break };
+        match frame.msg_type {
+            WS_MSG_SYNC => {
+                offset = f64::from_be_bytes(frame.data[..8].try_into().unwrap());
+                syncs += 1;
+            }
+            WS_MSG_DATA => offset += frame.data.len() as f64,
+            _ => {}
+        }
+        frames.push(frame);
+    }
+    assert!(syncs > 0, "lagging client should be resynced");
+    assert_eq!(offset, expected as f64, "client offset should track the output");
+    let output = collect_output(&frames);
+    assert_eq!(output.len(), expected, "resync should neither drop nor repeat output");
+    assert!(output.split_terminator("\r\n").all(|line| line == "slow_line"));
+}
+
+#[test]
+fn lagging_client_is_disconnected_by_policy() {
+    let handle = spawn_pty_host_with_flags(&["--slow-clients", "disconnect"], "/bin/sh", &["-c", &flood_command()])
+        .expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+
+    std::thread::sleep(Duration::from_secs(4));
+
+    let mut received = 0;
+    let start = std::time::Instant::now();
+    while let Some(frame) = client.recv_frame() {
+        received += frame.data.len();
+    }
+    assert!(start.elapsed() < Duration::from_secs(4), "connection should be closed, not idle");
+    assert!(received < SLOW_LINE.len() * SLOW_LINES, "client should be dropped before catching up");
+}
+
+// ── Scrollback search tests ─────────────────────────────────────────
+
+#[test]
+fn search_request_returns_stream_offsets() {
+    let handle = spawn_pty_host(
+        "/bin/sh",
+        &["-c", "printf 'alpha\\n\\033[32mneedle_here\\033[0m\\nomega\\n'; sleep 30"],
+    )
+    .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    // Viewers can search
+    let mut viewer = connect(&handle.socket_path).expect("connect failed");
+    viewer.send_auth(AUTH_ROLE_VIEWER, "").expect("send_auth failed");
+    viewer.send_resume(0.0).expect("send_resume failed");
+    viewer.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    viewer
+        .send_frame(WS_MSG_SEARCH_REQUEST, br#"{"id":3,"query":"NEEDLE_HERE"}"#)
+        .expect("send failed");
+    let frame = viewer
+        .wait_for_message(WS_MSG_SEARCH_RESULTS, Duration::from_secs(2))
+        .expect("no SEARCH_RESULTS");
+    let results: serde_json::Value = serde_json::from_slice(&frame.data).expect("invalid JSON");
+    assert_eq!(results["id"], 3);
+    let matches = results["matches"].as_array().expect("no matches array");
+    assert_eq!(matches.len(), 1, "results: {}", results);
+    assert_eq!(matches[0]["line"], "needle_here");
+    assert_eq!(matches[0]["before"], serde_json::json!(["alpha"]));
+    assert_eq!(matches[0]["after"], serde_json::json!(["omega"]));
+
+    // The offset is a stream offset: resuming from it starts at the match
+    let offset = matches[0]["offset"].as_f64().expect("no offset");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(offset).expect("send_resume failed");
+    let output = collect_output(&client.collect_frames(Duration::from_millis(500)));
+    assert!(output.starts_with("needle_here"), "delta from match offset: {:?}", output);
+
+    viewer
+        .send_frame(WS_MSG_SEARCH_REQUEST, br#"{"query":"(","regex":true}"#)
+        .expect("send failed");
+    let frame = viewer
+        .wait_for_message(WS_MSG_SEARCH_RESULTS, Duration::from_secs(2))
+        .expect("no SEARCH_RESULTS");
+    let results: serde_json::Value = serde_json::from_slice(&frame.data).expect("invalid JSON");
+    assert!(results["error"].is_string(), "invalid regex should be reported");
+}
+
+#[test]
+fn search_request_before_resume_is_served_without_replay() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo needle_early; sleep 30"]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, 0).expect("send_hello failed");
+    client.wait_for_message(WS_MSG_HELLO, Duration::from_secs(2)).expect("no HELLO reply");
+    client
+        .send_frame(WS_MSG_SEARCH_REQUEST, br#"{"id":7,"query":"needle_early"}"#)
+        .expect("send failed");
+    let frames = client.collect_frames(Duration::from_millis(500));
+    let types: Vec<u8> = frames.iter().map(|f| f.msg_type).collect();
+    assert!(
+        !types.contains(&WS_MSG_BUFFER_REPLAY) && !types.contains(&WS_MSG_SYNC),
+        "a request must not force a replay: {:?}",
+        types
+    );
+    let frame = frames
+        .iter()
+        .find(|f| f.msg_type == WS_MSG_SEARCH_RESULTS)
+        .expect("no SEARCH_RESULTS");
+    let results: serde_json::Value = serde_json::from_slice(&frame.data).expect("invalid JSON");
+    assert_eq!(results["id"], 7);
+    assert_eq!(results["matches"].as_array().map(Vec::len), Some(1), "results: {}", results);
+
+    // The handshake is still waiting for RESUME
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+}
+
+#[test]
+fn request_without_hello_is_answered_before_replay() {
+    // Like the server's fetchSparkline: a bare SPARKLINE_REQUEST as the first frame
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo hello && sleep 30"]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(1500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_sparkline_request().expect("send_sparkline_request failed");
+    let frames = client.collect_frames(Duration::from_millis(1000));
+    let types: Vec<u8> = frames.iter().map(|f| f.msg_type).collect();
+    assert_eq!(types.first(), Some(&WS_MSG_SPARKLINE_HISTORY), "frames: {:?}", types);
+    // Without HELLO the RESUME wait still times out into a full replay
+    assert!(types.contains(&WS_MSG_SYNC), "frames: {:?}", types);
+}
+
+// ── Range request tests ─────────────────────────────────────────────
+
+/// Decode RANGE_DATA into (start, end, bytes).
+fn range_data(frame: &Frame) -> (f64, f64, Vec<u8>) {
+    assert_eq!(frame.msg_type, WS_MSG_RANGE_DATA);
+    let start = f64::from_be_bytes(frame.data[..8].try_into().unwrap());
+    let end = f64::from_be_bytes(frame.data[8..16].try_into().unwrap());
+    (start, end, frame.data[16..].to_vec())
+}
+
+#[test]
+fn range_request_serves_tail_then_pages_history() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "seq 1 500; sleep 30"]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    // Tail first: HELLO, a small RANGE_REQUEST up to now, then RESUME from its end
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, 0).expect("send_hello failed");
+    client.wait_for_message(WS_MSG_HELLO, Duration::from_secs(2)).expect("no HELLO reply");
+    client.send_range_request(0.0, 0.0, 64).expect("send failed");
+    let frame = client.wait_for_message(WS_MSG_RANGE_DATA, Duration::from_secs(2)).expect("no RANGE_DATA");
+    let (start, end, tail) = range_data(&frame);
+    let tail = String::from_utf8(tail).unwrap();
+    assert!(tail.len() <= 64 && tail.ends_with("500\r\n"), "tail: {:?}", tail);
+    assert_eq!(end - start, tail.len() as f64);
+
+    client.send_resume(end).expect("send_resume failed");
+    let frames = client.collect_frames(Duration::from_millis(500));
+    assert!(collect_output(&frames).is_empty(), "nothing new since the tail");
+    let sync = frames.iter().find(|f| f.msg_type == WS_MSG_SYNC).expect("no SYNC");
+    assert_eq!(f64::from_be_bytes(sync.data[..8].try_into().unwrap()), end);
+
+    // Page older history in, contiguously and on line boundaries
+    client.send_range_request(0.0, start, 64).expect("send failed");
+    let frame = client.wait_for_message(WS_MSG_RANGE_DATA, Duration::from_secs(2)).expect("no RANGE_DATA");
+    let (older_start, older_end, older) = range_data(&frame);
+    assert_eq!(older_end, start, "pages are contiguous");
+    assert!(older_start < start);
+    let older = String::from_utf8(older).unwrap();
+    assert!(older.ends_with("\r\n") && !older.starts_with('\n'), "page: {:?}", older);
+    let first: u32 = older.lines().next().unwrap().trim().parse().expect("page starts on a line");
+    assert!(first > 1 && first < 500);
+}
+
+// ── Export tests ────────────────────────────────────────────────────
+
+#[test]
+fn export_subcommand_prints_text_and_html() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "printf '\\033[31mexported\\033[0m & done\\n'; sleep 30"])
+        .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let output = run_subcommand(&handle.home_dir, &["export", &handle.session_id]).expect("export failed");
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("exported & done\n"), "text: {:?}", text);
+    assert!(!text.contains('\x1b'), "escapes are stripped");
+
+    let output = run_subcommand(&handle.home_dir, &["export", "--html", &handle.session_id]).expect("export failed");
+    assert!(output.status.success());
+    let html = String::from_utf8(output.stdout).unwrap();
+    assert!(html.contains("<span style=\"color:#cd0000;\">exported</span> &amp; done"), "html: {}", html);
+
+    // Over the socket: viewers may export too
+    let mut viewer = connect(&handle.socket_path).expect("connect failed");
+    viewer.send_auth(AUTH_ROLE_VIEWER, "").expect("send_auth failed");
+    viewer.send_resume(0.0).expect("send_resume failed");
+    viewer.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+    viewer.send_frame(WS_MSG_EXPORT, &[0]).expect("send failed");
+    let frame = viewer.wait_for_message(WS_MSG_EXPORT, Duration::from_secs(2)).expect("no EXPORT reply");
+    assert_eq!(frame.data[0], 0);
+    assert!(String::from_utf8_lossy(&frame.data[1..]).contains("exported & done"));
+
+    let missing = run_subcommand(&handle.home_dir, &["export", "nosuchsession"]).expect("export failed");
+    assert!(!missing.status.success());
+}
+
+// ── Command line tests ──────────────────────────────────────────────
+
+#[test]
+fn spawn_passes_child_login_flag_through() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo \"args:$*\"; sleep 5", "sh", "--login", "-x"])
+        .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let output = collect_output(&client.collect_frames(Duration::from_secs(1)));
+    assert!(output.contains("args:--login -x"), "output: {:?}", output);
+}
+
+#[test]
+fn detached_spawn_then_list_and_kill() {
+    let home_dir = tempfile::tempdir().unwrap();
+    let home = home_dir.path();
+    let output = run_subcommand(home, &["spawn", "--detach", "--cols", "100", "--", "/bin/sh", "-c", "sleep 30"])
+        .expect("spawn failed");
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let id = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    assert_eq!(id.len(), 8, "prints a generated session ID: {:?}", id);
+    assert!(home.join(".relay-tty/sockets").join(format!("{}.sock", id)).exists());
+
+    let list = run_subcommand(home, &["list"]).expect("list failed");
+    let table = String::from_utf8(list.stdout).unwrap();
+    let line = table.lines().find(|l| l.starts_with(&id)).expect("session listed");
+    assert!(line.contains("running") && line.contains("100x24") && line.contains("/bin/sh -c sleep 30"), "{}", line);
+
+    let kill = run_subcommand(home, &["kill", &id]).expect("kill failed");
+    assert!(kill.status.success(), "stderr: {}", String::from_utf8_lossy(&kill.stderr));
+    std::thread::sleep(Duration::from_millis(500));
+
+    let list = run_subcommand(home, &["list", "--json"]).expect("list failed");
+    let sessions: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(sessions[0]["id"], id.as_str());
+    assert_eq!(sessions[0]["status"], "exited");
+    assert!(!run_subcommand(home, &["kill", &id]).unwrap().status.success(), "already exited");
+}
+
+#[test]
+fn attach_subcommand_relays_input_and_detaches() {
+    use std::io::Write;
+
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo ready; read line; echo \"got:$line\"; sleep 30"])
+        .expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut attach = start_subcommand(&handle.home_dir, &["attach", &handle.session_id]).expect("attach failed");
+    let mut stdin = attach.stdin.take().unwrap();
+    stdin.write_all(b"hello\n").unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    stdin.write_all(&[0x1d]).unwrap();
+
+    let output = attach.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(stdout.contains("ready") && stdout.contains("got:hello"), "stdout: {:?}", stdout);
+    assert!(stderr.contains("Detached from"), "stderr: {}", stderr);
+}
+
+#[test]
+fn attach_subcommand_exits_with_session_exit_code() {
+    let handle = spawn_pty_host("/bin/sh", &["-c", "echo bye; sleep 1; exit 3"]).expect("failed to spawn");
+    let attach = start_subcommand(&handle.home_dir, &["attach", &handle.session_id]).expect("attach failed");
+    let output = attach.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(3), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("bye"));
+
+    let missing = run_subcommand(&handle.home_dir, &["attach", "nosuchsession"]).unwrap();
+    assert!(!missing.status.success());
+}
+
+// ── Watch rule tests ────────────────────────────────────────────────
+
+#[test]
+fn watch_rules_notify_mark_and_exit() {
+    let handle = spawn_pty_host_with_flags(&["--watch", "FAILED", "--watch", "mark:^step \\d+"], "/bin/cat", &[])
+        .expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.wait_for_message(WS_MSG_SYNC, Duration::from_secs(2)).expect("no SYNC");
+
+    client.send_data(b"step 1\n").expect("send failed");
+    client.send_data(b"tests FAILED\n").expect("send failed");
+    let notif = client.wait_for_message(WS_MSG_NOTIFICATION, Duration::from_secs(2)).expect("no NOTIFICATION");
+    assert_eq!(String::from_utf8_lossy(&notif.data), "tests FAILED");
+    let meta = read_session_json(&handle.session_path).expect("no session json");
+    let marks = meta["watchMarks"].as_array().expect("no watchMarks");
+    assert_eq!(marks[0]["line"], "step 1");
+
+    // Replace the rules at runtime: hang up on "bye"
+    client.send_frame(WS_MSG_WATCH, br#"{"rules":[{"pattern":"^bye$","action":"exit"}]}"#).expect("send failed");
+    let reply = client.wait_for_message(WS_MSG_WATCH, Duration::from_secs(2)).expect("no WATCH reply");
+    let reply: serde_json::Value = serde_json::from_slice(&reply.data).unwrap();
+    assert_eq!(reply["rules"][0]["action"], "exit");
+    assert!(reply.get("error").is_none());
+    client.send_data(b"bye\n").expect("send failed");
+    client.wait_for_message(WS_MSG_EXIT, Duration::from_secs(3)).expect("session did not exit");
+}
+
+// ── Command history tests ───────────────────────────────────────────
+
+#[test]
+fn osc133_marks_build_command_history() {
+    // A shell prompt cycle: A, B, command line, C, output, D with status
+    let script = "sleep 0.5; printf '\\033]133;A\\007$ \\033]133;B\\007false\\r\\n\\033]133;C\\007'; \
+                  sleep 0.2; printf 'oops\\n\\033]133;D;1\\007\\033]133;A\\007$ '; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, CAP_COMMANDS).expect("send_hello failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let started = client.wait_for_message(WS_MSG_COMMANDS, Duration::from_secs(3)).expect("no start update");
+    let started: serde_json::Value = serde_json::from_slice(&started.data).unwrap();
+    assert_eq!(started["commands"][0]["command"], "false");
+    assert!(started["commands"][0].get("exitCode").is_none());
+    let finished = client.wait_for_message(WS_MSG_COMMANDS, Duration::from_secs(3)).expect("no finish update");
+    let finished: serde_json::Value = serde_json::from_slice(&finished.data).unwrap();
+    let record = &finished["commands"][0];
+    assert_eq!(record["exitCode"], 1);
+    assert!(record["durationMs"].as_u64().unwrap() >= 100);
+
+    // The offsets point at the command's output in the stream
+    let (output, end) = (record["outputOffset"].as_f64().unwrap(), record["endOffset"].as_f64().unwrap());
+    client.send_range_request(output, end, 1024).expect("send failed");
+    let range = client.wait_for_message(WS_MSG_RANGE_DATA, Duration::from_secs(2)).expect("no RANGE_DATA");
+    assert_eq!(&range.data[16..], b"oops\r\n");
+
+    client.send_frame(WS_MSG_COMMANDS, &[]).expect("send failed");
+    let history = client.wait_for_message(WS_MSG_COMMANDS, Duration::from_secs(2)).expect("no history");
+    let history: serde_json::Value = serde_json::from_slice(&history.data).unwrap();
+    assert_eq!(history["commands"].as_array().unwrap().len(), 1);
+    let meta = read_session_json(&handle.session_path).expect("no session json");
+    assert_eq!(meta["lastCommand"]["exitCode"], 1);
+    assert_eq!(meta["lastCommand"]["command"], "false");
+}
+
+// ── Link index tests ────────────────────────────────────────────────
+
+#[test]
+fn links_message_returns_osc8_index() {
+    let script = "sleep 0.5; printf 'built \\033]8;;file:///tmp/out.txt\\033\\\\out.txt\\033]8;;\\033\\\\\\n'; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let frames = client.collect_frames(Duration::from_secs(1));
+    // Links reach live clients intact
+    assert!(collect_output(&frames).contains("\x1b]8;;file:///tmp/out.txt\x1b\\out.txt"));
+
+    client.send_frame(WS_MSG_LINKS, &[]).expect("send failed");
+    let reply = client.wait_for_message(WS_MSG_LINKS, Duration::from_secs(2)).expect("no LINKS reply");
+    let reply: serde_json::Value = serde_json::from_slice(&reply.data).unwrap();
+    let link = &reply["links"][0];
+    assert_eq!(link["uri"], "file:///tmp/out.txt");
+    assert_eq!(link["text"], "out.txt");
+    assert_eq!(link["offset"], 6.0);
+}
+
+#[test]
+fn reconnecting_client_replays_osc8_links() {
+    // The link is drawn over a placeholder, so only the synthesized screen
+    // (not the raw byte log) has it in the right place
+    let script = "printf 'see XXXX\\033[5G\\033]8;;https://example.com/\\033\\\\docs\\033]8;;\\033\\\\ now\\n'; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    for _ in 0..2 {
+        let mut client = connect(&handle.socket_path).expect("connect failed");
+        client.send_resume(0.0).expect("send_resume failed");
+        let replay = collect_output(&client.collect_frames(Duration::from_secs(1)));
+        assert!(
+            replay.contains("see \x1b]8;;https://example.com/\x1b\\docs\x1b]8;;\x1b\\ now"),
+            "replay: {replay:?}"
+        );
+    }
+}
+
+#[test]
+fn kitty_graphics_answers_queries_and_sends_images() {
+    // The probe reply arrives on the program's stdin, as from a real kitty
+    let script = "stty raw -echo; sleep 0.5; \
+        printf '\\033_Gi=1,a=q,s=1,v=1,f=24;AAAA\\033\\\\'; \
+        printf 'reply=%s\\n' \"$(head -c 11 | tr '\\033' E)\"; \
+        printf '\\033_Ga=T,f=100,m=1;UE\\033\\\\'; printf '\\033_Gm=0;5H\\033\\\\'; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, CAP_IMAGE).expect("send_hello failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let frames = client.collect_frames(Duration::from_secs(2));
+    let image = frames.iter().find(|f| f.msg_type == WS_MSG_IMAGE).expect("no IMAGE frame");
+    let id_len = u32::from_be_bytes(image.data[..4].try_into().unwrap()) as usize;
+    let id = String::from_utf8_lossy(&image.data[4..4 + id_len]).to_string();
+    assert!(image.data.ends_with(b"image/png\0PNG"));
+
+    let output = collect_output(&frames);
+    assert!(output.contains("reply=E_Gi=1;OKE\\"), "output: {output:?}");
+    assert!(output.contains(&format!("[image: {}]", id)));
+    assert!(!output.contains("\x1b_G"), "graphics commands are stripped");
+}
+
+#[test]
+fn sixel_images_become_png_image_frames() {
+    let script = "sleep 0.5; printf 'chart\\n\\033Pq\"1;1;2;6#0;2;100;0;0#0!2~\\033\\\\after\\n'; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, CAP_IMAGE).expect("send_hello failed");
+    client.send_resume(0.0).expect("send_resume failed");
+
+    let frames = client.collect_frames(Duration::from_secs(1));
+    let image = frames.iter().find(|f| f.msg_type == WS_MSG_IMAGE).expect("no IMAGE frame");
+    let id_len = u32::from_be_bytes(image.data[..4].try_into().unwrap()) as usize;
+    let id = String::from_utf8_lossy(&image.data[4..4 + id_len]).to_string();
+    assert!(image.data[4 + id_len..].starts_with(b"image/png\0\x89PNG"));
+
+    let output = collect_output(&frames);
+    assert!(output.contains(&format!("[image: {}]", id)));
+    assert!(output.contains("after"));
+    assert!(!output.contains("\x1bPq"), "sixel data is stripped");
+}
+
+#[test]
+fn late_joiners_get_replayed_images_and_can_request_them() {
+    // "PNG" base64 = "UE5H"
+    let script = "printf 'before\\n\\033]1337;File=inline=1:UE5H\\007after\\n'; sleep 30";
+    let handle = spawn_pty_host("/bin/sh", &["-c", script]).expect("failed to spawn");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_hello(PROTOCOL_VERSION, CAP_IMAGE).expect("send_hello failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    let frames = client.collect_frames(Duration::from_secs(1));
+    let image = frames.iter().find(|f| f.msg_type == WS_MSG_IMAGE).expect("image not replayed");
+    let id_len = u32::from_be_bytes(image.data[..4].try_into().unwrap()) as usize;
+    let id = image.data[4..4 + id_len].to_vec();
+    assert!(image.data.ends_with(b"image/png\0PNG"));
+    assert!(collect_output(&frames).contains(&format!("[image: {}]", String::from_utf8_lossy(&id))));
+
+    client.send_frame(WS_MSG_IMAGE_REQUEST, &id).expect("send failed");
+    let fetched = client.wait_for_message(WS_MSG_IMAGE, Duration::from_secs(2)).expect("no IMAGE reply");
+    assert_eq!(fetched.data, image.data);
+
+    client.send_frame(WS_MSG_IMAGE_REQUEST, b"img-999999").expect("send failed");
+    assert!(client.wait_for_message(WS_MSG_IMAGE, Duration::from_millis(500)).is_none());
+}
+
+#[test]
+fn osc52_query_reads_clipboard_set_by_client() {
+    // The program waits for a keypress, so the clipboard is set before it asks
+    let script = "stty raw -echo; head -c 1 >/dev/null; printf '\\033]52;c;?\\007'; \
+        printf 'reply=%s\\n' \"$(head -c 12 | tr '\\033\\007' EB)\"; sleep 30";
+    let handle =
+        spawn_pty_host_with_flags(&["--clipboard-read"], "/bin/sh", &["-c", script]).expect("failed to spawn");
+    let mut client = connect(&handle.socket_path).expect("connect failed");
+    client.send_resume(0.0).expect("send_resume failed");
+    client.collect_frames(Duration::from_millis(200));
+    client.send_frame(WS_MSG_CLIPBOARD_SET, b"hi").expect("send failed");
+    client.send_frame(WS_MSG_DATA, b"x").expect("send failed");
+
+    let frames = client.collect_frames(Duration::from_secs(2));
+    let output = collect_output(&frames);
+    // "hi" base64 = "aGk="
+    assert!(output.contains("reply=E]52;c;aGk=B"), "output: {output:?}");
+}
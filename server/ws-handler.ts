@@ -253,7 +253,10 @@ export class WsHandler {
 
   /**
    * Read-only connection: receives output but cannot send input or resize.
-   * Only RESUME messages are forwarded so the client can do delta replay.
+   * Only HELLO, RESUME, SEARCH_REQUEST, RANGE_REQUEST, EXPORT, WATCH, COMMANDS,
+   * LINKS and IMAGE_REQUEST messages are forwarded (negotiation, delta replay,
+   * search, history paging, export, watch rule queries — pty-host refuses rule
+   * changes from viewers — command history, the link index and stored images).
    */
   private handleReadOnlyConnection(ws: WebSocket, sessionId: string): void {
     this.initKeepAlive(ws);
@@ -272,6 +275,13 @@ export class WsHandler {
     }
 
     const ptySocket = net.createConnection(socketPath);
+    // Declare the viewer role before RESUME so pty-host itself drops input,
+    // even if something gets past the filter below.
+    const auth = Buffer.from([WS_MSG.AUTH, 0x00]);
+    const authHeader = Buffer.alloc(4);
+    authHeader.writeUInt32BE(auth.length, 0);
+    ptySocket.write(authHeader);
+    ptySocket.write(auth);
     let pending = Buffer.alloc(0);
     let paused = false;
 
@@ -308,7 +318,7 @@ export class WsHandler {
       if (ws.readyState === WebSocket.OPEN) ws.close();
     });
 
-    // Only forward RESUME messages from read-only clients (for delta replay)
+    // Only forward HELLO, RESUME, SEARCH_REQUEST, RANGE_REQUEST, EXPORT, WATCH, COMMANDS, LINKS and IMAGE_REQUEST from read-only clients
     ws.on("message", (data: Buffer) => {
       if (data.length < 1) return;
       // Respond to application-level PING with PONG
@@ -316,7 +326,17 @@ export class WsHandler {
         if (ws.readyState === WebSocket.OPEN) ws.send(Buffer.from([WS_MSG.PONG]));
         return;
       }
-      if (data[0] === WS_MSG.RESUME && ptySocket.writable) {
+      const forwarded =
+        data[0] === WS_MSG.HELLO ||
+        data[0] === WS_MSG.RESUME ||
+        data[0] === WS_MSG.SEARCH_REQUEST ||
+        data[0] === WS_MSG.RANGE_REQUEST ||
+        data[0] === WS_MSG.EXPORT ||
+        data[0] === WS_MSG.WATCH ||
+        data[0] === WS_MSG.COMMANDS ||
+        data[0] === WS_MSG.LINKS ||
+        data[0] === WS_MSG.IMAGE_REQUEST;
+      if (forwarded && ptySocket.writable) {
         const header = Buffer.alloc(4);
         header.writeUInt32BE(data.length, 0);
         ptySocket.write(header);
@@ -378,19 +398,6 @@ export class WsHandler {
         const payload = Buffer.from(pending.subarray(4, 4 + msgLen));
         pending = pending.subarray(4 + msgLen);
 
-        // IMAGE messages from pty-host (OSC 1337): broadcast to all clients
-        if (payload.length > 0 && payload[0] === WS_MSG.IMAGE) {
-          const clients = this.sessionClients.get(sessionId);
-          if (clients) {
-            for (const client of clients) {
-              if (client.readyState === WebSocket.OPEN) {
-                client.send(payload);
-              }
-            }
-          }
-          continue;
-        }
-
         // CLIPBOARD messages from pty-host (OSC 52): broadcast to all clients
         if (payload.length > 0 && payload[0] === WS_MSG.CLIPBOARD) {
           const clients = this.sessionClients.get(sessionId);
@@ -5,6 +5,8 @@
  * Payload format: [1 byte type][data]
  */
 
+import { WS_MSG, PROTOCOL_VERSION } from "./types.js";
+
 /** Write a length-prefixed frame to a buffer-like target. */
 export function encodeFrame(payload: Buffer): Buffer {
   const header = Buffer.alloc(4);
@@ -12,6 +14,15 @@ export function encodeFrame(payload: Buffer): Buffer {
   return Buffer.concat([header, payload]);
 }
 
+/** HELLO payload declaring the optional message types a client handles (see CAP). */
+export function encodeHello(capabilities: number): Buffer {
+  const payload = Buffer.alloc(7);
+  payload[0] = WS_MSG.HELLO;
+  payload.writeUInt16BE(PROTOCOL_VERSION, 1);
+  payload.writeUInt32BE(capabilities, 3);
+  return payload;
+}
+
 /**
  * Parse length-prefixed frames from a stream buffer.
  * Calls `handler` for each complete frame.
@@ -53,10 +53,10 @@ export function buildSpawnArgs(
   if (isShellCommand(command)) {
     // Shell commands: spawn directly as a login shell using the standard
     // Unix argv[0] convention (same as iTerm2/tmux/Terminal.app).
-    // pty-host detects the --login flag and sets argv[0] to "-<shell>"
-    // which tells the shell to behave as a login shell and source
-    // /etc/zprofile, ~/.zprofile, ~/.zshrc, ~/.zlogin etc.
-    return [id, String(cols), String(rows), cwd, command, "--login", ...args];
+    // pty-host's --login flag sets argv[0] to "-<shell>" which tells the
+    // shell to behave as a login shell and source /etc/zprofile,
+    // ~/.zprofile, ~/.zshrc, ~/.zlogin etc.
+    return [...spawnFlags(id, cols, rows, cwd), "--login", "--", command, ...args];
   }
 
   // Non-shell commands: wrap in an interactive login shell (-li) so the
@@ -66,7 +66,12 @@ export function buildSpawnArgs(
   const fullCmd = args.length > 0
     ? `exec ${shellEscape(command)} ${args.map(shellEscape).join(" ")}`
     : `exec ${shellEscape(command)}`;
-  return [id, String(cols), String(rows), cwd, userShell, "-li", "-c", fullCmd];
+  return [...spawnFlags(id, cols, rows, cwd), "--", userShell, "-li", "-c", fullCmd];
+}
+
+/** `relay-pty-host spawn` options for a session; the command follows `--`. */
+function spawnFlags(id: string, cols: number, rows: number, cwd: string): string[] {
+  return ["spawn", "--id", id, "--cols", String(cols), "--rows", String(rows), "--cwd", cwd];
 }
 
 /**
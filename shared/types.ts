@@ -28,6 +28,10 @@ export interface Session {
   bps15?: number;
   /** Name of the foreground process (absent when shell itself is in foreground) */
   foregroundProcess?: string;
+  /** Matches recorded by `mark` watch rules, oldest first */
+  watchMarks?: WatchMark[];
+  /** Most recent command from OSC 133 marks: running, or last finished */
+  lastCommand?: CommandRecord;
 }
 
 export const WS_MSG = {
@@ -52,7 +56,7 @@ export const WS_MSG = {
   SESSION_UPDATE: 0x15,
   /** Bidirectional: clipboard text sync between devices [UTF-8 text]. */
   CLIPBOARD: 0x16,
-  /** Server→client: inline image from iTerm2 OSC 1337 [4B id_len][id UTF-8][mime UTF-8 NUL-terminated][raw image bytes]. */
+  /** Server→client: inline image from iTerm2 OSC 1337, kitty graphics or sixel [4B id_len][id UTF-8][mime UTF-8 NUL-terminated][raw image bytes]. */
   IMAGE: 0x17,
   /** Client→server: 1-byte heartbeat probe. */
   PING: 0x20,
@@ -66,8 +70,153 @@ export const WS_MSG = {
   DETACH: 0x22,
   /** Client→server: clear scrollback ring buffer (no payload). */
   CLEAR_SCROLLBACK: 0x23,
+  /** Client→server (replay mode): player control [1B op: 0=pause, 1=play, 2=seek, 3=speed][f64 seconds/multiplier for seek/speed]. */
+  PLAYBACK_CONTROL: 0x24,
+  /** Server→client (replay mode): player position [1B paused][f64 position][f64 duration][f64 speed]. */
+  PLAYBACK_STATE: 0x25,
+  /** Client→server: request a role, sent before RESUME [1B role: 0=viewer, 1=read-write][write token UTF-8 (read-write only)]. */
+  AUTH: 0x26,
+  /** Bidirectional: protocol negotiation, sent first [u16 version BE][u32 capability bits BE]. The reply carries the version and capabilities in use. */
+  HELLO: 0x27,
+  /** Client→server: search buffered output [UTF-8 JSON of SearchRequest]. */
+  SEARCH_REQUEST: 0x28,
+  /** Server→client: answer to SEARCH_REQUEST [UTF-8 JSON of SearchResults]. */
+  SEARCH_RESULTS: 0x29,
+  /** Client→server: fetch older output [f64 start][f64 end, 0 = now][optional u32 max bytes], all BE. */
+  RANGE_REQUEST: 0x2a,
+  /** Server→client: answer to RANGE_REQUEST [f64 start][f64 end][raw output bytes], cut at line/escape-safe boundaries. */
+  RANGE_DATA: 0x2b,
+  /** Bidirectional: export buffered output. Client→server [1B format: 0=text, 1=HTML]; server→client [1B format][UTF-8 document]. */
+  EXPORT: 0x2c,
+  /** Bidirectional: output watch rules. Client→server [UTF-8 JSON of WatchRequest, or empty to query]; server→client [UTF-8 JSON of WatchReply]. */
+  WATCH: 0x2d,
+  /** Bidirectional: OSC 133 command history. Client→server: empty; server→client [UTF-8 JSON of CommandsMessage], as a reply or a live update. */
+  COMMANDS: 0x2e,
+  /** Bidirectional: OSC 8 link index. Client→server: empty; server→client [UTF-8 JSON of LinksMessage]. */
+  LINKS: 0x2f,
+  /** Client→server: fetch a stored image [image id UTF-8]; answered with IMAGE, or nothing once evicted. */
+  IMAGE_REQUEST: 0x30,
+  /** Client→server: set the session clipboard that answers OSC 52 read queries (with --clipboard-read) [UTF-8 text]. Read-write clients only. */
+  CLIPBOARD_SET: 0x31,
 } as const;
 
+/** Protocol version sent in HELLO. */
+export const PROTOCOL_VERSION = 1;
+
+/** HELLO capability bits: optional server→client message types a client accepts. */
+export const CAP = {
+  BUFFER_REPLAY_GZ: 1 << 0,
+  TITLE: 1 << 1,
+  NOTIFICATION: 1 << 2,
+  SESSION_STATE: 1 << 3,
+  SESSION_METRICS: 1 << 4,
+  CLIPBOARD: 1 << 5,
+  IMAGE: 1 << 6,
+  SPARKLINE: 1 << 7,
+  PLAYBACK: 1 << 8,
+  COMMANDS: 1 << 9,
+} as const;
+
+/** Server-side search over pty-host's output buffer (SEARCH_REQUEST). */
+export interface SearchRequest {
+  /** Echoed in SearchResults. */
+  id?: number;
+  query: string;
+  /** Treat query as a regular expression (default: literal). */
+  regex?: boolean;
+  caseSensitive?: boolean;
+  /** Context lines either side of each match (default 2, max 10). */
+  context?: number;
+  /** Maximum matches (default 100, max 1000). */
+  limit?: number;
+}
+
+export interface SearchMatch {
+  /** Stream offset of the match, in the same coordinates as SYNC. */
+  offset: number;
+  /** Match length in stream bytes. */
+  length: number;
+  /** The matching line, without escape sequences. */
+  line: string;
+  before: string[];
+  after: string[];
+}
+
+export interface SearchResults {
+  id: number;
+  matches: SearchMatch[];
+  /** More matches exist than the requested limit. */
+  truncated: boolean;
+  /** Set for invalid requests, e.g. a bad regex. */
+  error?: string;
+}
+
+/** Output watch rule: a regex matched against each line of output, without escapes. */
+export interface WatchRule {
+  pattern: string;
+  /** notify: NOTIFICATION; exit: hang up the session; mark: record a WatchMark (default notify). */
+  action?: "notify" | "exit" | "mark";
+  /** Notification text (default: the matching line). */
+  message?: string;
+}
+
+export interface WatchMark {
+  pattern: string;
+  line: string;
+  /** Stream offset of the start of the line, in the same coordinates as SYNC. */
+  offset: number;
+  /** Milliseconds since the epoch. */
+  at: number;
+}
+
+/** WATCH request: replaces the session's rules (read-write clients only). */
+export interface WatchRequest {
+  rules: WatchRule[];
+}
+
+export interface WatchReply {
+  /** Rules in effect. */
+  rules: WatchRule[];
+  /** Set when a request was rejected; the previous rules stay in effect. */
+  error?: string;
+}
+
+/** A command delimited by OSC 133 marks. Offsets are stream offsets, as in SYNC. */
+export interface CommandRecord {
+  id: number;
+  promptOffset: number;
+  inputOffset?: number;
+  outputOffset?: number;
+  endOffset?: number;
+  /** The command line as displayed. */
+  command?: string;
+  /** Milliseconds since the epoch. */
+  startedAt?: number;
+  /** Absent while the command runs, like durationMs and exitCode. */
+  finishedAt?: number;
+  durationMs?: number;
+  exitCode?: number;
+}
+
+export interface CommandsMessage {
+  /** Oldest first. A live update carries the one record that changed. */
+  commands: CommandRecord[];
+}
+
+/** An OSC 8 hyperlink seen in the session's output. */
+export interface LinkRecord {
+  uri: string;
+  /** Display text, without escapes. */
+  text: string;
+  /** Stream offset of the sequence that opened the link, as in SYNC. */
+  offset: number;
+}
+
+export interface LinksMessage {
+  /** Oldest first. */
+  links: LinkRecord[];
+}
+
 export interface CreateSessionRequest {
   command: string;
   args?: string[];